@@ -0,0 +1,109 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use rust_htslib::{bam, bam::HeaderView, bam::Record, errors::Error};
+
+use crate::reservoir::RecordSet;
+
+// where the samplers read records from: an htslib reader, or anything else that hands records over
+// in queryname order, e.g. a stream fetched over htsget or a fixture in memory
+pub trait RecordSource {
+    // the next record into `r`, as bam::Read::read does; None at the end
+    fn read_record(&mut self, r: &mut Record) -> Option<Result<(), Error>>;
+
+    // where the next record is, for a checkpoint to go back to; -1 if the source cannot tell
+    fn position(&self) -> i64 {
+        -1
+    }
+
+    // go back to a `position`; only sources that can tell where they are can
+    fn seek_to(&mut self, _offset: i64) -> Result<(), Error> {
+        Err(Error::FileSeek)
+    }
+}
+
+impl<R: bam::Read> RecordSource for R {
+    fn read_record(&mut self, r: &mut Record) -> Option<Result<(), Error>> {
+        self.read(r)
+    }
+
+    fn position(&self) -> i64 {
+        self.tell()
+    }
+
+    fn seek_to(&mut self, offset: i64) -> Result<(), Error> {
+        self.seek(offset)
+    }
+}
+
+// records from an iterator, e.g. those of `parse_sam`
+pub struct Records<I>(pub I);
+
+impl<I: Iterator<Item = Record>> RecordSource for Records<I> {
+    fn read_record(&mut self, r: &mut Record) -> Option<Result<(), Error>> {
+        let x = self.0.next()?;
+        *r = x;
+        Some(Ok(()))
+    }
+}
+
+// hand `f` the templates of `infh` one at a time, as they are read: the records of a qname, which a
+// queryname-sorted input has one after another
+pub fn each_template<R: RecordSource + ?Sized, F: FnMut(RecordSet)>(infh: &mut R, mut f: F) -> Result<(), Error> {
+    let mut rs: RecordSet = RecordSet::new();
+    loop {
+        let mut r = Record::new();
+        match infh.read_record(&mut r) {
+            None => break,
+            Some(x) => x?,
+        }
+        if !rs.is_empty() && rs[0].qname() != r.qname() {
+            f(std::mem::take(&mut rs));
+        }
+        rs.push(r);
+    }
+    if !rs.is_empty() {
+        f(rs);
+    }
+    Ok(())
+}
+
+// where sampled records go: an htslib writer, or a Vec to look at afterwards
+pub trait RecordSink {
+    fn write_record(&mut self, r: &Record) -> Result<(), Error>;
+}
+
+impl RecordSink for bam::Writer {
+    fn write_record(&mut self, r: &Record) -> Result<(), Error> {
+        self.write(r)
+    }
+}
+
+impl RecordSink for Vec<Record> {
+    fn write_record(&mut self, r: &Record) -> Result<(), Error> {
+        self.push(r.clone());
+        Ok(())
+    }
+}
+
+// write the records of every template to `out`; returns the number of records written
+pub fn write_templates<W: RecordSink + ?Sized>(out: &mut W, templates: &[RecordSet]) -> Result<usize, Error> {
+    let mut n = 0;
+    for r in templates.iter().flatten() {
+        out.write_record(r)?;
+        n += 1;
+    }
+    Ok(n)
+}
+
+// the header and records of SAM text, so that the samplers can be run on a few lines written out in
+// place rather than on a file
+pub fn parse_sam(text: &str) -> Result<(HeaderView, Vec<Record>), Error> {
+    let (head, body): (Vec<&str>, Vec<&str>) = text.lines().filter(|l| !l.is_empty()).partition(|l| l.starts_with('@'));
+    let header = HeaderView::from_bytes((head.join("\n") + "\n").as_bytes());
+    let records = body.iter().map(|l| Record::from_sam(&header, l.as_bytes())).collect::<Result<Vec<Record>, Error>>()?;
+    Ok((header, records))
+}
@@ -0,0 +1,86 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::io::Write;
+use rust_htslib::bam::Record;
+
+// where the sampled templates lie, to view the footprint of the sample or intersect it with annotations
+// without the BAM: a BED6 line for every reference a template is aligned to, spanning its primary
+// alignments there, or a BEDPE line for every template, its first and last read in their own columns
+pub struct Spans {
+    fh: Box<dyn Write>,
+    paired: bool,
+}
+
+// the reference of a record and where it starts and ends, 0-based and half-open, None if unmapped
+fn span(r: &Record) -> Option<(i32, i64, i64)> {
+    match r.is_unmapped() || r.tid() < 0 {
+        true => None,
+        false => Some((r.tid(), r.pos(), r.cigar().end_pos())),
+    }
+}
+
+fn strand(r: &Record) -> char {
+    if r.is_reverse() { '-' } else { '+' }
+}
+
+impl Spans {
+    // BEDPE if `path` ends in .bedpe or .bedpe.gz, BED otherwise
+    pub fn new(fh: Box<dyn Write>, path: &str) -> Spans {
+        let paired = path.trim_end_matches(".gz").ends_with(".bedpe");
+        Spans { fh, paired }
+    }
+
+    // `rs` are the records of a template as written and `refs` names the tids; unmapped templates are
+    // left out
+    pub fn add(&mut self, rs: &[Record], refs: &[String]) -> std::io::Result<()> {
+        let primary: Vec<&Record> = rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary()).collect();
+        if primary.iter().all(|r| span(r).is_none()) {
+            return Ok(());
+        }
+        let chrom = |tid: i32| refs.get(tid as usize).map_or("*", |x| x.as_str());
+        match self.paired {
+            true => {
+                // mate 1, or the read of a single-end template, and mate 2, as BEDPE has them: . -1 -1 .
+                // for a mate missing or unmapped
+                let first = primary.iter().find(|r| !r.is_paired() || r.is_first_in_template()).or(primary.first());
+                let last = primary.iter().find(|r| r.is_paired() && r.is_last_in_template());
+                let mut columns = Vec::new();
+                for r in [first, last] {
+                    match r.and_then(|r| span(r).map(|s| (s, strand(r)))) {
+                        Some(((tid, start, end), s)) => columns.push((format!("{}\t{}\t{}", chrom(tid), start, end), s)),
+                        None => columns.push((".\t-1\t-1".to_string(), '.')),
+                    }
+                }
+                write!(self.fh, "{}\t{}\t", columns[0].0, columns[1].0)?;
+                self.fh.write_all(primary[0].qname())?;
+                writeln!(self.fh, "\t0\t{}\t{}", columns[0].1, columns[1].1)
+            }
+            false => {
+                // a line for every reference, in the order the template first reaches it
+                let mut spans: Vec<(i32, i64, i64, char)> = Vec::new();
+                for r in &primary {
+                    if let Some((tid, start, end)) = span(r) {
+                        match spans.iter_mut().find(|s| s.0 == tid) {
+                            Some(s) => { s.1 = s.1.min(start); s.2 = s.2.max(end); },
+                            None => spans.push((tid, start, end, strand(r))),
+                        }
+                    }
+                }
+                for (tid, start, end, s) in spans {
+                    write!(self.fh, "{}\t{}\t{}\t", chrom(tid), start, end)?;
+                    self.fh.write_all(primary[0].qname())?;
+                    writeln!(self.fh, "\t0\t{}", s)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.fh.flush()
+    }
+}
@@ -0,0 +1,130 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read as _};
+use std::mem::size_of;
+use rust_htslib::{bam, bam::Read, bam::Record, bgzf};
+use log::error;
+
+use crate::ospath;
+use crate::reservoir::RecordSet;
+use crate::status::{self, Code};
+
+// bytes a template takes up in the reservoir on average, judging by the first `n` records of `path`
+pub fn template_size(path: &str, n: usize) -> usize {
+    let mut fh = match bam::Reader::from_path(ospath::path(path)) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("failed to read {}: {}", path, e);
+            status::fail(Code::Malformed);
+        },
+    };
+    let mut r = Record::new();
+    let mut qname: Vec<u8> = Vec::new();
+    let mut bytes = 0;
+    let mut templates = 0;
+    for _ in 0..n {
+        match fh.read(&mut r) {
+            Some(Ok(())) => {},
+            _ => break,
+        }
+        bytes += size_of::<Record>() + r.inner().m_data as usize;
+        if templates == 0 || qname != r.qname() {
+            templates += 1;
+            bytes += size_of::<RecordSet>();
+            qname.clear();
+            qname.extend_from_slice(r.qname());
+        }
+    }
+    match templates {
+        0 => 0,
+        _ => bytes / templates,
+    }
+}
+
+// templates in `path`, going by its first `n` records: all of them if it has no more, else its size over
+// the bytes the templates among those take up in it, by compressed offset in a BAM; None for a gzipped
+// SAM, whose compressed offsets are out of reach. A template is counted by its first primary read, so
+// that records need not be grouped by qname
+pub fn template_count(path: &str, n: usize) -> Option<usize> {
+    let size = fs::metadata(ospath::path(path)).map(|m| m.len()).unwrap_or_default();
+    let mut magic = [0u8; 2];
+    let gzipped = File::open(ospath::path(path)).and_then(|mut f| f.read_exact(&mut magic)).is_ok() && magic == [0x1f, 0x8b];
+    let mut templates: usize = 0;
+    let mut bytes: u64 = 0;
+    let mut ended = true;
+    if !gzipped {
+        // plain SAM, read as text so that the bytes are known
+        let fh = BufReader::new(File::open(ospath::path(path)).ok()?);
+        let mut records = 0;
+        for line in fh.split(b'\n') {
+            let line = line.ok()?;
+            if records == n {
+                ended = false;
+                break;
+            }
+            bytes += line.len() as u64 + 1;
+            if line.first() == Some(&b'@') {
+                continue;
+            }
+            records += 1;
+            let flag: u16 = line.split(|&c| c == b'\t').nth(1).and_then(|x| std::str::from_utf8(x).ok()?.parse().ok()).unwrap_or_default();
+            if is_head(flag) {
+                templates += 1;
+            }
+        }
+    } else {
+        let mut fh = bam::Reader::from_path(ospath::path(path)).ok()?;
+        if !is_bam(path) {
+            return None;
+        }
+        let mut r = Record::new();
+        for _ in 0..n {
+            match fh.read(&mut r) {
+                Some(Ok(())) => {},
+                _ => break,
+            }
+            if is_head(r.flags()) {
+                templates += 1;
+            }
+        }
+        ended = fh.read(&mut r).is_none();
+        bytes = (fh.tell() >> 16) as u64;
+    }
+    match (ended, bytes) {
+        (true, _) | (false, 0) => Some(templates),
+        _ => Some((templates as f64 * size as f64 / bytes as f64).round() as usize),
+    }
+}
+
+// templates in the input at `path`, counted exactly in a pass over its records, by the one each is
+// counted by whatever order they are in; None if it cannot be read through
+pub fn exact_template_count(path: &str, threads: usize) -> Option<usize> {
+    let mut fh = bam::Reader::from_path(ospath::path(path)).ok()?;
+    if threads > 1 {
+        fh.set_threads(threads).ok()?;
+    }
+    let mut r = Record::new();
+    let mut templates = 0;
+    while let Some(x) = fh.read(&mut r) {
+        x.ok()?;
+        templates += usize::from(is_head(r.flags()));
+    }
+    Some(templates)
+}
+
+// whether a record of these flags is the one a template is counted by: its primary first read, or its
+// only one
+fn is_head(flag: u16) -> bool {
+    flag & 0x900 == 0 && (flag & 0x1 == 0 || flag & 0x40 != 0)
+}
+
+// whether the BGZF file at `path` holds a BAM rather than a SAM
+fn is_bam(path: &str) -> bool {
+    let mut magic = [0u8; 4];
+    bgzf::Reader::from_path(ospath::path(path)).is_ok_and(|mut f| f.read_exact(&mut magic).is_ok()) && &magic == b"BAM\x01"
+}
@@ -0,0 +1,229 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::fs;
+use rust_htslib::{bam, bam::Read};
+use log::error;
+
+use crate::ospath;
+use crate::reservoir::RecordSet;
+use crate::rng::Kind;
+use crate::status::{self, Code};
+
+static PREFIX: &str = "sam_subsample-checkpoint ";
+
+// where a run stood right before the template starting at `offset` (a BGZF virtual offset) of
+// input number `file`; the reservoir contents are stored alongside as the records of the checkpoint
+#[derive(Default)]
+pub struct State {
+    pub infiles: Vec<String>,
+    pub num: usize,
+    pub seed: u64,
+    // the generator drawn from, per --rng; None for a file of a run before --rng, which drew from PCG64
+    pub rng: Option<Kind>,
+    pub seen: usize,
+    pub file: usize,
+    pub offset: i64,
+    pub n_broken: usize,
+    pub n_singletons: usize,
+    pub n_unmapped: usize,
+    pub n_tagged: usize,
+    pub n_timed: usize,
+    pub n_off_contigs: usize,
+    pub n_excluded: usize,
+    pub n_off_regions: usize,
+    pub n_off_sites: usize,
+    pub n_improper: usize,
+    pub n_off_tlen: usize,
+    pub n_duplicates: usize,
+    pub n_unflagged: usize,
+    pub n_divergent: usize,
+    pub n_clipped: usize,
+    pub n_splicing: usize,
+    pub n_misnamed: usize,
+    pub n_off_tiles: usize,
+    pub n_half_mapped: usize,
+    pub n_overlapping: usize,
+    // the tallies of --filter-stats: the templates looked at, and per filter those it turned down first
+    // and in all
+    pub filter_templates: usize,
+    pub filter_first: Vec<usize>,
+    pub filter_any: Vec<usize>,
+    // the references of the header the records were read under, as loaded, for --load-state to check the
+    // inputs against
+    pub refs: Vec<String>,
+}
+
+// write the checkpoint as a BAM whose @CO lines carry the state and whose records are the reservoir;
+// it goes to a temporary file first so that a run killed mid-write leaves the previous one intact
+pub fn save(path: &str, header: &bam::Header, state: &State, v: &[RecordSet]) {
+    let mut header = header.clone();
+    for infile in &state.infiles {
+        header.push_comment(format!("{}infile={}", PREFIX, infile).as_bytes());
+    }
+    if let Some(rng) = state.rng {
+        header.push_comment(format!("{}rng={}", PREFIX, rng.name()).as_bytes());
+    }
+    for (k, x) in [
+        ("num", state.num.to_string()),
+        ("seed", state.seed.to_string()),
+        ("seen", state.seen.to_string()),
+        ("file", state.file.to_string()),
+        ("offset", state.offset.to_string()),
+        ("broken", state.n_broken.to_string()),
+        ("singletons", state.n_singletons.to_string()),
+        ("unmapped", state.n_unmapped.to_string()),
+        ("tagged", state.n_tagged.to_string()),
+        ("timed", state.n_timed.to_string()),
+        ("off_contigs", state.n_off_contigs.to_string()),
+        ("excluded", state.n_excluded.to_string()),
+        ("off_regions", state.n_off_regions.to_string()),
+        ("off_sites", state.n_off_sites.to_string()),
+        ("improper", state.n_improper.to_string()),
+        ("off_tlen", state.n_off_tlen.to_string()),
+        ("duplicates", state.n_duplicates.to_string()),
+        ("unflagged", state.n_unflagged.to_string()),
+        ("divergent", state.n_divergent.to_string()),
+        ("clipped", state.n_clipped.to_string()),
+        ("splicing", state.n_splicing.to_string()),
+        ("misnamed", state.n_misnamed.to_string()),
+        ("off_tiles", state.n_off_tiles.to_string()),
+        ("half_mapped", state.n_half_mapped.to_string()),
+        ("overlapping", state.n_overlapping.to_string()),
+        ("filter_templates", state.filter_templates.to_string()),
+        ("filter_first", list(&state.filter_first)),
+        ("filter_any", list(&state.filter_any)),
+    ] {
+        header.push_comment(format!("{}{}={}", PREFIX, k, x).as_bytes());
+    }
+    let tmp = format!("{}.tmp", path);
+    {
+        let mut fh = match bam::Writer::from_path(ospath::path(&tmp), &header, bam::Format::Bam) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("failed to write checkpoint {}: {}", tmp, e);
+                status::fail(Code::Io);
+            },
+        };
+        for rs in v {
+            for r in rs {
+                if let Err(e) = fh.write(r) {
+                    error!("failed to write checkpoint {}: {}", tmp, e);
+                    status::fail(Code::Io);
+                }
+            }
+        }
+    }
+    if let Err(e) = fs::rename(ospath::path(&tmp), ospath::path(path)) {
+        error!("failed to move checkpoint {} in place: {}", tmp, e);
+        status::fail(Code::Io);
+    }
+}
+
+// counts separated by commas, as a @CO line holds them
+fn list(v: &[usize]) -> String {
+    v.iter().map(usize::to_string).collect::<Vec<_>>().join(",")
+}
+
+// read a checkpoint back; records are regrouped into templates by qname, in the order they were kept
+pub fn load(path: &str) -> (State, Vec<RecordSet>) {
+    let mut fh = match bam::Reader::from_path(ospath::path(path)) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("failed to read checkpoint {}: {}", path, e);
+            status::fail(Code::Malformed);
+        },
+    };
+    let mut state = State::default();
+    let header = bam::Header::from_template(fh.header());
+    state.refs = crate::header::names(&header);
+    for line in header.comments() {
+        let (k, x) = match line.strip_prefix(PREFIX).and_then(|l| l.split_once('=')) {
+            Some(a) => a,
+            None => continue,
+        };
+        let bad = || -> ! {
+            error!("malformed checkpoint {}: {}={}", path, k, x);
+            status::fail(Code::Malformed);
+        };
+        match k {
+            "infile" => state.infiles.push(x.to_string()),
+            "num" => state.num = x.parse().unwrap_or_else(|_| bad()),
+            "seed" => state.seed = x.parse().unwrap_or_else(|_| bad()),
+            "rng" => state.rng = Some(Kind::parse(x).unwrap_or_else(|| bad())),
+            "seen" => state.seen = x.parse().unwrap_or_else(|_| bad()),
+            "file" => state.file = x.parse().unwrap_or_else(|_| bad()),
+            "offset" => state.offset = x.parse().unwrap_or_else(|_| bad()),
+            "broken" => state.n_broken = x.parse().unwrap_or_else(|_| bad()),
+            "singletons" => state.n_singletons = x.parse().unwrap_or_else(|_| bad()),
+            "unmapped" => state.n_unmapped = x.parse().unwrap_or_else(|_| bad()),
+            "tagged" => state.n_tagged = x.parse().unwrap_or_else(|_| bad()),
+            "timed" => state.n_timed = x.parse().unwrap_or_else(|_| bad()),
+            "off_contigs" => state.n_off_contigs = x.parse().unwrap_or_else(|_| bad()),
+            "excluded" => state.n_excluded = x.parse().unwrap_or_else(|_| bad()),
+            "off_regions" => state.n_off_regions = x.parse().unwrap_or_else(|_| bad()),
+            "off_sites" => state.n_off_sites = x.parse().unwrap_or_else(|_| bad()),
+            "improper" => state.n_improper = x.parse().unwrap_or_else(|_| bad()),
+            "off_tlen" => state.n_off_tlen = x.parse().unwrap_or_else(|_| bad()),
+            "duplicates" => state.n_duplicates = x.parse().unwrap_or_else(|_| bad()),
+            "unflagged" => state.n_unflagged = x.parse().unwrap_or_else(|_| bad()),
+            "divergent" => state.n_divergent = x.parse().unwrap_or_else(|_| bad()),
+            "clipped" => state.n_clipped = x.parse().unwrap_or_else(|_| bad()),
+            "splicing" => state.n_splicing = x.parse().unwrap_or_else(|_| bad()),
+            "misnamed" => state.n_misnamed = x.parse().unwrap_or_else(|_| bad()),
+            "off_tiles" => state.n_off_tiles = x.parse().unwrap_or_else(|_| bad()),
+            "half_mapped" => state.n_half_mapped = x.parse().unwrap_or_else(|_| bad()),
+            "overlapping" => state.n_overlapping = x.parse().unwrap_or_else(|_| bad()),
+            "filter_templates" => state.filter_templates = x.parse().unwrap_or_else(|_| bad()),
+            "filter_first" => state.filter_first = x.split(',').filter(|y| !y.is_empty()).map(|y| y.parse().unwrap_or_else(|_| bad())).collect(),
+            "filter_any" => state.filter_any = x.split(',').filter(|y| !y.is_empty()).map(|y| y.parse().unwrap_or_else(|_| bad())).collect(),
+            _ => {},
+        }
+    }
+    if state.infiles.is_empty() {
+        error!("{} is not a sam_subsample checkpoint!", path);
+        status::fail(Code::Malformed);
+    }
+    let mut v: Vec<RecordSet> = Vec::new();
+    for r in fh.records() {
+        let r = r.unwrap_or_else(|e| {
+            error!("malformed checkpoint {}: {}", path, e);
+            status::fail(Code::Malformed)
+        });
+        match v.last_mut() {
+            Some(rs) if rs[0].qname() == r.qname() => rs.push(r),
+            _ => v.push(vec![r]),
+        }
+    }
+    (state, v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_come_back_as_saved() {
+        let path = crate::temp_path(&format!("checkpoint.{}.bam", std::process::id()));
+        let mut state = State::default();
+        state.infiles = vec![String::from("a.bam"), String::from("b.bam")];
+        (state.num, state.seed, state.seen, state.file, state.offset) = (10, 43, 1000, 1, 12345);
+        state.rng = Some(Kind::ChaCha);
+        (state.n_unflagged, state.n_divergent, state.n_clipped, state.n_splicing) = (1, 2, 3, 4);
+        (state.n_misnamed, state.n_off_tiles, state.n_half_mapped, state.n_overlapping) = (5, 6, 7, 8);
+        (state.filter_templates, state.filter_first, state.filter_any) = (1000, vec![3, 0, 2], vec![4, 1, 2]);
+        save(&path, &bam::Header::new(), &state, &[]);
+        let (back, v) = load(&path);
+        fs::remove_file(ospath::path(&path)).unwrap();
+        assert!(v.is_empty());
+        assert_eq!(back.infiles, state.infiles);
+        assert_eq!((back.num, back.seed, back.seen, back.file, back.offset), (10, 43, 1000, 1, 12345));
+        assert_eq!(back.rng, Some(Kind::ChaCha));
+        assert_eq!((back.n_unflagged, back.n_divergent, back.n_clipped, back.n_splicing), (1, 2, 3, 4));
+        assert_eq!((back.n_misnamed, back.n_off_tiles, back.n_half_mapped, back.n_overlapping), (5, 6, 7, 8));
+        assert_eq!((back.filter_templates, back.filter_first, back.filter_any), (1000, vec![3, 0, 2], vec![4, 1, 2]));
+    }
+}
@@ -0,0 +1,180 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::mem::size_of;
+use rust_htslib::{bam, bam::Read, bam::Record, bam::HeaderView};
+use log::{error, info, warn};
+
+use crate::ospath;
+use crate::{group_key, interrupt, open_output, read_or_fail, GroupBy};
+use crate::status::{self, Code};
+
+// temporary files the records are spread over by the hash of their qname, as samtools collate does: as
+// many as it takes for each to be sorted within the memory budget, judging by the input's size, and
+// within these bounds; for a pipe, whose size is unknown, the fewest
+const MIN_BUCKETS: usize = 64;
+const MAX_BUCKETS: usize = 1024;
+// how much bigger records are in memory than in a BAM, about
+const INFLATION: u64 = 4;
+// the memory budget for a bucket without --max-mem
+pub const BUDGET: usize = 1 << 30;
+// times a bucket over the budget is spread over buckets of its own again, before it is sorted whatever
+// it takes, as one template alone may be over it
+const MAX_SPILLS: u32 = 3;
+// records looked at to tell whether an input is grouped
+const CHECKED: usize = 1_000_000;
+
+// whether the records of every template (or ZMW, per `group_by`) of `infh` are next to each other:
+// unaligned BAMs, without @SQ lines, always have them so; any other goes by its first records, none of
+// whose templates may turn up again once another has started, and then by its @HD, which has to say it
+// is sorted by queryname, in whichever collation its SS says, or grouped by query as collated inputs
+// are, unless those records were all of it. A template split further on is caught while sampling (see
+// --on-regrouped)
+pub fn is_grouped(infh: &mut bam::Reader, group_by: GroupBy) -> bool {
+    if infh.header().target_count() == 0 {
+        return true;
+    }
+    let text = String::from_utf8_lossy(infh.header().as_bytes()).into_owned();
+    let said = text.split('\n').filter(|l| l.starts_with("@HD")).flat_map(|l| l.split('\t')).any(|f| f == "SO:queryname" || f == "GO:query");
+    let mut done: HashSet<Vec<u8>> = HashSet::new();
+    let mut current: Vec<u8> = Vec::new();
+    let mut r = Record::new();
+    for _ in 0..CHECKED {
+        match infh.read(&mut r) {
+            Some(Ok(())) => {},
+            _ => return true,
+        }
+        let key = group_key(r.qname(), group_by);
+        if key != current.as_slice() {
+            if done.contains(key) {
+                return false;
+            }
+            done.insert(std::mem::replace(&mut current, key.to_vec()));
+        }
+    }
+    said
+}
+
+// write the records of `infile` to `outfile` with those of a template (or ZMW, per `group_by`) next to
+// each other, in the order they were read: spread over buckets by the hash of their qname, then each
+// bucket sorted by qname in memory, within `budget` bytes, or spread again if it is over it; the header
+// gets @HD SO:unsorted GO:query
+pub fn collate(infile: &str, outfile: &str, group_by: GroupBy, threads: usize, budget: usize) {
+    let mut infh = match bam::Reader::from_path(ospath::path(infile)) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("failed to read {}: {}", infile, e);
+            status::fail(Code::Malformed)
+        },
+    };
+    if threads > 1 {
+        infh.set_threads(threads).expect("failed to set --threads!");
+    }
+    let collator = Collator { outfile, header: grouped_header(infh.header()), group_by, budget };
+    let size = fs::metadata(ospath::path(infile)).ok().filter(|m| m.is_file()).map_or(0, |m| m.len());
+    let n = ((size * INFLATION).div_ceil(budget.max(1) as u64) as usize).clamp(MIN_BUCKETS, MAX_BUCKETS);
+    let mut outfh = open_output(outfile, &collator.header, threads);
+    let (buckets, records) = collator.spread(&mut infh, outfile, n, 0);
+    info!("{} records of {} spread over {} buckets, collating them.", records, infile, n);
+    for (path, bytes) in buckets {
+        collator.sort(&path, bytes, &mut outfh, 1);
+    }
+}
+
+// what the buckets of a collation go by: the output they are named after, its header, how templates
+// are told apart and the bytes of records a bucket may have in memory
+struct Collator<'a> {
+    outfile: &'a str,
+    header: bam::Header,
+    group_by: GroupBy,
+    budget: usize,
+}
+
+impl Collator<'_> {
+    // spread the records of `infh` over `n` buckets named after `prefix` by the hash of their group
+    // key, salted with `salt` so that a bucket spread again does not all go to one bucket; the buckets,
+    // with the bytes their records take up in memory, and the records spread
+    fn spread(&self, infh: &mut bam::Reader, prefix: &str, n: usize, salt: u32) -> (Vec<(String, usize)>, usize) {
+        let paths: Vec<String> = (0..n).map(|i| format!("{}.{}.{}.bam", prefix, salt, i)).collect();
+        let mut buckets: Vec<bam::Writer> = paths.iter().map(|p| open_output(p, &self.header, 1)).collect();
+        let mut bytes = vec![0; n];
+        let mut r = Record::new();
+        let mut records: usize = 0;
+        while read_or_fail(infh, &mut r) {
+            if interrupt::stopping() {
+                drop(buckets);
+                remove(&paths);
+                error!("Interrupted while collating into {}.", self.outfile);
+                status::fail(Code::Interrupted);
+            }
+            let mut h = DefaultHasher::new();
+            salt.hash(&mut h);
+            group_key(r.qname(), self.group_by).hash(&mut h);
+            let i = h.finish() as usize % n;
+            crate::write(&mut buckets[i], &r, &paths[i]);
+            bytes[i] += size_of::<Record>() + r.inner().m_data as usize;
+            records += 1;
+        }
+        (paths.into_iter().zip(bytes).collect(), records)
+    }
+
+    // write the records of the bucket at `path`, of `bytes` in memory, to `outfh` sorted by group key,
+    // stably, so that the records of a template stay in input order; one over the budget is spread over
+    // buckets of its own and those sorted in turn, `spills` deep
+    fn sort(&self, path: &str, bytes: usize, outfh: &mut bam::Writer, spills: u32) {
+        let mut fh = match bam::Reader::from_path(ospath::path(path)) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("failed to read {}: {}", path, e);
+                status::fail(Code::Io)
+            },
+        };
+        if bytes > self.budget && spills <= MAX_SPILLS {
+            let n = bytes.div_ceil(self.budget.max(1)).clamp(2, MAX_BUCKETS);
+            info!("{} takes {} bytes, over the budget of {}; spreading it over {} buckets.", path, bytes, self.budget, n);
+            let (buckets, _) = self.spread(&mut fh, path, n, spills);
+            drop(fh);
+            let _ = fs::remove_file(ospath::path(path));
+            for (p, b) in buckets {
+                self.sort(&p, b, outfh, spills + 1);
+            }
+            return;
+        }
+        if bytes > self.budget {
+            warn!("{} takes {} bytes, over the budget of {}, after spreading it {} times; sorting it anyway.", path, bytes, self.budget, MAX_SPILLS);
+        }
+        let mut rs: Vec<Record> = fh.records().map(|r| r.unwrap_or_else(|e| {
+            error!("failed to read {}: {}", path, e);
+            status::fail(Code::Io)
+        })).collect();
+        rs.sort_by(|a, b| group_key(a.qname(), self.group_by).cmp(group_key(b.qname(), self.group_by)));
+        for r in &rs {
+            crate::write(outfh, r, self.outfile);
+        }
+        let _ = fs::remove_file(ospath::path(path));
+    }
+}
+
+// the header of `view` marked as grouped by query, in no particular sort order
+fn grouped_header(view: &HeaderView) -> bam::Header {
+    let header = crate::header::with_sort_order(&bam::Header::from_template(view), "unsorted");
+    let text = String::from_utf8_lossy(&header.to_bytes()).into_owned();
+    let lines: Vec<String> = text.split('\n').filter(|l| !l.is_empty()).map(|l| match l.starts_with("@HD") {
+        true => l.split('\t').filter(|f| !f.starts_with("GO:")).collect::<Vec<_>>().join("\t") + "\tGO:query",
+        false => l.to_string(),
+    }).collect();
+    bam::Header::from_template(&HeaderView::from_bytes((lines.join("\n") + "\n").as_bytes()))
+}
+
+pub fn remove(paths: &[String]) {
+    for path in paths {
+        let _ = fs::remove_file(ospath::path(path));
+    }
+}
@@ -0,0 +1,185 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+#[cfg(not(feature = "arrow"))]
+use rust_htslib::bam::Record;
+
+// which file --arrow-out writes, by its extension
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Ipc,
+    Parquet,
+}
+
+impl Format {
+    pub fn of(path: &str) -> Option<Format> {
+        match path.rsplit_once('.').map(|x| x.1) {
+            Some("arrow") | Some("feather") | Some("ipc") => Some(Format::Ipc),
+            Some("parquet") => Some(Format::Parquet),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Ipc => "Arrow IPC",
+            Format::Parquet => "Parquet",
+        }
+    }
+}
+
+// the records written, a row each, as an Arrow IPC or Parquet file for DataFusion, Polars or pyarrow to
+// read without a BAM parser: the columns of --metadata-out, typed, plus the CIGAR, then a string column
+// for every tag asked for; the chrom, pos and CIGAR of an unmapped record, and tags it lacks, are null
+#[cfg(feature = "arrow")]
+pub use self::arrow_sink::Columns;
+
+#[cfg(feature = "arrow")]
+mod arrow_sink {
+    use std::fs::File;
+    use std::sync::Arc;
+    use arrow::array::{ArrayRef, Int64Builder, StringBuilder, UInt16Builder, UInt32Builder, UInt8Builder};
+    use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+    use arrow::ipc::writer::FileWriter;
+    use arrow::record_batch::RecordBatch;
+    use parquet::arrow::ArrowWriter;
+    use rust_htslib::bam::Record;
+
+    use super::Format;
+    use crate::tags;
+
+    // rows buffered before they go out as a record batch, or a row group of Parquet
+    const BATCH: usize = 65_536;
+
+    enum Writer {
+        Ipc(FileWriter<File>),
+        Parquet(ArrowWriter<File>),
+    }
+
+    pub struct Columns {
+        writer: Writer,
+        schema: SchemaRef,
+        tags: Vec<[u8; 2]>,
+        qname: StringBuilder,
+        flag: UInt16Builder,
+        chrom: StringBuilder,
+        pos: Int64Builder,
+        mapq: UInt8Builder,
+        cigar: StringBuilder,
+        tlen: Int64Builder,
+        length: UInt32Builder,
+        values: Vec<StringBuilder>,
+        rows: usize,
+    }
+
+    impl Columns {
+        pub fn new(path: &str, format: Format, tags: Vec<[u8; 2]>) -> Result<Columns, String> {
+            let mut fields = vec![
+                Field::new("qname", DataType::Utf8, false),
+                Field::new("flag", DataType::UInt16, false),
+                Field::new("chrom", DataType::Utf8, true),
+                Field::new("pos", DataType::Int64, true),
+                Field::new("mapq", DataType::UInt8, false),
+                Field::new("cigar", DataType::Utf8, true),
+                Field::new("tlen", DataType::Int64, false),
+                Field::new("length", DataType::UInt32, false),
+            ];
+            fields.extend(tags.iter().map(|t| Field::new(String::from_utf8_lossy(t), DataType::Utf8, true)));
+            let schema: SchemaRef = Arc::new(Schema::new(fields));
+            let fh = File::create(crate::ospath::path(path)).map_err(|e| e.to_string())?;
+            let writer = match format {
+                Format::Ipc => Writer::Ipc(FileWriter::try_new(fh, &schema).map_err(|e| e.to_string())?),
+                Format::Parquet => Writer::Parquet(ArrowWriter::try_new(fh, schema.clone(), None).map_err(|e| e.to_string())?),
+            };
+            Ok(Columns {
+                writer,
+                schema,
+                values: tags.iter().map(|_| StringBuilder::new()).collect(),
+                tags,
+                qname: StringBuilder::new(),
+                flag: UInt16Builder::new(),
+                chrom: StringBuilder::new(),
+                pos: Int64Builder::new(),
+                mapq: UInt8Builder::new(),
+                cigar: StringBuilder::new(),
+                tlen: Int64Builder::new(),
+                length: UInt32Builder::new(),
+                rows: 0,
+            })
+        }
+
+        // `refs` names the tids; the position is 1-based, as in SAM
+        pub fn add(&mut self, r: &Record, refs: &[String]) -> Result<(), String> {
+            let mapped = r.tid() >= 0 && !r.is_unmapped();
+            self.qname.append_value(String::from_utf8_lossy(r.qname()));
+            self.flag.append_value(r.flags());
+            self.chrom.append_option(refs.get(r.tid() as usize).filter(|_| r.tid() >= 0));
+            self.pos.append_option((r.pos() >= 0).then(|| r.pos() + 1));
+            self.mapq.append_value(r.mapq());
+            self.cigar.append_option((mapped && !r.raw_cigar().is_empty()).then(|| r.cigar().to_string()));
+            self.tlen.append_value(r.insert_size());
+            self.length.append_value(r.seq_len() as u32);
+            for (tag, values) in self.tags.iter().zip(self.values.iter_mut()) {
+                values.append_option(r.aux(tag).ok().and_then(|a| tags::text(&a)));
+            }
+            self.rows += 1;
+            if self.rows == BATCH {
+                self.flush()?;
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), String> {
+            if self.rows == 0 {
+                return Ok(());
+            }
+            let mut columns: Vec<ArrayRef> = vec![
+                Arc::new(self.qname.finish()),
+                Arc::new(self.flag.finish()),
+                Arc::new(self.chrom.finish()),
+                Arc::new(self.pos.finish()),
+                Arc::new(self.mapq.finish()),
+                Arc::new(self.cigar.finish()),
+                Arc::new(self.tlen.finish()),
+                Arc::new(self.length.finish()),
+            ];
+            columns.extend(self.values.iter_mut().map(|v| Arc::new(v.finish()) as ArrayRef));
+            let batch = RecordBatch::try_new(self.schema.clone(), columns).map_err(|e| e.to_string())?;
+            self.rows = 0;
+            match &mut self.writer {
+                Writer::Ipc(w) => w.write(&batch).map_err(|e| e.to_string()),
+                Writer::Parquet(w) => w.write(&batch).map_err(|e| e.to_string()),
+            }
+        }
+
+        pub fn finish(mut self) -> Result<(), String> {
+            self.flush()?;
+            match self.writer {
+                Writer::Ipc(mut w) => w.finish().map_err(|e| e.to_string()),
+                Writer::Parquet(w) => w.close().map(|_| ()).map_err(|e| e.to_string()),
+            }
+        }
+    }
+}
+
+// without the arrow feature, --arrow-out is turned down before anything is read
+#[cfg(not(feature = "arrow"))]
+pub struct Columns;
+
+#[cfg(not(feature = "arrow"))]
+impl Columns {
+    pub fn new(_path: &str, _format: Format, _tags: Vec<[u8; 2]>) -> Result<Columns, String> {
+        Err(String::from("sam_subsample was built without the arrow feature"))
+    }
+
+    pub fn add(&mut self, _r: &Record, _refs: &[String]) -> Result<(), String> {
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        Ok(())
+    }
+}
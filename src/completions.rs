@@ -0,0 +1,130 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    Flag,
+    Opt,
+    Multi,
+    // a flag that may be given more than once, e.g. -vv
+    Count,
+}
+
+// short name, long name, description, value hint, kind; as handed to getopts
+pub type OptDef = (&'static str, &'static str, &'static str, &'static str, Kind);
+
+static SHELLS: &[&str] = &["bash", "zsh", "fish"];
+
+// the values listed after "choose from" in a description, e.g. 'keep', 'drop', 'only'
+fn choices(desc: &str) -> Vec<&str> {
+    let rest = match desc.find("choose from ") {
+        Some(i) => &desc[i..],
+        None => return Vec::new(),
+    };
+    let rest = rest.split('(').next().unwrap_or_default();
+    rest.split('\'').skip(1).step_by(2).collect()
+}
+
+fn is_file(hint: &str) -> bool {
+    hint == "FILE"
+}
+
+fn names(o: &OptDef) -> Vec<String> {
+    let mut v = Vec::new();
+    if !o.0.is_empty() {
+        v.push(format!("-{}", o.0));
+    }
+    v.push(format!("--{}", o.1));
+    v
+}
+
+fn bash(prog: &str, opts: &[OptDef]) -> String {
+    let func = format!("_{}", prog.replace('-', "_"));
+    let mut s = format!("{}() {{\n", func);
+    s += "    local cur prev\n";
+    s += "    cur=\"${COMP_WORDS[COMP_CWORD]}\"\n";
+    s += "    prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n";
+    s += "    case \"$prev\" in\n";
+    s += &format!("        completions)\n            COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n            return;;\n", SHELLS.join(" "));
+    for o in opts.iter().filter(|o| !matches!(o.4, Kind::Flag | Kind::Count)) {
+        let action = match choices(o.2) {
+            c if !c.is_empty() => format!("COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))", c.join(" ")),
+            _ if is_file(o.3) => String::from("COMPREPLY=($(compgen -f -- \"$cur\"))"),
+            _ => String::from("COMPREPLY=()"),
+        };
+        s += &format!("        {})\n            {}\n            return;;\n", names(o).join("|"), action);
+    }
+    s += "    esac\n";
+    let words: Vec<String> = opts.iter().flat_map(names).collect();
+    s += "    if [ \"$COMP_CWORD\" -eq 1 ]; then\n";
+    s += &format!("        COMPREPLY=($(compgen -W \"completions {}\" -- \"$cur\"))\n", words.join(" "));
+    s += "    else\n";
+    s += &format!("        COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n", words.join(" "));
+    s += "    fi\n";
+    s += "}\n";
+    s += &format!("complete -F {} {}\n", func, prog);
+    s
+}
+
+fn zsh(prog: &str, opts: &[OptDef]) -> String {
+    let mut s = format!("#compdef {}\n\n_arguments \\\n", prog);
+    s += "    '1::subcommand:((completions\\:\"print a shell completion script\"))' \\\n";
+    s += &format!("    '2::shell:({})' \\\n", SHELLS.join(" "));
+    for o in opts {
+        let desc = o.2.replace('\'', "'\\''").replace('[', "\\[").replace(']', "\\]").replace(':', "\\:");
+        let ns = names(o);
+        let spec = match (o.4, ns.len()) {
+            (Kind::Multi | Kind::Count, 1) => format!("'*{}[{}]", ns[0], desc),
+            (Kind::Multi | Kind::Count, _) => format!("'*'{{{}}}'[{}]", ns.join(","), desc),
+            (_, 1) => format!("'{}[{}]", ns[0], desc),
+            (_, _) => format!("'({})'{{{}}}'[{}]", ns.join(" "), ns.join(","), desc),
+        };
+        let value = match o.4 {
+            Kind::Flag | Kind::Count => String::new(),
+            _ => match choices(o.2) {
+                c if !c.is_empty() => format!(":{}:({})", o.1, c.join(" ")),
+                _ if is_file(o.3) => format!(":{}:_files", o.1),
+                _ => format!(":{}: ", o.1),
+            },
+        };
+        s += &format!("    {}{}' \\\n", spec, value);
+    }
+    s.truncate(s.len() - 3);
+    s += "\n";
+    s
+}
+
+fn fish(prog: &str, opts: &[OptDef]) -> String {
+    let mut s = format!("complete -c {} -n '__fish_use_subcommand' -f -a completions -d 'print a shell completion script'\n", prog);
+    s += &format!("complete -c {} -n '__fish_seen_subcommand_from completions' -f -a '{}'\n", prog, SHELLS.join(" "));
+    for o in opts {
+        let mut line = format!("complete -c {}", prog);
+        if !o.0.is_empty() {
+            line += &format!(" -s {}", o.0);
+        }
+        line += &format!(" -l {} -d '{}'", o.1, o.2.replace('\\', "\\\\").replace('\'', "\\'"));
+        if !matches!(o.4, Kind::Flag | Kind::Count) {
+            match choices(o.2) {
+                c if !c.is_empty() => line += &format!(" -x -a '{}'", c.join(" ")),
+                _ if is_file(o.3) => line += " -r -F",
+                _ => line += " -x",
+            }
+        }
+        s += &line;
+        s += "\n";
+    }
+    s
+}
+
+// completion script of `prog` for `shell`, or None if the shell is not supported
+pub fn script(shell: &str, prog: &str, opts: &[OptDef]) -> Option<String> {
+    match shell {
+        "bash" => Some(bash(prog, opts)),
+        "zsh" => Some(zsh(prog, opts)),
+        "fish" => Some(fish(prog, opts)),
+        _ => None,
+    }
+}
@@ -0,0 +1,112 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use rust_htslib::bam::Record;
+
+use crate::ospath;
+use crate::stats::{self, Signature};
+
+// fractions of the input depth the curve of --complexity is given at: subsamples of it, then
+// extrapolations past it
+static DEPTHS: &[f64] = &[0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 20.0, 50.0, 100.0];
+
+// the curve, from the counts-of-counts `hist` of `n` templates: below the depth sequenced, the
+// expected distinct molecules of a subsample of m templates drawn without replacement (rarefaction);
+// past it, Chao and Jost's extrapolation from the molecules seen once and twice, which assumes
+// nothing of the abundances but holds up to a few times the depth sequenced and flattens towards the
+// Chao1 estimate of the library size beyond
+struct Curve {
+    n: usize,
+    distinct: usize,
+    f1: f64,
+    // estimated molecules never seen
+    f0: f64,
+    hist: BTreeMap<usize, usize>,
+}
+
+impl Curve {
+    fn new(hist: BTreeMap<usize, usize>) -> Curve {
+        let n: usize = hist.iter().map(|(&j, &c)| j * c).sum();
+        let distinct: usize = hist.values().sum();
+        let f1 = hist.get(&1).copied().unwrap_or_default() as f64;
+        let f2 = hist.get(&2).copied().unwrap_or_default() as f64;
+        let scale = (n as f64 - 1.0) / n as f64;
+        // bias-corrected when no molecule was seen twice
+        let f0 = match f2 > 0.0 {
+            true => scale * f1 * f1 / (2.0 * f2),
+            false => scale * f1 * (f1 - 1.0).max(0.0) / 2.0,
+        };
+        Curve { n, distinct, f1, f0, hist }
+    }
+
+    fn library_size(&self) -> f64 {
+        self.distinct as f64 + self.f0
+    }
+
+    fn at(&self, m: f64) -> f64 {
+        let n = self.n as f64;
+        if m <= n {
+            // a molecule seen j times is missed by the subsample with probability C(n - j, m) / C(n, m)
+            let m = m.round();
+            self.hist.iter().map(|(&j, &c)| {
+                let miss: f64 = (0..j).map(|i| ((n - m - i as f64) / (n - i as f64)).max(0.0)).product();
+                c as f64 * (1.0 - miss)
+            }).sum()
+        } else if self.f0 > 0.0 {
+            let extra = m - n;
+            self.distinct as f64 + self.f0 * (1.0 - (1.0 - self.f1 / (n * self.f0 + self.f1)).powf(extra))
+        } else {
+            self.distinct as f64
+        }
+    }
+}
+
+// how many times every molecule was seen, a molecule being the templates of one position signature
+// (see `stats::signature`), to tell how many distinct molecules more sequencing would turn up
+#[derive(Default)]
+pub struct Complexity {
+    counts: HashMap<Signature, usize>,
+}
+
+impl Complexity {
+    pub fn add(&mut self, rs: &[Record]) {
+        let sig = stats::signature(rs);
+        if !sig.is_empty() {
+            *self.counts.entry(sig).or_default() += 1;
+        }
+    }
+
+    // molecules seen once, twice, ...
+    fn histogram(&self) -> BTreeMap<usize, usize> {
+        let mut hist = BTreeMap::new();
+        for &c in self.counts.values() {
+            *hist.entry(c).or_default() += 1;
+        }
+        hist
+    }
+
+    // the curve as a TSV of templates sequenced against the distinct molecules expected among them,
+    // and the estimated library size; None if no mapped template was seen
+    pub fn write(&self, path: &str) -> std::io::Result<Option<f64>> {
+        let curve = Curve::new(self.histogram());
+        let mut fh = BufWriter::new(File::create(ospath::path(path))?);
+        writeln!(fh, "relative_depth\ttemplates\tdistinct\tkind")?;
+        if curve.n == 0 {
+            fh.flush()?;
+            return Ok(None);
+        }
+        for &t in DEPTHS {
+            let m = t * curve.n as f64;
+            let kind = if t <= 1.0 { "interpolated" } else { "extrapolated" };
+            writeln!(fh, "{}\t{:.0}\t{:.1}\t{}", t, m, curve.at(m), kind)?;
+        }
+        fh.flush()?;
+        Ok(Some(curve.library_size()))
+    }
+}
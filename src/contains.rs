@@ -0,0 +1,201 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::collections::HashMap;
+use std::io::Write;
+use getopts::Options;
+use rust_htslib::bam::{self, HeaderView, Read, Record};
+use log::{error, info};
+
+use crate::completions::{OptDef, Kind};
+use crate::ospath;
+use crate::status::{self, Code, bad_args};
+use crate::LogFormat;
+
+// the options of `sam_subsample contains`
+static OPTIONS: &[OptDef] = &[
+    ("", "source", "BAM/SAM the candidate is claimed to be a subsample of, in any order", "FILE", Kind::Opt),
+    ("", "candidate", "BAM/SAM claimed to be a subsample of --source, in any order; held in memory", "FILE", Kind::Opt),
+    ("", "records", "also require every record of a candidate template to be in the source as it is, not only its qname", "", Kind::Flag),
+    ("o", "outfile", "TSV of the templates of the candidate not in the source, and why (default: None)", "FILE", Kind::Opt),
+    ("h", "help", "print usage", "", Kind::Flag),
+];
+
+fn usage(opts: &Options) {
+    let s = "\
+Summary:
+Check that every template of a candidate BAM is in a source BAM, as it is of a subsample of it
+
+Usage:
+sam_subsample contains --source source.bam --candidate candidate.bam [--records] [--outfile missing.tsv]";
+    println!("{}", opts.usage(s));
+}
+
+fn open(path: &str) -> bam::Reader {
+    if !ospath::path(path).exists() {
+        error!("{} does not exist!", path);
+        status::fail(Code::Missing);
+    }
+    bam::Reader::from_path(ospath::path(path)).unwrap_or_else(|e| {
+        error!("failed to read {}: {}", path, e);
+        status::fail(Code::Malformed)
+    })
+}
+
+// every record of an input, to `f`
+fn for_each_record<F: FnMut(&Record)>(infh: &mut bam::Reader, path: &str, mut f: F) {
+    let mut r = Record::new();
+    while let Some(x) = infh.read(&mut r) {
+        if let Err(e) = x {
+            error!("failed to read a record of {}: {}", path, e);
+            status::fail(Code::Malformed);
+        }
+        f(&r);
+    }
+}
+
+fn name(header: &HeaderView, tid: i32) -> Vec<u8> {
+    match tid {
+        x if x < 0 => b"*".to_vec(),
+        x => header.tid2name(x as u32).to_vec(),
+    }
+}
+
+// a record as --records compares it: its fields, the references by name so that the headers need not
+// agree on their order, and its qname, CIGAR, sequence, qualities and tags as they are stored
+fn signature(r: &Record, header: &HeaderView) -> Vec<u8> {
+    let mut s = Vec::new();
+    s.extend_from_slice(&r.flags().to_le_bytes());
+    s.extend_from_slice(&name(header, r.tid()));
+    s.push(0);
+    s.extend_from_slice(&r.pos().to_le_bytes());
+    s.push(r.mapq());
+    s.extend_from_slice(&name(header, r.mtid()));
+    s.push(0);
+    s.extend_from_slice(&r.mpos().to_le_bytes());
+    s.extend_from_slice(&r.insert_size().to_le_bytes());
+    s.extend_from_slice(r.data());
+    s
+}
+
+// a template of the candidate: its records, and their signatures with --records, along with which of
+// them the source has been found to have so far, and whether it has the qname at all
+struct Template {
+    n: usize,
+    records: Vec<Vec<u8>>,
+    found: Vec<bool>,
+    seen: bool,
+}
+
+// `sam_subsample contains`, given the arguments after it
+pub fn run(args: &[String]) {
+    let mut opts = Options::new();
+    for &(short, long, desc, hint, kind) in OPTIONS {
+        match kind {
+            Kind::Flag => opts.optflag(short, long, desc),
+            _ => opts.optopt(short, long, desc, hint),
+        };
+    }
+    let m = opts.parse(args).unwrap_or_else(|e| bad_args(&format!("failed to parse arguments: {}", e)));
+    if m.opt_present("h") {
+        usage(&opts);
+        return;
+    }
+    let source = m.opt_str("source").unwrap_or_else(|| bad_args("--source is required!"));
+    let candidate = m.opt_str("candidate").unwrap_or_else(|| bad_args("--candidate is required!"));
+    let records = m.opt_present("records");
+    crate::init_logger("info", None, None, LogFormat::Plain);
+
+    // the candidate, a subsample, is the smaller; it is held by qname, and the source streamed past it
+    let mut infh = open(&candidate);
+    let header = infh.header().clone();
+    let mut templates: HashMap<Vec<u8>, Template> = HashMap::new();
+    let mut order: Vec<Vec<u8>> = Vec::new();
+    let mut n_records = 0;
+    for_each_record(&mut infh, &candidate, |r| {
+        n_records += 1;
+        let t = templates.entry(r.qname().to_vec()).or_insert_with(|| {
+            order.push(r.qname().to_vec());
+            Template { n: 0, records: Vec::new(), found: Vec::new(), seen: false }
+        });
+        t.n += 1;
+        if records {
+            t.records.push(signature(r, &header));
+            t.found.push(false);
+        }
+    });
+    info!("{} templates ({} records) in {}.", templates.len(), n_records, candidate);
+
+    let mut infh = open(&source);
+    let header = infh.header().clone();
+    let mut n_source: usize = 0;
+    for_each_record(&mut infh, &source, |r| {
+        n_source += 1;
+        if n_source.is_multiple_of(10_000_000) {
+            info!("{} records of {} read...", n_source, source);
+        }
+        if let Some(t) = templates.get_mut(r.qname()) {
+            t.seen = true;
+            if records {
+                let s = signature(r, &header);
+                // a record may turn up more than once, e.g. a secondary alignment written twice
+                if let Some(i) = (0..t.records.len()).find(|&i| !t.found[i] && t.records[i] == s) {
+                    t.found[i] = true;
+                }
+            }
+        }
+    });
+    info!("{} records in {}.", n_source, source);
+
+    // templates of the candidate not in the source, by qname, and, with --records, in it but with
+    // records the source does not have
+    let missing: Vec<&Vec<u8>> = order.iter().filter(|q| !templates[*q].seen).collect();
+    let differing: Vec<(&Vec<u8>, usize)> = order.iter()
+        .filter_map(|q| {
+            let t = &templates[q];
+            let n = t.found.iter().filter(|&&f| !f).count();
+            match t.seen && n > 0 {
+                true => Some((q, n)),
+                false => None,
+            }
+        })
+        .collect();
+    println!("templates\t{}", templates.len());
+    println!("found\t{}", templates.len() - missing.len() - differing.len());
+    println!("missing\t{}", missing.len());
+    if records {
+        println!("differing\t{}", differing.len());
+    }
+
+    if let Some(outfile) = m.opt_str("outfile") {
+        let mut fh = crate::open_text(&outfile);
+        let mut res = writeln!(fh, "qname\tproblem\trecords");
+        for q in &missing {
+            res = res.and_then(|_| writeln!(fh, "{}\tmissing\t{}", String::from_utf8_lossy(q), templates[*q].n));
+        }
+        for (q, n) in &differing {
+            res = res.and_then(|_| writeln!(fh, "{}\tdiffering\t{}", String::from_utf8_lossy(q), n));
+        }
+        if let Err(e) = res.and_then(|_| fh.flush()) {
+            error!("failed to write {}: {}", outfile, e);
+            status::fail(Code::Io);
+        }
+    }
+    for q in missing.iter().take(5) {
+        info!("{} is not in {}.", String::from_utf8_lossy(q), source);
+    }
+    for (q, n) in differing.iter().take(5) {
+        info!("{} has {} records not in {}.", String::from_utf8_lossy(q), n, source);
+    }
+    if !missing.is_empty() || !differing.is_empty() {
+        error!("{} is not a subsample of {}: {} of its {} templates are not in it{}.", candidate, source, missing.len() + differing.len(), templates.len(), match records {
+            true => " as they are",
+            false => "",
+        });
+        status::fail(Code::NotContained);
+    }
+    info!("Every template of {} is in {}.", candidate, source);
+}
@@ -0,0 +1,162 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::mem::take;
+use rust_htslib::bam::Record;
+
+use crate::rng::{Generator, Seed};
+
+// caps the number of reads starting at any one position of a coordinate-sorted stream, picking the
+// survivors at random; the mate of a read decided at an earlier position (along with any alignment of
+// the template showing up before it) follows that decision instead of counting against the cap, even
+// where the cap is reached, so a kept read keeps its mate
+pub struct DepthCap {
+    max_depth: usize,
+    rng: Generator,
+    pos: (i32, i64),
+    // reads starting at `pos`
+    bucket: Vec<Record>,
+    // decisions on qnames with records still to come, and where the mate is to show up; one whose mate
+    // has not by then is dropped, for memory to stay bounded by the reads in flight
+    pending: HashMap<Vec<u8>, (bool, (i32, i64))>,
+    // the mate positions of `pending`, soonest first, to drop the decisions passed by
+    due: BinaryHeap<Reverse<((i32, i64), Vec<u8>)>>,
+    // qnames of paired templates with one primary read kept so far; those left at the end are
+    // orphans, their mate missing from the input
+    pub unpaired: HashSet<Vec<u8>>,
+    pub seen: usize,
+    pub kept: usize,
+    pub capped: usize,
+}
+
+impl DepthCap {
+    pub fn new(max_depth: usize, seed: Seed) -> DepthCap {
+        DepthCap {
+            max_depth,
+            rng: seed.generator(),
+            pos: (-1, -1),
+            bucket: Vec::new(),
+            pending: HashMap::new(),
+            due: BinaryHeap::new(),
+            unpaired: HashSet::new(),
+            seen: 0,
+            kept: 0,
+            capped: 0,
+        }
+    }
+
+    // take the next read; once it moves past the current position, the survivors there are returned
+    // in input order
+    pub fn push(&mut self, r: Record) -> Vec<Record> {
+        self.seen += 1;
+        let pos = (r.tid(), r.pos());
+        let out = if pos != self.pos { self.flush() } else { Vec::new() };
+        self.pos = pos;
+        self.bucket.push(r);
+        out
+    }
+
+    // survivors at the last position
+    pub fn finish(&mut self) -> Vec<Record> {
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Vec<Record> {
+        let bucket = take(&mut self.bucket);
+        self.evict();
+        // unmapped reads without a mapped mate have no position to cap at
+        if self.pos.0 < 0 {
+            self.pair(&bucket);
+            self.kept += bucket.len();
+            return bucket;
+        }
+        // reads of a qname decided at an earlier position follow that decision, as do reads sharing a
+        // qname with an earlier one here; the rest compete for the cap
+        let mut keep = vec![false; bucket.len()];
+        let mut leader: Vec<Option<usize>> = vec![None; bucket.len()];
+        let mut open = Vec::new();
+        let mut here: HashMap<&[u8], (usize, usize)> = HashMap::new();
+        for (i, r) in bucket.iter().enumerate() {
+            if let Some(&(k, _)) = self.pending.get(r.qname()) {
+                keep[i] = k;
+                continue;
+            }
+            match here.get_mut(r.qname()) {
+                Some((j, n)) => {
+                    leader[i] = Some(*j);
+                    *n += 1;
+                },
+                None => {
+                    here.insert(r.qname(), (i, 1));
+                    open.push(i);
+                },
+            }
+        }
+        if open.len() > self.max_depth {
+            self.capped += 1;
+            for j in rand::seq::index::sample(&mut self.rng, open.len(), self.max_depth) {
+                keep[open[j]] = true;
+            }
+        } else {
+            for &i in &open {
+                keep[i] = true;
+            }
+        }
+        for (i, l) in leader.iter().enumerate() {
+            if let Some(j) = *l {
+                keep[i] = keep[j];
+            }
+        }
+        // a decision is remembered until the mate turns up, if the mate is mapped further downstream
+        let mut remember: Vec<(Vec<u8>, (bool, (i32, i64)))> = Vec::new();
+        for (r, &k) in bucket.iter().zip(&keep) {
+            if !r.is_paired() || r.is_secondary() || r.is_supplementary() {
+                continue;
+            }
+            if self.pending.remove(r.qname()).is_some() {
+                continue;
+            }
+            let alone = here.get(r.qname()).is_none_or(|&(_, n)| n == 1);
+            if alone && !r.is_mate_unmapped() && (r.mtid(), r.mpos()) >= self.pos {
+                remember.push((r.qname().to_vec(), (k, (r.mtid(), r.mpos()))));
+            }
+        }
+        for (qname, (k, at)) in remember {
+            self.due.push(Reverse((at, qname.clone())));
+            self.pending.insert(qname, (k, at));
+        }
+        let out: Vec<Record> = bucket.into_iter().zip(keep).filter(|(_, k)| *k).map(|(r, _)| r).collect();
+        self.pair(&out);
+        self.kept += out.len();
+        out
+    }
+
+    // forget the decisions on qnames whose mate was due before the current position, and never came;
+    // an entry of the heap whose qname was since settled, or decided anew, is passed over
+    fn evict(&mut self) {
+        while let Some(Reverse((at, _))) = self.due.peek() {
+            if *at >= self.pos {
+                break;
+            }
+            if let Some(Reverse((at, qname))) = self.due.pop() {
+                if self.pending.get(&qname).is_some_and(|x| x.1 == at) {
+                    self.pending.remove(&qname);
+                }
+            }
+        }
+    }
+
+    // pair up the primary reads kept with those kept before
+    fn pair(&mut self, rs: &[Record]) {
+        for r in rs.iter().filter(|r| r.is_paired() && !r.is_secondary() && !r.is_supplementary()) {
+            if !self.unpaired.remove(r.qname()) {
+                self.unpaired.insert(r.qname().to_vec());
+            }
+        }
+    }
+}
@@ -0,0 +1,193 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+
+use crate::ospath;
+
+static K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+// SHA-256 (FIPS 180-4), fed in pieces
+pub struct Sha256 {
+    state: [u32; 8],
+    block: Vec<u8>,
+    len: u64,
+}
+
+impl Default for Sha256 {
+    fn default() -> Sha256 {
+        Sha256 {
+            state: [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19],
+            block: Vec::with_capacity(64),
+            len: 0,
+        }
+    }
+}
+
+impl Sha256 {
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        while !data.is_empty() {
+            let n = (64 - self.block.len()).min(data.len());
+            self.block.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.block.len() == 64 {
+                self.compress();
+            }
+        }
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (i, c) in self.block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([c[0], c[1], c[2], c[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (s, x) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(x);
+        }
+        self.block.clear();
+    }
+
+    // the digest in lowercase hex, as sha256sum prints it
+    pub fn hex(mut self) -> String {
+        let bits = self.len.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.block.len() != 56 {
+            self.update(&[0]);
+        }
+        self.block.extend_from_slice(&bits.to_be_bytes());
+        self.compress();
+        self.state.iter().map(|x| format!("{:08x}", x)).collect()
+    }
+}
+
+pub fn file(path: &str) -> std::io::Result<String> {
+    let mut fh = File::open(ospath::path(path))?;
+    let mut sha = Sha256::default();
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        match fh.read(&mut buf)? {
+            0 => return Ok(sha.hex()),
+            n => sha.update(&buf[..n]),
+        }
+    }
+}
+
+// the qnames of the templates written, to tell whether two runs picked the same ones whatever the
+// order, compression or header of their outputs
+#[derive(Default)]
+pub struct Selection {
+    qnames: Vec<Vec<u8>>,
+}
+
+impl Selection {
+    pub fn add(&mut self, qname: &[u8]) {
+        self.qnames.push(qname.to_vec());
+    }
+
+    // the digest of the sorted, distinct qnames, one per line
+    pub fn hex(&mut self) -> String {
+        self.qnames.sort_unstable();
+        self.qnames.dedup();
+        let mut sha = Sha256::default();
+        for q in &self.qnames {
+            sha.update(q);
+            sha.update(b"\n");
+        }
+        sha.hex()
+    }
+
+    // the distinct qnames, once `hex` has been taken
+    pub fn templates(&self) -> usize {
+        self.qnames.len()
+    }
+}
+
+// the JSON of what was digested, every output by name and the selection, with their SHA-256:
+// {"outputs":[{"file":"out.bam","sha256":"..."}],"selection":{"templates":1000,"sha256":"..."}}
+pub fn write(path: &str, outputs: &[(&str, String)], selection: &str, templates: usize) -> std::io::Result<()> {
+    let mut fh = BufWriter::new(File::create(ospath::path(path))?);
+    let outputs: Vec<String> = outputs.iter().map(|(file, hex)| format!("{{\"file\":\"{}\",\"sha256\":\"{}\"}}", crate::json_escape(file), hex)).collect();
+    writeln!(fh, "{{\"outputs\":[{}],\"selection\":{{\"templates\":{},\"sha256\":\"{}\"}}}}", outputs.join(","), templates, selection)?;
+    fh.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256(data: &[u8]) -> String {
+        let mut sha = Sha256::default();
+        sha.update(data);
+        sha.hex()
+    }
+
+    #[test]
+    fn standard_vectors() {
+        assert_eq!(sha256(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(sha256(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        // 56 bytes, whose padding takes a second block
+        assert_eq!(sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"), "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+        assert_eq!(sha256(&[b'a'; 1_000_000]), "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+    }
+
+    #[test]
+    fn pieces_digest_as_a_whole() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let mut sha = Sha256::default();
+        for piece in data.chunks(37) {
+            sha.update(piece);
+        }
+        assert_eq!(sha.hex(), sha256(&data));
+    }
+
+    #[test]
+    fn selection_ignores_order_and_repeats() {
+        let mut a = Selection::default();
+        let mut b = Selection::default();
+        for q in [&b"r2"[..], b"r1", b"r3"] {
+            a.add(q);
+        }
+        for q in [&b"r3"[..], b"r1", b"r2", b"r1"] {
+            b.add(q);
+        }
+        assert_eq!(a.hex(), b.hex());
+        assert_eq!(b.templates(), 3);
+        assert_eq!(a.hex(), sha256(b"r1\nr2\nr3\n"));
+    }
+}
@@ -0,0 +1,110 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::io::{BufRead, BufReader, Write};
+use rust_htslib::bgzf;
+use log::{error, info, warn};
+
+use crate::ospath;
+use crate::reservoir::Reservoir;
+use crate::rng::Seed;
+use crate::status::{self, Code};
+use crate::{interrupt, open_text};
+
+// the next record of a FASTQ, its four lines as they are, newlines included; None at the end
+fn read_record(fh: &mut dyn BufRead, path: &str) -> Option<Vec<u8>> {
+    let mut rec: Vec<u8> = Vec::new();
+    for i in 0..4 {
+        let start = rec.len();
+        match fh.read_until(b'\n', &mut rec) {
+            Ok(0) if i == 0 => return None,
+            Ok(0) => {
+                error!("{} ends within a record!", path);
+                status::fail(Code::Malformed);
+            },
+            Ok(_) => {},
+            Err(e) => {
+                error!("failed to read {}: {}", path, e);
+                status::fail(Code::Io);
+            },
+        }
+        let ok = match i {
+            0 => rec[start] == b'@',
+            2 => rec[start] == b'+',
+            _ => true,
+        };
+        if !ok {
+            error!("{} is not a FASTQ: {}", path, String::from_utf8_lossy(&rec[start..]).trim_end());
+            status::fail(Code::Malformed);
+        }
+    }
+    if !rec.ends_with(b"\n") {
+        rec.push(b'\n');
+    }
+    Some(rec)
+}
+
+// the read id of a record, its header line up to the first whitespace and without a /1 or /2, which
+// the mates of a pair share
+fn read_id(rec: &[u8]) -> &[u8] {
+    let id = rec[1..].split(|c| c.is_ascii_whitespace()).next().unwrap_or_default();
+    match id {
+        [x @ .., b'/', b'1' | b'2'] => x,
+        _ => id,
+    }
+}
+
+// sample `num` templates of the FASTQs of single reads, or of the first and second reads of pairs, in
+// the same order, the records of a pair going by their shared read id, and write the reads of every
+// input to its output in input order; returns the templates read and written
+pub fn subsample(infiles: &[String], outfiles: &[String], num: usize, seed: Seed, finalize: bool) -> (usize, usize) {
+    let mut infhs: Vec<Box<dyn BufRead>> = infiles.iter().map(|f| match bgzf::Reader::from_path(ospath::path(f)) {
+        Ok(fh) => Box::new(BufReader::new(fh)) as Box<dyn BufRead>,
+        Err(e) => {
+            error!("failed to read {}: {}", f, e);
+            status::fail(Code::Malformed)
+        },
+    }).collect();
+    let mut res: Reservoir<(usize, Vec<Vec<u8>>)> = Reservoir::new(num, seed);
+    let mut seen: usize = 0;
+    while !interrupt::stopping() {
+        let recs: Vec<Option<Vec<u8>>> = infhs.iter_mut().zip(infiles).map(|(fh, f)| read_record(fh.as_mut(), f)).collect();
+        if recs.iter().all(Option::is_none) {
+            break;
+        }
+        let recs: Vec<Vec<u8>> = recs.into_iter().zip(infiles).map(|(r, f)| r.unwrap_or_else(|| {
+            error!("{} ends before the other FASTQ, after {} records!", f, seen);
+            status::fail(Code::Malformed)
+        })).collect();
+        if let Some(r) = recs[1..].iter().find(|r| read_id(r) != read_id(&recs[0])) {
+            error!("the FASTQs are out of step at record {}: {} against {}!", seen + 1, String::from_utf8_lossy(read_id(&recs[0])), String::from_utf8_lossy(read_id(r)));
+            status::fail(Code::Malformed);
+        }
+        res.sample((seen, recs));
+        seen += 1;
+        if seen.is_multiple_of(1_000_000) {
+            info!("{} reads (read pairs) processed...", seen);
+        }
+    }
+    if let Some(signal) = interrupt::received() {
+        if !finalize && !interrupt::timed_out() {
+            warn!("{} received; nothing written.", signal);
+            return (seen, 0);
+        }
+        warn!("{} received; writing the sample of the {} templates read so far.", signal, seen);
+    }
+    let mut v = res.v;
+    v.sort_unstable_by_key(|x| x.0);
+    for (i, outfile) in outfiles.iter().enumerate() {
+        let mut fh = open_text(outfile);
+        let written = v.iter().try_for_each(|x| fh.write_all(&x.1[i])).and_then(|_| fh.flush());
+        if let Err(e) = written {
+            error!("failed to write {}: {}", outfile, e);
+            status::fail(Code::Io);
+        }
+    }
+    (seen, v.len())
+}
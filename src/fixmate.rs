@@ -0,0 +1,90 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use rust_htslib::bam::{Record, record::Aux};
+
+// SAM flags a read carries as a mate of a pair: paired, proper pair, mate unmapped, mate reverse, first
+// and last in template
+const PAIRED: u16 = 0x1 | 0x2 | 0x8 | 0x20 | 0x40 | 0x80;
+
+// the primary record of the read of a pair, by qname and whether it is the last mate
+fn primary<'a>(rs: &'a [Record], qname: &[u8], last: bool) -> Option<&'a Record> {
+    rs.iter().find(|r| !r.is_secondary() && !r.is_supplementary() && r.is_paired() && r.qname() == qname && r.is_last_in_template() == last)
+}
+
+// TLEN of the primary record `r` whose mate's is `mate`, as samtools fixmate has it: from the leftmost
+// start to the rightmost end of both, positive for the leftmost one (the first mate if they start
+// together), 0 if either is unmapped or they are on different references
+fn tlen(r: &Record, mate: &Record) -> i64 {
+    if r.is_unmapped() || mate.is_unmapped() || r.tid() != mate.tid() {
+        return 0;
+    }
+    let span = r.cigar().end_pos().max(mate.cigar().end_pos()) - r.pos().min(mate.pos());
+    match r.pos() < mate.pos() || (r.pos() == mate.pos() && r.is_first_in_template()) {
+        true => span,
+        false => -span,
+    }
+}
+
+// the mate fields of the records of a template as written, so that they agree with what was left of it:
+// RNEXT, PNEXT, the mate reverse and unmapped flags, and MC of every record of a mate from the primary
+// record of the other, and TLEN of the primary records; a read whose mate was left out, e.g. by --reads,
+// is made single-end, without mate fields, MC and the pair flags. Returns the records made single-end
+pub fn fix(rs: &mut [Record]) -> usize {
+    // what every record gets from the primary record of its mate: tid, pos, reverse, unmapped, CIGAR
+    // and, for a primary record, TLEN; None if the mate is not there
+    let mates: Vec<Option<(i32, i64, bool, bool, Option<String>, Option<i64>)>> = rs.iter().map(|r| {
+        if !r.is_paired() {
+            return None;
+        }
+        let mate = primary(rs, r.qname(), !r.is_last_in_template())?;
+        let cigar = match mate.is_unmapped() {
+            true => None,
+            false => Some(mate.cigar().to_string()),
+        };
+        let tlen = match r.is_secondary() || r.is_supplementary() {
+            true => None,
+            false => Some(tlen(r, mate)),
+        };
+        Some((mate.tid(), mate.pos(), mate.is_reverse(), mate.is_unmapped(), cigar, tlen))
+    }).collect();
+    let mut unpaired = 0;
+    for (r, mate) in rs.iter_mut().zip(mates) {
+        if !r.is_paired() {
+            continue;
+        }
+        let _ = r.remove_aux(b"MC");
+        match mate {
+            Some((tid, pos, reverse, unmapped, cigar, tlen)) => {
+                r.set_mtid(tid);
+                r.set_mpos(pos);
+                let mut flags = r.flags() & !(0x8 | 0x20);
+                if unmapped {
+                    flags |= 0x8;
+                }
+                if reverse {
+                    flags |= 0x20;
+                }
+                r.set_flags(flags);
+                if let Some(tlen) = tlen {
+                    r.set_insert_size(tlen);
+                }
+                if let Some(cigar) = cigar {
+                    r.push_aux(b"MC", Aux::String(&cigar)).expect("failed to rewrite MC!");
+                }
+            },
+            None => {
+                r.set_flags(r.flags() & !PAIRED);
+                r.set_mtid(-1);
+                r.set_mpos(-1);
+                r.set_insert_size(0);
+                let _ = r.remove_aux(b"MQ");
+                unpaired += 1;
+            },
+        }
+    }
+    unpaired
+}
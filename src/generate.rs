@@ -0,0 +1,302 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use rand::prelude::*;
+use rand::SeedableRng;
+use rand_pcg::Pcg64;
+use rust_htslib::{bam, bam::HeaderView, bam::Record, bam::record::{Aux, Cigar, CigarString}};
+use getopts::Options;
+use log::info;
+
+use crate::completions::{OptDef, Kind};
+use crate::status::{self, Code, bad_args};
+use crate::units;
+use crate::LogFormat;
+
+// the options of `sam_subsample generate`
+static OPTIONS: &[OptDef] = &[
+    ("o", "outfile", "output BAM, sorted by queryname", "FILE", Kind::Opt),
+    ("n", "num", "number of templates; K/M/G suffixes allowed (default: 10000)", "INTEGER", Kind::Opt),
+    ("", "paired", "fraction of the templates that are read pairs rather than single reads (default: 1)", "NUMBER", Kind::Opt),
+    ("", "secondary", "fraction of the reads with a secondary alignment as well (default: 0)", "NUMBER", Kind::Opt),
+    ("", "supplementary", "fraction of the reads split into a primary and a supplementary alignment (default: 0)", "NUMBER", Kind::Opt),
+    ("", "chromosomes", "comma-separated NAME:LENGTH of the references, e.g. chr1:248956422,chr2:242193529 (default: chr1:1000000,chr2:500000)", "LIST", Kind::Opt),
+    ("", "read-length", "length of every read (default: 100)", "INTEGER", Kind::Opt),
+    ("s", "seed", "seed (default: 43)", "INTEGER", Kind::Opt),
+    ("", "level", "level of debugging info, choose from 'error', 'warn', 'info', 'debug', 'trace' (default: info)", "", Kind::Opt),
+    ("q", "quiet", "log errors only, in place of --level", "", Kind::Flag),
+    ("h", "help", "print usage", "", Kind::Flag),
+];
+
+static RG: &str = "synth";
+
+struct Spec {
+    chroms: Vec<(String, i64)>,
+    read_len: usize,
+    paired: f64,
+    secondary: f64,
+    supplementary: f64,
+}
+
+fn fraction(m: &getopts::Matches, name: &str, default: f64) -> f64 {
+    match m.opt_get_default(name, default) {
+        Ok(x) if (0.0..=1.0).contains(&x) => x,
+        _ => bad_args(&format!("invalid --{}, must be within 0 and 1", name)),
+    }
+}
+
+fn parse_chroms(s: &str) -> Option<Vec<(String, i64)>> {
+    s.split(',').map(|x| {
+        let (name, len) = x.rsplit_once(':')?;
+        match (name.is_empty(), len.parse::<i64>()) {
+            (false, Ok(len)) if len > 0 => Some((name.to_string(), len)),
+            _ => None,
+        }
+    }).collect()
+}
+
+// a random place to align `len` bases at, chromosomes weighted by length: (tid, 0-based position)
+fn locus(rng: &mut Pcg64, chroms: &[(String, i64)], len: i64) -> (i32, i64) {
+    let total: i64 = chroms.iter().map(|c| c.1).sum();
+    let mut x = rng.gen_range(0..total);
+    let tid = chroms.iter().position(|c| if x < c.1 { true } else { x -= c.1; false }).unwrap_or_default();
+    (tid as i32, rng.gen_range(0..=(chroms[tid].1 - len).max(0)))
+}
+
+// a mapped read; mate fields are left for the caller
+fn read(qname: &[u8], cigar: Vec<Cigar>, seq: &[u8], (tid, pos): (i32, i64), flags: u16, mapq: u8) -> Record {
+    let mut r = Record::new();
+    r.set(qname, Some(&CigarString(cigar)), seq, &vec![30; seq.len()]);
+    r.set_tid(tid);
+    r.set_pos(pos);
+    r.set_mapq(mapq);
+    r.set_flags(flags);
+    r.set_mtid(-1);
+    r.set_mpos(-1);
+    r.push_aux(b"RG", Aux::String(RG)).expect("failed to add RG!");
+    r
+}
+
+// the records of a read: its primary alignment, split with a supplementary one elsewhere or not, then
+// its secondary alignment, if any; `mate` is where the primary alignment of its mate is, and the
+// fragment length
+fn alignments(rng: &mut Pcg64, spec: &Spec, qname: &[u8], at: (i32, i64), flags: u16, mate: Option<((i32, i64), i64)>) -> Vec<Record> {
+    let len = spec.read_len;
+    let seq: Vec<u8> = (0..len).map(|_| b"ACGT"[rng.gen_range(0..4)]).collect();
+    let strand = if flags & 0x10 != 0 { '-' } else { '+' };
+    let mut rs = Vec::new();
+    let split = match len > 1 && rng.gen_bool(spec.supplementary) {
+        true => Some(rng.gen_range(1..len) as u32),
+        false => None,
+    };
+    match split {
+        None => rs.push(read(qname, vec![Cigar::Match(len as u32)], &seq, at, flags, 60)),
+        Some(a) => {
+            let b = len as u32 - a;
+            let elsewhere = locus(rng, &spec.chroms, b as i64);
+            let name = |tid: i32| &spec.chroms[tid as usize].0;
+            let mut primary = read(qname, vec![Cigar::Match(a), Cigar::SoftClip(b)], &seq, at, flags, 60);
+            let sa = format!("{},{},{},{}S{}M,60,0;", name(elsewhere.0), elsewhere.1 + 1, strand, a, b);
+            primary.push_aux(b"SA", Aux::String(&sa)).expect("failed to add SA!");
+            let mut supplementary = read(qname, vec![Cigar::HardClip(a), Cigar::Match(b)], &seq[a as usize..], elsewhere, flags | 0x800, 60);
+            let sa = format!("{},{},{},{}M{}S,60,0;", name(at.0), at.1 + 1, strand, a, b);
+            supplementary.push_aux(b"SA", Aux::String(&sa)).expect("failed to add SA!");
+            rs.push(primary);
+            rs.push(supplementary);
+        },
+    }
+    if rng.gen_bool(spec.secondary) {
+        rs.push(read(qname, vec![Cigar::Match(len as u32)], &seq, locus(rng, &spec.chroms, len as i64), flags | 0x100, 0));
+    }
+    if let Some(((mtid, mpos), tlen)) = mate {
+        for r in &mut rs {
+            r.set_mtid(mtid);
+            r.set_mpos(mpos);
+            if !r.is_secondary() && !r.is_supplementary() {
+                r.set_insert_size(tlen);
+            }
+        }
+    }
+    rs
+}
+
+// the records of a template: a pair in proper orientation, either mate on the forward strand, or a
+// single read
+fn template(rng: &mut Pcg64, spec: &Spec, qname: &[u8]) -> Vec<Record> {
+    let len = spec.read_len as i64;
+    let reverse = rng.gen_bool(0.5);
+    if !rng.gen_bool(spec.paired) {
+        let at = locus(rng, &spec.chroms, len);
+        return alignments(rng, spec, qname, at, if reverse { 0x10 } else { 0 }, None);
+    }
+    // fragments of 2 to 5 read lengths, as long as the chromosome allows
+    let (tid, _) = locus(rng, &spec.chroms, len);
+    let frag = rng.gen_range(2 * len..=5 * len).min(spec.chroms[tid as usize].1);
+    let start = rng.gen_range(0..=spec.chroms[tid as usize].1 - frag);
+    let (left, right) = ((tid, start), (tid, start + frag - len));
+    // the leftmost mate is forward, on either read 1 or read 2
+    let (r1, r2) = match reverse {
+        false => (left, right),
+        true => (right, left),
+    };
+    let strand = |at: (i32, i64)| if at == left { 0x20 } else { 0x10 };
+    let tlen = |at: (i32, i64)| if at == left { frag } else { -frag };
+    let mut rs = alignments(rng, spec, qname, r1, 0x1 | 0x2 | 0x40 | strand(r1), Some((r2, tlen(r1))));
+    rs.extend(alignments(rng, spec, qname, r2, 0x1 | 0x2 | 0x80 | strand(r2), Some((r1, tlen(r2)))));
+    rs
+}
+
+fn usage(opts: &Options) {
+    let s = "\
+Summary:
+Write a queryname-sorted BAM of synthetic templates, for tests and benchmarks
+
+Usage:
+sam_subsample generate --outfile synth.bam [--num 10000] [--paired 1] [--secondary 0] [--supplementary 0] [--chromosomes chr1:1000000,chr2:500000] [--read-length 100] [--seed 43] [--level info | -q]";
+    println!("{}", opts.usage(s));
+}
+
+// `sam_subsample generate`, given the arguments after it
+pub fn run(args: &[String]) {
+    let mut opts = Options::new();
+    for &(short, long, desc, hint, kind) in OPTIONS {
+        match kind {
+            Kind::Flag => opts.optflag(short, long, desc),
+            _ => opts.optopt(short, long, desc, hint),
+        };
+    }
+    let m = opts.parse(args).unwrap_or_else(|e| bad_args(&format!("failed to parse arguments: {}", e)));
+    if m.opt_present("h") {
+        usage(&opts);
+        return;
+    }
+    let outfile = m.opt_str("outfile").unwrap_or_else(|| bad_args("--outfile is required!"));
+    let num = match m.opt_str("num") {
+        Some(x) => units::parse_count(&x).unwrap_or_else(|| bad_args("invalid --num, e.g. 5000, 5k, 2.5M")),
+        None => 10000,
+    };
+    let chroms = parse_chroms(&m.opt_str("chromosomes").unwrap_or_else(|| String::from("chr1:1000000,chr2:500000")))
+        .unwrap_or_else(|| bad_args("invalid --chromosomes, e.g. chr1:248956422,chr2:242193529"));
+    let read_len: usize = m.opt_get_default("read-length", 100).unwrap_or_else(|_| bad_args("invalid --read-length"));
+    if read_len == 0 || chroms.iter().any(|c| c.1 < 2 * read_len as i64) {
+        bad_args("--read-length must be positive, and every chromosome at least twice as long");
+    }
+    let seed: u64 = m.opt_get_default("seed", 43).unwrap_or_else(|_| bad_args("invalid --seed, must be integer"));
+    let level = match (m.opt_present("quiet"), m.opt_str("level")) {
+        (true, Some(_)) => bad_args("--quiet does not work with --level"),
+        (true, None) => String::from("error"),
+        (false, level) => level.unwrap_or_else(|| String::from("info")),
+    };
+    if !["error", "warn", "info", "debug", "trace"].contains(&level.as_str()) {
+        bad_args("invalid --level, choose from 'info', 'warn', 'error', 'debug', 'trace'");
+    }
+    crate::init_logger(&level, None, None, LogFormat::Plain);
+    let spec = Spec {
+        chroms,
+        read_len,
+        paired: fraction(&m, "paired", 1.0),
+        secondary: fraction(&m, "secondary", 0.0),
+        supplementary: fraction(&m, "supplementary", 0.0),
+    };
+
+    let mut text = String::from("@HD\tVN:1.6\tSO:queryname\n");
+    for (name, len) in &spec.chroms {
+        text.push_str(&format!("@SQ\tSN:{}\tLN:{}\n", name, len));
+    }
+    text.push_str(&format!("@RG\tID:{}\tSM:{}\n", RG, RG));
+    text.push_str(&format!("@PG\tID:sam_subsample\tPN:sam_subsample\tVN:{}\tCL:sam_subsample generate {}\n", crate::VERSION, args.join(" ")));
+    let header = bam::Header::from_template(&HeaderView::from_bytes(text.as_bytes()));
+    let mut outfh = crate::open_output(&outfile, &header, 1);
+
+    let mut rng = Pcg64::seed_from_u64(seed);
+    // zero-padded, so that the qnames sort the same as text and as numbers
+    let width = num.max(1).to_string().len();
+    let mut n = 0;
+    for i in 1..=num {
+        let qname = format!("synth:{:0w$}", i, w = width);
+        for r in template(&mut rng, &spec, qname.as_bytes()) {
+            if let Err(e) = outfh.write(&r) {
+                status::complain(&format!("failed to write {}: {}", outfile, e));
+                status::fail(Code::Io);
+            }
+            n += 1;
+        }
+    }
+    info!("Wrote {} templates, {} records, to {}.", num, n, outfile);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(paired: f64, secondary: f64, supplementary: f64) -> Spec {
+        Spec {
+            chroms: vec![(String::from("chr1"), 1_000_000), (String::from("chr2"), 500_000)],
+            read_len: 100,
+            paired,
+            secondary,
+            supplementary,
+        }
+    }
+
+    fn templates(spec: &Spec, num: usize, seed: u64) -> Vec<Vec<Record>> {
+        let mut rng = Pcg64::seed_from_u64(seed);
+        (0..num).map(|i| template(&mut rng, spec, format!("synth:{}", i).as_bytes())).collect()
+    }
+
+    // within 5 standard deviations of a binomial proportion
+    fn close(x: usize, n: usize, p: f64) -> bool {
+        (x as f64 / n as f64 - p).abs() <= 5.0 * (p * (1.0 - p) / n as f64).sqrt()
+    }
+
+    #[test]
+    fn fractions_follow_the_spec() {
+        let n = 20_000;
+        let ts = templates(&spec(0.3, 0.1, 0.2), n, 1);
+        let paired = ts.iter().filter(|rs| rs[0].is_paired()).count();
+        assert!(close(paired, n, 0.3), "{} of {} paired", paired, n);
+        let primaries: Vec<&Record> = ts.iter().flatten().filter(|r| !r.is_secondary() && !r.is_supplementary()).collect();
+        let secondary = ts.iter().flatten().filter(|r| r.is_secondary()).count();
+        let supplementary = ts.iter().flatten().filter(|r| r.is_supplementary()).count();
+        assert!(close(secondary, primaries.len(), 0.1), "{} secondary of {} reads", secondary, primaries.len());
+        assert!(close(supplementary, primaries.len(), 0.2), "{} supplementary of {} reads", supplementary, primaries.len());
+    }
+
+    #[test]
+    fn pairs_point_at_each_other() {
+        for rs in templates(&spec(1.0, 0.0, 0.0), 1000, 2) {
+            assert_eq!(rs.len(), 2);
+            let (a, b) = (&rs[0], &rs[1]);
+            assert!(a.is_first_in_template() && b.is_last_in_template());
+            assert_eq!((a.mtid(), a.mpos()), (b.tid(), b.pos()));
+            assert_eq!((b.mtid(), b.mpos()), (a.tid(), a.pos()));
+            assert_eq!(a.insert_size(), -b.insert_size());
+            assert_ne!(a.is_reverse(), b.is_reverse());
+        }
+    }
+
+    #[test]
+    fn positions_are_spread_by_length() {
+        let n = 20_000;
+        let ts = templates(&spec(0.0, 0.0, 0.0), n, 3);
+        let on_chr1 = ts.iter().filter(|rs| rs[0].tid() == 0).count();
+        assert!(close(on_chr1, n, 2.0 / 3.0), "{} of {} on chr1", on_chr1, n);
+        // fourths of chr1 get a fourth of its reads each
+        let mut quarters = [0; 4];
+        for rs in ts.iter().filter(|rs| rs[0].tid() == 0) {
+            quarters[(rs[0].pos() * 4 / 1_000_000).min(3) as usize] += 1;
+        }
+        assert!(quarters.iter().all(|&q| close(q, on_chr1, 0.25)), "{:?}", quarters);
+    }
+
+    #[test]
+    fn same_seed_same_templates() {
+        let spec = spec(0.5, 0.1, 0.1);
+        let seqs = |seed| templates(&spec, 100, seed).iter().flatten().map(|r| (r.tid(), r.pos(), r.seq().as_bytes())).collect::<Vec<_>>();
+        assert_eq!(seqs(4), seqs(4));
+        assert_ne!(seqs(4), seqs(5));
+    }
+}
@@ -0,0 +1,219 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::collections::HashMap;
+use rust_htslib::{bam, bam::HeaderView, bam::Record};
+use log::{error, warn, debug};
+
+use crate::status::{self, Code};
+
+// new tid of every reference of one input, indexed by its old tid
+pub type TidMap = Vec<i32>;
+
+fn tag<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    line.split('\t').skip(1).find_map(|f| f.strip_prefix(key).and_then(|v| v.strip_prefix(':')))
+}
+
+// merge the headers of several inputs: @SQ are reconciled by name (and must agree on LN), @RG/@PG
+// are deduplicated by ID, keeping the first one on conflict, and @CO and anything else by content
+pub fn merge(views: &[HeaderView]) -> (bam::Header, Vec<TidMap>) {
+    let mut hd: Option<String> = None;
+    let mut sq: Vec<String> = Vec::new();
+    let mut sq_idx: HashMap<String, (usize, String)> = HashMap::new();
+    let mut ids: HashMap<(String, String), String> = HashMap::new();
+    let mut rg: Vec<String> = Vec::new();
+    let mut pg: Vec<String> = Vec::new();
+    let mut rest: Vec<String> = Vec::new();
+    let mut maps: Vec<TidMap> = Vec::new();
+
+    for view in views {
+        let text = String::from_utf8_lossy(view.as_bytes()).into_owned();
+        let mut map = TidMap::new();
+        for line in text.split('\n').filter(|l| !l.is_empty()) {
+            match &line[..3.min(line.len())] {
+                "@HD" => {
+                    if hd.is_none() {
+                        hd = Some(line.to_string());
+                    }
+                },
+                "@SQ" => {
+                    let name = tag(line, "SN").unwrap_or_default().to_string();
+                    let len = tag(line, "LN").unwrap_or_default().to_string();
+                    match sq_idx.get(&name) {
+                        Some((i, l)) => {
+                            if *l != len {
+                                error!("@SQ SN:{} has conflicting lengths {} and {} across inputs!", name, l, len);
+                                status::fail(Code::Malformed);
+                            }
+                            map.push(*i as i32);
+                        },
+                        None => {
+                            sq_idx.insert(name, (sq.len(), len));
+                            map.push(sq.len() as i32);
+                            sq.push(line.to_string());
+                        },
+                    }
+                },
+                t @ "@RG" | t @ "@PG" => {
+                    let id = tag(line, "ID").unwrap_or_default().to_string();
+                    match ids.get(&(t.to_string(), id.clone())) {
+                        Some(l) if l == line => {},
+                        Some(_) => {
+                            if t == "@RG" {
+                                warn!("@RG ID:{} differs across inputs, keeping the first one.", id);
+                            } else {
+                                debug!("@PG ID:{} differs across inputs, keeping the first one.", id);
+                            }
+                        },
+                        None => {
+                            ids.insert((t.to_string(), id), line.to_string());
+                            if t == "@RG" { rg.push(line.to_string()) } else { pg.push(line.to_string()) }
+                        },
+                    }
+                },
+                _ => {
+                    if !rest.iter().any(|l| l == line) {
+                        rest.push(line.to_string());
+                    }
+                },
+            }
+        }
+        maps.push(map);
+    }
+
+    let lines: Vec<String> = hd.into_iter().chain(sq).chain(rg).chain(pg).chain(rest).collect();
+    let text = lines.join("\n") + "\n";
+    let header = bam::Header::from_template(&HeaderView::from_bytes(text.as_bytes()));
+    (header, maps)
+}
+
+// a header with only the @SQ lines of the tids `used`, and the new tid of every old one in it (-1 if
+// left out), per --trim-header
+pub fn trim(view: &HeaderView, used: &[bool]) -> (bam::Header, TidMap) {
+    let text = String::from_utf8_lossy(view.as_bytes()).into_owned();
+    let mut lines: Vec<&str> = Vec::new();
+    let mut map = TidMap::new();
+    let mut n = 0;
+    for line in text.split('\n').filter(|l| !l.is_empty()) {
+        if !line.starts_with("@SQ") {
+            lines.push(line);
+            continue;
+        }
+        match used.get(map.len()).copied().unwrap_or_default() {
+            true => {
+                map.push(n);
+                n += 1;
+                lines.push(line);
+            },
+            false => map.push(-1),
+        }
+    }
+    let text = lines.join("\n") + "\n";
+    (bam::Header::from_template(&HeaderView::from_bytes(text.as_bytes())), map)
+}
+
+// point tid and mtid of a record at the merged header
+pub fn remap(r: &mut Record, map: &[i32]) {
+    if r.tid() >= 0 {
+        r.set_tid(map[r.tid() as usize]);
+    }
+    if r.mtid() >= 0 {
+        r.set_mtid(map[r.mtid() as usize]);
+    }
+}
+
+// reference names by tid; by tid2name, as target_names fails on a header without any
+pub fn names(header: &bam::Header) -> Vec<String> {
+    let view = HeaderView::from_header(header);
+    (0..view.target_count()).map(|tid| String::from_utf8_lossy(view.tid2name(tid)).into_owned()).collect()
+}
+
+// whether a map leaves every tid where it is
+pub fn is_identity(map: &[i32]) -> bool {
+    map.iter().enumerate().all(|(i, &t)| i as i32 == t)
+}
+
+// a header with the @RG lines of other read groups than `id` left out
+pub fn only_rg(header: &bam::Header, id: &str) -> bam::Header {
+    let text = String::from_utf8_lossy(&header.to_bytes()).into_owned();
+    let lines: Vec<&str> = text
+        .split('\n')
+        .filter(|l| !l.is_empty() && (!l.starts_with("@RG") || tag(l, "ID") == Some(id)))
+        .collect();
+    let text = lines.join("\n") + "\n";
+    bam::Header::from_template(&HeaderView::from_bytes(text.as_bytes()))
+}
+
+// a header with only the @RG lines of sample `sm`, for its output of --per-sample-num
+pub fn only_sample(header: &bam::Header, sm: &str) -> bam::Header {
+    let text = String::from_utf8_lossy(&header.to_bytes()).into_owned();
+    let lines: Vec<&str> = text
+        .split('\n')
+        .filter(|l| !l.is_empty() && (!l.starts_with("@RG") || tag(l, "SM") == Some(sm)))
+        .collect();
+    let text = lines.join("\n") + "\n";
+    bam::Header::from_template(&HeaderView::from_bytes(text.as_bytes()))
+}
+
+// the sample (SM) of every read group (ID) of the header that has one
+pub fn samples(header: &bam::Header) -> HashMap<String, String> {
+    let text = String::from_utf8_lossy(&header.to_bytes()).into_owned();
+    text.split('\n')
+        .filter(|l| l.starts_with("@RG"))
+        .filter_map(|l| Some((tag(l, "ID")?.to_string(), tag(l, "SM")?.to_string())))
+        .collect()
+}
+
+// a header with @HD SO set to `so`, e.g. unsorted once the records are no longer in the order they
+// were read in
+pub fn with_sort_order(header: &bam::Header, so: &str) -> bam::Header {
+    let text = String::from_utf8_lossy(&header.to_bytes()).into_owned();
+    let mut lines: Vec<String> = text.split('\n').filter(|l| !l.is_empty()).map(String::from).collect();
+    match lines.iter_mut().find(|l| l.starts_with("@HD")) {
+        Some(hd) => {
+            let mut fields: Vec<String> = hd.split('\t').filter(|f| !f.starts_with("SO:")).map(String::from).collect();
+            fields.push(format!("SO:{}", so));
+            *hd = fields.join("\t");
+        },
+        None => lines.insert(0, format!("@HD\tVN:1.6\tSO:{}", so)),
+    }
+    let text = lines.join("\n") + "\n";
+    bam::Header::from_template(&HeaderView::from_bytes(text.as_bytes()))
+}
+
+// a header with `comments` added as @CO lines, the @RG line `read_group` added unless one of its ID is
+// there already, and the SM of every @RG set to `sample`, per --add-comment, --set-read-group and
+// --replace-sample-name
+pub fn relabel(header: &bam::Header, comments: &[String], read_group: Option<(&str, &str)>, sample: Option<&str>) -> bam::Header {
+    let text = String::from_utf8_lossy(&header.to_bytes()).into_owned();
+    let mut lines: Vec<String> = text.split('\n').filter(|l| !l.is_empty()).map(String::from).collect();
+    if let Some((id, line)) = read_group {
+        let id_field = format!("ID:{}", id);
+        match lines.iter().any(|l| l.starts_with("@RG") && l.split('\t').any(|f| f == id_field)) {
+            true => warn!("@RG ID:{} is in the header already; it is kept as it is.", id),
+            // after the last @RG, or the @SQ lines, ahead of @PG and @CO
+            false => {
+                let at = lines.iter().rposition(|l| l.starts_with("@RG") || l.starts_with("@SQ") || l.starts_with("@HD")).map_or(0, |i| i + 1);
+                lines.insert(at, line.to_string());
+            },
+        }
+    }
+    if let Some(sm) = sample {
+        let mut n = 0;
+        for l in lines.iter_mut().filter(|l| l.starts_with("@RG")) {
+            let mut fields: Vec<String> = l.split('\t').filter(|f| !f.starts_with("SM:")).map(String::from).collect();
+            fields.push(format!("SM:{}", sm));
+            *l = fields.join("\t");
+            n += 1;
+        }
+        if n == 0 {
+            warn!("No @RG in the header to set SM:{} in.", sm);
+        }
+    }
+    lines.extend(comments.iter().map(|c| format!("@CO\t{}", c)));
+    let text = lines.join("\n") + "\n";
+    bam::Header::from_template(&HeaderView::from_bytes(text.as_bytes()))
+}
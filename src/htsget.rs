@@ -0,0 +1,374 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::ffi::CString;
+use std::os::unix::ffi::OsStringExt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use log::{debug, error};
+
+use crate::ospath;
+use crate::status::{self, Code};
+
+// whether `path` is the URL of a slice on a GA4GH htsget server rather than a file: htsget://, or
+// htsget+http:// for a server without TLS
+pub fn is_url(path: &str) -> bool {
+    path.starts_with("htsget://") || path.starts_with("htsget+http://")
+}
+
+// the URL the ticket of an htsget URL is asked for at, in BAM unless it asks for a format
+fn ticket_url(url: &str) -> String {
+    let mut t = match url.strip_prefix("htsget://") {
+        Some(x) => format!("https://{}", x),
+        None => format!("http://{}", url.trim_start_matches("htsget+http://")),
+    };
+    if !t.contains("format=") {
+        t.push(if t.contains('?') { '&' } else { '?' });
+        t.push_str("format=BAM");
+    }
+    t
+}
+
+// curl, getting `url` with `headers`; they go in by stdin, the token among them kept off the command line
+fn curl(url: &str, headers: &[(String, String)]) -> io::Result<std::process::Child> {
+    let mut child = Command::new("curl")
+        .args(["-sSfL", "-H", "@-", url])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("curl has no stdin!");
+    for (k, v) in headers {
+        writeln!(stdin, "{}: {}", k, v)?;
+    }
+    Ok(child)
+}
+
+// a JSON value, as much of it as a ticket needs
+enum Json {
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+    Other,
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Obj(x) => x.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn str(&self) -> Option<&str> {
+        match self {
+            Json::Str(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    i: usize,
+}
+
+impl Parser<'_> {
+    fn skip(&mut self) {
+        while self.i < self.s.len() && self.s[self.i].is_ascii_whitespace() {
+            self.i += 1;
+        }
+    }
+
+    fn eat(&mut self, c: u8) -> Result<(), String> {
+        self.skip();
+        match self.s.get(self.i) {
+            Some(&x) if x == c => {
+                self.i += 1;
+                Ok(())
+            },
+            _ => Err(format!("expected '{}' at byte {}", c as char, self.i)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip();
+        match self.s.get(self.i) {
+            Some(b'"') => self.string().map(Json::Str),
+            Some(b'[') => {
+                self.i += 1;
+                let mut v = Vec::new();
+                self.skip();
+                if self.s.get(self.i) == Some(&b']') {
+                    self.i += 1;
+                    return Ok(Json::Arr(v));
+                }
+                loop {
+                    v.push(self.value()?);
+                    self.skip();
+                    match self.s.get(self.i) {
+                        Some(b',') => self.i += 1,
+                        Some(b']') => {
+                            self.i += 1;
+                            return Ok(Json::Arr(v));
+                        },
+                        _ => return Err(format!("expected ',' or ']' at byte {}", self.i)),
+                    }
+                }
+            },
+            Some(b'{') => {
+                self.i += 1;
+                let mut v = Vec::new();
+                self.skip();
+                if self.s.get(self.i) == Some(&b'}') {
+                    self.i += 1;
+                    return Ok(Json::Obj(v));
+                }
+                loop {
+                    self.skip();
+                    let k = self.string()?;
+                    self.eat(b':')?;
+                    v.push((k, self.value()?));
+                    self.skip();
+                    match self.s.get(self.i) {
+                        Some(b',') => self.i += 1,
+                        Some(b'}') => {
+                            self.i += 1;
+                            return Ok(Json::Obj(v));
+                        },
+                        _ => return Err(format!("expected ',' or '}}' at byte {}", self.i)),
+                    }
+                }
+            },
+            // numbers, true, false and null, none of which a ticket needs the value of
+            Some(_) => {
+                let start = self.i;
+                while self.i < self.s.len() && !b",]} \t\r\n".contains(&self.s[self.i]) {
+                    self.i += 1;
+                }
+                match self.i > start {
+                    true => Ok(Json::Other),
+                    false => Err(format!("unexpected '{}' at byte {}", self.s[start] as char, start)),
+                }
+            },
+            None => Err(String::from("unexpected end")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.eat(b'"')?;
+        let mut v: Vec<u8> = Vec::new();
+        loop {
+            let c = *self.s.get(self.i).ok_or("unterminated string")?;
+            self.i += 1;
+            match c {
+                b'"' => return String::from_utf8(v).map_err(|e| e.to_string()),
+                b'\\' => {
+                    let e = *self.s.get(self.i).ok_or("unterminated string")?;
+                    self.i += 1;
+                    match e {
+                        b'n' => v.push(b'\n'),
+                        b't' => v.push(b'\t'),
+                        b'r' => v.push(b'\r'),
+                        b'b' => v.push(8),
+                        b'f' => v.push(12),
+                        b'u' => {
+                            let hex = self.s.get(self.i..self.i + 4).ok_or("unterminated string")?;
+                            let c = u32::from_str_radix(&String::from_utf8_lossy(hex), 16).map_err(|e| e.to_string())?;
+                            self.i += 4;
+                            let mut buf = [0; 4];
+                            v.extend_from_slice(char::from_u32(c).unwrap_or('\u{fffd}').encode_utf8(&mut buf).as_bytes());
+                        },
+                        x => v.push(x),
+                    }
+                },
+                x => v.push(x),
+            }
+        }
+    }
+}
+
+// a block of a ticket: its URL, and the headers it is to be got with
+type Block = (String, Vec<(String, String)>);
+
+// the URLs of the blocks of a ticket, in order, with the headers each is to be got with
+fn parse_ticket(text: &[u8]) -> Result<Vec<Block>, String> {
+    let json = Parser { s: text, i: 0 }.value()?;
+    let htsget = json.get("htsget").ok_or("no \"htsget\" in the ticket")?;
+    if let Some(e) = htsget.get("error").and_then(Json::str) {
+        return Err(format!("{}: {}", e, htsget.get("message").and_then(Json::str).unwrap_or_default()));
+    }
+    let urls = match htsget.get("urls") {
+        Some(Json::Arr(x)) => x,
+        _ => return Err(String::from("no \"urls\" in the ticket")),
+    };
+    urls.iter().map(|u| {
+        let url = u.get("url").and_then(Json::str).ok_or("a block of the ticket has no \"url\"")?;
+        let headers = match u.get("headers") {
+            Some(Json::Obj(x)) => x.iter().filter_map(|(k, v)| v.str().map(|v| (k.clone(), v.to_string()))).collect(),
+            _ => Vec::new(),
+        };
+        Ok((url.to_string(), headers))
+    }).collect()
+}
+
+// the bytes of a data: URI, base64 or percent-encoded
+fn decode_data(uri: &str) -> Result<Vec<u8>, String> {
+    let (meta, data) = uri["data:".len()..].split_once(',').ok_or("a data: block has no ','")?;
+    if !meta.ends_with(";base64") {
+        let mut v = Vec::new();
+        let b = data.as_bytes();
+        let mut i = 0;
+        while i < b.len() {
+            match (b[i], b.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(&String::from_utf8_lossy(h), 16).ok())) {
+                (b'%', Some(x)) => {
+                    v.push(x);
+                    i += 3;
+                },
+                (x, _) => {
+                    v.push(x);
+                    i += 1;
+                },
+            }
+        }
+        return Ok(v);
+    }
+    let mut v = Vec::new();
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in data.bytes().filter(|&c| c != b'=' && !c.is_ascii_whitespace()) {
+        let x = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return Err(format!("'{}' in a base64 block", c as char)),
+        };
+        acc = (acc << 6) | x as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            v.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Ok(v)
+}
+
+// the ticket of `url`, asked for with a bearer token of $HTSGET_TOKEN when there is one
+fn ticket(url: &str) -> Vec<Block> {
+    let headers: Vec<(String, String)> = std::env::var("HTSGET_TOKEN").ok().filter(|t| !t.is_empty())
+        .map(|t| (String::from("Authorization"), format!("Bearer {}", t))).into_iter().collect();
+    let tu = ticket_url(url);
+    debug!("Asking {} for a ticket.", tu);
+    let out = curl(&tu, &headers).and_then(|c| c.wait_with_output()).unwrap_or_else(|e| {
+        error!("failed to run curl for {}: {}", url, e);
+        status::fail(Code::Io)
+    });
+    if !out.status.success() {
+        error!("failed to get the ticket of {} from {}!", url, tu);
+        status::fail(Code::Io);
+    }
+    parse_ticket(&out.stdout).unwrap_or_else(|e| {
+        error!("the ticket of {} is malformed: {}", url, e);
+        status::fail(Code::Malformed)
+    })
+}
+
+// write the blocks of a ticket to `out` in order, as they come; a reader gone early is no error
+fn relay(url: &str, blocks: &[Block], out: &mut dyn Write) -> io::Result<()> {
+    for (i, (u, headers)) in blocks.iter().enumerate() {
+        if u.starts_with("data:") {
+            let data = decode_data(u).unwrap_or_else(|e| {
+                error!("block {} of {} is malformed: {}", i + 1, url, e);
+                status::fail(Code::Malformed)
+            });
+            out.write_all(&data)?;
+            continue;
+        }
+        let mut child = curl(u, headers)?;
+        let mut stdout = child.stdout.take().expect("curl has no stdout!");
+        let copied = io::copy(&mut stdout, out);
+        drop(stdout);
+        let ok = child.wait()?.success();
+        copied?;
+        if !ok {
+            error!("failed to get block {} of {}!", i + 1, url);
+            status::fail(Code::Io);
+        }
+    }
+    out.flush()
+}
+
+// a named pipe at `fifo` the slice of `url` is read from, as a pipe of <(...) would be: its ticket is
+// got now, its blocks as the pipe is read
+pub fn open(url: &str, fifo: &str) {
+    let blocks = ticket(url);
+    let path = CString::new(ospath::path(fifo).into_os_string().into_vec()).expect("a temporary path with a NUL!");
+    if unsafe { libc::mkfifo(path.as_ptr(), 0o600) } != 0 {
+        error!("failed to create {}: {}", fifo, io::Error::last_os_error());
+        status::fail(Code::Io);
+    }
+    status::temporary(fifo);
+    let (url, fifo) = (url.to_string(), fifo.to_string());
+    thread::spawn(move || {
+        // opening blocks until the pipe is read
+        let relayed = OpenOptions::new().write(true).open(ospath::path(&fifo)).and_then(|mut fh| relay(&url, &blocks, &mut fh));
+        if let Err(e) = relayed {
+            debug!("stopped relaying {}: {}", url, e);
+        }
+    });
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticket_urls_ask_for_bam() {
+        assert_eq!(ticket_url("htsget://example.org/reads/NA12878?referenceName=chr1"), "https://example.org/reads/NA12878?referenceName=chr1&format=BAM");
+        assert_eq!(ticket_url("htsget+http://localhost:3000/reads/x"), "http://localhost:3000/reads/x?format=BAM");
+        assert_eq!(ticket_url("htsget://h/reads/x?format=CRAM"), "https://h/reads/x?format=CRAM");
+    }
+
+    #[test]
+    fn tickets_give_blocks_in_order() {
+        let ticket = br#"{"htsget": {"format": "BAM", "urls": [
+            {"url": "data:application/vnd.ga4gh.bam;base64,QkFNAQ=="},
+            {"url": "https://h/blocks/1", "headers": {"Range": "bytes=0-1023", "Authorization": "Bearer a\"b"}, "class": "body"},
+            {"url": "https://h/blocks/\u00e9"}
+        ], "md5": null, "size": 1024}}"#;
+        let blocks = parse_ticket(ticket).unwrap();
+        assert_eq!(blocks.len(), 3);
+        assert!(blocks[0].0.starts_with("data:") && blocks[0].1.is_empty());
+        assert_eq!(blocks[1].1, [(String::from("Range"), String::from("bytes=0-1023")), (String::from("Authorization"), String::from("Bearer a\"b"))]);
+        assert_eq!(blocks[2].0, "https://h/blocks/\u{e9}");
+    }
+
+    #[test]
+    fn ticket_errors_and_malformed_json() {
+        let e = parse_ticket(br#"{"htsget": {"error": "NotFound", "message": "no such read set"}}"#).unwrap_err();
+        assert_eq!(e, "NotFound: no such read set");
+        assert!(parse_ticket(br#"{"htsget": {"urls": [}"#).is_err());
+        assert!(parse_ticket(br#"{"htsget": {"urls": [{"url": "x"}]"#).is_err());
+        assert!(parse_ticket(br#"{"other": 1}"#).is_err());
+        assert!(parse_ticket(b"").is_err());
+    }
+
+    #[test]
+    fn data_blocks_decode() {
+        assert_eq!(decode_data("data:;base64,QkFNAQ==").unwrap(), b"BAM\x01");
+        assert_eq!(decode_data("data:;base64,").unwrap(), b"");
+        // the URL-safe alphabet, without padding
+        assert_eq!(decode_data("data:;base64,-_8").unwrap(), [0xfb, 0xff]);
+        assert_eq!(decode_data("data:;base64,aGVs bG8=").unwrap(), b"hello");
+        assert_eq!(decode_data("data:text/plain,a%20b%2").unwrap(), b"a b%2");
+        assert!(decode_data("data:;base64,a*b").is_err());
+        assert!(decode_data("data:nocomma").is_err());
+    }
+}
@@ -0,0 +1,94 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::time::{Duration, Instant};
+
+// the signal a run was asked to stop by, 0 until then
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+// what stands for a signal once --time-limit is up
+const TIME_LIMIT: i32 = -1;
+// when --time-limit is up, if given, and whether the passes reading now are ones it stops: those drawing
+// a sample, not those writing out one drawn already
+static DEADLINE: OnceLock<Instant> = OnceLock::new();
+static ARMED: AtomicBool = AtomicBool::new(true);
+// the input, and the virtual offset in it, a pass over the inputs stopped at
+static STOPPED_AT: Mutex<Option<(usize, i64)>> = Mutex::new(None);
+
+// only stores the signal, the reading loops being the ones to stop; a second signal, for a run that
+// does not stop soon enough, exits at once as the default action would
+extern "C" fn handle(signal: libc::c_int) {
+    if RECEIVED.swap(signal, Ordering::SeqCst) != 0 {
+        unsafe { libc::_exit(128 + signal) };
+    }
+}
+
+// catch SIGINT and SIGTERM, for the run to stop reading and either remove or finish its outputs
+pub fn install() {
+    let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+// stop the run as a signal would once `limit` is up, unless one came first; it is up for the reading
+// loops only (see `stopping`), so that a run done reading by then is not stopped at all
+pub fn limit(limit: Duration) {
+    if let Some(deadline) = Instant::now().checked_add(limit) {
+        let _ = DEADLINE.set(deadline);
+    }
+}
+
+// the passes from here on draw a sample, and are stopped by --time-limit
+pub fn arm() {
+    ARMED.store(true, Ordering::SeqCst);
+}
+
+// the passes from here on write out a sample drawn already, and are only stopped by a signal
+pub fn disarm() {
+    ARMED.store(false, Ordering::SeqCst);
+}
+
+// whether a reading loop is to stop: a signal came in, or --time-limit is up while armed, which then
+// stands for a signal received; a pass writing out a sample drawn already goes on once it is, so that
+// the sample drawn up to the limit is written out whole
+pub fn stopping() -> bool {
+    let armed = ARMED.load(Ordering::Relaxed);
+    if armed && DEADLINE.get().is_some_and(|&deadline| Instant::now() >= deadline) {
+        let _ = RECEIVED.compare_exchange(0, TIME_LIMIT, Ordering::SeqCst, Ordering::SeqCst);
+    }
+    match RECEIVED.load(Ordering::Relaxed) {
+        0 => false,
+        TIME_LIMIT => armed,
+        _ => true,
+    }
+}
+
+// the name of the signal received, if any
+pub fn received() -> Option<&'static str> {
+    match RECEIVED.load(Ordering::Relaxed) {
+        0 => None,
+        TIME_LIMIT => Some("--time-limit"),
+        libc::SIGINT => Some("SIGINT"),
+        _ => Some("SIGTERM"),
+    }
+}
+
+// whether it was --time-limit that stopped the run, which always finishes its outputs
+pub fn timed_out() -> bool {
+    RECEIVED.load(Ordering::Relaxed) == TIME_LIMIT
+}
+
+pub fn stopped_at(input: usize, voffset: i64) {
+    *STOPPED_AT.lock().unwrap() = Some((input, voffset));
+}
+
+// where the last pass stopped, for how much of the inputs a run stopped by --time-limit read
+pub fn stopped() -> Option<(usize, i64)> {
+    *STOPPED_AT.lock().unwrap()
+}
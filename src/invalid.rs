@@ -0,0 +1,109 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use log::{debug, error, warn};
+use rust_htslib::bam::Record;
+
+use crate::status::{self, Code};
+
+// per --skip-invalid, records htslib decodes but whose fields make no sense are skipped rather than
+// panicked on further down, up to --max-invalid-frac of those read
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// --max-invalid-frac, by its bits
+static MAX_FRAC: AtomicU64 = AtomicU64::new(0);
+// records read and skipped, over every pass over the inputs
+static SEEN: AtomicU64 = AtomicU64::new(0);
+static SKIPPED: AtomicU64 = AtomicU64::new(0);
+
+// records skipped to warn of one by one, the rest being logged at debug
+const WARNED: u64 = 10;
+// records to have read before the fraction skipped is held to --max-invalid-frac as the run goes, so
+// that a bad record early on does not stop it by itself
+const EARLY: u64 = 10_000;
+
+pub fn enable(max_frac: f64) {
+    MAX_FRAC.store(max_frac.to_bits(), Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn on() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn max_frac() -> f64 {
+    f64::from_bits(MAX_FRAC.load(Ordering::Relaxed))
+}
+
+// what is wrong with `r`, if anything: no qname, a position or a mate position before the start of its
+// reference, a reference id below -1, or a CIGAR of an unknown op or taking up more or fewer bases of the
+// read than SEQ has
+pub fn problem(r: &Record) -> Option<&'static str> {
+    if r.qname().is_empty() {
+        return Some("empty qname");
+    }
+    if r.tid() < -1 || r.mtid() < -1 {
+        return Some("negative reference id");
+    }
+    if r.pos() < -1 || (r.pos() < 0 && !r.is_unmapped()) || r.mpos() < -1 {
+        return Some("negative position");
+    }
+    let mut qlen = 0;
+    for &c in r.raw_cigar() {
+        match c & 0xf {
+            // M, I, S, = and X take up bases of the read, D, N, H and P do not
+            0 | 1 | 4 | 7 | 8 => qlen += (c >> 4) as usize,
+            2 | 3 | 5 | 6 => {},
+            _ => return Some("unknown CIGAR op"),
+        }
+    }
+    // a SEQ of '*' goes with any CIGAR
+    if qlen > 0 && r.seq_len() > 0 && qlen != r.seq_len() {
+        return Some("CIGAR and SEQ lengths differ");
+    }
+    None
+}
+
+// whether to skip `r`, counting it as read and, if skipped, logging why; always false without
+// --skip-invalid. Past `EARLY` records read, skipping more than --max-invalid-frac of them fails the run
+// then and there
+pub fn skip(r: &Record) -> bool {
+    if !on() {
+        return false;
+    }
+    let seen = SEEN.fetch_add(1, Ordering::Relaxed) + 1;
+    let Some(why) = problem(r) else {
+        return false;
+    };
+    let skipped = SKIPPED.fetch_add(1, Ordering::Relaxed) + 1;
+    let qname = String::from_utf8_lossy(r.qname());
+    match skipped {
+        WARNED => warn!("Skipping record {} ({}) as malformed: {}; further ones are logged at debug.", seen, qname, why),
+        n if n < WARNED => warn!("Skipping record {} ({}) as malformed: {}.", seen, qname, why),
+        _ => debug!("Skipping record {} ({}) as malformed: {}.", seen, qname, why),
+    }
+    if seen >= EARLY && skipped as f64 / seen as f64 > max_frac() {
+        error!("{} of the first {} records are malformed, over --max-invalid-frac {}!", skipped, seen, max_frac());
+        status::fail(Code::Malformed);
+    }
+    true
+}
+
+// log how many records were skipped, at the end of the run; whether they were more than
+// --max-invalid-frac of those read, which fails it
+pub fn report() -> bool {
+    let (seen, skipped) = (SEEN.load(Ordering::Relaxed), SKIPPED.load(Ordering::Relaxed));
+    if skipped == 0 {
+        return false;
+    }
+    let frac = skipped as f64 / seen as f64;
+    if frac > max_frac() {
+        error!("{} of {} records ({:.4}%) are malformed, over --max-invalid-frac {}!", skipped, seen, 100.0 * frac, max_frac());
+        return true;
+    }
+    warn!("{} of {} records ({:.4}%) skipped as malformed.", skipped, seen, 100.0 * frac);
+    false
+}
@@ -0,0 +1,17 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+// the samplers behind sam_subsample, for other programs to subsample with; the command line is in
+// main.rs
+pub mod reservoir;
+pub mod backend;
+pub mod uniform;
+pub mod rng;
+pub mod observer;
+pub mod stream;
+pub mod sampler;
+pub mod pipeline;
+pub mod tagging;
@@ -0,0 +1,348 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use rust_htslib::bgzf;
+use rust_htslib::bam::{self, HeaderView, Read, Record};
+use log::warn;
+
+use crate::ospath;
+
+// intervals of a BED file by reference name, 0-based half-open, sorted and merged
+pub struct Regions(HashMap<String, Vec<(i64, i64)>>);
+
+impl Regions {
+    pub fn from_bed(path: &str) -> Result<Regions, String> {
+        let text = fs::read_to_string(ospath::path(path)).map_err(|e| format!("failed to read {}: {}", path, e))?;
+        let mut by_name: HashMap<String, Vec<(i64, i64)>> = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") {
+                continue;
+            }
+            let mut fields = line.split('\t');
+            let bad = || format!("{}:{} is not a BED line: {}", path, i + 1, line);
+            let name = fields.next().ok_or_else(bad)?;
+            let start: i64 = fields.next().and_then(|x| x.trim().parse().ok()).ok_or_else(bad)?;
+            let end: i64 = fields.next().and_then(|x| x.trim().parse().ok()).ok_or_else(bad)?;
+            if start < 0 || end < start {
+                return Err(bad());
+            }
+            by_name.entry(name.to_string()).or_default().push((start, end));
+        }
+        by_name.values_mut().for_each(merge);
+        Ok(Regions(by_name))
+    }
+
+}
+
+// variant sites of a VCF by reference name, each spanning its REF allele, 0-based half-open and sorted
+pub struct Sites(HashMap<String, Vec<(i64, i64)>>);
+
+impl Sites {
+    // a VCF, bgzipped or not
+    pub fn from_vcf(path: &str) -> Result<Sites, String> {
+        let fh = bgzf::Reader::from_path(ospath::path(path)).map_err(|e| format!("failed to read {}: {}", path, e))?;
+        let mut by_name: HashMap<String, Vec<(i64, i64)>> = HashMap::new();
+        for (i, line) in BufReader::new(fh).lines().enumerate() {
+            let line = line.map_err(|e| format!("failed to read {}: {}", path, e))?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split('\t');
+            let bad = || format!("{}:{} is not a VCF record: {}", path, i + 1, line);
+            let name = fields.next().ok_or_else(bad)?;
+            let pos: i64 = fields.next().and_then(|x| x.parse().ok()).filter(|&x| x > 0).ok_or_else(bad)?;
+            let len = fields.nth(1).filter(|x| !x.is_empty()).ok_or_else(bad)?.len() as i64;
+            by_name.entry(name.to_string()).or_default().push((pos - 1, pos - 1 + len));
+        }
+        for v in by_name.values_mut() {
+            v.sort_unstable();
+            v.dedup();
+        }
+        Ok(Sites(by_name))
+    }
+}
+
+// the alignments of another BAM, per --exclude-overlapping: the reference spans of its mapped records,
+// merged, per tid of the header of the run; it is read through once, when opened, and every template
+// is then looked up in memory
+pub struct Footprint(Vec<Vec<(i64, i64)>>);
+
+impl Footprint {
+    pub fn open(path: &str, header: &HeaderView) -> Result<Footprint, String> {
+        let mut fh = bam::Reader::from_path(ospath::path(path)).map_err(|e| format!("failed to read {}: {}", path, e))?;
+        // per tid of the other BAM, that of the header of the run; None for references it does not have
+        let tids: Vec<Option<u32>> = (0..fh.header().target_count()).map(|tid| header.tid(fh.header().tid2name(tid))).collect();
+        if tids.iter().all(Option::is_none) {
+            warn!("{} shares no reference with the inputs; --exclude-overlapping drops nothing.", path);
+        }
+        let mut by_tid = vec![Vec::new(); header.target_count() as usize];
+        let mut r = Record::new();
+        while let Some(x) = fh.read(&mut r) {
+            x.map_err(|e| format!("failed to read {}: {}", path, e))?;
+            // unmapped mates placed at the other's position are not alignments
+            if r.is_unmapped() {
+                continue;
+            }
+            if let Some(&Some(tid)) = tids.get(r.tid() as usize) {
+                add(&mut by_tid[tid as usize], r.pos(), r.cigar().end_pos().max(r.pos() + 1));
+            }
+        }
+        Ok(Footprint::merged(by_tid))
+    }
+
+    fn merged(mut by_tid: Vec<Vec<(i64, i64)>>) -> Footprint {
+        by_tid.iter_mut().for_each(merge);
+        Footprint(by_tid)
+    }
+
+    // whether a mapped alignment of the other BAM overlaps that of `r`
+    fn covers(&self, r: &Record) -> bool {
+        self.0.get(r.tid() as usize).is_some_and(|v| overlaps(v, r.pos(), r.cigar().end_pos()))
+    }
+}
+
+// `[start, end)` added to the intervals of a reference; those of a coordinate-sorted BAM come in order
+// and are merged as they come, so that only the merged ones are held, others are left to `merge`
+fn add(v: &mut Vec<(i64, i64)>, start: i64, end: i64) {
+    match v.last_mut() {
+        Some(last) if last.0 <= start && start <= last.1 => last.1 = last.1.max(end),
+        _ => v.push((start, end)),
+    }
+}
+
+// intervals sorted, and those overlapping or abutting merged
+fn merge(v: &mut Vec<(i64, i64)>) {
+    v.sort_unstable();
+    let mut merged: Vec<(i64, i64)> = Vec::with_capacity(v.len());
+    for &(start, end) in v.iter() {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *v = merged;
+}
+
+// intervals by reference name, per tid of `header`
+fn resolve(by_name: &HashMap<String, Vec<(i64, i64)>>, header: &HeaderView) -> Vec<Vec<(i64, i64)>> {
+    let mut by_tid = vec![Vec::new(); header.target_count() as usize];
+    for (name, v) in by_name {
+        if let Some(tid) = header.tid(name.as_bytes()) {
+            by_tid[tid as usize] = v.clone();
+        }
+    }
+    by_tid
+}
+
+// whether [start, end) overlaps any of the sorted, disjoint `intervals`
+fn overlaps(intervals: &[(i64, i64)], start: i64, end: i64) -> bool {
+    let i = intervals.partition_point(|&(_, e)| e <= start);
+    intervals.get(i).is_some_and(|&(s, _)| s < end.max(start + 1))
+}
+
+// where templates may align, resolved against the (merged) header of a run since tids differ by header
+pub struct Loci {
+    // per tid, whether it is one of --contigs; None if all are
+    allowed: Option<Vec<bool>>,
+    // per tid, the --exclude-regions intervals
+    excluded: Option<Vec<Vec<(i64, i64)>>>,
+    // per tid, the --regions intervals, and whether every mapped read has to overlap one
+    included: Option<Vec<Vec<(i64, i64)>>>,
+    require_all: bool,
+    // per tid, the --sites, and how many templates each has taken against --max-per-site; the longest
+    // REF bounds how far back a site overlapping a read may start
+    sites: Option<Vec<Vec<(i64, i64)>>>,
+    taken: Vec<Vec<usize>>,
+    max_per_site: Option<usize>,
+    longest: i64,
+    // the alignments of --exclude-overlapping
+    footprint: Option<Footprint>,
+}
+
+// a mapped primary read overlapping some of `by_tid`
+fn hits(by_tid: &[Vec<(i64, i64)>], r: &Record) -> bool {
+    by_tid.get(r.tid() as usize).is_some_and(|v| overlaps(v, r.pos(), r.cigar().end_pos()))
+}
+
+fn mapped_primary(rs: &[Record]) -> impl Iterator<Item = &Record> {
+    rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary() && !r.is_unmapped())
+}
+
+impl Loci {
+    pub fn new(header: &HeaderView, contigs: Option<&[String]>, exclude: Option<&Regions>, include: Option<&Regions>, require_all: bool, sites: Option<&Sites>, max_per_site: Option<usize>) -> Loci {
+        let allowed = contigs.map(|names| {
+            let mut allowed = vec![false; header.target_count() as usize];
+            for name in names {
+                match header.tid(name.as_bytes()) {
+                    Some(tid) => allowed[tid as usize] = true,
+                    None => warn!("--contigs {} is not in the header, ignored.", name),
+                }
+            }
+            allowed
+        });
+        let sites = sites.map(|s| resolve(&s.0, header));
+        let taken = match (&sites, max_per_site) {
+            (Some(sites), Some(_)) => sites.iter().map(|v| vec![0; v.len()]).collect(),
+            _ => Vec::new(),
+        };
+        let longest = sites.iter().flatten().flatten().map(|&(start, end)| end - start).max().unwrap_or_default();
+        Loci {
+            allowed,
+            excluded: exclude.map(|r| resolve(&r.0, header)),
+            included: include.map(|r| resolve(&r.0, header)),
+            require_all,
+            sites,
+            taken,
+            max_per_site,
+            longest,
+            footprint: None,
+        }
+    }
+
+    // the same, but for --exclude-overlapping
+    pub fn avoiding(mut self, footprint: Option<Footprint>) -> Loci {
+        self.footprint = footprint;
+        self
+    }
+
+    // the (tid, index) of the --sites a primary alignment of a template overlaps; None without --sites
+    fn sites_of(&self, rs: &[Record]) -> Option<Vec<(usize, usize)>> {
+        let sites = self.sites.as_ref()?;
+        let mut at: Vec<(usize, usize)> = Vec::new();
+        for r in mapped_primary(rs) {
+            let (tid, start, end) = (r.tid() as usize, r.pos(), r.cigar().end_pos());
+            let v = match sites.get(tid) {
+                Some(v) => v,
+                None => continue,
+            };
+            let hi = v.partition_point(|&(s, _)| s < end.max(start + 1));
+            let lo = v[..hi].partition_point(|&(s, _)| s + self.longest <= start);
+            at.extend((lo..hi).filter(|&j| v[j].1 > start).map(|j| (tid, j)));
+        }
+        at.sort_unstable();
+        at.dedup();
+        Some(at)
+    }
+
+    // whether a template is at --sites: a primary alignment overlaps a site that, with --max-per-site,
+    // has not taken that many templates yet; every site it overlaps then takes it
+    pub fn at_sites(&mut self, rs: &[Record]) -> bool {
+        let at = match self.sites_of(rs) {
+            Some(x) => x,
+            None => return true,
+        };
+        if let Some(max) = self.max_per_site {
+            if at.iter().all(|&(tid, j)| self.taken[tid][j] >= max) {
+                return false;
+            }
+            for &(tid, j) in &at {
+                self.taken[tid][j] += 1;
+            }
+        }
+        !at.is_empty()
+    }
+
+    // the same, but leaving --max-per-site out of it, and what the sites have taken as it is
+    pub fn near_sites(&self, rs: &[Record]) -> bool {
+        self.sites_of(rs).map_or(true, |at| !at.is_empty())
+    }
+
+    // forget what --max-per-site has taken, for another pass over the inputs
+    pub fn rewind(&mut self) {
+        self.taken.iter_mut().for_each(|v| v.fill(0));
+    }
+
+    // whether a template is in --regions: one of its primary alignments overlaps them, and so does the
+    // other mate's with --require-both-in-region; the whole template goes along either way, mates
+    // aligned elsewhere included
+    pub fn includes(&self, rs: &[Record]) -> bool {
+        let included = match &self.included {
+            Some(x) => x,
+            None => return true,
+        };
+        match self.require_all {
+            true => {
+                let mut mapped = mapped_primary(rs).peekable();
+                mapped.peek().is_some() && mapped.all(|r| hits(included, r))
+            },
+            false => mapped_primary(rs).any(|r| hits(included, r)),
+        }
+    }
+
+    // whether a primary alignment of a template overlaps any of --exclude-regions
+    pub fn excludes(&self, rs: &[Record]) -> bool {
+        let excluded = match &self.excluded {
+            Some(x) => x,
+            None => return false,
+        };
+        mapped_primary(rs).any(|r| hits(excluded, r))
+    }
+
+    // whether a primary alignment of a template overlaps an alignment of --exclude-overlapping
+    pub fn overlaps_footprint(&self, rs: &[Record]) -> bool {
+        let footprint = match &self.footprint {
+            Some(x) => x,
+            None => return false,
+        };
+        mapped_primary(rs).any(|r| footprint.covers(r))
+    }
+
+    // whether the primary alignments of a template are all on allowed references; a template with
+    // none aligned is only allowed when every reference is
+    pub fn admits(&self, rs: &[Record]) -> bool {
+        let allowed = match &self.allowed {
+            Some(a) => a,
+            None => return true,
+        };
+        let mut mapped = mapped_primary(rs).peekable();
+        mapped.peek().is_some() && mapped.all(|r| allowed.get(r.tid() as usize).copied().unwrap_or(false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Cigar, CigarString};
+
+    fn read(tid: i32, pos: i64, len: u32) -> Record {
+        let mut r = Record::new();
+        r.set(b"r0", Some(&CigarString(vec![Cigar::Match(len)])), &vec![b'A'; len as usize], &vec![30; len as usize]);
+        r.set_tid(tid);
+        r.set_pos(pos);
+        r
+    }
+
+    #[test]
+    fn spans_merge_in_any_order() {
+        // sorted, as they come from a coordinate-sorted BAM, and shuffled
+        for spans in [[(10, 20), (15, 30), (30, 35), (50, 60)], [(50, 60), (30, 35), (10, 20), (15, 30)]] {
+            let mut v = Vec::new();
+            spans.iter().for_each(|&(start, end)| add(&mut v, start, end));
+            merge(&mut v);
+            assert_eq!(v, [(10, 35), (50, 60)]);
+        }
+    }
+
+    #[test]
+    fn footprint_covers_what_overlaps() {
+        let mut by_tid = vec![Vec::new(); 2];
+        add(&mut by_tid[0], 100, 150);
+        add(&mut by_tid[0], 120, 200);
+        add(&mut by_tid[1], 1000, 1001);
+        let footprint = Footprint::merged(by_tid);
+        assert!(footprint.covers(&read(0, 190, 20)));
+        assert!(footprint.covers(&read(0, 50, 51)));
+        assert!(!footprint.covers(&read(0, 200, 10)));
+        assert!(!footprint.covers(&read(0, 50, 50)));
+        assert!(footprint.covers(&read(1, 990, 11)));
+        assert!(!footprint.covers(&read(1, 1001, 5)));
+        // a reference the other BAM does not have
+        assert!(!footprint.covers(&read(2, 100, 10)));
+    }
+}
@@ -7,39 +7,479 @@
 use std::env;
 use std::process::exit;
 use std::mem::take;
-use std::path::Path;
-use std::io::Write;
-use rust_htslib::{bam, bam::Read, bam::Record};
-use rand::prelude::*;
-use rand::SeedableRng;
-use rand_pcg::Pcg64;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::os::unix::fs::FileTypeExt;
+use std::fs::{self, File};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::io::{BufWriter, Read as _, Write};
+use std::time::{Duration, Instant};
+use rust_htslib::{bam, bam::Read, bam::Record, bgzf};
 use chrono::Local;
-use getopts::Options;
+use getopts::{Matches, Options};
 use env_logger::{self, Builder};
-use log::{error, warn, info, debug, trace, LevelFilter};
+use log::{error, warn, info, debug, LevelFilter};
+use regex::bytes::Regex;
+use rand::seq::SliceRandom;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sam_subsample::{pipeline, reservoir, rng, tagging, uniform};
+use sam_subsample::backend::{RecordSink, RecordSource};
+use sam_subsample::sampler::{self, Decision, Sampler};
+
+mod header;
+mod checkpoint;
+mod budget;
+mod status;
+mod completions;
+mod units;
+mod depth;
+mod tags;
+mod strata;
+mod timing;
+mod loci;
+mod stats;
+mod split;
+mod seek;
+mod complexity;
+mod saturation;
+mod digest;
+mod profile;
+mod track;
+mod metadata;
+mod bed;
+mod fixmate;
+mod generate;
+mod verify;
+mod interrupt;
+mod collate;
+mod regroup;
+mod snapshot;
+mod fastq;
+mod validate;
+mod htsget;
+mod mix;
+mod contains;
+mod tui;
+mod resources;
+mod invalid;
+mod qidx;
+mod columnar;
+mod ospath;
+
+use reservoir::{RecordSet, Reservoir, Chunked, Sampling, BottomK, Ends};
+use rng::Seed;
+use checkpoint::State;
+use header::TidMap;
+use status::{Code, bad_args};
+use completions::{OptDef, Kind};
+use depth::DepthCap;
+use uniform::Coverage;
+use tags::{TagEdit, TagFilter};
+use strata::{Alleles, Amplicons, Bins, Chromosomes, Combined, GcBins, Lanes, Samples, Strata, TagValues, TileList, Tiles};
+use timing::TimeRange;
+use loci::{Footprint, Loci, Regions, Sites};
+use track::Track;
+use metadata::Metadata;
+use columnar::Columns;
+use bed::Spans;
+use stats::{TemplateStats, Tallies, Distributions, DupBy, Duplication, Signature};
+use complexity::Complexity;
+use saturation::Saturation;
+use digest::Selection;
+use profile::Phase;
+use split::{Chunks, ShardBy, Shards, SplitBy, Splitter};
+use regroup::OnRegrouped;
 
 
-type RecordSet = Vec<Record>; 
 static VERSION: &str = "0.1.0";
 
 struct Params {
-    infile: String,
-    outfile: String,
+    infiles: Vec<String>,
+    outfiles: Vec<String>,
     num: usize,
+    // of the templates of the inputs, turned into `num` once logging is set up
+    fraction: Option<f64>,
+    // the smaller of several --num, largest first, with their outfiles
+    nested: Vec<(usize, String)>,
     seed: u64,
+    rng: rng::Kind,
     level: String,
+    // levels of modules over `level`, per --log-filter
+    log_filter: Option<String>,
+    on_broken_pairs: BrokenPairs,
+    singletons: Policy,
+    unmapped: Policy,
+    half_mapped: Policy,
+    // the options given going by where reads align, which inputs without @SQ lines do not say, and
+    // whether there are such inputs, found once logging is set up
+    coordinate_options: Vec<String>,
+    unaligned: bool,
+    threads: usize,
+    chunk_size: usize,
+    checkpoint: Option<String>,
+    checkpoint_every: usize,
+    // a reservoir saved by an earlier run to carry on over the inputs, and where to save it for the next
+    load_state: Option<String>,
+    save_state: Option<String>,
+    // where the templates passing the filters are kept per --cache-index, and the options deciding which do
+    cache_index: Option<(String, String)>,
+    max_mem: Option<usize>,
+    compress_reservoir: bool,
+    max_records: Option<usize>,
+    offsets_only: bool,
+    bernoulli: bool,
+    expected_templates: Option<usize>,
+    log_file: Option<String>,
+    log_format: LogFormat,
+    strict: bool,
+    allow_fewer: bool,
+    min_templates: usize,
+    exact: bool,
+    finalize_on_interrupt: bool,
+    time_limit: Option<Duration>,
+    mode: Mode,
+    pick: Pick,
+    max_depth: usize,
+    target_depth: f64,
+    window: usize,
+    track: Option<Track>,
+    // per --target-depths, in place of --target-depth
+    depths: Option<Track>,
+    off_track: f64,
+    allocate: Allocate,
+    normalize_by: NormalizeBy,
+    ratio: Option<Vec<usize>>,
+    name_regex: Option<Regex>,
+    name_regex_invert: bool,
+    exclude_tiles: Option<TileList>,
+    require_tags: Vec<TagFilter>,
+    exclude_tags: Vec<TagFilter>,
+    rest_outfile: Option<String>,
+    names_out: Option<String>,
+    metadata_out: Option<String>,
+    metadata_tags: Vec<[u8; 2]>,
+    // the file of --arrow-out, in the format of its extension, and the tags of --arrow-tags
+    arrow_out: Option<(String, columnar::Format)>,
+    arrow_tags: Vec<[u8; 2]>,
+    bed_out: Option<String>,
+    strata: Option<Strata>,
+    // how far the allele fraction of a site of --preserve-vaf may drift
+    vaf_tolerance: f64,
+    fractions: Option<Vec<f64>>,
+    // templates taken from every stratum, per --per-amplicon, --per-sample-num or --per-tile-num, rather than --num split
+    per_stratum: Option<usize>,
+    // templates taken from strata by key, per --quota
+    quotas: Option<Vec<(String, usize)>>,
+    equal_strata: bool,
+    // templates every stratum gets before the rest of --num is split in proportion, per --min-per-stratum
+    min_per_stratum: Option<usize>,
+    deterministic: bool,
+    every: Option<usize>,
+    phase: Option<usize>,
+    // the stages of --pipeline, parsed again once the seed is known
+    pipeline: Option<String>,
+    group_by: GroupBy,
+    auto_collate: bool,
+    on_regrouped: Option<OnRegrouped>,
+    max_per_molecule: Option<usize>,
+    time_range: Option<TimeRange>,
+    contigs: Option<Vec<String>>,
+    exclude_regions: Option<Regions>,
+    // the BAM of --exclude-overlapping
+    exclude_overlapping: Option<String>,
+    regions: Option<Regions>,
+    require_both_in_region: bool,
+    sites: Option<Sites>,
+    max_per_site: Option<usize>,
+    proper_pairs_only: bool,
+    only_duplicates: bool,
+    max_nm: Option<i64>,
+    max_nm_frac: Option<f64>,
+    min_aligned_frac: Option<f64>,
+    spliced: Spliced,
+    min_tlen: Option<i64>,
+    max_tlen: Option<i64>,
+    template_stats: Option<String>,
+    tallies: Option<String>,
+    distribution_report: Option<String>,
+    duplication: Option<DupBy>,
+    dedup: bool,
+    complexity: Option<String>,
+    saturation: Option<String>,
+    per_cell: bool,
+    digest: Option<String>,
+    validate_output: bool,
+    // rewrite every output with only the @SQ lines its records are on
+    trim_header: bool,
+    // whether to walk every BGZF block of the inputs per --check-input blocks, rather than only look for
+    // the EOF block
+    check_input: Option<bool>,
+    // --max-invalid-frac, if malformed records are skipped per --skip-invalid
+    skip_invalid: Option<f64>,
+    plan: bool,
+    // draw a dashboard on stderr instead of logging info lines there
+    tui: bool,
+    timing: bool,
+    // tally, for every filter, the templates it turns down, first or not
+    filter_stats: bool,
+    // the qnames of --always-include
+    always_include: Option<HashSet<Vec<u8>>>,
+    keep_order: bool,
+    sort_output: bool,
+    split_by: Option<SplitBy>,
+    reads: Reads,
+    primary_only: bool,
+    max_secondary: Option<usize>,
+    // most secondary and supplementary records written of a template, per --max-records-per-template
+    max_records_per_template: Option<usize>,
+    repair_sa: bool,
+    rescue_unmapped_mates: bool,
+    strip_bad_modifications: bool,
+    fixmate: bool,
+    tag_edit: Option<TagEdit>,
+    anonymize_names: bool,
+    comments: Vec<String>,
+    sample_name: Option<String>,
+    // the ID and the @RG line of --set-read-group
+    read_group: Option<(String, String)>,
+    min_records: usize,
+    allowlist: Option<HashSet<String>>,
+    max_open_files: usize,
+    records_per_file: Option<usize>,
+    shards: Option<usize>,
+    shard_by: ShardBy,
+    skip: usize,
+    head: Option<usize>,
+}
+
+// what records are sampled together: those of a qname (a template), of a PacBio ZMW, or none with
+// --per-read; with --group-by tag, templates are still read by qname but sampled by the molecule
+// barcode in this tag, e.g. BX of linked reads
+#[derive(Clone, Copy, PartialEq)]
+enum GroupBy {
+    Qname,
+    Zmw,
+    Read,
+    Molecule([u8; 2]),
+}
+
+impl GroupBy {
+    // the sort order the inputs need; none when records are not grouped
+    fn sort_order(self) -> &'static str {
+        match self {
+            GroupBy::Read => "",
+            _ => "queryname",
+        }
+    }
+}
+
+// the part of a qname records are grouped by; for a ZMW, the movie/zmw prefix of movie/zmw/start_end
+fn group_key(qname: &[u8], group_by: GroupBy) -> &[u8] {
+    match group_by {
+        GroupBy::Qname | GroupBy::Read | GroupBy::Molecule(_) => qname,
+        GroupBy::Zmw => match qname.iter().enumerate().filter(|(_, &c)| c == b'/').nth(1) {
+            Some((i, _)) => &qname[..i],
+            None => qname,
+        },
+    }
+}
+
+// what --mode normalize equalizes inputs on
+#[derive(Clone, Copy, PartialEq)]
+enum NormalizeBy {
+    Templates,
+    Bases,
+}
+
+// how --num is split among inputs written to outputs of their own
+#[derive(Clone, Copy, PartialEq)]
+enum Allocate {
+    Proportional,
+    Equal,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Subsample,
+    CapDepth,
+    Uniform,
+    Normalize,
+    RandomAccess,
+    Thin,
+    // --mode subsample with --stream-through
+    StreamThrough,
+    // --mode subsample of --fq1 and --fq2
+    Fastq,
+}
+
+// which templates --mode subsample takes: --num at random, or the first or last --num of the input, per
+// --mode first and last
+#[derive(Clone, Copy, PartialEq)]
+enum Pick {
+    Random,
+    First,
+    Last,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LogFormat {
+    Plain,
+    Json,
+}
+
+// how the sample is held until it is written out
+#[derive(Clone, Copy, PartialEq)]
+enum Strategy {
+    Reservoir,
+    TwoPass,
+    Systematic,
+    Offsets,
+    // by the molecule barcodes of --group-by tag, in two passes
+    Molecules,
+    // by a coin flip per template, then cut down to --num, per --bernoulli
+    Bernoulli,
+    // by indices into the templates of a --cache-index, read back by where they are
+    Cached,
+    // by the stages of --pipeline, the sample held in memory
+    Pipeline,
+}
+
+impl Strategy {
+    fn name(self) -> &'static str {
+        match self {
+            Strategy::Reservoir => "reservoir",
+            Strategy::TwoPass => "two-pass",
+            Strategy::Systematic => "systematic",
+            Strategy::Offsets => "offsets",
+            Strategy::Molecules => "molecules",
+            Strategy::Bernoulli => "bernoulli",
+            Strategy::Cached => "cached",
+            Strategy::Pipeline => "pipeline",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum BrokenPairs {
+    Keep,
+    Drop,
+    Fail,
+}
+
+// which templates --spliced keeps, by whether a primary alignment spans a splice junction
+#[derive(Clone, Copy, PartialEq)]
+enum Spliced {
+    Any,
+    Only,
+    Exclude,
 }
 
-fn init_logger(level: &str) {
+// which mates of a sampled template are written, per --reads; SE reads count as read 1
+#[derive(Clone, Copy, PartialEq)]
+enum Reads {
+    R1,
+    R2,
+    Both,
+}
+
+impl Reads {
+    fn admits(self, r: &Record) -> bool {
+        match self {
+            Reads::R1 => !r.is_paired() || r.is_first_in_template(),
+            Reads::R2 => r.is_paired() && r.is_last_in_template(),
+            Reads::Both => true,
+        }
+    }
+}
+
+// whether a class of templates is kept alongside the rest, dropped, or the only one kept
+#[derive(Clone, Copy, PartialEq)]
+enum Policy {
+    Keep,
+    Drop,
+    Only,
+}
+
+impl Policy {
+    fn parse(opt: &str, s: &str) -> Policy {
+        match s {
+            "keep" => Policy::Keep,
+            "drop" => Policy::Drop,
+            "only" => Policy::Only,
+            _ => bad_args(&format!("invalid --{}, choose from 'keep', 'drop', 'only'", opt)),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Policy::Keep => "keep",
+            Policy::Drop => "drop",
+            Policy::Only => "only",
+        }
+    }
+
+    // whether a template in (or not in) the class passes
+    fn admits(self, in_class: bool) -> bool {
+        match self {
+            Policy::Keep => true,
+            Policy::Drop => !in_class,
+            Policy::Only => in_class,
+        }
+    }
+}
+
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// log to stderr and, if given, to a file as well, one line per message either way
+fn init_logger(level: &str, filter: Option<&str>, log_file: Option<&str>, log_format: LogFormat) {
+    let file = log_file.map(|path| match File::create(ospath::path(path)) {
+        Ok(f) => Arc::new(Mutex::new(f)),
+        Err(e) => {
+            status::complain(&format!("failed to write --log-file {}: {}", path, e));
+            status::fail(Code::Io)
+        },
+    });
     Builder::new()
-    .format(|buf, record| {
-        writeln!(
-            buf,
-            "[{} {}] {}",
-            Local::now().format("%Y-%m-%d %H:%M:%S%.3f %z"),
-            record.level(),
-            record.args()
-        )
+    .format(move |buf, record| {
+        let time = Local::now().format("%Y-%m-%d %H:%M:%S%.3f %z");
+        if record.level() == log::Level::Error {
+            status::note(&record.args().to_string());
+        }
+        let line = match log_format {
+            LogFormat::Plain => format!("[{} {}] {}", time, record.level(), record.args()),
+            LogFormat::Json => format!(
+                "{{\"time\":\"{}\",\"level\":\"{}\",\"message\":\"{}\"}}",
+                time,
+                record.level(),
+                json_escape(&record.args().to_string())
+            ),
+        };
+        if let Some(f) = &file {
+            writeln!(f.lock().unwrap(), "{}", line)?;
+        }
+        // the --tui dashboard takes the place of log lines on stderr
+        if tui::on() {
+            tui::message(record.level(), &line);
+            return Ok(());
+        }
+        writeln!(buf, "{}", line)
     })
     .filter(None, 
         match level {
@@ -48,186 +488,4642 @@ fn init_logger(level: &str) {
             "info"  => LevelFilter::Info,
             "debug" => LevelFilter::Debug,
             "trace" => LevelFilter::Trace,
-            _ => panic!("unknown debug level!"),
+            _ => bad_args("invalid --level, choose from 'error', 'warn', 'info', 'debug', 'trace'"),
             })
+    // per --log-filter, on top of the level of the rest
+    .parse_filters(filter.unwrap_or_default())
     .init();
 }
 
+// every option, registered with getopts and listed in shell completions alike
+// options going by where reads align, of no use on unaligned BAMs
+static COORDINATE_OPTIONS: &[&str] = &[
+    "contigs", "exclude-regions", "exclude-overlapping", "regions", "sites", "proper-pairs-only", "min-tlen", "max-tlen",
+    "max-nm", "max-nm-frac", "min-aligned-frac", "spliced", "balance-strands", "amplicons", "preserve-vaf", "repair-sa",
+    "rescue-unmapped-mates", "fixmate", "trim-header",
+];
+
+static OPTIONS: &[OptDef] = &[
+    ("i", "infile", "input BAM/SAM/SAM.GZ, queryname sorted, or an htsget:// URL, fetched with curl, which has to be on the PATH; repeat to concatenate several inputs", "FILE", Kind::Multi),
+    ("", "pool", "an input and its weight, in place of --infile; repeat for every input, whose templates make up their weight's share of --num in a single output, e.g. a.bam:0.7", "FILE:WEIGHT", Kind::Multi),
+    ("", "input-format", "format of --infile, choose from 'auto', 'sam', 'bam' (default: auto); auto goes by the extension, .bam, .sam or .sam.gz, while sam (gzipped or not) and bam take any name, e.g. /dev/fd/63", "", Kind::Opt),
+    ("", "fq1", "FASTQ of the first reads of pairs, or of single reads, gzipped or not, to sample instead of --infile; written as FASTQ to the first --outfile, gzipped if its name ends in .gz (default: None)", "FILE", Kind::Opt),
+    ("", "fq2", "with --fq1, FASTQ of the second reads, in the same order; written to the second --outfile (default: None)", "FILE", Kind::Opt),
+    ("o", "outfile", "output BAM; repeat once per --infile to downsample each into its own output instead of merging", "FILE", Kind::Multi),
+    ("", "outfile-template", "name the outputs instead of --outfile, filling in {stem} (the input without directory and extension), {num}, {seed}, {index} (the number of the input, from 1) and, with --split-by, {group} or {stratum}; one output per --infile unless all come out the same, e.g. '{stem}.n{num}.s{seed}.bam' (default: None)", "TEMPLATE", Kind::Opt),
+    ("", "output-fd", "write the output BAM to this file descriptor, inherited from the parent process, instead of --outfile (default: None)", "INTEGER", Kind::Opt),
+    ("n", "num", "number of reads (read pairs if PE) to downsample; K/M/G suffixes allowed, e.g. 2.5M; given several times, the smaller samples are subsets of the larger, written to the --outfile with {num} in its name filled in (default: 5000)", "INTEGER", Kind::Multi),
+    ("", "fraction", "sample this fraction of the templates of the inputs instead of --num, e.g. 0.1, turned into --num up front by counting the templates of the inputs in a pass of their own, so not with a pipe (default: None)", "NUMBER", Kind::Opt),
+    ("", "always-include", "write the templates of the qnames in this file, one per line, gzipped or not, whatever the filters, counted against --num, and fill the rest of --num at random (default: None)", "FILE", Kind::Opt),
+    ("s", "seed", "seed (default: None)", "INTEGER", Kind::Opt),
+    ("", "seed-from-input", "derive the seed from the size and first MiB of the inputs, so that the same inputs always give the same subsample", "", Kind::Flag),
+    ("", "rng", "generator to draw with, choose from 'pcg64', 'xoshiro' (xoshiro256++, the quickest), 'chacha' (ChaCha20, cryptographically secure, seeded from the operating system rather than the clock without --seed) (default: pcg64)", "", Kind::Opt),
+    ("", "level", "level of debugging info, choose from 'error', 'warn', 'info', 'debug', 'trace' (default: $SAM_SUBSAMPLE_LEVEL, or info)", "", Kind::Opt),
+    ("v", "verbose", "log at level debug, or trace given twice (-vv), in place of --level", "", Kind::Count),
+    ("q", "quiet", "log errors only, in place of --level", "", Kind::Flag),
+    ("", "log-filter", "levels of modules, in the syntax of env_logger's RUST_LOG, over the level of the rest, e.g. sam_subsample::strata=trace or info,sam_subsample::loci=debug (default: None)", "SPEC", Kind::Opt),
+    ("", "on-broken-pairs", "what to do with PE templates missing a mate, choose from 'keep', 'drop', 'fail' (default: keep)", "", Kind::Opt),
+    ("", "singletons", "what to do with templates of a single read (SE or orphan), choose from 'keep', 'drop', 'only' (default: keep)", "", Kind::Opt),
+    ("", "unmapped", "what to do with templates whose reads are all unmapped, choose from 'keep', 'drop', 'only' (default: keep)", "", Kind::Opt),
+    ("", "half-mapped", "what to do with pairs of one mate mapped and the other not, choose from 'keep', 'drop', 'only', 'prefer' (default: keep); 'prefer' takes all of them before any other, reading the inputs twice", "", Kind::Opt),
+    ("t", "threads", "number of threads; more than 1 also decompresses/compresses in parallel (default: $SAM_SUBSAMPLE_THREADS, or 1)", "INTEGER", Kind::Opt),
+    ("", "chunk-size", "number of templates handed to a sampling thread at a time (default: 10000)", "INTEGER", Kind::Opt),
+    ("", "checkpoint", "save the progress to this file periodically, and resume from it if it exists; BAM inputs and a single thread only", "FILE", Kind::Opt),
+    ("", "checkpoint-every", "number of templates between checkpoints (default: 10000000)", "INTEGER", Kind::Opt),
+    ("", "load-state", "carry on the reservoir saved by --save-state of an earlier run over the inputs, which are new templates of the same dataset, as though they had been read after its own", "FILE", Kind::Opt),
+    ("", "save-state", "save the reservoir once the inputs are read, as a BAM with what was read in its header, for a later run to carry on with --load-state (default: None)", "FILE", Kind::Opt),
+    ("", "cache-index", "keep where the templates passing the filters are in this file, written by the first run and read by the next over the same inputs and filters, which then sample without a pass over the inputs (default: None)", "FILE", Kind::Opt),
+    ("", "max-mem", "memory budget for the reservoir, e.g. 8G; when exceeded, the inputs are read twice instead (default: None)", "SIZE", Kind::Opt),
+    ("", "compress-reservoir", "hold the templates of the reservoir deflated rather than as records, for a fraction of the memory at the cost of CPU time", "", Kind::Flag),
+    ("", "max-records", "write no more than this many records in all, secondary and supplementary ones included, taking the templates of the sample in a random order until the next would go past it; K/M/G suffixes allowed (default: None)", "INTEGER", Kind::Opt),
+    ("", "offsets-only", "keep only where the sampled templates are in the reservoir, and seek back to copy them once sampling is done; BAM inputs only", "", Kind::Flag),
+    ("", "bernoulli", "keep every template with a probability a little over --num / templates in one pass, with next to no memory, into a temporary BAM next to --outfile cut down to exactly --num in a pass over it", "", Kind::Flag),
+    ("", "stream-through", "with a coordinate-sorted input, keep every template with probability --num / templates, drawn from its qname, and write its records as they are read, in input order, so that the output can be piped on; about --num templates are kept", "", Kind::Flag),
+    ("", "expected-templates", "with --bernoulli or --stream-through, templates passing the filters to set the probability by, rather than an estimate from the size of the inputs; needed for pipes; K/M/G suffixes allowed (default: None)", "INTEGER", Kind::Opt),
+    ("", "log-file", "also write the log to this file (default: None)", "FILE", Kind::Opt),
+    ("", "log-format", "format of log lines, choose from 'plain', 'json' (default: plain)", "", Kind::Opt),
+    ("", "tui", "show a dashboard on stderr, updated in place, of templates read per second, the reservoir filling up, memory, how far a coordinate pass is along the genome and the ETA of the pass, instead of info lines; needs stderr to be a terminal", "", Kind::Flag),
+    ("", "error-format", "how a failure is reported on stderr, choose from 'plain', 'json' (default: plain); json also gives it as one JSON object, with the exit code, its name, the error and what is known of where it happened, e.g. the file and record", "", Kind::Opt),
+    ("", "strict", "fail (exit code 6) instead of outputting all when there are fewer templates than --num", "", Kind::Flag),
+    ("", "allow-fewer", "with --strict, output all rather than fail when there are fewer templates than --num, as long as there are --min-templates", "", Kind::Flag),
+    ("", "min-templates", "fail (exit code 6) when fewer than N templates pass the filters, whatever --num; K/M/G suffixes allowed (default: 0)", "INTEGER", Kind::Opt),
+    ("", "exact", "check that exactly --num templates (or all, if fewer) were written, and fail (exit code 7) otherwise", "", Kind::Flag),
+    ("", "finalize-on-interrupt", "on SIGINT or SIGTERM, stop reading and write out the sample of the templates read so far rather than remove the outputs; exits with code 9 either way", "", Kind::Flag),
+    ("", "time-limit", "stop reading once the run has gone on this long, e.g. 2h, and write out the sample of the templates read so far, as --finalize-on-interrupt does, with how much of the input that was; exits with code 11; units s, m, h, d (default: None)", "DURATION", Kind::Opt),
+    ("", "mode", "downsampling mode, choose from 'subsample' (or 'random'), 'first', 'last', 'cap-depth', 'uniform', 'normalize', 'random-access', 'thin' (default: subsample); subsample takes --num templates of name-sorted inputs at random, first and last the first or last --num of them that pass the filters, without drawing, cap-depth keeps at most --max-depth reads starting at any position of a coordinate-sorted input, uniform thins a coordinate-sorted input down to --target-depth wherever a window is deeper, normalize takes every name-sorted input down to the size of the smallest, each into its own --outfile, random-access picks --num templates of an indexed, coordinate-sorted BAM by seeking rather than reading it through, thin keeps every template of a coordinate-sorted input with the probability --keep-track gives where it starts", "", Kind::Opt),
+    ("", "max-depth", "with --mode cap-depth, most reads kept starting at one position", "INTEGER", Kind::Opt),
+    ("", "target-depth", "with --mode uniform, mean depth to flatten windows down to", "NUMBER", Kind::Opt),
+    ("", "target-depths", "with --mode uniform, instead of --target-depth, a BED-like TSV of regions and the mean depth to thin each down to in its last column, e.g. mosdepth's regions.bed.gz of a reference sample, bgzipped or not; one pass, templates anchored in no region are kept", "FILE", Kind::Opt),
+    ("", "window", "with --mode uniform, size of the window, centred on where a template starts and sliding along with it, that coverage is averaged over (default: 1000)", "INTEGER", Kind::Opt),
+    ("", "keep-track", "with --mode thin, a BED whose score, or a bedGraph whose value, is the probability of keeping a template starting in each interval, from 0 to 1 (or 1000, as BED scores go); bgzipped or not", "FILE", Kind::Opt),
+    ("", "off-track", "with --mode thin, probability of keeping a template starting in no interval of --keep-track (default: 1)", "NUMBER", Kind::Opt),
+    ("", "allocate", "with an --outfile per --infile, how --num is split among them, choose from 'proportional', 'equal' (default: proportional)", "", Kind::Opt),
+    ("", "normalize-by", "with --mode normalize, what inputs are equalized on, choose from 'templates', 'bases' (default: templates)", "", Kind::Opt),
+    ("", "ratio", "with --mode normalize, colon-separated parts the outputs are to hold templates in, one per --infile, e.g. 1:3, instead of all as many; each input is downsampled to its part times the largest multiple all of them have templates for (default: None)", "RATIO", Kind::Opt),
+    ("", "require-tag", "keep only templates whose primary reads all carry this TAG:VALUE, e.g. NH:1, or TAG:* for any value; repeat to require several", "TAG:VALUE", Kind::Multi),
+    ("", "exclude-tag", "drop templates with a primary read carrying this TAG:VALUE, e.g. vA:*; repeat to exclude several", "TAG:VALUE", Kind::Multi),
+    ("", "name-regex", "keep only templates whose qname matches this regular expression anywhere, e.g. ':1101:' for a tile of Illumina names (default: None)", "PATTERN", Kind::Opt),
+    ("", "name-regex-invert", "with --name-regex, keep only templates whose qname does not match it instead", "", Kind::Flag),
+    ("", "exclude-tiles", "drop templates sequenced on these comma-separated tiles of Illumina qnames, each TILE on any lane, LANE:TILE or FLOWCELL:LANE:TILE, e.g. 1101,2:1102 (default: None)", "TILES", Kind::Opt),
+    ("", "rest-outfile", "also write every template not sampled, filtered ones included, to this BAM; reads the inputs twice (default: None)", "FILE", Kind::Opt),
+    ("", "names-out", "also list the qname of every sampled template in this text file, gzipped if it ends in .gz (default: None)", "FILE", Kind::Opt),
+    ("", "metadata-out", "also write a row for every record written to this TSV: qname, flag, chrom, pos, MAPQ, TLEN, length and the tags of --metadata-tags; gzipped if it ends in .gz (default: None)", "FILE", Kind::Opt),
+    ("", "metadata-tags", "with --metadata-out, comma-separated tags to add a column for, e.g. NM,AS,RG (default: None)", "TAGS", Kind::Opt),
+    ("", "arrow-out", "also write a row for every record written to this Arrow IPC (.arrow, .feather, .ipc) or Parquet (.parquet) file: qname, flag, chrom, pos, MAPQ, CIGAR, TLEN, length and the tags of --arrow-tags; needs the arrow feature (default: None)", "FILE", Kind::Opt),
+    ("", "arrow-tags", "with --arrow-out, comma-separated tags to add a column for, e.g. NM,AS,CB (default: None)", "TAGS", Kind::Opt),
+    ("", "bed-out", "also write where every sampled template is aligned to this BED, its primary alignments spanned on every reference, or to this BEDPE, a line with both mates, if it ends in .bedpe; gzipped if it ends in .gz (default: None)", "FILE", Kind::Opt),
+    ("", "stratify-by", "sample within strata of templates, so that the output keeps their distribution, choose from 'insert-size', 'gc', 'meanq', 'lane', 'chromosome', 'run', 'read-group', 'channel', 'tag', 'tag:XX', or several of them comma-separated to sample within their combinations (default: None); gc is the GC content of the primary reads' SEQ, meanq their mean base quality, lane the flowcell and lane in Illumina qnames, chromosome the reference of the first mate; run and channel are the RG and ch tags of nanopore reads, read-group the RG tag as well, tag the one given to --strata-tag; tag:XX, e.g. tag:HP of phased reads, the values of tag XX and, as one more stratum, the templates without it; reads the inputs twice", "", Kind::Opt),
+    ("", "bins", "with --stratify-by insert-size or meanq, comma-separated half-open ranges of |TLEN| or mean Q, e.g. 0-200,200-400,400-1000 or 0-10,10-20,20+, the last one open-ended if it ends in +; templates in none are dropped", "RANGES", Kind::Opt),
+    ("", "gc-bins", "with --stratify-by gc, number of equal bins GC content is split into (default: 5)", "INTEGER", Kind::Opt),
+    ("", "strata-tag", "with --stratify-by tag, the tag whose values make the strata, e.g. RG", "TAG", Kind::Opt),
+    ("", "strata-allocate", "with --stratify-by meanq, lane, chromosome, run, read-group, channel, tag or several kinds of strata, or --amplicons, how --num is split among the strata, choose from 'proportional', 'equal' (default: proportional)", "", Kind::Opt),
+    ("", "equalize", "same as --strata-allocate equal", "", Kind::Flag),
+    ("", "min-per-stratum", "with --stratify-by, --amplicons or --balance-strands proportional, number of templates every stratum gets first, all of those with fewer, before the rest of --num is split in proportion; K/M/G suffixes allowed (default: None)", "INTEGER", Kind::Opt),
+    ("", "bin-fractions", "with --stratify-by insert-size, gc or meanq, comma-separated fractions of --num to draw from each bin, e.g. 0.2,0.5,0.3 (default: as in the input)", "NUMBERS", Kind::Opt),
+    ("", "amplicons", "sample within the amplicons of this primer BED, e.g. ARTIC's, each template going to the one overlapping its primary reads most; templates of none are dropped; reads the inputs twice (default: None)", "FILE", Kind::Opt),
+    ("", "preserve-vaf", "sample so that the allele fraction of every SNV of this VCF, bgzipped or not, is in the output what it is in the input, by sampling in proportion the templates of every combination of alleles they carry at the sites; reads the inputs twice (default: None)", "FILE", Kind::Opt),
+    ("", "vaf-tolerance", "with --preserve-vaf, how far the allele fraction of a site may drift before it is reported, and with --strict fails the run (default: 0.02)", "FLOAT", Kind::Opt),
+    ("", "per-amplicon", "with --amplicons, number of templates to take from every amplicon, all of those with fewer, instead of splitting --num; K/M/G suffixes allowed (default: None)", "INTEGER", Kind::Opt),
+    ("", "per-sample-num", "number of templates to take from every sample (the SM of the read groups), all of those with fewer, each into an output of its own, e.g. out.NA12878.bam for out.bam, whose header keeps the read groups of that sample only; templates without a read group of an SM are dropped; K/M/G suffixes allowed (default: None)", "INTEGER", Kind::Opt),
+    ("", "per-tile-num", "number of templates to take from every tile of Illumina qnames (flowcell, lane and tile), all of those with fewer, instead of --num; templates of other qnames are dropped; K/M/G suffixes allowed (default: None)", "INTEGER", Kind::Opt),
+    ("", "quota", "with --stratify-by, --amplicons or --balance-strands, a TSV of the number of templates to take from strata by their key, e.g. chr1 and 1M on a line with --stratify-by chromosome, instead of splitting --num; the strata not in it are not sampled (default: None)", "FILE", Kind::Opt),
+    ("", "balance-strands", "sample forward and reverse templates (by the strand of the first mate) apart, choose from 'equal', 'proportional' (default: None); 'equal' splits --num 50/50, 'proportional' as in the input; unmapped templates are dropped; reads the inputs twice", "", Kind::Opt),
+    ("", "deterministic", "keep the --num templates ranking lowest by a hash of the seed and qname, so that the same templates are picked whatever order the input is in", "", Kind::Flag),
+    ("", "keep-order", "write the sample in input order, as the header of a single input says it is, rather than in the reservoir's order, said to be unsorted", "", Kind::Flag),
+    ("", "sort-output", "write the sample sorted by coordinate, as the header then says, holding it in memory until the inputs are read", "", Kind::Flag),
+    ("", "every", "keep every Nth template instead of --num at random (systematic sampling) (default: None)", "INTEGER", Kind::Opt),
+    ("", "pipeline", "sample the templates passing the filters through these stages instead of --num, separated by '|': filters and group-by, a sampler, then transforms, e.g. 'min-mapq:30 | group-by:tag:CB | weighted:1000:XW | drop-tags:OQ,BI,BD' (default: None)", "SPEC", Kind::Opt),
+    ("", "phase", "with --every, which of every N templates to keep, from 0 to N-1 (default: drawn from the seed)", "INTEGER", Kind::Opt),
+    ("", "group-by", "what records are kept or dropped together, choose from 'qname', 'zmw', 'tag:XX' (default: qname); zmw takes the movie/zmw prefix of PacBio subread qnames, so whole ZMWs are sampled; tag:XX, e.g. tag:BX of linked reads, samples --num molecules, all templates of a barcode in the tag together, dropping those without it, and reads the inputs twice", "", Kind::Opt),
+    ("", "max-per-molecule", "with --group-by tag, most templates to keep of every molecule sampled, drawn at random (default: all)", "INTEGER", Kind::Opt),
+    ("", "per-read", "sample records one by one rather than by template; the input then needs no sorting", "", Kind::Flag),
+    ("", "on-regrouped", "check that no qname turns up again after its template closed, as in an input merged or edited by hand, beyond the last 1024 templates, always checked, and what to do when one does, choose from 'warn', 'fail'; takes 128M of memory (default: None)", "", Kind::Opt),
+    ("", "auto-collate", "put the records of every template of an input together first, in temporary files under $TMPDIR, within --max-mem, if its first records have them apart or its header says it is neither sorted by queryname nor grouped by query", "", Kind::Flag),
+    ("", "time-range", "keep only reads that started sequencing within START..END of the start of their run, by the st tag of nanopore reads, e.g. 0..6h; either end may be left out, units s, m, h, d (default: None)", "START..END", Kind::Opt),
+    ("", "contigs", "keep only templates whose primary alignments are all on these references, comma-separated, e.g. chr1,chr2,chrX; unmapped templates are dropped (default: None)", "NAMES", Kind::Opt),
+    ("", "exclude-regions", "drop templates with a primary alignment overlapping a region of this BED file, e.g. the ENCODE blacklist (default: None)", "FILE", Kind::Opt),
+    ("", "exclude-overlapping", "drop templates with a primary alignment overlapping any alignment of this BAM (default: None)", "FILE", Kind::Opt),
+    ("", "regions", "keep only templates with a primary alignment overlapping a region of this BED file, along with their mates wherever those align (default: None)", "FILE", Kind::Opt),
+    ("", "require-both-in-region", "with --regions, keep only templates whose mapped primary reads all overlap the regions", "", Kind::Flag),
+    ("", "sites", "keep only templates with a primary alignment overlapping a variant of this VCF, bgzipped or not (default: None)", "FILE", Kind::Opt),
+    ("", "max-per-site", "with --sites, keep no more than the first N templates at each site (default: None)", "INTEGER", Kind::Opt),
+    ("", "proper-pairs-only", "keep only PE templates whose primary reads are flagged as aligned in proper pair (0x2)", "", Kind::Flag),
+    ("", "only-duplicates", "keep only templates with a primary read flagged as a PCR or optical duplicate (0x400)", "", Kind::Flag),
+    ("", "max-nm", "keep only templates whose primary alignments have at most N mismatches and indels by their NM tag (default: None)", "INTEGER", Kind::Opt),
+    ("", "max-nm-frac", "keep only templates whose primary alignments have at most this fraction of mismatches and indels by their NM tag over their aligned bases, e.g. 0.05 (default: None)", "NUMBER", Kind::Opt),
+    ("", "min-aligned-frac", "keep only templates whose primary alignments have at least this fraction of their read aligned (M/=/X in CIGAR), leaving out mostly clipped ones, e.g. 0.8 (default: None)", "NUMBER", Kind::Opt),
+    ("", "spliced", "keep only templates with a primary alignment spanning a splice junction (N in CIGAR), 'only', only those without, 'exclude', or either, 'any' (default: any)", "", Kind::Opt),
+    ("", "min-tlen", "keep only proper pairs whose |TLEN| (insert size) is at least N (default: None)", "INTEGER", Kind::Opt),
+    ("", "max-tlen", "keep only proper pairs whose |TLEN| (insert size) is at most N, e.g. 150 for short cfDNA fragments (default: None)", "INTEGER", Kind::Opt),
+    ("", "reads", "which mates of the sampled templates to write, choose from 'r1', 'r2', 'both' (default: both); SE reads count as r1", "", Kind::Opt),
+    ("", "primary-only", "write only the primary records of the sampled templates, leaving out secondary and supplementary alignments", "", Kind::Flag),
+    ("", "max-secondary", "write at most this many secondary alignments of every read of the sampled templates, picked at random, lowering NH by those left out and numbering HI again (default: None)", "INTEGER", Kind::Opt),
+    ("", "max-records-per-template", "write at most this many secondary and supplementary records of every sampled template, picked at random, besides its primary records (default: None)", "INTEGER", Kind::Opt),
+    ("", "repair-sa", "when --reads, --primary-only or --max-records-per-template leave records out, remove the SA entries of alignments not written, rather than only count the records listing them", "", Kind::Flag),
+    ("", "strip-bad-modifications", "remove the modified-base tags, MM, ML and MN, of the records written they do not fit, e.g. ML left out by --keep-tags, rather than only count them", "", Kind::Flag),
+    ("", "rescue-unmapped-mates", "with --reads r1|r2, write the unmapped mate of a read written too", "", Kind::Flag),
+    ("", "fixmate", "rewrite the mate fields (RNEXT, PNEXT, TLEN, mate flags and MC) of the records written to agree with the mates written, making a read whose mate is left out single-end, as samtools fixmate would", "", Kind::Flag),
+    ("", "drop-tags", "remove these comma-separated tags from the records written, e.g. OQ,BI,BD (default: None)", "TAGS", Kind::Opt),
+    ("", "keep-tags", "remove all tags but these comma-separated ones from the records written, e.g. NM,MD,RG (default: None)", "TAGS", Kind::Opt),
+    ("", "anonymize-names", "replace the qnames of the records written by the number of their template in the output, 1, 2, 3, ...", "", Kind::Flag),
+    ("", "add-comment", "add this line of text to the output header as an @CO line; repeat to add several", "TEXT", Kind::Multi),
+    ("", "replace-sample-name", "set the sample (SM) of every read group in the output header to this name", "NAME", Kind::Opt),
+    ("", "set-read-group", "add a read group of these fields to the output header, e.g. 'ID:sub SM:sample1 PL:ILLUMINA', and tag every record written without one with its ID (default: None)", "FIELDS", Kind::Opt),
+    ("", "split-by", "write the sampled templates into an output per group instead, named after --outfile, e.g. out.RG1.bam for out.bam, choose from 'read-group', 'chromosome', 'tag:XX' (default: None); chromosome goes by the primary alignment of the first mate, tag:XX by the value of the XX tag, e.g. tag:CB for cell barcodes", "", Kind::Opt),
+    ("", "min-records", "with --split-by, remove the outputs of fewer than N records (default: 1)", "INTEGER", Kind::Opt),
+    ("", "allowlist", "with --split-by, write only the groups listed in this file, one per line, gzipped or not, e.g. barcodes.tsv.gz (default: None)", "FILE", Kind::Opt),
+    ("", "max-open-files", "with --split-by, how many outputs to keep open at a time; the templates of further groups are split off in more passes (default: 500)", "INTEGER", Kind::Opt),
+    ("", "records-per-file", "roll the output over into numbered files of N templates each, e.g. out.0001.bam, out.0002.bam, ... for out.bam; K/M/G suffixes allowed (default: None)", "INTEGER", Kind::Opt),
+    ("", "shard", "spread the sampled templates over N outputs named by --shard-template, for as many jobs downstream (default: None)", "INTEGER", Kind::Opt),
+    ("", "shard-template", "names of the outputs of --shard in the place of --outfile, {} becoming the shard number, 1 to N, zero-padded to the width of N, e.g. out_{}.bam", "FILE", Kind::Opt),
+    ("", "shard-by", "how --shard assigns templates, choose from 'round-robin', in turn as they are written so that shards are at most one template apart, or 'qname', by the hash of the qname so that a template goes to the same shard whatever the sample (default: round-robin)", "", Kind::Opt),
+    ("", "template-stats", "write a TSV of how many templates have 1, 2, 3, ... records, in the input and in the output, to this file (default: None)", "FILE", Kind::Opt),
+    ("", "tallies", "write a TSV of how many templates were written per reference and per read group to this file, and log them (default: None)", "FILE", Kind::Opt),
+    ("", "distribution-report", "write a TSV comparing the template lengths, read lengths and MAPQs of the input and the output (count, mean, quantiles, KS distance) to this file (default: None)", "FILE", Kind::Opt),
+    ("", "dedup-by", "sample molecules rather than templates: all templates starting at the same 'position's on the same strands count as one, the one with the highest MAPQ standing for them (default: None)", "STRING", Kind::Opt),
+    ("", "duplication", "log the fraction of duplicate templates in the input and in the output, by the duplicate 'flag' or by identical 'position's (default: None)", "STRING", Kind::Opt),
+    ("", "complexity", "write a TSV of the distinct molecules (templates of distinct positions) expected at fractions and multiples of the input depth to this file, and log the estimated library size (default: None)", "FILE", Kind::Opt),
+    ("", "saturation", "write a TSV of the templates, UMIs and sequencing saturation of single-cell input, molecules being the templates of one CB and UB, expected at fractions of the input depth to this file, and log the saturation (default: None)", "FILE", Kind::Opt),
+    ("", "per-cell", "with --saturation, give them for every CB barcode, one row per barcode and fraction", "", Kind::Flag),
+    ("", "digest", "write a JSON of the SHA-256 of every output, and of the qnames sampled whatever their order, to this file, and log them (default: None)", "FILE", Kind::Opt),
+    ("", "trim-header", "rewrite every output with only the @SQ lines of the references its records are on, e.g. of a sample of a few chromosomes, rather than every one of the inputs", "", Kind::Flag),
+    ("", "validate-output", "read every output back once written, and fail (exit code 5) unless it ends with the BGZF EOF block, its records decode, are in the order of its @HD SO and keep templates whole, and it holds as many templates as were written", "", Kind::Flag),
+    ("", "check-input", "check the inputs before sampling, and fail (exit code 4) on one cut short, choose from 'eof', 'blocks' (default: None); 'eof' looks for the BGZF EOF block at the end of a BAM or bgzipped SAM, 'blocks' also walks the headers of all its BGZF blocks", "", Kind::Opt),
+    ("", "skip-invalid", "skip records with an empty qname, a negative position or a CIGAR not matching SEQ, logging them, rather than fail on them", "", Kind::Flag),
+    ("", "max-invalid-frac", "with --skip-invalid, most records skipped, as a fraction of those read, before the run fails (exit code 4) (default: 0.001)", "FLOAT", Kind::Opt),
+    ("", "plan", "print what a run would do, the sampling strategy, the memory it would take at its peak by the size of the templates at the start of the first input, the threads and the outputs, and stop without sampling", "", Kind::Flag),
+    ("", "timing", "log the time spent reading, grouping records into templates, selecting and writing, and the records read per second, at the end", "", Kind::Flag),
+    ("", "filter-stats", "log, at the end, how many templates each filter turned down: those it was the first to, and all it would have on its own", "", Kind::Flag),
+    ("", "skip", "ignore the first N templates of the input; K/M/G suffixes allowed (default: 0)", "INTEGER", Kind::Opt),
+    ("", "head", "read no more than N templates of the input, after --skip; K/M/G suffixes allowed (default: None)", "INTEGER", Kind::Opt),
+    ("h", "help", "print usage", "", Kind::Flag),
+    ("V", "version", "print version", "", Kind::Flag),
+];
+
 fn usage(prog: &str, opts: Options) {
     let s = format!("\
 Summary:
 Random sample --num reads (SE) or read pairs (PE) from BAM or SAM
 
 Usage:
-{} --infile input.[bam|sam] --outfile output.bam [--num 5000] [--seed 43] [--help] [--version] [--debug error|warn|info|debug|trace]",
-prog);
+{} --infile input.[bam|sam|sam.gz] [--infile input2.[bam|sam|sam.gz] ... | --pool a.bam:0.7 --pool b.bam:0.3] [--input-format auto|sam|bam] (--outfile output.bam | --outfile-template '{{stem}}.n{{num}}.s{{seed}}.bam' | --output-fd 3) [--num 5000 | --fraction 0.1] [--always-include controls.txt] [--seed 43 | --seed-from-input] [--rng pcg64|xoshiro|chacha] [--checkpoint run.ckpt.bam] [--load-state state.bam] [--save-state state.bam] [--cache-index input.qidx] [--max-mem 8G] [--offsets-only] [--compress-reservoir] [--max-records 1M] [--bernoulli [--expected-templates 100M]] [--log-file run.log] [--log-format plain|json] [--error-format plain|json] [--tui] [--strict [--allow-fewer]] [--min-templates 1M] [--exact] [--finalize-on-interrupt] [--time-limit 2h] [--require-tag NH:1] [--exclude-tag vA:*] [--name-regex ':1101:' [--name-regex-invert]] [--exclude-tiles 1101,2:1102] [--rest-outfile rest.bam] [--names-out names.txt.gz] [--metadata-out sampled.tsv [--metadata-tags NM,AS,RG]] [--arrow-out sampled.parquet [--arrow-tags NM,AS,CB]] [--bed-out sampled.bedpe] [--stratify-by insert-size --bins 0-200,200-400,400-1000 [--bin-fractions 0.2,0.5,0.3]] [--stratify-by gc [--gc-bins 5] [--bin-fractions 0.1,0.2,0.4,0.2,0.1]] [--stratify-by meanq --bins 0-10,10-20,20+ [--bin-fractions 0.2,0.3,0.5 | --equalize]] [--stratify-by lane|chromosome|run|read-group|channel|tag|tag:HP[,...] [--strata-tag RG] [--strata-allocate proportional|equal | --equalize]] [--amplicons primers.bed [--per-amplicon 1000 | --strata-allocate proportional|equal]] [--preserve-vaf calls.vcf.gz [--vaf-tolerance 0.02]] [--min-per-stratum 100] [--per-sample-num 10000] [--per-tile-num 10000] [--balance-strands equal|proportional] [--quota quotas.tsv] [--deterministic] [--keep-order | --sort-output] [--every 10 [--phase 0]] [--pipeline 'min-mapq:30 | group-by:tag:CB | weighted:1000:XW | drop-tags:OQ,BI,BD'] [--group-by qname|zmw|tag:BX [--max-per-molecule 100] | --per-read] [--auto-collate] [--on-regrouped warn|fail] [--time-range 0..6h] [--contigs chr1,chr2] [--exclude-regions blacklist.bed] [--exclude-overlapping other.bam] [--regions targets.bed [--require-both-in-region]] [--sites calls.vcf.gz [--max-per-site 50]] [--proper-pairs-only] [--only-duplicates] [--max-nm 5] [--max-nm-frac 0.05] [--min-aligned-frac 0.8] [--spliced only|exclude|any] [--min-tlen 0] [--max-tlen 150] [--reads r1|r2|both] [--primary-only] [--max-secondary 5] [--max-records-per-template 10] [--repair-sa] [--strip-bad-modifications] [--rescue-unmapped-mates] [--fixmate] [--drop-tags OQ,BI,BD | --keep-tags NM,MD,RG] [--anonymize-names] [--add-comment text ...] [--set-read-group 'ID:sub SM:sample1 PL:ILLUMINA'] [--replace-sample-name NA12878_10pct] [--split-by read-group|chromosome|tag:CB [--min-records 1000] [--allowlist barcodes.tsv.gz] [--max-open-files 500] | --records-per-file 1M | --shard 8 --shard-template out_{{}}.bam [--shard-by round-robin|qname]] [--template-stats stats.tsv] [--tallies tallies.tsv] [--distribution-report dist.tsv] [--dedup-by position] [--duplication flag|position] [--complexity curve.tsv] [--saturation saturation.tsv [--per-cell]] [--digest digest.json] [--trim-header] [--validate-output] [--check-input eof|blocks] [--skip-invalid [--max-invalid-frac 0.001]] [--plan] [--timing] [--filter-stats] [--skip 0] [--head 100000] [--help] [--version] [--level info | -q | -v[v]] [--log-filter sam_subsample::strata=trace]
+{} --infile input1.bam --infile input2.bam --outfile output1.bam --outfile output2.bam [--num 5000] [--allocate proportional|equal] [...]
+{} --mode first|last --infile input.[bam|sam|sam.gz] --outfile output.bam [--num 5000]
+{} --mode normalize --infile input1.bam --infile input2.bam --outfile output1.bam --outfile output2.bam [--normalize-by templates|bases | --ratio 1:3] [--seed 43]
+{} --mode cap-depth --infile input.[bam|sam|sam.gz] --outfile output.bam --max-depth 1000 [--seed 43]
+{} --mode uniform --infile input.[bam|sam|sam.gz] --outfile output.bam (--target-depth 100 [--window 1000] | --target-depths regions.bed.gz) [--seed 43]
+{} --mode random-access --infile input.bam --outfile output.bam [--num 10000] [--seed 43] [--strict]
+{} --mode thin --infile input.[bam|sam|sam.gz] --outfile output.bam --keep-track probs.bedgraph [--off-track 0.05] [--seed 43]
+{} --fq1 input_R1.fastq.gz [--fq2 input_R2.fastq.gz] --outfile output_R1.fastq.gz [--outfile output_R2.fastq.gz] [--num 5000] [--seed 43]
+{} --stream-through --infile input.[bam|sam|sam.gz] (--outfile /dev/stdout | --output-fd 3) [--num 10000] [--expected-templates 100M] [--seed 43]
+{} split --by read-group|chromosome|tag:CB --infile input.[bam|sam|sam.gz] --outfile out.bam [--num 5000 | --fraction 0.1] [...]
+{} completions bash|zsh|fish
+{} generate --outfile synth.bam [--num 10000] [...] (see {} generate --help)
+{} verify --infile small.bam [--num 100] [--rounds 1000] [...] (see {} verify --help)
+{} snapshot --infile input.bam --loci variants.bed --outdir bundles [--num 50] [...] (see {} snapshot --help)
+{} mix --infile normal.bam --infile tumor.bam --proportions 0.95,0.05 --outfile mix.bam [--num 1M] [...] (see {} mix --help)
+{} contains --source source.bam --candidate candidate.bam [--records] [--outfile missing.tsv]",
+prog, prog, prog, prog, prog, prog, prog, prog, prog, prog, prog, prog, prog, prog, prog, prog, prog, prog, prog, prog, prog);
     println!("{}", opts.usage(&s));
 }
 
-fn parse_args(args: &Vec<String>, mut opts: Options) -> Params {
-    opts.optopt("i", "infile", "input BAM/SAM, queryname sorted", "FILE");
-    opts.optopt("o", "outfile", "output BAM", "FILE");
-    opts.optopt("n", "num", "number of reads (read pairs if PE) to downsample (default: 5000)", "INTEGER");
-    opts.optopt("s", "seed", "seed (default: None)", "INTEGER");
-    opts.optopt("", "level", "level of debugging info, choose from 'error', 'warn', 'info', 'debug', 'trace'", "");
-    opts.optflag("h", "help", "print usage");
-    opts.optflag("v", "version", "print version");
+// whether `path` is a pipe or a device, e.g. /dev/fd/63 of <(...) or /dev/stdin, rather than a file; an
+// htsget URL is read through one
+fn is_stream(path: &str) -> bool {
+    htsget::is_url(path) || fs::metadata(ospath::path(path)).is_ok_and(|x| x.file_type().is_fifo() || x.file_type().is_char_device())
+}
 
-    let m = opts.parse(&args[1..]).expect("failed to parse arguments!");
-    if m.opt_present("h") {
-        usage(&args[0], opts);
-        exit(0);
-    }
-    if m.opt_present("v") {
-        println!("v{}", VERSION);
-        exit(0);
-    }
-    let infile = match m.opt_str("infile") {
-        Some(f) => match Path::new(&f).exists() {
-            true => match &*(f
-                .split('.')
-                .last()
-                .expect("Faied to find the file extension!")
-                .to_lowercase())
-            {
-                "sam" | "bam" => f,
-                _ => panic!("{} does not seem to be a SAM or BAM!", f),
-            },
-            false => panic!("{} does not exist!", f),
-        },
-        None => panic!("--infile is empty!"),
-    };
-    let outfile = m.opt_str("outfile").expect("invalid --outfile");
-    let num = m.opt_get_default("num", 5000).expect("invalid --num");
-    let seed = m.opt_get::<u64>("seed").expect("invalid --seed, must be integer");
-    let seed = match seed {
-        Some(x) => x,
-        None => Local::now().timestamp_millis() as u64,
-    };
-    let level = m.opt_get_default("level", String::from("info")).expect("invalid --level, choose from 'info', 'warn', 'error', 'debug', 'trace'");
-    Params {
-        infile, 
-        outfile,
-        num,
-        seed,
-        level,
+// fail on an input cut short per --check-input, before it is read; pipes, which cannot be looked at
+// ahead, are left alone
+fn check_inputs(infiles: &[String], blocks: bool) {
+    for f in infiles.iter().filter(|f| !is_stream(f)) {
+        match validate::check_input(f, blocks) {
+            Ok(None) => info!("{} is not BGZF-compressed; not checked.", f),
+            Ok(Some(0)) => info!("{} ends with the BGZF EOF block.", f),
+            Ok(Some(n)) => info!("{} ends with the BGZF EOF block, after {} blocks all in order.", f, n),
+            Err(e) => {
+                error!("{} failed --check-input: {}!", f, e);
+                status::fail(Code::Malformed);
+            },
+        }
     }
 }
 
-fn check_header(header: &bam::Header) {
-    let header = header.to_hashmap();
-    let so = match header.get("HD") {
-        Some(a) => a,
-        None => { 
-            error!("'@HD' not found in header!");
-            panic!()
-        },
+// --plan: what a run would do, on stdout, one `key<TAB>value` a line; the inputs are only read as far as
+// --fraction or --max-mem read them anyway, and the start of the first for the size of its templates
+fn plan(params: &Params) {
+    let infiles = &params.infiles;
+    let num = match params.fraction {
+        Some(fraction) => fraction_num(infiles, fraction, params.skip, params.head, params.threads),
+        None => params.num,
     };
+    let resumed = params.checkpoint.as_ref().is_some_and(|path| ospath::path(path).exists()) || params.load_state.is_some();
+    let threads = params.threads;
+    println!("mode\t{}", match params.mode {
+        Mode::Normalize => "normalize",
+        _ => "subsample",
+    });
+    println!("inputs\t{}", infiles.join(","));
+    if params.auto_collate {
+        println!("collate\tinputs not grouped by qname are first collated into temporary BAMs");
+    }
+    if let (Some(path), true) = (&params.checkpoint, resumed) {
+        println!("resume\tfrom {}", path);
+    }
+    if let Some(path) = &params.load_state {
+        println!("resume\tthe reservoir of {}, over the inputs as new templates", path);
+    }
+    // the inputs are sampled one after another into outputs of their own, each with its share of --num,
+    // or as many as the smallest input has when normalizing, which takes counting them
+    match (params.mode, params.outfiles.len()) {
+        (Mode::Normalize, _) => println!("num\tas many as the smallest input has, per output, by counting the inputs first"),
+        (_, 1) => println!("num\t{}", num),
+        _ => println!("num\t{}, split among the outputs", num),
+    }
 
-    let so = match so[0].get("SO") {
-        Some(a) => a,
-        None => { 
-            error!("'SO' not found in '@HD'!");
-            panic!();
+    let strategy = strategy(params, infiles, num, resumed);
+    let chunked = strategy == Strategy::Reservoir && !resumed && params.pick == Pick::Random && !params.deterministic && !params.compress_reservoir && threads > 1;
+    println!("strategy\t{}: {}", strategy.name(), match strategy {
+        Strategy::Reservoir if params.pick == Pick::First => "the first --num templates, held in memory".to_string(),
+        Strategy::Reservoir if params.pick == Pick::Last => "the last --num templates, held in memory".to_string(),
+        Strategy::Reservoir if params.deterministic => "the --num templates of the lowest hashes of their qnames, held in memory".to_string(),
+        Strategy::Reservoir if params.compress_reservoir => "one pass, the sampled templates held in memory compressed".to_string(),
+        Strategy::Reservoir if chunked => format!("one pass, chunks of {} templates sampled by {} reservoirs, merged at the end", params.chunk_size, threads),
+        Strategy::Reservoir => "one pass, the sampled templates held in memory".to_string(),
+        Strategy::TwoPass if params.strata.is_some() => "two passes, counting the templates of every stratum, then writing those picked".to_string(),
+        Strategy::TwoPass if params.rest_outfile.is_some() => "two passes, counting the templates, then writing those picked and the rest".to_string(),
+        Strategy::TwoPass => "two passes, counting the templates, then writing those picked, the reservoir being over --max-mem".to_string(),
+        Strategy::Systematic => format!("one pass, one template in every {}", params.every.unwrap_or_default()),
+        Strategy::Pipeline => format!("one pass through the stages of --pipeline '{}', the sampled templates held in memory", params.pipeline.as_deref().unwrap_or_default()),
+        Strategy::Offsets => "one pass keeping where the sampled templates are, then reading them back".to_string(),
+        Strategy::Molecules => "two passes, counting the templates of every molecule, then writing those picked".to_string(),
+        Strategy::Bernoulli => format!("one pass keeping templates by a coin flip into a temporary BAM, {}.bernoulli.bam, then cutting them down to --num", params.outfiles[0]),
+        Strategy::Cached => match params.cache_index.as_ref().is_some_and(|(path, options)| qidx::Index::open(path, &qidx::fingerprint(infiles, options)).ok().flatten().is_some()) {
+            true => "picking templates by where --cache-index has them, without a pass over the inputs, then reading them back".to_string(),
+            false => "one pass writing where the templates are into --cache-index, then picking from it and reading them back".to_string(),
         },
-    };
+    });
 
-    if so != "queryname" {
-        error!("Not sorted by queryname! Please run 'samtools sort -n -o output.bam input.bam' first!");
-        panic!();
+    // bytes a sampled template takes up in memory, going by the start of the first input; a stream
+    // would be used up by looking
+    let size = match is_stream(&infiles[0]) {
+        true => None,
+        false => Some(budget::template_size(&infiles[0], 100_000)),
+    };
+    match size {
+        Some(x) => println!("template size\t{} bytes on average, by the first 100000 records of {}", x, infiles[0]),
+        None => println!("template size\tunknown, {} being a stream", infiles[0]),
     }
+    // what is held until the end, besides the records of a template or two being read
+    let indices = num * std::mem::size_of::<usize>();
+    let peak = match strategy {
+        Strategy::Reservoir if chunked => size.map(|x| x * (num * threads + params.chunk_size * (2 * threads + 1))),
+        Strategy::Reservoir => size.map(|x| x * num),
+        Strategy::TwoPass | Strategy::Molecules | Strategy::Bernoulli => Some(indices),
+        Strategy::Offsets | Strategy::Cached => Some(num * std::mem::size_of::<(usize, i64, usize)>()),
+        Strategy::Systematic => size,
+        Strategy::Pipeline => None,
+    };
+    println!("peak memory\t{}", match (peak, params.mode) {
+        (None, _) if strategy == Strategy::Pipeline => "unknown, as many templates as the sampler of --pipeline holds".to_string(),
+        (None, _) => "unknown, without the size of the templates".to_string(),
+        (Some(x), Mode::Normalize) => format!("{} a template sampled", units::format_size(x / num.max(1))),
+        (Some(x), _) if strategy == Strategy::Reservoir && params.compress_reservoir => format!("under {}, before compression", units::format_size(x)),
+        (Some(x), _) => format!("about {}{}", units::format_size(x), match strategy {
+            Strategy::TwoPass if params.strata.is_some() => ", and a count for every stratum",
+            Strategy::Molecules => ", and a count for every molecule",
+            _ => "",
+        }),
+    });
+    if let (Strategy::Bernoulli, Some(x)) = (strategy, size) {
+        println!("temporary disk\tunder {}, about --num templates before compression", units::format_size(x * num));
+    }
+    println!("threads\t{}", match threads {
+        1 => "1, reading, sampling and writing all on it".to_string(),
+        _ if chunked => format!("{}, decompressing the inputs and compressing the outputs, and as many sampling", threads),
+        _ => format!("{}, decompressing the inputs and compressing the outputs", threads),
+    });
 
+    // every file written, and what it is
+    let outfile = &params.outfiles[0];
+    let mut outputs: Vec<(String, String)> = match (params.split_by, params.records_per_file, params.shards) {
+        (_, _, Some(n)) => vec![(outfile.clone(), format!("{} BAMs, one a shard", n))],
+        (Some(_), _, _) => vec![(outfile.clone(), "BAMs, one a group of --split-by, named after it".to_string())],
+        (_, Some(n), _) => vec![(outfile.clone(), format!("BAMs of {} records each, named after it", n))],
+        _ => params.outfiles.iter().map(|f| (f.clone(), "BAM".to_string())).collect(),
+    };
+    for (path, what) in [
+        (&params.rest_outfile, "BAM of the templates not sampled"),
+        (&params.checkpoint, "BAM checkpoint"),
+        (&params.save_state, "BAM of the reservoir, for --load-state"),
+        (&params.template_stats, "TSV of template stats"),
+        (&params.tallies, "TSV of tallies"),
+        (&params.distribution_report, "TSV of distributions"),
+        (&params.complexity, "TSV of complexity"),
+        (&params.saturation, "TSV of saturation"),
+        (&params.digest, "JSON of digests"),
+        (&params.log_file, "log"),
+    ] {
+        if let Some(f) = path {
+            outputs.push((f.clone(), what.to_string()));
+        }
+    }
+    if let Some((f, _)) = &params.cache_index {
+        outputs.push((f.clone(), "index of the templates passing the filters, unless it is there already".to_string()));
+    }
+    if let Some((f, format)) = &params.arrow_out {
+        outputs.push((f.clone(), format!("{} of the records written", format.name())));
+    }
+    // these are BGZF-compressed if they end in .gz
+    for (path, what) in [
+        (&params.names_out, "qnames"),
+        (&params.metadata_out, "TSV of metadata"),
+        (&params.bed_out, "BED of spans"),
+    ] {
+        if let Some(f) = path {
+            let what = if what.starts_with("BED") && f.trim_end_matches(".gz").ends_with(".bedpe") { "BEDPE of spans" } else { what };
+            outputs.push((f.clone(), format!("{}{}", what, if f.ends_with(".gz") { ", BGZF-compressed" } else { "" })));
+        }
+    }
+    for (f, what) in outputs {
+        println!("output\t{}: {}", f, what);
+    }
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let params = parse_args(&args, Options::new());
+// --num for --fraction of the templates of the inputs, past --skip and up to --head, counted exactly in
+// a pass over every input ahead of the sampling one; a pipe, which can only be read once, is turned down
+fn fraction_num(infiles: &[String], fraction: f64, skip: usize, head: Option<usize>, threads: usize) -> usize {
+    let total = infiles.iter().map(|f| {
+        if is_stream(f) {
+            error!("{} is a pipe, which can only be read once, so its templates cannot be counted for --fraction; please give --num.", f);
+            status::fail(Code::Args);
+        }
+        let n = budget::exact_template_count(f, threads).unwrap_or_else(|| {
+            error!("failed to count the templates of {} for --fraction!", f);
+            status::fail(Code::Malformed)
+        });
+        info!("{} templates in {}.", n, f);
+        n
+    }).sum::<usize>().saturating_sub(skip);
+    let total = head.map_or(total, |h| h.min(total));
+    let num = (fraction * total as f64).round() as usize;
+    info!("--fraction {}: {} of {} templates to be sampled.", fraction, num, total);
+    num
+}
 
-    let infile = params.infile;
-    let outfile = params.outfile;
-    let num = params.num;
-    let seed = params.seed;
-    let level = params.level;
-    init_logger(&level);
-    info!("{{ infile = {}, outfile = {}, num = {}, seed = {}, level = {} }}", infile, outfile, num, seed, level);
+// an output named by --outfile-template for the `index`th input: {stem} is the name of the input without
+// its directory and extension, {num} the --num, {seed} the seed and {index} the number of the input,
+// from 1; {group} goes through as it is, for --split-by
+fn outfile_from_template(template: &str, infile: &str, index: usize, num: Option<usize>, seed: u64) -> Result<String, String> {
+    let name = infile.split('?').next().unwrap_or_default().rsplit('/').next().unwrap_or_default();
+    let stem = [".sam.gz", ".bam", ".sam", ".cram", ".fastq.gz", ".fq.gz", ".fastq", ".fq"].iter().find_map(|x| name.strip_suffix(x)).unwrap_or(name);
+    let stem = match stem {
+        "" | "-" => "stdin",
+        x => x,
+    };
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(i) = rest.find('{') {
+        out.push_str(&rest[..i]);
+        let end = rest[i..].find('}').ok_or_else(|| format!("unclosed {{ in '{}'", template))? + i;
+        match &rest[i + 1..end] {
+            "stem" => out.push_str(stem),
+            "num" => match num {
+                Some(n) => out.push_str(&n.to_string()),
+                None => out.push_str("{num}"),
+            },
+            "seed" => out.push_str(&seed.to_string()),
+            "index" => out.push_str(&index.to_string()),
+            "group" => out.push_str("{group}"),
+            x => return Err(format!("unknown variable {{{}}}, choose from {{stem}}, {{num}}, {{seed}}, {{index}}, {{group}} or {{stratum}}", x)),
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
 
-    let mut infh = match bam::Reader::from_path(&infile) {
-            Ok(f) => f,
+// a seed the same for the same inputs and different for others, wherever they are: a hash of the
+// size and the first MiB of every input, which holds its header and first records
+fn seed_from_inputs(infiles: &[String]) -> u64 {
+    let mut h: u64 = 0;
+    for infile in infiles {
+        let mut buf = Vec::new();
+        let size = File::open(ospath::path(infile)).and_then(|fh| {
+            let size = fh.metadata()?.len();
+            fh.take(1 << 20).read_to_end(&mut buf)?;
+            Ok(size)
+        });
+        match size {
+            Ok(size) => buf.extend_from_slice(&size.to_le_bytes()),
             Err(e) => {
-                error!("failed to read {}: {}", &infile, e);
-                panic!()
+                status::complain(&format!("failed to read {}: {}", infile, e));
+                status::fail(Code::Malformed);
             },
-    };
+        }
+        h = uniform::hash(&buf, h);
+    }
+    h
+}
 
-    let header = bam::Header::from_template(infh.header());
-    let mut outfh = match bam::Writer::from_path(&outfile, &header, bam::Format::Bam) {
-        Ok(f) => f,
-        Err(e) => {
-            error!("failed to write {}: {}", &outfile, e);
-            panic!();
-        },
-    };
-    check_header(&header);
+// a count option, which may carry a K/M/G/T suffix
+fn opt_count(m: &Matches, name: &str, default: usize) -> usize {
+    match m.opt_str(name) {
+        Some(x) => units::parse_count(&x).unwrap_or_else(|| bad_args(&format!("invalid --{}, e.g. 5000, 5k, 2.5M", name))),
+        None => default,
+    }
+}
 
-    let mut k = 0;
-    let mut v = Vec::<RecordSet>::new();
-    let mut rs: RecordSet = RecordSet::new();
-    let mut rng = Pcg64::seed_from_u64(seed);
-    let mut rid: Option<String>;
-    let mut rid_prev: Option<String> = None;
+// `split --by KEY ...` is the run with --split-by KEY, over the whole input unless told how to sample it
+fn split_args(args: &[String]) -> Vec<String> {
+    let mut out = vec![args[0].clone()];
+    let mut by = false;
+    for a in &args[2..] {
+        match a.as_str() {
+            "--by" => {
+                by = true;
+                out.push(String::from("--split-by"));
+            },
+            x if x.starts_with("--by=") => {
+                by = true;
+                out.push(format!("--split-by={}", &x[5..]));
+            },
+            _ => out.push(a.clone()),
+        }
+    }
+    if !by {
+        bad_args("usage: sam_subsample split --by read-group|chromosome|tag:CB --infile input.bam --outfile out.bam [--num 5000 | --fraction 0.1]");
+    }
+    let sampled = ["-n", "--num", "--fraction", "--every", "--bernoulli"];
+    if !args[2..].iter().any(|a| sampled.iter().any(|&o| a == o || a.starts_with(&format!("{}=", o)))) {
+        out.extend([String::from("--every"), String::from("1")]);
+    }
+    out
+}
 
-    info!("Iteration starts.");
+fn parse_args(args: &[String], mut opts: Options) -> Params {
+    let split;
+    let args = match args.get(1).map(String::as_str) {
+        Some("split") => {
+            split = split_args(args);
+            &split[..]
+        },
+        _ => args,
+    };
+    for &(short, long, desc, hint, kind) in OPTIONS {
+        match kind {
+            Kind::Flag => opts.optflag(short, long, desc),
+            Kind::Opt => opts.optopt(short, long, desc, hint),
+            Kind::Multi => opts.optmulti(short, long, desc, hint),
+            Kind::Count => opts.optflagmulti(short, long, desc),
+        };
+    }
+    // looked for ahead of everything else, so that any failure, of the arguments or of a subcommand, is given
+    // as JSON
+    if args.windows(2).any(|x| x[0] == "--error-format" && x[1] == "json") || args.iter().any(|x| x == "--error-format=json") {
+        status::json_errors();
+    }
+    // a subcommand rather than an option, so it needs none of the required ones
+    if args.get(1).map(String::as_str) == Some("completions") {
+        match args.get(2).and_then(|shell| completions::script(shell, "sam_subsample", OPTIONS)) {
+            Some(s) => print!("{}", s),
+            None => bad_args("usage: sam_subsample completions bash|zsh|fish"),
+        }
+        exit(0);
+    }
+    if args.get(1).map(String::as_str) == Some("generate") {
+        generate::run(&args[2..]);
+        exit(0);
+    }
+    if args.get(1).map(String::as_str) == Some("verify") {
+        verify::run(&args[2..]);
+        exit(0);
+    }
+    if args.get(1).map(String::as_str) == Some("snapshot") {
+        snapshot::run(&args[2..]);
+        exit(0);
+    }
+    if args.get(1).map(String::as_str) == Some("mix") {
+        mix::run(&args[2..]);
+        exit(0);
+    }
+    if args.get(1).map(String::as_str) == Some("contains") {
+        contains::run(&args[2..]);
+        exit(0);
+    }
 
-    for rec in infh.records() {
-        match rec {
-            Ok(r) => { 
-                rid = Some(String::from_utf8(r.qname().to_vec()).expect("invalid qname!"));
-                if rid_prev.is_none() || rid_prev.as_ref().expect("invalid qname!").eq(rid.as_ref().expect("invalid qname!")) { 
-                    // first record or current record has same qname as previous one; cache it
-                    rid_prev = rid.take();
-                    rs.push(r);
-                    continue;
-                } else { // current record is a new template; process the cached; cache it
-                    if k < num {
-                        v.push(take(&mut rs));
-                    } else {
-                        let f: f64 = rng.gen();
-                        let i = (f * (k as f64)) as usize;
-                        if i < num {
-                            v[i] = take(&mut rs);
-                        }
-                    }
-                    rid_prev = rid.take();
-                    rs.clear();
-                    rs.push(r);
-                    k += 1;
-                    if k % 1_000_000 == 0 {
-                        info!("{} reads (read pairs) processed...", k);
-                    }
+    let m = opts.parse(&args[1..]).unwrap_or_else(|e| bad_args(&format!("failed to parse arguments: {}", e)));
+    if m.opt_present("h") {
+        usage(&args[0], opts);
+        exit(0);
+    }
+    if m.opt_present("version") {
+        println!("v{}", VERSION);
+        exit(0);
+    }
+    // htslib tells the formats apart by content; the extension is only checked to catch wrong files early
+    let input_format = match &*m.opt_get_default("input-format", String::from("auto")).unwrap_or_else(|_| bad_args("invalid --input-format")) {
+        "auto" => None,
+        x @ ("sam" | "bam") => Some(x.to_string()),
+        _ => bad_args("invalid --input-format, choose from 'auto', 'sam', 'bam'"),
+    };
+    // FASTQs take the place of --infile, sampled by the reservoir alone, none of the filters and reports
+    // of alignments applying to them
+    let fastq = m.opt_present("fq1") || m.opt_present("fq2");
+    if fastq {
+        let allowed = ["fq1", "fq2", "outfile", "num", "seed", "rng", "level", "verbose", "quiet", "log-filter", "log-file", "log-format", "strict", "allow-fewer", "finalize-on-interrupt", "time-limit"];
+        if let Some(o) = OPTIONS.iter().find(|o| m.opt_present(o.1) && !allowed.contains(&o.1)) {
+            bad_args(&format!("--{} does not work with --fq1", o.1));
+        }
+        if !m.opt_present("fq1") {
+            bad_args("--fq2 needs --fq1");
+        }
+        if m.opt_strs("num").len() > 1 {
+            bad_args("several --num do not work with --fq1");
+        }
+        if m.opt_strs("outfile").len() != 1 + usize::from(m.opt_present("fq2")) {
+            bad_args("give an --outfile for --fq1, and another for --fq2");
+        }
+    }
+    // every --pool is an input and the share of the output its templates make up
+    let pool: Vec<(String, f64)> = m.opt_strs("pool").iter().map(|x| {
+        x.rsplit_once(':')
+            .and_then(|(f, w)| Some((f.to_string(), w.parse::<f64>().ok().filter(|w| w.is_finite() && *w >= 0.0)?)))
+            .filter(|(f, _)| !f.is_empty())
+            .unwrap_or_else(|| bad_args("invalid --pool, e.g. a.bam:0.7"))
+    }).collect();
+    if !pool.is_empty() {
+        if fastq || m.opt_present("infile") {
+            bad_args("--pool does not work with --infile or --fq1; it gives the inputs itself");
+        }
+        if pool.len() < 2 || pool.iter().all(|x| x.1 == 0.0) {
+            bad_args("--pool takes two inputs or more, not all of weight 0");
+        }
+        if let Some(x) = pool.iter().enumerate().find(|(i, x)| pool[..*i].iter().any(|y| y.0 == x.0)) {
+            bad_args(&format!("{} is given to --pool more than once", x.1.0));
+        }
+    }
+    let infiles: Vec<String> = match fastq {
+        true => ["fq1", "fq2"].iter().filter_map(|x| m.opt_str(x)).collect(),
+        false if !pool.is_empty() => pool.iter().map(|x| x.0.clone()).collect(),
+        false => m.opt_strs("infile"),
+    };
+    let infiles: Vec<String> = infiles.into_iter().map(|f| match ospath::path(&f).exists() || (!fastq && htsget::is_url(&f)) {
+        true if fastq => f,
+        true => match input_format {
+            Some(_) => f,
+            // a pipe has no extension to go by, e.g. /dev/fd/63 of <(...)
+            None if is_stream(&f) => f,
+            None => {
+                let lower = f.to_lowercase();
+                match [".sam", ".bam", ".sam.gz"].iter().any(|x| lower.ends_with(x)) {
+                    true => f,
+                    false => bad_args(&format!("{} does not seem to be a SAM, SAM.GZ or BAM! Give --input-format to read it anyway.", f)),
                 }
             },
-            Err(e) => { 
-                error!("empty record: {}", e); 
-                panic!();    
+        },
+        false => {
+            status::complain(&format!("{} does not exist!", f));
+            status::fail(Code::Missing)
+        },
+    }).collect();
+    if infiles.is_empty() {
+        bad_args("--infile is empty!");
+    }
+    // an input that can only be read once, from start to end
+    let stream = infiles.iter().find(|f| is_stream(f)).cloned();
+    let mut outfiles = m.opt_strs("outfile");
+    // the outputs of --shard are named by --shard-template instead
+    if let Some(t) = m.opt_str("shard-template") {
+        if !outfiles.is_empty() {
+            bad_args("--outfile does not work with --shard-template, which names the outputs");
+        }
+        outfiles.push(t);
+    }
+    // a file descriptor handed down by the parent process is written to through /dev/fd, as /dev/stdout is
+    let output_fd = m.opt_str("output-fd").map(|x| {
+        let fd = x.parse::<i32>().ok().filter(|fd| *fd >= 0).unwrap_or_else(|| bad_args("invalid --output-fd, must be a non-negative integer"));
+        if !outfiles.is_empty() {
+            bad_args("--output-fd does not work with --outfile or --shard-template");
+        }
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            bad_args(&format!("--output-fd {} is not an open file descriptor", fd));
+        }
+        outfiles.push(format!("/dev/fd/{}", fd));
+        fd
+    });
+    let outfile_template = m.opt_str("outfile-template");
+    if outfile_template.is_some() && (!outfiles.is_empty() || m.opt_present("shard")) {
+        bad_args("--outfile-template does not work with --outfile, --output-fd, --shard or --shard-template");
+    }
+    if outfiles.is_empty() && outfile_template.is_none() {
+        bad_args("--outfile is required!");
+    }
+    if outfiles.len() > 1 && outfiles.len() != infiles.len() {
+        bad_args("give either one --outfile, or one per --infile");
+    }
+    let mut nums: Vec<usize> = m.opt_strs("num").iter().map(|x| units::parse_count(x).unwrap_or_else(|| bad_args("invalid --num, e.g. 5000, 5k, 2.5M"))).collect();
+    nums.sort_unstable_by(|a, b| b.cmp(a));
+    nums.dedup();
+    let num = nums.first().copied().unwrap_or(5000);
+    let fraction = m.opt_str("fraction").map(|x| x.parse::<f64>().ok().filter(|f| *f > 0.0 && *f <= 1.0).unwrap_or_else(|| bad_args("invalid --fraction, must be over 0 and at most 1, e.g. 0.1")));
+    if fraction.is_some() && m.opt_present("num") {
+        bad_args("give either --num or --fraction");
+    }
+    if let (Some(f), Some(_)) = (&stream, fraction) {
+        bad_args(&format!("{} is a pipe, whose templates cannot be counted ahead, so it does not work with --fraction", f));
+    }
+    if let (Some(f), Some(_)) = (infiles.iter().find(|f| htsget::is_url(f)), fraction) {
+        bad_args(&format!("{} is on an htsget server, whose templates cannot be counted ahead, so it does not work with --fraction", f));
+    }
+    let seed = m.opt_get::<u64>("seed").unwrap_or_else(|_| bad_args("invalid --seed, must be integer"));
+    if let (Some(f), true) = (&stream, m.opt_present("seed-from-input")) {
+        bad_args(&format!("{} is a pipe, which can only be read once, so it does not work with --seed-from-input", f));
+    }
+    let rng = match m.opt_str("rng") {
+        Some(x) => rng::Kind::parse(&x).unwrap_or_else(|| bad_args("invalid --rng, choose from 'pcg64', 'xoshiro', 'chacha'")),
+        None => rng::Kind::Pcg64,
+    };
+    let seed = match (seed, m.opt_present("seed-from-input")) {
+        (Some(_), true) => bad_args("give either --seed or --seed-from-input"),
+        (Some(x), false) => x,
+        (None, true) => seed_from_inputs(&infiles),
+        // a seed of the clock is as good as known to whoever knows when the run was
+        (None, false) if rng == rng::Kind::ChaCha => OsRng.next_u64(),
+        (None, false) => Local::now().timestamp_millis() as u64,
+    };
+    // the outputs named by --outfile-template, one per input unless they all come out the same; {num} is
+    // left for several --num to fill in, and {group} for --split-by
+    if let Some(template) = &outfile_template {
+        let template = template.replace("{stratum}", "{group}");
+        if template.contains("{num}") && fraction.is_some() {
+            bad_args("--outfile-template with {num} does not work with --fraction, whose --num is only known once the inputs are counted");
+        }
+        let num = (nums.len() <= 1).then_some(num);
+        for (i, infile) in infiles.iter().enumerate() {
+            let outfile = outfile_from_template(&template, infile, i + 1, num, seed).unwrap_or_else(|e| bad_args(&format!("invalid --outfile-template: {}", e)));
+            if !outfiles.contains(&outfile) {
+                outfiles.push(outfile);
             }
         }
+        if outfiles.len() > 1 && outfiles.len() != infiles.len() {
+            bad_args("--outfile-template names the outputs of some inputs the same and others not; add {index} to tell them apart");
+        }
+        if template.contains("{group}") && !m.opt_present("split-by") {
+            bad_args("--outfile-template with {group} needs --split-by");
+        }
     }
-    // last record; process the cached
-    if k < num {
-        v.push(take(&mut rs));
-        warn!("--num exceeds the input read counts! output all.");
-    } else {
-        let f: f64 = rng.gen();
-        let i = (f * (k as f64)) as usize;
-        if i < num {
-            v[i] = take(&mut rs);
+    let level = m.opt_get_default("level", env_default("LEVEL").unwrap_or_else(|| String::from("info"))).unwrap_or_else(|_| bad_args("invalid --level, choose from 'info', 'warn', 'error', 'debug', 'trace'"));
+    let (verbose, quiet) = (m.opt_count("verbose"), m.opt_present("quiet"));
+    if (verbose > 0 || quiet) && m.opt_present("level") {
+        bad_args("give either --level, or -v or -q");
+    }
+    let level = match (quiet, verbose) {
+        (true, 0) => String::from("error"),
+        (true, _) => bad_args("-q does not work with -v"),
+        (false, 0) => level,
+        (false, 1) => String::from("debug"),
+        (false, _) => String::from("trace"),
+    };
+    // every directive a level, or a module and its level, as env_logger would otherwise ignore a typo
+    let log_filter = m.opt_str("log-filter");
+    if let Some(spec) = &log_filter {
+        let levels = ["off", "error", "warn", "info", "debug", "trace"];
+        if spec.split(',').any(|d| !levels.contains(&d.rsplit_once('=').map_or(d, |x| x.1).to_lowercase().as_str())) {
+            bad_args("invalid --log-filter, e.g. sam_subsample::strata=trace or info,sam_subsample::loci=debug");
         }
     }
-    rs.clear();
-    for rs in &v {
-        for r in rs {
-            outfh.write(&r).unwrap();
+    let on_broken_pairs = match &*m.opt_get_default("on-broken-pairs", String::from("keep")).unwrap_or_else(|_| bad_args("invalid --on-broken-pairs")) {
+        "keep" => BrokenPairs::Keep,
+        "drop" => BrokenPairs::Drop,
+        "fail" => BrokenPairs::Fail,
+        _ => bad_args("invalid --on-broken-pairs, choose from 'keep', 'drop', 'fail'"),
+    };
+    let singletons = Policy::parse("singletons", &m.opt_get_default("singletons", String::from("keep")).unwrap_or_else(|_| bad_args("invalid --singletons")));
+    let unmapped = Policy::parse("unmapped", &m.opt_get_default("unmapped", String::from("keep")).unwrap_or_else(|_| bad_args("invalid --unmapped")));
+    // prefer keeps them, taken first as a stratum of their own
+    let half_mapped_arg = m.opt_get_default("half-mapped", String::from("keep")).unwrap_or_else(|_| bad_args("invalid --half-mapped"));
+    let prefer_half_mapped = half_mapped_arg == "prefer";
+    let half_mapped = match prefer_half_mapped {
+        true => Policy::Keep,
+        false => match &*half_mapped_arg {
+            "keep" | "drop" | "only" => Policy::parse("half-mapped", &half_mapped_arg),
+            _ => bad_args("invalid --half-mapped, choose from 'keep', 'drop', 'only', 'prefer'"),
+        },
+    };
+    let threads = match env_default("THREADS") {
+        Some(x) => x.parse().unwrap_or_else(|_| bad_args("invalid $SAM_SUBSAMPLE_THREADS")),
+        None => 1,
+    };
+    let threads = m.opt_get_default("threads", threads).unwrap_or_else(|_| bad_args("invalid --threads"));
+    if threads == 0 {
+        bad_args("invalid --threads, must be at least 1");
+    }
+    let chunk_size = opt_count(&m, "chunk-size", 10000);
+    if chunk_size == 0 {
+        bad_args("invalid --chunk-size, must be at least 1");
+    }
+    let checkpoint = m.opt_str("checkpoint");
+    let checkpoint_every = opt_count(&m, "checkpoint-every", 10_000_000);
+    if checkpoint_every == 0 {
+        bad_args("invalid --checkpoint-every, must be at least 1");
+    }
+    if checkpoint.is_some() {
+        // resuming seeks to a BGZF virtual offset, and only the serial reservoir can be saved
+        if let Some(f) = infiles.iter().find(|f| input_format.as_deref() != Some("bam") && !f.to_lowercase().ends_with(".bam")) {
+            bad_args(&format!("--checkpoint needs BAM inputs, but {} is not!", f));
+        }
+        if threads > 1 {
+            bad_args("--checkpoint does not work with --threads > 1");
         }
     }
-    info!("All done.");
+    let max_mem = m.opt_str("max-mem").map(|x| units::parse_size(&x).unwrap_or_else(|| bad_args("invalid --max-mem, e.g. 512M, 8G")));
+    let offsets_only = m.opt_present("offsets-only");
+    if offsets_only {
+        // templates are sought back by BGZF virtual offset
+        if let Some(f) = infiles.iter().find(|f| input_format.as_deref() != Some("bam") && !f.to_lowercase().ends_with(".bam")) {
+            bad_args(&format!("--offsets-only needs BAM inputs, but {} is not!", f));
+        }
+        if checkpoint.is_some() {
+            bad_args("--checkpoint does not work with --offsets-only");
+        }
+    }
+    let log_file = m.opt_str("log-file");
+    if !matches!(m.opt_str("error-format").as_deref(), None | Some("plain" | "json")) {
+        bad_args("invalid --error-format, choose from 'plain', 'json'");
+    }
+    let log_format = match &*m.opt_get_default("log-format", String::from("plain")).unwrap_or_else(|_| bad_args("invalid --log-format")) {
+        "plain" => LogFormat::Plain,
+        "json" => LogFormat::Json,
+        _ => bad_args("invalid --log-format, choose from 'plain', 'json'"),
+    };
+    // first and last subsample too, by position in the input rather than at random
+    let (mode, pick) = match &*m.opt_get_default("mode", String::from("subsample")).unwrap_or_else(|_| bad_args("invalid --mode")) {
+        "subsample" | "random" => (Mode::Subsample, Pick::Random),
+        "first" => (Mode::Subsample, Pick::First),
+        "last" => (Mode::Subsample, Pick::Last),
+        "cap-depth" => (Mode::CapDepth, Pick::Random),
+        "uniform" => (Mode::Uniform, Pick::Random),
+        "normalize" => (Mode::Normalize, Pick::Random),
+        "random-access" => (Mode::RandomAccess, Pick::Random),
+        "thin" => (Mode::Thin, Pick::Random),
+        _ => bad_args("invalid --mode, choose from 'subsample', 'random', 'first', 'last', 'cap-depth', 'uniform', 'normalize', 'random-access', 'thin'"),
+    };
+    // records are written as soon as they are read, so that whatever needs a template whole, or the input
+    // read again, is out
+    let mode = match (m.opt_present("stream-through"), mode) {
+        (false, _) => mode,
+        (true, Mode::Subsample) => {
+            let allowed = ["infile", "input-format", "outfile", "output-fd", "num", "seed", "level", "verbose", "quiet", "log-filter", "threads", "stream-through", "expected-templates", "log-file", "log-format", "finalize-on-interrupt", "time-limit", "skip-invalid", "max-invalid-frac", "mode"];
+            if let Some(o) = OPTIONS.iter().find(|o| m.opt_present(o.1) && !allowed.contains(&o.1)) {
+                bad_args(&format!("--{} does not work with --stream-through", o.1));
+            }
+            Mode::StreamThrough
+        },
+        (true, _) => bad_args("--stream-through only works with --mode subsample"),
+    };
+    let mode = match fastq {
+        true => Mode::Fastq,
+        false => mode,
+    };
+    let max_depth = opt_count(&m, "max-depth", 0);
+    if mode == Mode::CapDepth && max_depth == 0 {
+        bad_args("--mode cap-depth needs --max-depth of at least 1");
+    }
+    let target_depth = m.opt_get_default("target-depth", 0.0).unwrap_or_else(|_| bad_args("invalid --target-depth"));
+    let depths = m.opt_str("target-depths").map(|f| {
+        if mode != Mode::Uniform {
+            bad_args("--target-depths only works with --mode uniform");
+        }
+        if m.opt_present("target-depth") || m.opt_present("window") {
+            bad_args("--target-depths does not work with --target-depth or --window");
+        }
+        if !ospath::path(&f).exists() {
+            status::complain(&format!("{} does not exist!", f));
+            status::fail(Code::Missing);
+        }
+        Track::depths_from_file(&f).unwrap_or_else(|e| {
+            status::complain(&e);
+            status::fail(Code::Malformed)
+        })
+    });
+    if mode == Mode::Uniform && target_depth <= 0.0 && depths.is_none() {
+        bad_args("--mode uniform needs a positive --target-depth, or --target-depths");
+    }
+    let window = opt_count(&m, "window", 1000);
+    if window == 0 {
+        bad_args("invalid --window, must be at least 1");
+    }
+    let track = match (m.opt_str("keep-track"), mode) {
+        (Some(f), Mode::Thin) => {
+            if !ospath::path(&f).exists() {
+                status::complain(&format!("{} does not exist!", f));
+                status::fail(Code::Missing);
+            }
+            Some(Track::from_file(&f).unwrap_or_else(|e| {
+                status::complain(&e);
+                status::fail(Code::Malformed)
+            }))
+        },
+        (None, Mode::Thin) => bad_args("--mode thin needs --keep-track"),
+        (Some(_), _) => bad_args("--keep-track only works with --mode thin"),
+        (None, _) => None,
+    };
+    let off_track = m.opt_get_default("off-track", 1.0).unwrap_or_else(|_| bad_args("invalid --off-track"));
+    if !(0.0..=1.0).contains(&off_track) {
+        bad_args("invalid --off-track, must be between 0 and 1");
+    }
+    if m.opt_present("off-track") && mode != Mode::Thin {
+        bad_args("--off-track only works with --mode thin");
+    }
+    let allocate = match &*m.opt_get_default("allocate", String::from("proportional")).unwrap_or_else(|_| bad_args("invalid --allocate")) {
+        "proportional" => Allocate::Proportional,
+        "equal" => Allocate::Equal,
+        _ => bad_args("invalid --allocate, choose from 'proportional', 'equal'"),
+    };
+    if outfiles.len() > 1 && checkpoint.is_some() {
+        bad_args("--checkpoint does not work with an --outfile per --infile");
+    }
+    let normalize_by = match &*m.opt_get_default("normalize-by", String::from("templates")).unwrap_or_else(|_| bad_args("invalid --normalize-by")) {
+        "templates" => NormalizeBy::Templates,
+        "bases" => NormalizeBy::Bases,
+        _ => bad_args("invalid --normalize-by, choose from 'templates', 'bases'"),
+    };
+    let ratio = m.opt_str("ratio").map(|x| {
+        let v: Vec<usize> = x.split(':').map(|p| p.trim().parse::<usize>().ok().filter(|p| *p > 0).unwrap_or_else(|| bad_args("invalid --ratio, e.g. 1:3"))).collect();
+        if mode != Mode::Normalize {
+            bad_args("--ratio only works with --mode normalize");
+        }
+        if normalize_by == NormalizeBy::Bases {
+            bad_args("--ratio does not work with --normalize-by bases");
+        }
+        if v.len() != infiles.len() {
+            bad_args("--ratio needs one part per --infile");
+        }
+        v
+    });
+    let tag_filters = |name: &str| -> Vec<TagFilter> {
+        m.opt_strs(name).iter().map(|x| TagFilter::parse(x).unwrap_or_else(|| bad_args(&format!("invalid --{} {}, e.g. NH:1 or vA:*", name, x)))).collect()
+    };
+    let require_tags = tag_filters("require-tag");
+    let exclude_tags = tag_filters("exclude-tag");
+    let name_regex = m.opt_str("name-regex").map(|x| Regex::new(&x).unwrap_or_else(|e| bad_args(&format!("invalid --name-regex: {}", e))));
+    if name_regex.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--name-regex only works with --mode subsample and normalize");
+    }
+    let name_regex_invert = m.opt_present("name-regex-invert");
+    if name_regex_invert && name_regex.is_none() {
+        bad_args("--name-regex-invert needs --name-regex");
+    }
+    let exclude_tiles = m.opt_str("exclude-tiles").map(|x| TileList::parse(&x).unwrap_or_else(|| bad_args("invalid --exclude-tiles, e.g. 1101,2:1102")));
+    if exclude_tiles.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--exclude-tiles only works with --mode subsample and normalize");
+    }
+    let rest_outfile = m.opt_str("rest-outfile");
+    if rest_outfile.is_some() && (mode != Mode::Subsample || outfiles.len() > 1) {
+        bad_args("--rest-outfile only works with --mode subsample and a single --outfile");
+    }
+    let names_out = m.opt_str("names-out");
+    if names_out.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--names-out only works with --mode subsample and normalize");
+    }
+    let template_stats = m.opt_str("template-stats");
+    if template_stats.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--template-stats only works with --mode subsample and normalize");
+    }
+    let tallies = m.opt_str("tallies");
+    if tallies.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--tallies only works with --mode subsample and normalize");
+    }
+    let distribution_report = m.opt_str("distribution-report");
+    if distribution_report.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--distribution-report only works with --mode subsample and normalize");
+    }
+    let duplication = m.opt_str("duplication").map(|x| DupBy::parse(&x).unwrap_or_else(|| bad_args("invalid --duplication, choose from 'flag', 'position'")));
+    if duplication.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--duplication only works with --mode subsample and normalize");
+    }
+    let dedup = match m.opt_str("dedup-by").as_deref() {
+        None => false,
+        Some("position") => true,
+        Some(_) => bad_args("invalid --dedup-by, choose from 'position'"),
+    };
+    if dedup && mode != Mode::Subsample {
+        bad_args("--dedup-by only works with --mode subsample");
+    }
+    let complexity = m.opt_str("complexity");
+    if complexity.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--complexity only works with --mode subsample and normalize");
+    }
+    let saturation = m.opt_str("saturation");
+    if saturation.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--saturation only works with --mode subsample and normalize");
+    }
+    let per_cell = m.opt_present("per-cell");
+    if per_cell && saturation.is_none() {
+        bad_args("--per-cell needs --saturation");
+    }
+    let digest = m.opt_str("digest");
+    if digest.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--digest only works with --mode subsample and normalize");
+    }
+    let validate_output = m.opt_present("validate-output");
+    if validate_output && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--validate-output only works with --mode subsample and normalize");
+    }
+    let trim_header = m.opt_present("trim-header");
+    if trim_header && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--trim-header only works with --mode subsample and normalize");
+    }
+    if trim_header && m.opt_present("output-fd") {
+        bad_args("--trim-header does not work with --output-fd, which cannot be rewritten");
+    }
+    let check_input = match m.opt_str("check-input").as_deref() {
+        None => None,
+        Some("eof") => Some(false),
+        Some("blocks") => Some(true),
+        Some(_) => bad_args("invalid --check-input, choose from 'eof', 'blocks'"),
+    };
+    let max_invalid_frac = m.opt_get_default("max-invalid-frac", 0.001).ok().filter(|x: &f64| (0.0..=1.0).contains(x)).unwrap_or_else(|| bad_args("invalid --max-invalid-frac, must be from 0 to 1, e.g. 0.001"));
+    let skip_invalid = m.opt_present("skip-invalid").then_some(max_invalid_frac);
+    if m.opt_present("max-invalid-frac") && skip_invalid.is_none() {
+        bad_args("--max-invalid-frac needs --skip-invalid");
+    }
+    if skip_invalid.is_some() && mode == Mode::RandomAccess {
+        bad_args("--skip-invalid does not work with --mode random-access");
+    }
+    let plan = m.opt_present("plan");
+    if plan && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--plan only works with --mode subsample and normalize");
+    }
+    let tui = m.opt_present("tui");
+    let timing = m.opt_present("timing");
+    if timing && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--timing only works with --mode subsample and normalize");
+    }
+    let filter_stats = m.opt_present("filter-stats");
+    if filter_stats && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--filter-stats only works with --mode subsample and normalize");
+    }
+    // several kinds of strata, comma-separated, are sampled within their combinations
+    let stratify_by: Vec<String> = m.opt_str("stratify-by").map_or(Vec::new(), |x| x.split(',').map(|x| x.trim().to_string()).collect());
+    if stratify_by.iter().enumerate().any(|(i, x)| stratify_by[..i].contains(x)) {
+        bad_args("--stratify-by names a kind of strata more than once");
+    }
+    let mut parts: Vec<Strata> = stratify_by.iter().map(|x| match x.as_str() {
+        "insert-size" => match m.opt_str("bins") {
+            Some(x) => Strata::InsertSize(Bins::parse(&x).unwrap_or_else(|| bad_args("invalid --bins, e.g. 0-200,200-400,400-1000"))),
+            None => bad_args("--stratify-by insert-size needs --bins"),
+        },
+        "meanq" => match m.opt_str("bins") {
+            _ if stratify_by.iter().any(|x| x == "insert-size") => bad_args("--stratify-by insert-size and meanq cannot share --bins"),
+            Some(x) => Strata::MeanQ(Bins::parse(&x).unwrap_or_else(|| bad_args("invalid --bins, e.g. 0-10,10-20,20+"))),
+            None => bad_args("--stratify-by meanq needs --bins"),
+        },
+        "gc" => match m.opt_get_default("gc-bins", 5usize) {
+            Ok(x) if x > 0 => Strata::Gc(GcBins::new(x)),
+            _ => bad_args("invalid --gc-bins, must be a positive integer"),
+        },
+        "lane" => Strata::Lane(Lanes::default()),
+        "chromosome" => Strata::Chromosome(Chromosomes::default()),
+        "run" | "read-group" => Strata::Tag(TagValues::new(*b"RG")),
+        "channel" => Strata::Tag(TagValues::new(*b"ch")),
+        "tag" => match m.opt_str("strata-tag") {
+            Some(x) if x.len() == 2 && x.is_ascii() => Strata::Tag(TagValues::new([x.as_bytes()[0], x.as_bytes()[1]])),
+            Some(_) => bad_args("invalid --strata-tag, e.g. RG"),
+            None => bad_args("--stratify-by tag needs --strata-tag"),
+        },
+        x if x.starts_with("tag:") => match &x.as_bytes()["tag:".len()..] {
+            _ if m.opt_present("strata-tag") => bad_args("--strata-tag does not work with --stratify-by tag:XX"),
+            &[a, b] if x.is_ascii() => Strata::Tag(TagValues::with_untagged([a, b])),
+            _ => bad_args("invalid --stratify-by tag:XX, e.g. tag:HP"),
+        },
+        _ => bad_args("invalid --stratify-by, choose from 'insert-size', 'gc', 'meanq', 'lane', 'chromosome', 'run', 'read-group', 'channel', 'tag', 'tag:XX', or several of them comma-separated"),
+    }).collect();
+    let mut strata = match parts.len() {
+        0 => None,
+        1 => parts.pop(),
+        _ => Some(Strata::Combined(Combined::new(parts))),
+    };
+    if m.opt_present("gc-bins") && !stratify_by.iter().any(|x| x == "gc") {
+        bad_args("--gc-bins needs --stratify-by gc");
+    }
+    if m.opt_present("bins") && !stratify_by.iter().any(|x| x == "insert-size" || x == "meanq") {
+        bad_args("--bins needs --stratify-by insert-size or meanq");
+    }
+    if let Some(f) = m.opt_str("amplicons") {
+        if strata.is_some() {
+            bad_args("--amplicons does not work with --stratify-by");
+        }
+        if !ospath::path(&f).exists() {
+            status::complain(&format!("{} does not exist!", f));
+            status::fail(Code::Missing);
+        }
+        strata = Some(Strata::Amplicon(Amplicons::from_bed(&f).unwrap_or_else(|e| {
+            status::complain(&e);
+            status::fail(Code::Malformed)
+        })));
+    }
+    if let Some(f) = m.opt_str("preserve-vaf") {
+        if strata.is_some() {
+            bad_args("--preserve-vaf does not work with --stratify-by or --amplicons");
+        }
+        // the strata are sampled in proportion to their sizes, or the fractions would drift
+        if ["strata-allocate", "equalize", "min-per-stratum", "quota", "bin-fractions"].iter().any(|x| m.opt_present(x)) {
+            bad_args("--preserve-vaf does not work with --strata-allocate, --equalize, --min-per-stratum, --quota or --bin-fractions");
+        }
+        if !ospath::path(&f).exists() {
+            status::complain(&format!("{} does not exist!", f));
+            status::fail(Code::Missing);
+        }
+        strata = Some(Strata::Allele(Alleles::from_vcf(&f).unwrap_or_else(|e| {
+            status::complain(&e);
+            status::fail(Code::Malformed)
+        })));
+    }
+    let vaf_tolerance = m.opt_get_default("vaf-tolerance", 0.02).ok().filter(|x: &f64| (0.0..=1.0).contains(x)).unwrap_or_else(|| bad_args("invalid --vaf-tolerance, must be from 0 to 1, e.g. 0.02"));
+    if m.opt_present("vaf-tolerance") && !matches!(strata, Some(Strata::Allele(_))) {
+        bad_args("--vaf-tolerance needs --preserve-vaf");
+    }
+    let per_amplicon = m.opt_str("per-amplicon").map(|_| opt_count(&m, "per-amplicon", 0));
+    if per_amplicon.is_some() && !matches!(strata, Some(Strata::Amplicon(_))) {
+        bad_args("--per-amplicon needs --amplicons");
+    }
+    if per_amplicon.is_some() && (m.opt_present("num") || m.opt_present("strata-allocate") || m.opt_present("equalize")) {
+        bad_args("--per-amplicon does not work with --num, --strata-allocate or --equalize");
+    }
+    let per_sample_num = m.opt_str("per-sample-num").map(|_| opt_count(&m, "per-sample-num", 0));
+    if per_sample_num.is_some() {
+        if mode != Mode::Subsample {
+            bad_args("--per-sample-num only works with --mode subsample");
+        }
+        if strata.is_some() || m.opt_present("balance-strands") {
+            bad_args("--per-sample-num does not work with --stratify-by, --amplicons or --balance-strands");
+        }
+        if m.opt_present("num") || m.opt_present("strata-allocate") || m.opt_present("equalize") {
+            bad_args("--per-sample-num does not work with --num, --strata-allocate or --equalize");
+        }
+        if m.opt_present("split-by") || m.opt_present("replace-sample-name") || m.opt_present("set-read-group") {
+            bad_args("--per-sample-num does not work with --split-by, --replace-sample-name or --set-read-group");
+        }
+        strata = Some(Strata::Sample(Samples::default()));
+    }
+    let per_tile_num = m.opt_str("per-tile-num").map(|_| opt_count(&m, "per-tile-num", 0));
+    if per_tile_num.is_some() {
+        if mode != Mode::Subsample {
+            bad_args("--per-tile-num only works with --mode subsample");
+        }
+        if strata.is_some() || m.opt_present("balance-strands") {
+            bad_args("--per-tile-num does not work with --stratify-by, --amplicons, --per-sample-num or --balance-strands");
+        }
+        if m.opt_present("num") || m.opt_present("strata-allocate") || m.opt_present("equalize") {
+            bad_args("--per-tile-num does not work with --num, --strata-allocate or --equalize");
+        }
+        strata = Some(Strata::Tile(Tiles::default()));
+    }
+    let per_stratum = per_amplicon.or(per_sample_num).or(per_tile_num);
+    let equal_strata = match m.opt_str("strata-allocate").as_deref() {
+        _ if m.opt_present("equalize") && m.opt_present("strata-allocate") => bad_args("--equalize does not work with --strata-allocate"),
+        None => m.opt_present("equalize"),
+        Some("proportional") => false,
+        Some("equal") => true,
+        Some(_) => bad_args("invalid --strata-allocate, choose from 'proportional', 'equal'"),
+    };
+    if equal_strata && !matches!(strata, Some(Strata::MeanQ(_) | Strata::Tag(_) | Strata::Lane(_) | Strata::Chromosome(_) | Strata::Combined(_) | Strata::Amplicon(_))) {
+        bad_args("--strata-allocate and --equalize need --stratify-by meanq, lane, chromosome, run, read-group, channel, tag or several kinds of strata, or --amplicons");
+    }
+    let mut fractions = m.opt_str("bin-fractions").map(|x| {
+        let v: Vec<f64> = x.split(',').map(|f| f.trim().parse::<f64>().ok().filter(|f| *f >= 0.0).unwrap_or_else(|| bad_args("invalid --bin-fractions, e.g. 0.2,0.5,0.3"))).collect();
+        match &strata {
+            Some(s @ (Strata::InsertSize(_) | Strata::Gc(_) | Strata::MeanQ(_))) if s.len() == v.len() && v.iter().sum::<f64>() > 0.0 => v,
+            Some(Strata::InsertSize(_) | Strata::Gc(_) | Strata::MeanQ(_)) => bad_args("--bin-fractions needs one fraction per bin, not all 0"),
+            _ => bad_args("--bin-fractions needs --stratify-by insert-size, gc or meanq"),
+        }
+    });
+    if let Some(x) = m.opt_str("balance-strands") {
+        if strata.is_some() {
+            bad_args("--balance-strands does not work with --stratify-by or --amplicons");
+        }
+        strata = Some(Strata::Strand);
+        fractions = match &*x {
+            "equal" => Some(vec![1.0, 1.0]),
+            "proportional" => None,
+            _ => bad_args("invalid --balance-strands, choose from 'equal', 'proportional'"),
+        };
+    }
+    if prefer_half_mapped {
+        if strata.is_some() {
+            bad_args("--half-mapped prefer does not work with --stratify-by, --amplicons or --balance-strands");
+        }
+        strata = Some(Strata::HalfMapped);
+    }
+    if !pool.is_empty() {
+        if strata.is_some() {
+            bad_args("--pool does not work with --stratify-by, --amplicons, --preserve-vaf, --per-sample-num, --per-tile-num, --balance-strands or --half-mapped prefer");
+        }
+        if mode != Mode::Subsample || outfiles.len() > 1 {
+            bad_args("--pool only works with --mode subsample, into a single --outfile");
+        }
+        strata = Some(Strata::Input(infiles.clone()));
+        fractions = Some(pool.iter().map(|x| x.1).collect());
+    }
+    if strata.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--stratify-by, --amplicons, --balance-strands and --half-mapped prefer only work with --mode subsample and normalize");
+    }
+    let quotas = m.opt_str("quota").map(|f| {
+        if strata.is_none() || per_sample_num.is_some() || per_tile_num.is_some() {
+            bad_args("--quota needs --stratify-by, --amplicons or --balance-strands");
+        }
+        if m.opt_present("num") || m.opt_present("strata-allocate") || m.opt_present("equalize") || fractions.is_some() || per_amplicon.is_some() {
+            bad_args("--quota does not work with --num, --strata-allocate, --equalize, --bin-fractions, --balance-strands equal, --per-amplicon or --pool");
+        }
+        if !ospath::path(&f).exists() {
+            status::complain(&format!("{} does not exist!", f));
+            status::fail(Code::Missing);
+        }
+        strata::read_quotas(&f).unwrap_or_else(|e| {
+            status::complain(&e);
+            status::fail(Code::Malformed)
+        })
+    });
+    let min_per_stratum = m.opt_str("min-per-stratum").map(|_| opt_count(&m, "min-per-stratum", 0));
+    if min_per_stratum.is_some() {
+        if strata.is_none() {
+            bad_args("--min-per-stratum needs --stratify-by, --amplicons or --balance-strands proportional");
+        }
+        if equal_strata || fractions.is_some() || per_stratum.is_some() || quotas.is_some() || prefer_half_mapped {
+            bad_args("--min-per-stratum does not work with --strata-allocate equal, --equalize, --bin-fractions, --balance-strands equal, --per-amplicon, --per-sample-num, --per-tile-num, --quota or --half-mapped prefer");
+        }
+    }
+    let deterministic = m.opt_present("deterministic");
+    if deterministic && checkpoint.is_some() {
+        bad_args("--checkpoint does not work with --deterministic");
+    }
+    if deterministic && offsets_only {
+        bad_args("--offsets-only does not work with --deterministic");
+    }
+    let every = m.opt_str("every").map(|_| opt_count(&m, "every", 0));
+    if every == Some(0) {
+        bad_args("--every must be positive");
+    }
+    let phase = m.opt_get::<usize>("phase").unwrap_or_else(|_| bad_args("invalid --phase, must be integer"));
+    match (every, phase) {
+        (None, Some(_)) => bad_args("--phase needs --every"),
+        (Some(n), Some(k)) if k >= n => bad_args("--phase must be less than --every"),
+        _ => {},
+    }
+    if every.is_some() {
+        if mode != Mode::Subsample {
+            bad_args("--every only works with --mode subsample");
+        }
+        if m.opt_present("num") {
+            bad_args("--every does not work with --num");
+        }
+        if strata.is_some() || deterministic || checkpoint.is_some() {
+            bad_args("--every does not work with --stratify-by, --balance-strands, --deterministic or --checkpoint");
+        }
+    }
+    let pipeline = m.opt_str("pipeline");
+    if let Some(spec) = &pipeline {
+        if let Err(e) = pipeline::parse(spec, Seed::new(rng::Kind::Pcg64, 0)) {
+            bad_args(&format!("invalid --pipeline: {}", e));
+        }
+        if mode != Mode::Subsample || outfiles.len() > 1 {
+            bad_args("--pipeline only works with --mode subsample and a single --outfile");
+        }
+        if m.opt_present("num") || m.opt_present("fraction") {
+            bad_args("--pipeline does not work with --num or --fraction, its sampler stage says how many");
+        }
+        if strata.is_some() || every.is_some() || deterministic || checkpoint.is_some() || offsets_only || max_mem.is_some() || m.opt_present("bernoulli") || m.opt_present("compress-reservoir") || m.opt_present("rest-outfile") || m.opt_present("load-state") || m.opt_present("save-state") || m.opt_present("cache-index") || m.opt_present("group-by") {
+            bad_args("--pipeline does not work with --stratify-by, --balance-strands, --pool, --every, --deterministic, --checkpoint, --offsets-only, --max-mem, --bernoulli, --compress-reservoir, --rest-outfile, --load-state, --save-state, --cache-index or --group-by");
+        }
+    }
+    let group_by = match &*m.opt_get_default("group-by", String::from("qname")).unwrap_or_else(|_| bad_args("invalid --group-by")) {
+        "qname" => GroupBy::Qname,
+        "zmw" => GroupBy::Zmw,
+        x => match x.strip_prefix("tag:") {
+            Some(t) if t.len() == 2 && t.is_ascii() => GroupBy::Molecule([t.as_bytes()[0], t.as_bytes()[1]]),
+            _ => bad_args("invalid --group-by, choose from 'qname', 'zmw', 'tag:XX', e.g. tag:BX"),
+        },
+    };
+    if let GroupBy::Molecule(_) = group_by {
+        if mode != Mode::Subsample {
+            bad_args("--group-by tag only works with --mode subsample");
+        }
+        if strata.is_some() || every.is_some() || deterministic || checkpoint.is_some() || offsets_only {
+            bad_args("--group-by tag does not work with --stratify-by, --amplicons, --balance-strands, --every, --deterministic, --checkpoint or --offsets-only");
+        }
+    }
+    let max_per_molecule = m.opt_str("max-per-molecule").map(|_| opt_count(&m, "max-per-molecule", 0));
+    if max_per_molecule == Some(0) {
+        bad_args("--max-per-molecule must be positive");
+    }
+    if max_per_molecule.is_some() && !matches!(group_by, GroupBy::Molecule(_)) {
+        bad_args("--max-per-molecule needs --group-by tag");
+    }
+    if group_by == GroupBy::Zmw && checkpoint.is_some() {
+        bad_args("--checkpoint does not work with --group-by zmw");
+    }
+    let group_by = match m.opt_present("per-read") {
+        false => group_by,
+        true if m.opt_present("group-by") => bad_args("--per-read does not work with --group-by"),
+        true if m.opt_present("singletons") || m.opt_present("on-broken-pairs") || m.opt_present("half-mapped") => bad_args("--per-read does not work with --singletons, --on-broken-pairs or --half-mapped"),
+        true if checkpoint.is_some() => bad_args("--checkpoint does not work with --per-read"),
+        true if !matches!(mode, Mode::Subsample | Mode::Normalize) => bad_args("--per-read only works with --mode subsample and normalize"),
+        true => GroupBy::Read,
+    };
+    let auto_collate = m.opt_present("auto-collate");
+    if auto_collate && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--auto-collate only works with --mode subsample and normalize");
+    }
+    if auto_collate && group_by == GroupBy::Read {
+        bad_args("--auto-collate does not work with --per-read, which needs no sorting");
+    }
+    if auto_collate && checkpoint.is_some() {
+        bad_args("--auto-collate does not work with --checkpoint");
+    }
+    let on_regrouped = match m.opt_str("on-regrouped").as_deref() {
+        None => None,
+        Some("warn") => Some(OnRegrouped::Warn),
+        Some("fail") => Some(OnRegrouped::Fail),
+        Some(_) => bad_args("invalid --on-regrouped, choose from 'warn', 'fail'"),
+    };
+    if on_regrouped.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--on-regrouped only works with --mode subsample and normalize");
+    }
+    if on_regrouped.is_some() && group_by == GroupBy::Read {
+        bad_args("--on-regrouped does not work with --per-read");
+    }
+    let time_range = m.opt_str("time-range").map(|x| TimeRange::parse(&x).unwrap_or_else(|| bad_args("invalid --time-range, e.g. 0..6h, 1h..2h, ..30m")));
+    if time_range.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--time-range only works with --mode subsample and normalize");
+    }
+    let contigs = m.opt_str("contigs").map(|x| x.split(',').map(|c| c.trim().to_string()).filter(|c| !c.is_empty()).collect::<Vec<String>>());
+    if contigs.as_ref().is_some_and(|c| c.is_empty()) {
+        bad_args("invalid --contigs, e.g. chr1,chr2,chrX");
+    }
+    if contigs.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--contigs only works with --mode subsample and normalize");
+    }
+    let bed = |f: String| {
+        if !ospath::path(&f).exists() {
+            status::complain(&format!("{} does not exist!", f));
+            status::fail(Code::Missing);
+        }
+        Regions::from_bed(&f).unwrap_or_else(|e| {
+            status::complain(&e);
+            status::fail(Code::Malformed)
+        })
+    };
+    let exclude_regions = m.opt_str("exclude-regions").map(bed);
+    let regions = m.opt_str("regions").map(bed);
+    if (exclude_regions.is_some() || regions.is_some()) && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--regions and --exclude-regions only work with --mode subsample and normalize");
+    }
+    // the BAM of --exclude-overlapping
+    let exclude_overlapping = m.opt_str("exclude-overlapping").inspect(|f| {
+        if !ospath::path(f).exists() {
+            status::complain(&format!("{} does not exist!", f));
+            status::fail(Code::Missing);
+        }
+    });
+    if exclude_overlapping.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--exclude-overlapping only works with --mode subsample and normalize");
+    }
+    let require_both_in_region = m.opt_present("require-both-in-region");
+    if require_both_in_region && regions.is_none() {
+        bad_args("--require-both-in-region needs --regions");
+    }
+    let sites = m.opt_str("sites").map(|f| {
+        if !ospath::path(&f).exists() {
+            status::complain(&format!("{} does not exist!", f));
+            status::fail(Code::Missing);
+        }
+        Sites::from_vcf(&f).unwrap_or_else(|e| {
+            status::complain(&e);
+            status::fail(Code::Malformed)
+        })
+    });
+    if sites.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--sites only works with --mode subsample and normalize");
+    }
+    let max_per_site = m.opt_str("max-per-site").map(|_| opt_count(&m, "max-per-site", 0));
+    if max_per_site.is_some() && sites.is_none() {
+        bad_args("--max-per-site needs --sites");
+    }
+    if max_per_site == Some(0) {
+        bad_args("--max-per-site must be positive");
+    }
+    if max_per_site.is_some() && checkpoint.is_some() {
+        bad_args("--checkpoint does not work with --max-per-site");
+    }
+    let proper_pairs_only = m.opt_present("proper-pairs-only");
+    if proper_pairs_only && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--proper-pairs-only only works with --mode subsample and normalize");
+    }
+    let only_duplicates = m.opt_present("only-duplicates");
+    if only_duplicates && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--only-duplicates only works with --mode subsample and normalize");
+    }
+    if only_duplicates && dedup {
+        bad_args("--only-duplicates does not work with --dedup-by, which leaves one template per molecule");
+    }
+    let max_nm = m.opt_get::<i64>("max-nm").unwrap_or_else(|_| bad_args("invalid --max-nm, must be integer"));
+    if max_nm.is_some_and(|x| x < 0) {
+        bad_args("invalid --max-nm, must be at least 0");
+    }
+    let max_nm_frac = m.opt_get::<f64>("max-nm-frac").unwrap_or_else(|_| bad_args("invalid --max-nm-frac"));
+    if max_nm_frac.is_some_and(|x| !(0.0..=1.0).contains(&x)) {
+        bad_args("invalid --max-nm-frac, must be between 0 and 1");
+    }
+    if (max_nm.is_some() || max_nm_frac.is_some()) && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--max-nm and --max-nm-frac only work with --mode subsample and normalize");
+    }
+    let min_aligned_frac = m.opt_get::<f64>("min-aligned-frac").unwrap_or_else(|_| bad_args("invalid --min-aligned-frac"));
+    if min_aligned_frac.is_some_and(|x| !(0.0..=1.0).contains(&x)) {
+        bad_args("invalid --min-aligned-frac, must be between 0 and 1");
+    }
+    if min_aligned_frac.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--min-aligned-frac only works with --mode subsample and normalize");
+    }
+    let spliced = match &*m.opt_get_default("spliced", String::from("any")).unwrap_or_else(|_| bad_args("invalid --spliced")) {
+        "only" => Spliced::Only,
+        "exclude" => Spliced::Exclude,
+        "any" => Spliced::Any,
+        _ => bad_args("invalid --spliced, choose from 'only', 'exclude', 'any'"),
+    };
+    if spliced != Spliced::Any && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--spliced only works with --mode subsample and normalize");
+    }
+    let tlen = |name: &str| m.opt_get::<i64>(name).unwrap_or_else(|_| bad_args(&format!("invalid --{}, must be integer", name)));
+    let (min_tlen, max_tlen) = (tlen("min-tlen"), tlen("max-tlen"));
+    if (min_tlen.is_some() || max_tlen.is_some()) && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--min-tlen and --max-tlen only work with --mode subsample and normalize");
+    }
+    if min_tlen.is_some_and(|x| x < 0) || max_tlen.is_some_and(|x| x < 0) {
+        bad_args("--min-tlen and --max-tlen must not be negative");
+    }
+    if let (Some(x), Some(y)) = (min_tlen, max_tlen) {
+        if x > y {
+            bad_args("--min-tlen exceeds --max-tlen");
+        }
+    }
+    let reads = match &*m.opt_get_default("reads", String::from("both")).unwrap_or_else(|_| bad_args("invalid --reads")) {
+        "r1" => Reads::R1,
+        "r2" => Reads::R2,
+        "both" => Reads::Both,
+        _ => bad_args("invalid --reads, choose from 'r1', 'r2', 'both'"),
+    };
+    if reads != Reads::Both && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--reads only works with --mode subsample and normalize");
+    }
+    let primary_only = m.opt_present("primary-only");
+    if primary_only && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--primary-only only works with --mode subsample and normalize");
+    }
+    let max_secondary = m.opt_get::<usize>("max-secondary").unwrap_or_else(|_| bad_args("invalid --max-secondary, must be integer"));
+    if max_secondary.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--max-secondary only works with --mode subsample and normalize");
+    }
+    if max_secondary.is_some() && primary_only {
+        bad_args("--max-secondary does not work with --primary-only, which leaves out every secondary alignment");
+    }
+    let max_records_per_template = m.opt_get::<usize>("max-records-per-template").unwrap_or_else(|_| bad_args("invalid --max-records-per-template, must be integer"));
+    if max_records_per_template.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--max-records-per-template only works with --mode subsample and normalize");
+    }
+    if max_records_per_template.is_some() && primary_only {
+        bad_args("--max-records-per-template does not work with --primary-only, which leaves out every secondary and supplementary record");
+    }
+    let repair_sa = m.opt_present("repair-sa");
+    if repair_sa && reads == Reads::Both && !primary_only && max_records_per_template.is_none() {
+        bad_args("--repair-sa needs --reads r1|r2, --primary-only or --max-records-per-template");
+    }
+    let strip_bad_modifications = m.opt_present("strip-bad-modifications");
+    if strip_bad_modifications && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--strip-bad-modifications only works with --mode subsample and normalize");
+    }
+    let rescue_unmapped_mates = m.opt_present("rescue-unmapped-mates");
+    if rescue_unmapped_mates && reads == Reads::Both {
+        bad_args("--rescue-unmapped-mates needs --reads r1|r2, without which every mate is written anyway");
+    }
+    let fixmate = m.opt_present("fixmate");
+    if fixmate && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--fixmate only works with --mode subsample and normalize");
+    }
+    let tag_list = |name: &str| m.opt_str(name).map(|x| tags::parse_list(&x).unwrap_or_else(|| bad_args(&format!("invalid --{}, e.g. OQ,BI,BD", name))));
+    let tag_edit = match (tag_list("drop-tags"), tag_list("keep-tags")) {
+        (Some(_), Some(_)) => bad_args("give either --drop-tags or --keep-tags"),
+        (Some(v), None) => Some(TagEdit::Drop(v)),
+        (None, Some(v)) => Some(TagEdit::Keep(v)),
+        (None, None) => None,
+    };
+    if tag_edit.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--drop-tags and --keep-tags only work with --mode subsample and normalize");
+    }
+    let anonymize_names = m.opt_present("anonymize-names");
+    if anonymize_names && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--anonymize-names only works with --mode subsample and normalize");
+    }
+    let metadata_out = m.opt_str("metadata-out");
+    if metadata_out.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--metadata-out only works with --mode subsample and normalize");
+    }
+    if metadata_out.is_some() && anonymize_names {
+        bad_args("--metadata-out does not work with --anonymize-names");
+    }
+    let metadata_tags = tag_list("metadata-tags").unwrap_or_default();
+    if m.opt_present("metadata-tags") && metadata_out.is_none() {
+        bad_args("--metadata-tags needs --metadata-out");
+    }
+    let arrow_out = m.opt_str("arrow-out").map(|f| match columnar::Format::of(&f) {
+        Some(format) => (f, format),
+        None => bad_args("invalid --arrow-out, which must end in .arrow, .feather, .ipc or .parquet"),
+    });
+    if arrow_out.is_some() && !cfg!(feature = "arrow") {
+        bad_args("--arrow-out needs sam_subsample built with the arrow feature: cargo build --release --features arrow");
+    }
+    if arrow_out.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--arrow-out only works with --mode subsample and normalize");
+    }
+    if arrow_out.is_some() && anonymize_names {
+        bad_args("--arrow-out does not work with --anonymize-names");
+    }
+    let arrow_tags = tag_list("arrow-tags").unwrap_or_default();
+    if m.opt_present("arrow-tags") && arrow_out.is_none() {
+        bad_args("--arrow-tags needs --arrow-out");
+    }
+    let bed_out = m.opt_str("bed-out");
+    if bed_out.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--bed-out only works with --mode subsample and normalize");
+    }
+    if bed_out.is_some() && anonymize_names {
+        bad_args("--bed-out does not work with --anonymize-names");
+    }
+    let comments = m.opt_strs("add-comment");
+    if comments.iter().any(|c| c.contains('\n')) {
+        bad_args("--add-comment must be a single line");
+    }
+    let sample_name = m.opt_str("replace-sample-name");
+    if sample_name.as_ref().is_some_and(|x| x.is_empty() || x.contains(['\t', '\n'])) {
+        bad_args("invalid --replace-sample-name, must be non-empty without tabs");
+    }
+    // the ID and the @RG line of --set-read-group, of fields given as TAG:VALUE separated by spaces or tabs
+    let read_group = m.opt_str("set-read-group").map(|x| {
+        let fields: Vec<&str> = x.split_whitespace().collect();
+        let valid = |f: &&str| f.len() > 3 && f.as_bytes()[2] == b':' && f.as_bytes()[..2].iter().all(u8::is_ascii_alphanumeric) && f.as_bytes()[0].is_ascii_alphabetic();
+        if fields.is_empty() || !fields.iter().all(valid) {
+            bad_args("invalid --set-read-group, e.g. 'ID:sub SM:sample1 PL:ILLUMINA'");
+        }
+        if fields.iter().enumerate().any(|(i, f)| fields[..i].iter().any(|g| g[..2] == f[..2])) {
+            bad_args("invalid --set-read-group, which has a tag more than once");
+        }
+        match fields.iter().find_map(|f| f.strip_prefix("ID:")) {
+            Some(id) => (id.to_string(), format!("@RG\t{}", fields.join("\t"))),
+            None => bad_args("--set-read-group needs an ID, e.g. 'ID:sub SM:sample1 PL:ILLUMINA'"),
+        }
+    });
+    if (!comments.is_empty() || read_group.is_some() || sample_name.is_some()) && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--add-comment, --set-read-group and --replace-sample-name only work with --mode subsample and normalize");
+    }
+    // every sample of --per-sample-num goes to its own output
+    let split_by = match m.opt_str("split-by") {
+        Some(x) => Some(SplitBy::parse(&x).unwrap_or_else(|| bad_args("invalid --split-by, choose from 'read-group', 'chromosome', 'tag:XX'"))),
+        None => per_sample_num.map(|_| SplitBy::Sample),
+    };
+    if split_by.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--split-by only works with --mode subsample and normalize");
+    }
+    let min_records = opt_count(&m, "min-records", 1);
+    if m.opt_present("min-records") && split_by.is_none() {
+        bad_args("--min-records needs --split-by");
+    }
+    let allowlist = m.opt_str("allowlist").map(|f| {
+        if !ospath::path(&f).exists() {
+            status::complain(&format!("{} does not exist!", f));
+            status::fail(Code::Missing);
+        }
+        split::read_allowlist(&f).unwrap_or_else(|e| {
+            status::complain(&e);
+            status::fail(Code::Malformed)
+        })
+    });
+    let max_open_files = opt_count(&m, "max-open-files", 500);
+    if (allowlist.is_some() || m.opt_present("max-open-files")) && split_by.is_none() {
+        bad_args("--allowlist and --max-open-files need --split-by");
+    }
+    if max_open_files == 0 {
+        bad_args("--max-open-files must be positive");
+    }
+    let records_per_file = m.opt_str("records-per-file").map(|_| opt_count(&m, "records-per-file", 0));
+    if records_per_file == Some(0) {
+        bad_args("--records-per-file must be positive");
+    }
+    if records_per_file.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--records-per-file only works with --mode subsample and normalize");
+    }
+    if records_per_file.is_some() && split_by.is_some() {
+        bad_args("give either --split-by or --records-per-file");
+    }
+    let shards = m.opt_str("shard").map(|_| opt_count(&m, "shard", 0));
+    if shards == Some(0) {
+        bad_args("--shard must be positive");
+    }
+    if shards.is_some() != m.opt_present("shard-template") {
+        bad_args("--shard needs --shard-template, and --shard-template needs --shard");
+    }
+    let shard_by = match &*m.opt_get_default("shard-by", String::from("round-robin")).unwrap_or_else(|_| bad_args("invalid --shard-by")) {
+        "round-robin" => ShardBy::RoundRobin,
+        "qname" => ShardBy::Qname,
+        _ => bad_args("invalid --shard-by, choose from 'round-robin', 'qname'"),
+    };
+    if m.opt_present("shard-by") && shards.is_none() {
+        bad_args("--shard-by needs --shard");
+    }
+    if shards.is_some() {
+        if mode != Mode::Subsample {
+            bad_args("--shard only works with --mode subsample");
+        }
+        if split_by.is_some() || records_per_file.is_some() {
+            bad_args("give only one of --split-by, --records-per-file and --shard");
+        }
+        if !outfiles[0].contains("{}") {
+            bad_args("--shard-template needs a {} for the shard number, e.g. out_{}.bam");
+        }
+    }
+    let skip = opt_count(&m, "skip", 0);
+    let head = m.opt_str("head").map(|_| opt_count(&m, "head", 0));
+    if head == Some(0) {
+        bad_args("--head must be positive");
+    }
+    if (skip > 0 || head.is_some()) && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--skip and --head only work with --mode subsample and normalize");
+    }
+    if (skip > 0 || head.is_some()) && checkpoint.is_some() {
+        bad_args("--checkpoint does not work with --skip or --head");
+    }
+    let strict = m.opt_present("strict");
+    let allow_fewer = m.opt_present("allow-fewer");
+    if allow_fewer && !strict {
+        bad_args("--allow-fewer only relaxes --strict, which is not given");
+    }
+    let min_templates = opt_count(&m, "min-templates", 0);
+    if min_templates > 0 && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--min-templates only works with --mode subsample and normalize");
+    }
+    let exact = m.opt_present("exact");
+    if exact && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--exact only works with --mode subsample and normalize");
+    }
+    if exact && split_by.is_some() {
+        bad_args("--exact does not work with --split-by, which drops templates of no group");
+    }
+    let finalize_on_interrupt = m.opt_present("finalize-on-interrupt");
+    if finalize_on_interrupt && mode == Mode::RandomAccess {
+        bad_args("--finalize-on-interrupt does not work with --mode random-access");
+    }
+    let time_limit = m.opt_str("time-limit").map(|x| match timing::parse_duration(&x).filter(|&s| s > 0.0).map(Duration::try_from_secs_f64) {
+        Some(Ok(d)) => d,
+        _ => bad_args("invalid --time-limit, e.g. 90, 30m, 2h, 1.5d"),
+    });
+    if time_limit.is_some() && mode == Mode::RandomAccess {
+        bad_args("--time-limit does not work with --mode random-access");
+    }
+    if mode == Mode::Normalize && (infiles.len() < 2 || outfiles.len() != infiles.len()) {
+        bad_args("--mode normalize takes several --infile, and an --outfile for each");
+    }
+    if mode != Mode::Subsample {
+        if matches!(mode, Mode::CapDepth | Mode::Uniform | Mode::RandomAccess | Mode::Thin | Mode::StreamThrough) && infiles.len() > 1 {
+            bad_args("--mode cap-depth, uniform, random-access and thin, and --stream-through, take a single --infile");
+        }
+        if checkpoint.is_some() {
+            bad_args("--checkpoint only works with --mode subsample");
+        }
+    }
+    let bernoulli = m.opt_present("bernoulli");
+    let expected_templates = m.opt_present("expected-templates").then(|| opt_count(&m, "expected-templates", 0));
+    if bernoulli && mode != Mode::Subsample {
+        bad_args("--bernoulli only works with --mode subsample");
+    }
+    if bernoulli && (max_mem.is_some() || offsets_only || every.is_some() || strata.is_some() || deterministic || checkpoint.is_some() || rest_outfile.is_some() || matches!(group_by, GroupBy::Molecule(_))) {
+        bad_args("--bernoulli does not work with --max-mem, --offsets-only, --every, --stratify-by, --balance-strands, --deterministic, --checkpoint, --rest-outfile or --group-by tag");
+    }
+    let compress_reservoir = m.opt_present("compress-reservoir");
+    if compress_reservoir && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--compress-reservoir only works with --mode subsample and normalize");
+    }
+    if compress_reservoir && (max_mem.is_some() || offsets_only || bernoulli || every.is_some() || strata.is_some() || deterministic || checkpoint.is_some() || rest_outfile.is_some() || matches!(group_by, GroupBy::Molecule(_))) {
+        bad_args("--compress-reservoir does not work with --max-mem, --offsets-only, --bernoulli, --every, --stratify-by, --balance-strands, --deterministic, --checkpoint, --rest-outfile or --group-by tag, which hold no reservoir of records");
+    }
+    if pick != Pick::Random && (mode == Mode::StreamThrough || max_mem.is_some() || offsets_only || bernoulli || every.is_some() || strata.is_some() || deterministic || checkpoint.is_some() || rest_outfile.is_some() || compress_reservoir || matches!(group_by, GroupBy::Molecule(_))) {
+        bad_args("--mode first and last do not work with --stream-through, --max-mem, --offsets-only, --bernoulli, --every, --stratify-by, --balance-strands, --deterministic, --checkpoint, --rest-outfile, --compress-reservoir or --group-by tag");
+    }
+    let max_records = m.opt_str("max-records").map(|_| opt_count(&m, "max-records", 0));
+    if max_records.is_some() && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--max-records only works with --mode subsample and normalize");
+    }
+    if max_records.is_some() && (nums.len() > 1 || max_mem.is_some() || offsets_only || bernoulli || every.is_some() || strata.is_some() || rest_outfile.is_some() || compress_reservoir || matches!(group_by, GroupBy::Molecule(_))) {
+        bad_args("--max-records does not work with several --num, --max-mem, --offsets-only, --bernoulli, --every, --stratify-by, --balance-strands, --rest-outfile, --compress-reservoir or --group-by tag");
+    }
+    if expected_templates.is_some() && !bernoulli && mode != Mode::StreamThrough {
+        bad_args("--expected-templates needs --bernoulli or --stream-through");
+    }
+    if expected_templates == Some(0) {
+        bad_args("invalid --expected-templates, must be at least 1");
+    }
+    if let Some(f) = stream.as_ref().filter(|_| bernoulli && expected_templates.is_none()) {
+        bad_args(&format!("--bernoulli needs --expected-templates with a pipe such as {}, whose size is unknown", f));
+    }
+    if let Some(f) = stream.as_ref().filter(|_| mode == Mode::StreamThrough && expected_templates.is_none()) {
+        bad_args(&format!("--stream-through needs --expected-templates with a pipe such as {}, whose size is unknown", f));
+    }
+    if let Some(f) = &stream {
+        // reading twice, or seeking, is out
+        let twice = [
+            (max_mem.is_some(), "--max-mem"),
+            (offsets_only, "--offsets-only"),
+            (checkpoint.is_some(), "--checkpoint"),
+            (rest_outfile.is_some(), "--rest-outfile"),
+            (strata.is_some(), "--stratify-by, --amplicons or --balance-strands"),
+            (matches!(group_by, GroupBy::Molecule(_)), "--group-by tag"),
+            (time_range.is_some(), "--time-range"),
+            (dedup, "--dedup-by"),
+            (outfiles.len() > 1 && allocate == Allocate::Proportional && every.is_none(), "--allocate proportional"),
+            (matches!(mode, Mode::Normalize | Mode::Uniform | Mode::RandomAccess), "--mode normalize, uniform and random-access"),
+        ];
+        if let Some((_, option)) = twice.iter().find(|x| x.0) {
+            bad_args(&format!("{} is a pipe, which can only be read once, so it does not work with {}", f, option));
+        }
+    }
+    let load_state = m.opt_str("load-state");
+    let save_state = m.opt_str("save-state");
+    if load_state.is_some() || save_state.is_some() {
+        // the state is that of the serial reservoir, over every template of the inputs read
+        if mode != Mode::Subsample || outfiles.len() > 1 || nums.len() > 1 {
+            bad_args("--load-state and --save-state only work with --mode subsample, a single --outfile and a single --num");
+        }
+        if threads > 1 || fraction.is_some() || max_mem.is_some() || offsets_only || bernoulli || every.is_some() || strata.is_some() || deterministic || checkpoint.is_some() || rest_outfile.is_some() || compress_reservoir || pick != Pick::Random || skip > 0 || head.is_some() || matches!(group_by, GroupBy::Molecule(_) | GroupBy::Zmw | GroupBy::Read) {
+            // the state is read back into templates by qname, as a checkpoint is
+            bad_args("--load-state and --save-state do not work with --threads > 1, --fraction, --max-mem, --offsets-only, --bernoulli, --every, --stratify-by, --balance-strands, --deterministic, --checkpoint, --rest-outfile, --compress-reservoir, --mode first and last, --skip, --head, --group-by zmw or tag, or --per-read");
+        }
+    }
+    // every option but those leaving which templates pass the filters as they are, for an index to be
+    // reused by runs that only differ by those
+    let cache_index = m.opt_str("cache-index").map(|path| {
+        let unfiltered = ["num", "fraction", "seed", "seed-from-input", "rng", "outfile", "output-fd", "cache-index", "threads", "level", "verbose", "quiet", "log-filter", "log-file", "log-format", "error-format", "tui", "timing", "filter-stats", "strict", "allow-fewer", "exact", "finalize-on-interrupt", "time-limit"];
+        let options: Vec<String> = OPTIONS.iter().map(|x| x.1).filter(|x| !unfiltered.contains(x) && m.opt_present(x)).map(|x| format!("--{}={}", x, m.opt_strs(x).join(","))).collect();
+        (path, options.join(" "))
+    });
+    if cache_index.is_some() {
+        if mode != Mode::Subsample || pick != Pick::Random || outfiles.len() > 1 || nums.len() > 1 {
+            bad_args("--cache-index only works with --mode subsample, a single --outfile and a single --num");
+        }
+        if let Some(f) = stream.as_ref().or(infiles.iter().find(|f| htsget::is_url(f))) {
+            bad_args(&format!("{} is a pipe or on an htsget server, which cannot be read at offsets, so it does not work with --cache-index", f));
+        }
+        if bernoulli || every.is_some() || strata.is_some() || deterministic || checkpoint.is_some() || load_state.is_some() || save_state.is_some() || rest_outfile.is_some() || auto_collate || matches!(group_by, GroupBy::Molecule(_)) {
+            bad_args("--cache-index does not work with --bernoulli, --every, --stratify-by, --balance-strands, --deterministic, --checkpoint, --load-state, --save-state, --rest-outfile, --auto-collate or --group-by tag");
+        }
+        if template_stats.is_some() || distribution_report.is_some() || duplication.is_some() || complexity.is_some() || saturation.is_some() {
+            bad_args("--cache-index does not work with --template-stats, --distribution-report, --duplication, --complexity or --saturation, which need every template of the inputs read");
+        }
+    }
+    if let Some(f) = load_state.as_ref().filter(|f| !ospath::path(f).exists()) {
+        status::complain(&format!("--load-state {} does not exist!", f));
+        status::fail(Code::Missing);
+    }
+    // several --num are drawn from the one reservoir, whose sample the smaller ones are cut from
+    if output_fd.is_some() && (nums.len() > 1 || split_by.is_some() || records_per_file.is_some() || per_sample_num.is_some() || !matches!(mode, Mode::Subsample | Mode::StreamThrough)) {
+        bad_args("--output-fd only works with --mode subsample and --stream-through, and a single output, so not with several --num, --split-by, --records-per-file or --per-sample-num");
+    }
+    if fraction.is_some() && mode != Mode::Subsample {
+        bad_args("--fraction only works with --mode subsample");
+    }
+    if fraction.is_some() && (per_stratum.is_some() || quotas.is_some()) {
+        bad_args("--fraction does not work with --per-amplicon, --per-sample-num, --per-tile-num or --quota");
+    }
+    let nested: Vec<(usize, String)> = match nums.len() {
+        0 | 1 => Vec::new(),
+        _ => {
+            if mode != Mode::Subsample || outfiles.len() > 1 || !outfiles[0].contains("{num}") {
+                bad_args("several --num need --mode subsample and a single --outfile with {num} in its name, e.g. out.{num}.bam");
+            }
+            if max_mem.is_some() || offsets_only || bernoulli || every.is_some() || strata.is_some() || deterministic || checkpoint.is_some() || rest_outfile.is_some() || split_by.is_some() || records_per_file.is_some() || shards.is_some() || compress_reservoir || matches!(group_by, GroupBy::Molecule(_)) {
+                bad_args("several --num do not work with --max-mem, --offsets-only, --bernoulli, --every, --stratify-by, --balance-strands, --deterministic, --checkpoint, --rest-outfile, --split-by, --records-per-file, --shard, --compress-reservoir or --group-by tag");
+            }
+            let nested = nums[1..].iter().map(|&n| (n, outfiles[0].replace("{num}", &n.to_string()))).collect();
+            outfiles[0] = outfiles[0].replace("{num}", &num.to_string());
+            nested
+        },
+    };
+    // qnames to write whatever the filters and the draw say, and count against --num
+    let always_include = m.opt_str("always-include").map(|f| {
+        if !ospath::path(&f).exists() {
+            status::complain(&format!("{} does not exist!", f));
+            status::fail(Code::Missing);
+        }
+        let names: HashSet<Vec<u8>> = split::read_allowlist(&f).unwrap_or_else(|e| {
+            status::complain(&e);
+            status::fail(Code::Malformed)
+        }).into_iter().map(String::into_bytes).collect();
+        if names.is_empty() {
+            bad_args(&format!("--always-include {} lists no names", f));
+        }
+        names
+    });
+    // the reservoir's order is no order at all, unless the sample is put back in that of the input or sorted
+    let keep_order = m.opt_present("keep-order");
+    let sort_output = m.opt_present("sort-output");
+    if (keep_order || sort_output) && !matches!(mode, Mode::Subsample | Mode::Normalize) {
+        bad_args("--keep-order and --sort-output only work with --mode subsample and normalize");
+    }
+    if keep_order && sort_output {
+        bad_args("--keep-order does not work with --sort-output");
+    }
+    if keep_order && (!nested.is_empty() || always_include.is_some() || checkpoint.is_some() || compress_reservoir || pipeline.is_some() || m.opt_present("load-state") || m.opt_present("save-state")) {
+        bad_args("--keep-order does not work with several --num, --always-include, --checkpoint, --compress-reservoir, --pipeline, --load-state or --save-state");
+    }
+    if sort_output && (split_by.is_some() || records_per_file.is_some() || shards.is_some()) {
+        bad_args("--sort-output writes a single output, so does not work with --split-by, --records-per-file or --shard");
+    }
+    if always_include.is_some() {
+        if mode != Mode::Subsample || pick != Pick::Random {
+            bad_args("--always-include only works with --mode subsample");
+        }
+        if !nested.is_empty() || max_mem.is_some() || offsets_only || bernoulli || every.is_some() || strata.is_some() || deterministic || checkpoint.is_some() || rest_outfile.is_some() || compress_reservoir || max_records.is_some() || matches!(group_by, GroupBy::Molecule(_)) || pipeline.is_some() || m.opt_present("load-state") || m.opt_present("save-state") || m.opt_present("cache-index") {
+            bad_args("--always-include does not work with several --num, --max-mem, --offsets-only, --bernoulli, --every, --stratify-by, --balance-strands, --deterministic, --checkpoint, --rest-outfile, --compress-reservoir, --max-records, --group-by tag, --pipeline, --load-state, --save-state or --cache-index");
+        }
+    }
+    // a chromosome, an insert size or a position is what these go by
+    let mut coordinate_options: Vec<String> = COORDINATE_OPTIONS.iter().filter(|o| m.opt_present(o)).map(|o| format!("--{}", o)).collect();
+    for (o, x) in [("stratify-by", "chromosome"), ("stratify-by", "insert-size"), ("split-by", "chromosome"), ("dedup-by", "position"), ("duplication", "position")] {
+        if m.opt_strs(o).iter().any(|v| v.split(',').any(|v| v == x)) {
+            coordinate_options.push(format!("--{} {}", o, x));
+        }
+    }
+    for (o, policy) in [("unmapped", unmapped), ("half-mapped", half_mapped)] {
+        if policy != Policy::Keep {
+            coordinate_options.push(format!("--{} {}", o, policy.name()));
+        }
+    }
+    if prefer_half_mapped {
+        coordinate_options.push(String::from("--half-mapped prefer"));
+    }
+    Params {
+        infiles,
+        outfiles,
+        num,
+        fraction,
+        nested,
+        seed,
+        rng,
+        level,
+        log_filter,
+        on_broken_pairs,
+        singletons,
+        unmapped,
+        half_mapped,
+        coordinate_options,
+        unaligned: false,
+        threads,
+        chunk_size,
+        checkpoint,
+        checkpoint_every,
+        load_state,
+        save_state,
+        cache_index,
+        max_mem,
+        compress_reservoir,
+        max_records,
+        offsets_only,
+        bernoulli,
+        expected_templates,
+        log_file,
+        log_format,
+        strict,
+        allow_fewer,
+        min_templates,
+        exact,
+        finalize_on_interrupt,
+        time_limit,
+        mode,
+        pick,
+        max_depth,
+        target_depth,
+        window,
+        track,
+        depths,
+        off_track,
+        allocate,
+        normalize_by,
+        ratio,
+        name_regex,
+        name_regex_invert,
+        exclude_tiles,
+        require_tags,
+        exclude_tags,
+        rest_outfile,
+        names_out,
+        metadata_out,
+        metadata_tags,
+        arrow_out,
+        arrow_tags,
+        bed_out,
+        strata,
+        vaf_tolerance,
+        fractions,
+        per_stratum,
+        quotas,
+        equal_strata,
+        min_per_stratum,
+        deterministic,
+        every,
+        pipeline,
+        phase,
+        group_by,
+        auto_collate,
+        on_regrouped,
+        max_per_molecule,
+        time_range,
+        contigs,
+        exclude_regions,
+        exclude_overlapping,
+        regions,
+        require_both_in_region,
+        sites,
+        max_per_site,
+        proper_pairs_only,
+        only_duplicates,
+        max_nm,
+        max_nm_frac,
+        min_aligned_frac,
+        spliced,
+        min_tlen,
+        max_tlen,
+        template_stats,
+        tallies,
+        distribution_report,
+        duplication,
+        dedup,
+        complexity,
+        saturation,
+        per_cell,
+        digest,
+        validate_output,
+        trim_header,
+        check_input,
+        skip_invalid,
+        plan,
+        tui,
+        timing,
+        filter_stats,
+        always_include,
+        keep_order,
+        sort_output,
+        split_by,
+        reads,
+        primary_only,
+        max_secondary,
+        max_records_per_template,
+        repair_sa,
+        rescue_unmapped_mates,
+        strip_bad_modifications,
+        fixmate,
+        tag_edit,
+        anonymize_names,
+        comments,
+        sample_name,
+        read_group,
+        min_records,
+        allowlist,
+        max_open_files,
+        records_per_file,
+        shards,
+        shard_by,
+        skip,
+        head,
+    }
+}
+
+// inputs must be sorted by `so_expected`, queryname for templates to be contiguous or coordinate for
+// positions to be
+fn check_header(header: &bam::Header, so_expected: &str) {
+    let header = header.to_hashmap();
+    // unaligned BAMs have the records of a template together whatever @HD says, if they have one
+    if so_expected == "queryname" && !header.contains_key("SQ") {
+        return;
+    }
+    let so = match header.get("HD") {
+        Some(a) => a,
+        None => { 
+            error!("'@HD' not found in header!");
+            status::fail(Code::Malformed)
+        },
+    };
+
+    let so = match so[0].get("SO") {
+        Some(a) => a,
+        None => { 
+            error!("'SO' not found in '@HD'!");
+            status::fail(Code::Malformed);
+        },
+    };
+
+    // collated inputs, by `samtools collate` or --auto-collate, have the records of a template together too
+    let grouped = so_expected == "queryname" && header["HD"][0].get("GO").is_some_and(|go| go == "query");
+    if so != so_expected && !grouped {
+        match so_expected {
+            "queryname" => error!("Not sorted by queryname! Please run 'samtools sort -n -o output.bam input.bam' first, or use --auto-collate!"),
+            _ => error!("Not sorted by coordinate! Please run 'samtools sort -o output.bam input.bam' first!"),
+        }
+        status::fail(Code::Malformed);
+    }
+    // a sub-sort of SO:queryname, natural as `samtools sort -n` has it or lexicographical as Picard and
+    // `samtools sort -N` have it, or one of its own, is all the same here: only that the records of a
+    // template are together matters, which the reading checks as it goes
+    if so == "queryname" {
+        match header["HD"][0].get("SS").map(String::as_str) {
+            None => {},
+            Some(ss @ ("queryname:natural" | "queryname:lexicographical")) => debug!("sorted by queryname, {} order", &ss[10..]),
+            Some(ss) if ss.starts_with("queryname:") => info!("Sorted by queryname in an order of its own, 'SS:{}'; taken as grouped by qname all the same.", ss),
+            Some(ss) => warn!("'SS:{}' does not go with 'SO:queryname'; taken as sorted by queryname.", ss),
+        }
+    }
+}
+
+// templates set apart while reading, reported at the end
+#[derive(Default)]
+struct Counts {
+    broken: usize,
+    singletons: usize,
+    half_mapped: usize,
+    unmapped: usize,
+    tagged: usize,
+    misnamed: usize,
+    off_tiles: usize,
+    timed: usize,
+    off_contigs: usize,
+    excluded: usize,
+    overlapping: usize,
+    off_regions: usize,
+    off_sites: usize,
+    improper: usize,
+    off_tlen: usize,
+    duplicates: usize,
+    unflagged: usize,
+    divergent: usize,
+    clipped: usize,
+    splicing: usize,
+    unstratified: usize,
+    untagged: usize,
+    filters: FilterStats,
+}
+
+// the filters of `admit`, in the order it runs them
+const FILTERS: [&str; 20] = [
+    "--require-tag/--exclude-tag", "--name-regex", "--exclude-tiles", "--time-range", "--contigs",
+    "--exclude-regions", "--exclude-overlapping", "--regions", "--proper-pairs-only", "--min-tlen/--max-tlen",
+    "--only-duplicates", "--max-nm/--max-nm-frac", "--min-aligned-frac", "--spliced", "--unmapped",
+    "--singletons", "--half-mapped", "--on-broken-pairs", "--dedup-by", "--sites",
+];
+
+// with --filter-stats, the templates each of `FILTERS` turned down: those it was the first to turn down,
+// which add up to the templates filtered out, and all it would have on its own, which overlap
+#[derive(Default)]
+struct FilterStats {
+    first: [usize; FILTERS.len()],
+    any: [usize; FILTERS.len()],
+    templates: usize,
+}
+
+impl FilterStats {
+    fn add(&mut self, rejecting: &[usize]) {
+        self.templates += 1;
+        if let Some(&i) = rejecting.first() {
+            self.first[i] += 1;
+        }
+        for &i in rejecting {
+            self.any[i] += 1;
+        }
+    }
+
+    // a line for every filter that turned any template down, after the number of templates looked at; none
+    // when there were none, as with a --cache-index reused
+    fn log(&self) {
+        if self.templates == 0 {
+            return;
+        }
+        let filtered: usize = self.first.iter().sum();
+        info!("Filters: {} of {} templates turned down.", filtered, self.templates);
+        for (i, name) in FILTERS.iter().enumerate().filter(|&(i, _)| self.any[i] > 0) {
+            info!("  {}: {} first, {} in all ({:.2}%).", name, self.first[i], self.any[i], 100.0 * self.any[i] as f64 / self.templates as f64);
+        }
+    }
+}
+
+// a PE template is complete when its primary records include both the first and the last mate
+fn is_broken_pair(rs: &[Record]) -> bool {
+    let mut paired = false;
+    let mut first = false;
+    let mut last = false;
+    for r in rs {
+        if !r.is_paired() || r.is_secondary() || r.is_supplementary() {
+            continue;
+        }
+        paired = true;
+        first |= r.is_first_in_template();
+        last |= r.is_last_in_template();
+    }
+    paired && !(first && last)
+}
+
+// an unmapped template has no primary read aligned
+fn is_unmapped(rs: &[Record]) -> bool {
+    rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary()).all(|r| r.is_unmapped())
+}
+
+// a proper pair has all of its primary reads flagged as aligned in proper pair; SE reads never do
+fn is_proper_pair(rs: &[Record]) -> bool {
+    let mut primary = rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary()).peekable();
+    primary.peek().is_some() && primary.all(|r| r.is_paired() && r.is_proper_pair())
+}
+
+// whether a template is flagged as a duplicate, by any of its primary reads as with --duplication flag
+fn is_duplicate(rs: &[Record]) -> bool {
+    rs.iter().any(|r| !r.is_secondary() && !r.is_supplementary() && r.is_duplicate())
+}
+
+// whether a template is a proper pair of |TLEN| within [min, max]; TLEN is only an insert size for
+// proper pairs
+fn in_tlen_range(rs: &[Record], min: Option<i64>, max: Option<i64>) -> bool {
+    let x = strata::insert_size(rs);
+    is_proper_pair(rs) && min.is_none_or(|y| x >= y) && max.is_none_or(|y| x <= y)
+}
+
+// a singleton template has a single primary read, either SE or a mate whose partner is absent
+fn is_singleton(rs: &[Record]) -> bool {
+    rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary()).count() <= 1
+}
+
+fn write<W: RecordSink + ?Sized>(outfh: &mut W, r: &Record, outfile: &str) {
+    if let Err(e) = profile::time(Phase::Write, || outfh.write_record(r)) {
+        error!("failed to write {}: {}", outfile, e);
+        status::fail(Code::Io);
+    }
+}
+
+// where sampled templates go: --outfile, an output per group with --split-by, numbered files with
+// --records-per-file, or the shards of --shard; a single output is a bam::Writer but for the tests,
+// which write to a Vec
+enum Sink<W = bam::Writer> {
+    One(W),
+    // per --sort-output, the records written so far, to go out sorted once all are in
+    Sorted(W, Vec<Record>),
+    Split(Box<Splitter>),
+    Chunks(Chunks),
+    Shards(Shards),
+}
+
+struct Output<W = bam::Writer> {
+    sink: Sink<W>,
+    // templates written so far, which --anonymize-names numbers them by
+    n: usize,
+    // reference names of the output, by tid, which SA entries go by
+    refs: Vec<String>,
+    fixes: Fixes,
+}
+
+// what was changed of the records written, or found wrong with them, to report at the end
+#[derive(Default)]
+struct Fixes {
+    // records written with SA entries of alignments not written, or rewritten without them per --repair-sa
+    dangling_sa: usize,
+    // records made single-end per --fixmate, their mate not written
+    unpaired: usize,
+    // records written with MM/ML tags that do not fit them, or stripped of them per --strip-bad-modifications
+    bad_modifications: usize,
+    // templates of more secondary and supplementary records than --max-records-per-template, and the
+    // records left out of them
+    capped: usize,
+    capped_records: usize,
+    // records of reads whose secondary alignments were left out, with NH lowered and HI numbered again
+    hits: usize,
+    // records without a read group tagged with that of --set-read-group
+    read_group: usize,
+    // paired primary records written without their mate's, as --reads or the input has them, for
+    // --validate-output to find as many of
+    mateless: usize,
+}
+
+// whether a record of a template `rs` written goes out, per --reads and --primary-only; with
+// --rescue-unmapped-mates, the unmapped mate of a mapped read going out does too, whatever --reads
+fn keeps(r: &Record, rs: &[Record], params: &Params) -> bool {
+    let primary = |x: &Record| !x.is_secondary() && !x.is_supplementary();
+    (params.reads.admits(r) && !(params.primary_only && !primary(r)))
+        || (params.rescue_unmapped_mates && primary(r) && r.is_unmapped()
+            && rs.iter().any(|x| primary(x) && !x.is_unmapped() && x.qname() == r.qname() && params.reads.admits(x)))
+}
+
+// the templates of a sample that fit in `max` records as written, per --max-records: taken in a random
+// order, or from the start with --mode first and the end with --mode last, until the next would go past
+// it, so that they are a uniform subset of the sample; they keep the order of the sample. The random
+// order is of a seed of its own, `seed` being that the sample was drawn with
+fn within_records(sample: Vec<RecordSet>, max: usize, params: &Params, seed: Seed) -> Vec<RecordSet> {
+    let mut order: Vec<usize> = (0..sample.len()).collect();
+    match params.pick {
+        Pick::Random => order.shuffle(&mut seed.stage("max-records").generator()),
+        Pick::First => {},
+        Pick::Last => order.reverse(),
+    }
+    let mut taken = vec![false; sample.len()];
+    let mut total = 0;
+    for i in order {
+        let n = sample[i].iter().filter(|r| keeps(r, &sample[i], params)).count();
+        if total + n > max {
+            break;
+        }
+        total += n;
+        taken[i] = true;
+    }
+    sample.into_iter().zip(taken).filter(|x| x.1).map(|x| x.0).collect()
+}
+
+// the records of the `n`th sampled template as written: the mates per --reads, the primary records only
+// per --primary-only, with the tags per --drop-tags or --keep-tags, and the qname replaced by `n` per
+// --anonymize-names; should the template have several qnames, as a ZMW does, they become n.1, n.2, ...
+// in turn, and with the mate fields per --fixmate (see `fixmate::fix`), the records made single-end
+// counted in `unpaired`, and at most --max-records-per-template of its secondary and supplementary
+// records, those left out counted in `capped_records`; the NH and HI tags of the reads whose secondary
+// alignments were left out are rewritten (see `tags::recount_hits`), counted in `hits`. When records
+// are left out, the SA tags of the rest are checked against those written (see `tags::check_sa`), the
+// records listing others counted in `dangling_sa`. The modified-base tags of every record written are
+// checked against it (see `tags::modifications_consistent`), those that do not fit counted in
+// `bad_modifications`
+fn prepare<'a>(rs: &'a [Record], params: &Params, n: usize, refs: &[String], fixes: &mut Fixes) -> Cow<'a, [Record]> {
+    if params.reads == Reads::Both && !params.primary_only && params.max_secondary.is_none() && params.max_records_per_template.is_none() && !params.fixmate && params.tag_edit.is_none() && params.read_group.is_none() && !params.anonymize_names {
+        let bad = rs.iter().filter(|r| !tags::modifications_consistent(r)).count();
+        if bad == 0 || !params.strip_bad_modifications {
+            fixes.bad_modifications += bad;
+            fixes.mateless += validate::mateless(rs);
+            return Cow::Borrowed(rs);
+        }
+    }
+    let mut qnames: Vec<&[u8]> = Vec::new();
+    if params.anonymize_names {
+        for r in rs {
+            if !qnames.contains(&r.qname()) {
+                qnames.push(r.qname());
+            }
+        }
+    }
+    let mut out: Vec<Record> = rs.iter().filter(|r| keeps(r, rs, params)).map(|r| {
+        let mut out = r.clone();
+        if params.anonymize_names {
+            let name = match qnames.len() {
+                1 => n.to_string(),
+                _ => format!("{}.{}", n, qnames.iter().position(|&q| q == r.qname()).unwrap_or_default() + 1),
+            };
+            out.set_qname(name.as_bytes());
+        }
+        out
+    }).collect();
+    if let Some(max) = params.max_secondary {
+        tags::cap_secondary(&mut out, max, Seed::new(params.rng, params.seed));
+    }
+    if let Some(max) = params.max_records_per_template {
+        let left_out = tags::cap_records(&mut out, max, Seed::new(params.rng, params.seed));
+        fixes.capped += usize::from(left_out > 0);
+        fixes.capped_records += left_out;
+    }
+    // before --drop-tags or --keep-tags, which may take NH and HI off
+    if out.len() < rs.len() {
+        fixes.hits += tags::recount_hits(rs, &mut out);
+    }
+    if params.fixmate {
+        fixes.unpaired += fixmate::fix(&mut out);
+    }
+    // after --fixmate, so that MC goes as --drop-tags or --keep-tags have it
+    if let Some(edit) = &params.tag_edit {
+        out.iter_mut().for_each(|r| edit.apply(r));
+    }
+    // after --keep-tags, which would take it off again
+    if let Some((id, _)) = &params.read_group {
+        fixes.read_group += out.iter_mut().map(|r| tags::set_read_group(r, id) as usize).sum::<usize>();
+    }
+    if out.len() < rs.len() {
+        fixes.dangling_sa += tags::check_sa(&mut out, refs, params.repair_sa);
+    }
+    // after --drop-tags or --keep-tags, which may take ML and leave MM, or the other way round
+    fixes.bad_modifications += tags::check_modifications(&mut out, params.strip_bad_modifications);
+    fixes.mateless += validate::mateless(&out);
+    Cow::Owned(out)
+}
+
+impl<W: RecordSink> Output<W> {
+    fn new(sink: Sink<W>, header: &bam::Header) -> Output<W> {
+        Output { sink, n: 0, refs: header::names(header), fixes: Fixes::default() }
+    }
+
+    // how many of the template's records were written, as prepare left them; None if it was not: --split-by
+    // drops those of no group, going by the records as read, and nothing more goes out of a run interrupted
+    // without --finalize-on-interrupt, or --time-limit
+    fn write(&mut self, rs: &[Record], outfile: &str, params: &Params) -> Option<usize> {
+        if abandoned(params) {
+            return None;
+        }
+        profile::time(Phase::Write, || self.write_to_sink(rs, outfile, params))
+    }
+
+    fn write_to_sink(&mut self, rs: &[Record], outfile: &str, params: &Params) -> Option<usize> {
+        match &mut self.sink {
+            Sink::One(fh) => {
+                self.n += 1;
+                let out = prepare(rs, params, self.n, &self.refs, &mut self.fixes);
+                out.iter().for_each(|r| write(fh, r, outfile));
+                Some(out.len())
+            },
+            Sink::Sorted(_, held) => {
+                self.n += 1;
+                let out = prepare(rs, params, self.n, &self.refs, &mut self.fixes);
+                held.extend(out.iter().cloned());
+                Some(out.len())
+            },
+            Sink::Split(s) => {
+                let group = s.group_of(rs)?;
+                self.n += 1;
+                let out = prepare(rs, params, self.n, &self.refs, &mut self.fixes);
+                s.write(group, &out);
+                Some(out.len())
+            },
+            Sink::Chunks(c) => {
+                self.n += 1;
+                let out = prepare(rs, params, self.n, &self.refs, &mut self.fixes);
+                c.write(&out);
+                Some(out.len())
+            },
+            Sink::Shards(s) => {
+                self.n += 1;
+                let out = prepare(rs, params, self.n, &self.refs, &mut self.fixes);
+                s.write(&out);
+                Some(out.len())
+            },
+        }
+    }
+
+    // write out what --sort-output held back, sorted as samtools sort has it: by reference, unmapped
+    // reads last, then position and strand
+    fn release(&mut self, outfile: &str) {
+        if let Sink::Sorted(fh, held) = &mut self.sink {
+            held.sort_by_key(|r| (r.tid() as u32, r.pos(), r.is_reverse()));
+            held.drain(..).for_each(|r| write(fh, &r, outfile));
+        }
+    }
+}
+
+// whether a SIGINT or SIGTERM came in and the outputs are to be removed rather than finished; a run
+// out of --time-limit always finishes them
+fn abandoned(params: &Params) -> bool {
+    !params.finalize_on_interrupt && !interrupt::timed_out() && interrupt::received().is_some()
+}
+
+// how much of the inputs, by their bytes, the last pass over them read before it stopped; None unless
+// they are all files, or if the pass read them through
+fn fraction_read(infiles: &[String]) -> Option<f64> {
+    let (i, voffset) = interrupt::stopped()?;
+    let sizes: Vec<u64> = infiles.iter().map(|f| fs::metadata(ospath::path(f)).ok().filter(|m| m.is_file()).map(|m| m.len())).collect::<Option<_>>()?;
+    let total: u64 = sizes.iter().sum();
+    if total == 0 || voffset < 0 {
+        return None;
+    }
+    let before: u64 = sizes.iter().take(i).sum();
+    Some(((before + (voffset >> 16) as u64) as f64 / total as f64).min(1.0))
+}
+
+// remove the outputs of a failed run, so that nothing downstream picks them up
+fn discard(outfh: Output, outfile: &str, restfh: Option<bam::Writer>, rest_outfile: &Option<String>) {
+    match outfh.sink {
+        Sink::One(fh) | Sink::Sorted(fh, _) => {
+            drop(fh);
+            let _ = fs::remove_file(ospath::path(outfile));
+        },
+        Sink::Split(s) => s.remove(),
+        Sink::Chunks(c) => c.remove(),
+        Sink::Shards(s) => s.remove(),
+    }
+    if let Some(f) = rest_outfile {
+        drop(restfh);
+        let _ = fs::remove_file(ospath::path(f));
+    }
+}
+
+// keep at most `max_depth` reads starting at any one position of a coordinate-sorted input
+// returns the qnames of the reads kept whose mate is not, for `drop_orphans`
+fn cap_depth(infh: &mut bam::Reader, outfh: &mut bam::Writer, outfile: &str, max_depth: usize, seed: Seed) -> HashSet<Vec<u8>> {
+    let mut cap = DepthCap::new(max_depth, seed);
+    while !interrupt::stopping() {
+        let mut r = Record::new();
+        match infh.read(&mut r) {
+            None => break,
+            Some(Ok(())) => {
+                tui::record(&r);
+                if invalid::skip(&r) {
+                    continue;
+                }
+                for r in cap.push(r) {
+                    write(outfh, &r, outfile);
+                }
+                if cap.seen.is_multiple_of(1_000_000) {
+                    info!("{} reads processed...", cap.seen);
+                }
+            },
+            Some(Err(e)) => {
+                error!("empty record: {}", e);
+                status::fail(Code::Malformed);
+            },
+        }
+    }
+    for r in cap.finish() {
+        write(outfh, &r, outfile);
+    }
+    info!("{} of {} reads kept, {} positions capped at --max-depth {}.", cap.kept, cap.seen, cap.capped, max_depth);
+    cap.unpaired
+}
+
+// rewrite `outfile` with only the @SQ lines of the references its records are on, by tid or mate tid,
+// or name in SA, and their tids remapped, per --trim-header; in a pass to find them, and one to rewrite it
+fn trim_header(outfile: &str, threads: usize) {
+    let mut infh = open_inputs(&[outfile.to_string()], threads, "");
+    let view = infh[0].header().clone();
+    let mut used = vec![false; view.target_count() as usize];
+    let mut r = Record::new();
+    while read_or_fail(&mut infh[0], &mut r) {
+        for tid in [r.tid(), r.mtid()].into_iter().filter(|&x| x >= 0) {
+            used[tid as usize] = true;
+        }
+        for name in tags::sa_references(&r) {
+            if let Some(tid) = view.tid(name.as_bytes()) {
+                used[tid as usize] = true;
+            }
+        }
+    }
+    let kept = used.iter().filter(|&&x| x).count();
+    if kept == used.len() {
+        info!("{} has records on all {} references; its header is kept as it is.", outfile, kept);
+        return;
+    }
+    let (header, map) = header::trim(&view, &used);
+    let tmp = format!("{}.tmp", outfile);
+    let mut infh = open_inputs(&[outfile.to_string()], threads, "");
+    let mut outfh = open_output(&tmp, &header, threads);
+    while read_or_fail(&mut infh[0], &mut r) {
+        header::remap(&mut r, &map);
+        write(&mut outfh, &r, &tmp);
+    }
+    drop(outfh);
+    if let Err(e) = fs::rename(ospath::path(&tmp), ospath::path(outfile)) {
+        error!("failed to move {} to {}: {}", tmp, outfile, e);
+        status::fail(Code::Io);
+    }
+    info!("{}: header trimmed to the {} of {} references its records are on.", outfile, kept, used.len());
+}
+
+// the pairing-consistency pass of --mode cap-depth: rewrite `outfile` without the templates of
+// `orphans`, whose mate was missing from the input, so that no read points to a mate left out
+fn drop_orphans(outfile: &str, orphans: &HashSet<Vec<u8>>, threads: usize) {
+    let tmp = format!("{}.tmp", outfile);
+    let header = bam::Header::from_template(open_inputs(&[outfile.to_string()], 1, "")[0].header());
+    let mut outfh = open_output(&tmp, &header, threads);
+    copy_without(outfile, &mut outfh, &tmp, orphans, threads);
+    drop(outfh);
+    if let Err(e) = fs::rename(ospath::path(&tmp), ospath::path(outfile)) {
+        error!("failed to move {} to {}: {}", tmp, outfile, e);
+        status::fail(Code::Io);
+    }
+}
+
+// copy the records of `infile` to `outfh` but those of the templates of `orphans`; a stream output of
+// --mode cap-depth, which cannot be read back, is written this way from a temporary file
+fn copy_without(infile: &str, outfh: &mut bam::Writer, outfile: &str, orphans: &HashSet<Vec<u8>>, threads: usize) {
+    if !orphans.is_empty() {
+        info!("{} reads kept have no mate in the input; dropping them in a final pass.", orphans.len());
+    }
+    let mut infh = open_inputs(&[infile.to_string()], threads, "");
+    let mut r = Record::new();
+    let mut dropped = 0;
+    while read_or_fail(&mut infh[0], &mut r) {
+        match orphans.contains(r.qname()) {
+            true => dropped += 1,
+            false => write(outfh, &r, outfile),
+        }
+    }
+    if dropped > 0 {
+        info!("{} records of orphaned templates dropped.", dropped);
+    }
+}
+
+fn read_or_fail(infh: &mut bam::Reader, r: &mut Record) -> bool {
+    loop {
+        match infh.read(r) {
+            None => return false,
+            Some(Ok(())) => {
+                tui::record(r);
+                if !invalid::skip(r) {
+                    return true;
+                }
+            },
+            Some(Err(e)) => {
+                error!("empty record: {}", e);
+                status::fail(Code::Malformed);
+            },
+        }
+    }
+}
+
+// thin a coordinate-sorted input out where it runs deeper than `target`: a first pass over `infh`
+// takes the coverage, and a second over `again` keeps each template with probability target / mean
+// depth of the window centred on where it is anchored, drawn from its qname so mates agree
+fn flatten(infh: &mut bam::Reader, again: &mut bam::Reader, outfh: &mut bam::Writer, outfile: &str, target: f64, window: usize, seed: Seed) {
+    let mut cov = Coverage::new(infh.header(), window);
+    let mut r = Record::new();
+    while !interrupt::stopping() && read_or_fail(infh, &mut r) {
+        cov.add(&r);
+    }
+    info!("Coverage computed, second pass starts.");
+    tui::pass();
+    let (mut seen, mut kept): (usize, usize) = (0, 0);
+    while !interrupt::stopping() && read_or_fail(again, &mut r) {
+        seen += 1;
+        let (tid, pos) = uniform::anchor(&r);
+        let keep = tid < 0 || {
+            let depth = cov.depth(tid, pos);
+            depth <= target || seed.draw(r.qname()) < target / depth
+        };
+        if keep {
+            kept += 1;
+            write(outfh, &r, outfile);
+        }
+        if seen.is_multiple_of(1_000_000) {
+            info!("{} reads processed...", seen);
+        }
+    }
+    info!("{} of {} reads kept flattening to --target-depth {}.", kept, seen, target);
+}
+
+// keep every template of a coordinate-sorted input with the probability of the interval of `track` it
+// is anchored in, `off_track` out of all; the draw is shared by the mates, as in `flatten`
+fn thin(infh: &mut bam::Reader, outfh: &mut bam::Writer, outfile: &str, track: &Track, off_track: f64, seed: Seed) {
+    let mut r = Record::new();
+    let (mut seen, mut kept): (usize, usize) = (0, 0);
+    while !interrupt::stopping() && read_or_fail(infh, &mut r) {
+        seen += 1;
+        let (tid, pos) = uniform::anchor(&r);
+        let p = match tid < 0 {
+            true => 1.0,
+            false => track.at(tid, pos).unwrap_or(off_track),
+        };
+        if seed.draw(r.qname()) < p {
+            kept += 1;
+            write(outfh, &r, outfile);
+        }
+        if seen.is_multiple_of(1_000_000) {
+            info!("{} reads processed...", seen);
+        }
+    }
+    info!("{} of {} reads kept by --keep-track.", kept, seen);
+}
+
+// thin a coordinate-sorted input region by region down to the depths of `depths`, in one pass: the
+// depth of a region is complete once a record starts past its end, and its templates are then kept with
+// probability target / depth, drawn from the qname as in `flatten`; records wait in input order until
+// the region they are anchored in is done, so regions are best kept small, e.g. windows of mosdepth --by
+fn harmonize(infh: &mut bam::Reader, outfh: &mut bam::Writer, outfile: &str, depths: &Track, seed: Seed) {
+    // aligned bases of the regions of the current reference so far, the probabilities of those done, and
+    // the first one not done
+    let (mut tid, mut bases, mut probs, mut done): (i32, Vec<u64>, Vec<f64>, usize) = (-1, Vec::new(), Vec::new(), 0);
+    // records waiting for their turn, with the index of their region, if any
+    let mut waiting: VecDeque<(Record, Option<usize>)> = VecDeque::new();
+    let (mut seen, mut kept, mut thinned): (usize, usize, usize) = (0, 0, 0);
+    let mut r = Record::new();
+    loop {
+        let more = !interrupt::stopping() && read_or_fail(infh, &mut r);
+        // the regions started before this record are done, and all of them at the end of a reference
+        let (at_tid, at_pos) = match more {
+            true => (r.tid(), r.pos()),
+            false => (i32::MAX, 0),
+        };
+        let regions = depths.intervals(tid);
+        while done < regions.len() && (at_tid != tid || regions[done].1 <= at_pos) {
+            let (start, end, target) = regions[done];
+            let depth = bases[done] as f64 / (end - start).max(1) as f64;
+            probs.push(if depth <= target { 1.0 } else { target / depth });
+            thinned += usize::from(depth > target);
+            done += 1;
+        }
+        while let Some((x, i)) = waiting.front() {
+            let keep = match *i {
+                Some(i) if i >= done => break,
+                Some(i) => seed.draw(x.qname()) < probs[i],
+                None => true,
+            };
+            if keep {
+                kept += 1;
+                write(outfh, x, outfile);
+            }
+            waiting.pop_front();
+        }
+        if !more {
+            break;
+        }
+        if at_tid != tid {
+            tid = at_tid;
+            (bases, probs, done) = (vec![0; depths.intervals(tid).len()], Vec::new(), 0);
+        }
+        seen += 1;
+        let regions = depths.intervals(tid);
+        if !r.is_unmapped() && !r.is_secondary() && !r.is_supplementary() {
+            let (start, end) = (r.pos(), r.cigar().end_pos());
+            let first = regions.partition_point(|x| x.1 <= start);
+            for (i, &(lo, hi, _)) in regions.iter().enumerate().skip(first).take_while(|(_, x)| x.0 < end) {
+                bases[i] += (end.min(hi) - start.max(lo)).max(0) as u64;
+            }
+        }
+        // unmapped reads, and those anchored in no region, are kept; those anchored in a region done with,
+        // the mates of reads seen earlier, are decided on already
+        let (x_tid, x_pos) = uniform::anchor(&r);
+        match depths.index(x_tid, x_pos) {
+            Some(i) if i < done && waiting.is_empty() => {
+                if seed.draw(r.qname()) < probs[i] {
+                    kept += 1;
+                    write(outfh, &r, outfile);
+                }
+            },
+            None if waiting.is_empty() => {
+                kept += 1;
+                write(outfh, &r, outfile);
+            },
+            i => waiting.push_back((r.clone(), i)),
+        }
+        if seen.is_multiple_of(1_000_000) {
+            info!("{} reads processed...", seen);
+        }
+    }
+    info!("{} of {} reads kept thinning to --target-depths, {} regions deeper than their target.", kept, seen, thinned);
+}
+
+// keep every template of a coordinate-sorted input with probability `rate`, by a draw from its qname
+// shared by its records wherever they are, writing them as they are read
+fn stream_through(infh: &mut bam::Reader, outfh: &mut bam::Writer, outfile: &str, rate: f64, seed: Seed) {
+    let mut hash = sampler::HashThreshold::new(rate, seed);
+    let mut r = Record::new();
+    let (mut seen, mut kept): (usize, usize) = (0, 0);
+    while !interrupt::stopping() && read_or_fail(infh, &mut r) {
+        seen += 1;
+        if hash.observe(std::slice::from_ref(&r)) == Decision::Keep {
+            kept += 1;
+            write(outfh, &r, outfile);
+        }
+        if seen.is_multiple_of(1_000_000) {
+            info!("{} reads processed...", seen);
+        }
+    }
+    info!("{} of {} reads kept by --stream-through.", kept, seen);
+}
+
+fn open_inputs(infiles: &[String], threads: usize, so: &str) -> Vec<bam::Reader> {
+    infiles.iter().map(|infile| {
+        let mut infh = match bam::Reader::from_path(ospath::path(infile)) {
+            Ok(f) => f,
+            Err(e) => {
+                status::context("file", infile);
+                error!("failed to read {}: {}", infile, e);
+                status::fail(Code::Malformed)
+            },
+        };
+        if !so.is_empty() {
+            check_header(&bam::Header::from_template(infh.header()), so);
+        }
+        if threads > 1 {
+            infh.set_threads(threads).expect("failed to set --threads!");
+        }
+        infh
+    }).collect()
+}
+
+// set by whatever `for_each_template` hands templates to once it needs no more, for reading to stop there
+static ENOUGH: AtomicBool = AtomicBool::new(false);
+
+fn stop_reading() {
+    ENOUGH.store(true, Ordering::Relaxed);
+}
+
+// where a template handed over by `for_each_template` is: the (input, virtual offset) of its first
+// record and of the record past its last, where the next template starts; only tracked when asked
+#[derive(Clone, Copy)]
+struct At {
+    first: (usize, i64),
+    next: (usize, i64),
+}
+
+// walk the templates of the inputs, logically concatenated, optionally from a (input, virtual offset)
+// `start`; each template goes to `f` along with where it is (see `At`), and the records of templates `f` hands back are read into again; with
+// --group-by zmw, a "template" is all subreads of a ZMW; only the templates within `window`, a number
+// to skip and at most how many to take after those, are handed over, and reading stops past it
+fn for_each_template<S: RecordSource, F>(infhs: &mut [S], tid_maps: &[TidMap], start: Option<(usize, i64)>, track: bool, group_by: GroupBy, window: (usize, Option<usize>), mut f: F)
+where
+    F: FnMut(RecordSet, At) -> Option<RecordSet>,
+{
+    // whatever is not reading a record or handling a template is grouping, for --timing
+    let _group = profile::enter(Phase::Group);
+    let mut rs: RecordSet = RecordSet::new();
+    // where the cached template starts
+    let mut first = (0, 0);
+    // qname of the cached template, reused across templates to avoid allocating per record
+    let mut rid_prev: Vec<u8> = Vec::new();
+    // records of templates that never made it into (or were evicted from) the reservoir, read into again
+    let mut pool: Vec<Record> = Vec::new();
+    let (skip, head) = window;
+    // templates read so far
+    let mut n = 0;
+    // whether a SIGINT or SIGTERM cut the inputs short, leaving the cached template maybe incomplete
+    let mut stopped = false;
+    ENOUGH.store(false, Ordering::Relaxed);
+    // a record on its own is a template with --per-read, its mate the next one
+    let mut regrouped = (group_by != GroupBy::Read).then(regroup::check);
+    tui::pass();
+
+    // a template only ends when a new qname shows up
+    'inputs: for (i, (infh, tid_map)) in infhs.iter_mut().zip(tid_maps).enumerate() {
+        match start {
+            Some((file, _)) if i < file => continue,
+            Some((file, offset)) if i == file => infh.seek_to(offset).expect("failed to seek to the checkpointed offset!"),
+            _ => {},
+        }
+        let remap = !header::is_identity(tid_map);
+        if let Some(c) = &mut regrouped {
+            c.reset();
+        }
+        // records read of this input, for the context of an error
+        let mut records: u64 = 0;
+        loop {
+            if interrupt::stopping() || ENOUGH.load(Ordering::Relaxed) {
+                stopped = true;
+                interrupt::stopped_at(i, infh.position());
+                break 'inputs;
+            }
+            // virtual offset of the record about to be read
+            let pos = if track { infh.position() } else { 0 };
+            let mut r = pool.pop().unwrap_or_default();
+            match profile::time(Phase::Read, || infh.read_record(&mut r)) {
+                None => break,
+                Some(Ok(())) => { 
+                    profile::count_record();
+                    records += 1;
+                    if invalid::skip(&r) {
+                        pool.push(r);
+                        continue;
+                    }
+                    if remap {
+                        header::remap(&mut r, tid_map);
+                    }
+                    if !rs.is_empty() && (group_by == GroupBy::Read || rid_prev != group_key(r.qname(), group_by)) {
+                        // current record is a new template; process the cached
+                        let old = take(&mut rs);
+                        tui::template(old.len());
+                        if tui::on() && n.is_multiple_of(1024) {
+                            tui::offset(i, infh.position());
+                        }
+                        if n < skip {
+                            pool.extend(old);
+                        } else if let Some(old) = profile::time(Phase::Select, || f(old, At { first, next: (i, pos) })) {
+                            pool.extend(old);
+                        }
+                        n += 1;
+                        if head.is_some_and(|h| n >= skip + h) {
+                            break 'inputs;
+                        }
+                    }
+                    if rs.is_empty() {
+                        first = (i, pos);
+                        rid_prev.clear();
+                        rid_prev.extend_from_slice(group_key(r.qname(), group_by));
+                        if let Some(c) = &mut regrouped {
+                            c.open(&rid_prev);
+                        }
+                    }
+                    // cache it
+                    rs.push(r);
+                },
+                Some(Err(e)) => { 
+                    status::context("input", &(i + 1).to_string());
+                    status::context("record", &(records + 1).to_string());
+                    error!("empty record: {}", e); 
+                    status::fail(Code::Malformed);
+                }
+            }
+        }
+    }
+    // last record; process the cached
+    if !rs.is_empty() && n >= skip && !stopped {
+        tui::template(rs.len());
+        profile::time(Phase::Select, || f(take(&mut rs), At { first, next: (infhs.len(), 0) }));
+    }
+    if let Some(c) = regrouped {
+        c.finish();
+    }
+}
+
+fn open_output(outfile: &str, header: &bam::Header, threads: usize) -> bam::Writer {
+    let mut outfh = match bam::Writer::from_path(ospath::path(outfile), header, bam::Format::Bam) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("failed to write {}: {}", outfile, e);
+            status::fail(Code::Io);
+        },
+    };
+    if threads > 1 {
+        outfh.set_threads(threads).expect("failed to set --threads!");
+    }
+    outfh
+}
+
+// a plain text file, or a BGZF (thus gzip-compatible) one if it ends in .gz
+fn open_text(path: &str) -> Box<dyn Write> {
+    let fh: Result<Box<dyn Write>, String> = match path.ends_with(".gz") {
+        true => bgzf::Writer::from_path(ospath::path(path)).map(|f| Box::new(f) as Box<dyn Write>).map_err(|e| e.to_string()),
+        false => File::create(ospath::path(path)).map(|f| Box::new(BufWriter::new(f)) as Box<dyn Write>).map_err(|e| e.to_string()),
+    };
+    fh.unwrap_or_else(|e| {
+        error!("failed to write {}: {}", path, e);
+        status::fail(Code::Io)
+    })
+}
+
+// what is reported on besides the output, over every input written on its own: the qnames of
+// --names-out, the histogram of --template-stats, the counts of --tallies, the distributions of
+// --distribution-report, the duplication rates of --duplication, the molecule counts of --complexity,
+// the UMI counts of --saturation, the qnames of --digest, the rows of --metadata-out and --arrow-out and
+// the spans of --bed-out
+struct Reports {
+    names: Option<Box<dyn Write>>,
+    stats: Option<TemplateStats>,
+    tallies: Option<Tallies>,
+    dists: Option<Distributions>,
+    dups: Option<Duplication>,
+    complexity: Option<Complexity>,
+    saturation: Option<Saturation>,
+    selection: Option<Selection>,
+    metadata: Option<Metadata>,
+    columns: Option<Columns>,
+    spans: Option<Spans>,
+    // every BAM written, for --digest and --validate-output
+    written: Vec<String>,
+    // the outputs of every sample, and the templates written to them, for --validate-output
+    samples: Vec<(Vec<String>, usize, usize)>,
+    // reference names of the output being written, by tid
+    refs: Vec<String>,
+}
+
+impl Reports {
+    fn input(&mut self, rs: &[Record]) {
+        if let Some(stats) = &mut self.stats {
+            stats.add_input(rs.len());
+        }
+        if let Some(dists) = &mut self.dists {
+            dists.add_input(rs);
+        }
+        if let Some(dups) = &mut self.dups {
+            dups.add_input(rs);
+        }
+        if let Some(complexity) = &mut self.complexity {
+            complexity.add(rs);
+        }
+        if let Some(saturation) = &mut self.saturation {
+            saturation.add(rs);
+        }
+    }
+
+    // a template `rs` as read, of which `written` records went out as prepare left them
+    fn output(&mut self, rs: &[Record], written: usize, params: &Params) {
+        write_name(&mut self.names, rs, &params.names_out);
+        if let (Some(spans), Some(path)) = (&mut self.spans, &params.bed_out) {
+            let kept: Vec<Record> = rs.iter().filter(|r| keeps(r, rs, params)).cloned().collect();
+            if let Err(e) = spans.add(&kept, &self.refs) {
+                error!("failed to write {}: {}", path, e);
+                status::fail(Code::Io);
+            }
+        }
+        if self.metadata.is_some() || self.columns.is_some() {
+            let mut kept: Vec<Record> = rs.iter().filter(|r| keeps(r, rs, params)).cloned().collect();
+            if let Some(max) = params.max_secondary {
+                tags::cap_secondary(&mut kept, max, Seed::new(params.rng, params.seed));
+            }
+            if let Some(max) = params.max_records_per_template {
+                tags::cap_records(&mut kept, max, Seed::new(params.rng, params.seed));
+            }
+            if let (Some(metadata), Some(path)) = (&mut self.metadata, &params.metadata_out) {
+                for r in &kept {
+                    if let Err(e) = metadata.add(r, &self.refs) {
+                        error!("failed to write {}: {}", path, e);
+                        status::fail(Code::Io);
+                    }
+                }
+            }
+            if let (Some(columns), Some((path, _))) = (&mut self.columns, &params.arrow_out) {
+                for r in &kept {
+                    if let Err(e) = columns.add(r, &self.refs) {
+                        error!("failed to write {}: {}", path, e);
+                        status::fail(Code::Io);
+                    }
+                }
+            }
+        }
+        if let Some(stats) = &mut self.stats {
+            stats.add_output(written);
+        }
+        if let Some(dists) = &mut self.dists {
+            dists.add_output(rs);
+        }
+        if let Some(dups) = &mut self.dups {
+            dups.add_output(rs);
+        }
+        if let Some(tallies) = &mut self.tallies {
+            tallies.add(split::group(SplitBy::Chromosome, &self.refs, rs), split::group(SplitBy::ReadGroup, &self.refs, rs));
+        }
+        if let Some(selection) = &mut self.selection {
+            for (i, r) in rs.iter().enumerate() {
+                if i == 0 || rs[i - 1].qname() != r.qname() {
+                    selection.add(r.qname());
+                }
+            }
+        }
+    }
+}
+
+// list the qname of a sampled template, or every qname of a ZMW, if --names-out is given
+fn write_name(names: &mut Option<Box<dyn Write>>, rs: &[Record], path: &Option<String>) {
+    if let (Some(fh), Some(path)) = (names, path) {
+        for (i, r) in rs.iter().enumerate() {
+            if i > 0 && rs[i - 1].qname() == r.qname() {
+                continue;
+            }
+            if let Err(e) = fh.write_all(r.qname()).and_then(|_| fh.write_all(b"\n")) {
+                error!("failed to write {}: {}", path, e);
+                status::fail(Code::Io);
+            }
+        }
+    }
+}
+
+// the output header of several inputs concatenated, along with how to remap their tids
+fn merged_header(infhs: &[bam::Reader]) -> (bam::Header, Vec<TidMap>) {
+    match infhs.len() {
+        1 => (bam::Header::from_template(infhs[0].header()), vec![vec![]]),
+        _ => {
+            let views: Vec<bam::HeaderView> = infhs.iter().map(|f| f.header().clone()).collect();
+            header::merge(&views)
+        },
+    }
+}
+
+// where templates may align per --contigs, --exclude-regions, --regions, --sites and
+// --exclude-overlapping, against `header`
+fn new_loci(params: &Params, header: &bam::HeaderView) -> Loci {
+    let footprint = params.exclude_overlapping.as_ref().map(|f| Footprint::open(f, header).unwrap_or_else(|e| {
+        status::complain(&e);
+        status::fail(Code::Malformed)
+    }));
+    Loci::new(header, params.contigs.as_deref(), params.exclude_regions.as_ref(), params.regions.as_ref(), params.require_both_in_region, params.sites.as_ref(), params.max_per_site).avoiding(footprint)
+}
+
+// whether a cached template passes; tags are checked per --require-tag and --exclude-tag first, then the
+// qname per --name-regex and --exclude-tiles, the
+// start time per --time-range, the references per --contigs and the positions per --exclude-regions,
+// --exclude-overlapping and --regions, then the flags per --proper-pairs-only and the insert size per --min-tlen and
+// --max-tlen, unmapped templates are handled per --unmapped, singletons per --singletons, pairs of a mate unmapped per --half-mapped and broken pairs per --on-broken-pairs, and the positions
+// per --sites come last
+fn admit(rs: &RecordSet, params: &Params, loci: &mut Loci, reps: Option<&HashSet<Vec<u8>>>, counts: &mut Counts) -> bool {
+    // a record on its own is neither a singleton nor a broken pair with --per-read
+    let per_read = params.group_by == GroupBy::Read;
+    // the filters turning the template down, as indices into `FILTERS`; the first to turn it down is the
+    // last to run, but for --filter-stats, which runs them all to count what each would turn down on its
+    // own. What is counted along the way, e.g. the unmapped templates, is of those a filter is reached by
+    let mut rejecting: Vec<usize> = Vec::new();
+    for i in 0..FILTERS.len() {
+        let reached = rejecting.is_empty();
+        if !reached && !params.filter_stats {
+            break;
+        }
+        let rejects = match i {
+            0 => !tags::passes(rs, &params.require_tags, &params.exclude_tags),
+            1 => params.name_regex.as_ref().is_some_and(|x| x.is_match(rs[0].qname()) == params.name_regex_invert),
+            2 => params.exclude_tiles.as_ref().is_some_and(|t| t.contains(rs)),
+            3 => params.time_range.as_ref().is_some_and(|t| !t.admits(rs)),
+            4 => !loci.admits(rs),
+            5 => loci.excludes(rs),
+            6 => loci.overlaps_footprint(rs),
+            7 => !loci.includes(rs),
+            8 => params.proper_pairs_only && !is_proper_pair(rs),
+            9 => (params.min_tlen.is_some() || params.max_tlen.is_some()) && !in_tlen_range(rs, params.min_tlen, params.max_tlen),
+            10 => params.only_duplicates && !is_duplicate(rs),
+            11 => (params.max_nm.is_some() || params.max_nm_frac.is_some()) && tags::too_divergent(rs, params.max_nm, params.max_nm_frac),
+            12 => params.min_aligned_frac.is_some_and(|f| tags::mostly_clipped(rs, f)),
+            13 => params.spliced != Spliced::Any && tags::spliced(rs) != (params.spliced == Spliced::Only),
+            14 => {
+                let unmapped = is_unmapped(rs);
+                if reached && unmapped {
+                    counts.unmapped += 1;
+                }
+                !params.unmapped.admits(unmapped)
+            },
+            15 => {
+                let singleton = !per_read && is_singleton(rs);
+                if reached && singleton {
+                    counts.singletons += 1;
+                }
+                !params.singletons.admits(singleton)
+            },
+            16 => {
+                let half_mapped = !per_read && strata::half_mapped(rs);
+                if reached && half_mapped {
+                    counts.half_mapped += 1;
+                }
+                !params.half_mapped.admits(half_mapped)
+            },
+            17 => {
+                let broken = !per_read && is_broken_pair(rs);
+                if reached && broken {
+                    counts.broken += 1;
+                    debug!("broken pair: {}", String::from_utf8_lossy(rs[0].qname()));
+                    if params.on_broken_pairs == BrokenPairs::Fail {
+                        error!("{} is missing a mate! Rerun with '--on-broken-pairs keep|drop' to proceed.", String::from_utf8_lossy(rs[0].qname()));
+                        status::fail(Code::Malformed);
+                    }
+                }
+                broken && params.on_broken_pairs != BrokenPairs::Keep
+            },
+            18 => reps.is_some_and(|reps| !reps.contains(rs[0].qname()) && !stats::signature(rs).is_empty()),
+            // last, so that only templates passing everything else take up --max-per-site; one turned down
+            // already is only looked at for being near a site, since how full the sites are depends on what
+            // the other filters let through
+            _ => match reached {
+                true => !loci.at_sites(rs),
+                false => !loci.near_sites(rs),
+            },
+        };
+        if rejects {
+            rejecting.push(i);
+        }
+    }
+    if params.filter_stats {
+        counts.filters.add(&rejecting);
+    }
+    let counted = match rejecting.first().copied() {
+        None => {
+            tui::admitted();
+            return true;
+        },
+        Some(0) => &mut counts.tagged,
+        Some(1) => &mut counts.misnamed,
+        Some(2) => &mut counts.off_tiles,
+        Some(3) => &mut counts.timed,
+        Some(4) => &mut counts.off_contigs,
+        Some(5) => &mut counts.excluded,
+        Some(6) => &mut counts.overlapping,
+        Some(7) => &mut counts.off_regions,
+        Some(8) => &mut counts.improper,
+        Some(9) => &mut counts.off_tlen,
+        Some(10) => &mut counts.unflagged,
+        Some(11) => &mut counts.divergent,
+        Some(12) => &mut counts.clipped,
+        Some(13) => &mut counts.splicing,
+        Some(18) => &mut counts.duplicates,
+        Some(19) => &mut counts.off_sites,
+        // --unmapped, --singletons, --half-mapped and --on-broken-pairs, counted above whether they
+        // turned the template down or not
+        Some(_) => return false,
+    };
+    *counted += 1;
+    false
+}
+
+// templates of an input that pass the filters of `admit`, and the bases of their primary reads
+fn count_templates(params: &Params, infile: &str) -> (usize, u64) {
+    let mut infhs = open_inputs(&[infile.to_string()], params.threads, params.group_by.sort_order());
+    let mut loci = new_loci(params, infhs[0].header());
+    let (mut n, mut bases) = (0, 0);
+    for_each_template(&mut infhs, &[vec![]], None, false, params.group_by, (params.skip, params.head), |rs, _| {
+        if admit(&rs, params, &mut loci, None, &mut Counts::default()) {
+            n += 1;
+            bases += rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary()).map(|r| r.seq_len() as u64).sum::<u64>();
+        }
+        Some(rs)
+    });
+    (n, bases)
+}
+
+// with --dedup-by position, the qnames of the templates standing for all those passing the filters
+// whose mapped primary reads start at the same positions on the same strands: the one with the highest
+// MAPQ (summed over those reads), the first of them on a tie; templates with no mapped read stand for
+// themselves and are left out
+fn representatives(params: &Params, infiles: &[String]) -> HashSet<Vec<u8>> {
+    let mut infhs = open_inputs(infiles, params.threads, params.group_by.sort_order());
+    let (header, tid_maps) = merged_header(&infhs);
+    let mut loci = new_loci(params, &bam::HeaderView::from_header(&header));
+    let mut best: HashMap<Signature, (u32, Vec<u8>)> = HashMap::new();
+    for_each_template(&mut infhs, &tid_maps, None, false, params.group_by, (params.skip, params.head), |rs, _| {
+        let sig = stats::signature(&rs);
+        if !sig.is_empty() && admit(&rs, params, &mut loci, None, &mut Counts::default()) {
+            let mapq: u32 = rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary() && !r.is_unmapped()).map(|r| u32::from(r.mapq())).sum();
+            let rep = best.entry(sig).or_insert_with(|| (mapq, rs[0].qname().to_vec()));
+            if mapq > rep.0 {
+                *rep = (mapq, rs[0].qname().to_vec());
+            }
+        }
+        Some(rs)
+    });
+    best.into_values().map(|x| x.1).collect()
+}
+
+// read the templates at the (input, virtual offset, records) of `picks` back from `infiles`, in that order,
+// and write them out
+fn copy_templates(params: &Params, infiles: &[String], tid_maps: &[TidMap], picks: &[(usize, i64, usize)], outfh: &mut Output, outfile: &str, reports: &mut Reports) {
+    let mut infhs = open_inputs(infiles, params.threads, params.group_by.sort_order());
+    for &(file, offset, n) in picks {
+        let infile = &infiles[file];
+        if let Err(e) = infhs[file].seek(offset) {
+            error!("failed to seek in {}: {}", infile, e);
+            status::fail(Code::Io);
+        }
+        let remap = !header::is_identity(&tid_maps[file]);
+        let mut rs: RecordSet = Vec::with_capacity(n);
+        while rs.len() < n {
+            let mut r = Record::new();
+            match infhs[file].read(&mut r) {
+                Some(Ok(())) => {},
+                Some(Err(e)) => {
+                    error!("empty record: {}", e);
+                    status::fail(Code::Malformed);
+                },
+                None => {
+                    error!("{} ended early; did it change since it was read?", infile);
+                    status::fail(Code::Malformed);
+                },
+            }
+            // skipped, and counted, when the template was read the first time
+            if invalid::on() && invalid::problem(&r).is_some() {
+                continue;
+            }
+            if remap {
+                header::remap(&mut r, &tid_maps[file]);
+            }
+            rs.push(r);
+        }
+        if let Some(n) = outfh.write(&rs, outfile, params) {
+            reports.output(&rs, n, params);
+        }
+    }
+}
+
+// how `num` templates of `infiles` are sampled: the reservoir keeps every sampled template in memory, or
+// only where it is with --offsets-only; past --max-mem, only their indices are kept and the inputs are
+// read twice, as they are when the rest is wanted too or strata are to be filled
+fn strategy(params: &Params, infiles: &[String], num: usize, resumed: bool) -> Strategy {
+    match params.max_mem {
+        _ if params.cache_index.is_some() => Strategy::Cached,
+        _ if params.pipeline.is_some() => Strategy::Pipeline,
+        _ if params.every.is_some() => Strategy::Systematic,
+        _ if params.bernoulli => Strategy::Bernoulli,
+        _ if matches!(params.group_by, GroupBy::Molecule(_)) => Strategy::Molecules,
+        _ if params.rest_outfile.is_some() || params.strata.is_some() => Strategy::TwoPass,
+        _ if params.offsets_only => Strategy::Offsets,
+        Some(budget) if !resumed => {
+            let need = budget::template_size(&infiles[0], 100_000) * num;
+            debug!("reservoir estimated at {} bytes against --max-mem {} bytes", need, budget);
+            if need > budget {
+                info!("Reservoir of --num {} estimated at {} bytes exceeds --max-mem, switching to two passes.", num, need);
+                Strategy::TwoPass
+            } else {
+                Strategy::Reservoir
+            }
+        },
+        _ => Strategy::Reservoir,
+    }
+}
+
+// the state of the serial reservoir after `seen` templates of `infiles`, the next one at `next`
+fn state(infiles: Vec<String>, num: usize, seed: Seed, seen: usize, next: (usize, i64), counts: &Counts) -> State {
+    State {
+        infiles,
+        num,
+        seed: seed.value,
+        rng: Some(seed.kind),
+        seen,
+        file: next.0,
+        offset: next.1,
+        n_broken: counts.broken,
+        n_singletons: counts.singletons,
+        n_unmapped: counts.unmapped,
+        n_tagged: counts.tagged,
+        n_timed: counts.timed,
+        n_off_contigs: counts.off_contigs,
+        n_excluded: counts.excluded,
+        n_off_regions: counts.off_regions,
+        n_off_sites: counts.off_sites,
+        n_improper: counts.improper,
+        n_off_tlen: counts.off_tlen,
+        n_duplicates: counts.duplicates,
+        n_unflagged: counts.unflagged,
+        n_divergent: counts.divergent,
+        n_clipped: counts.clipped,
+        n_splicing: counts.splicing,
+        n_misnamed: counts.misnamed,
+        n_off_tiles: counts.off_tiles,
+        n_half_mapped: counts.half_mapped,
+        n_overlapping: counts.overlapping,
+        filter_templates: counts.filters.templates,
+        filter_first: counts.filters.first.to_vec(),
+        filter_any: counts.filters.any.to_vec(),
+        refs: Vec::new(),
+    }
+}
+
+// a checkpoint or state is carried on with the generator it drew from, or the skips replayed and the
+// draws to come would be another generator's
+fn same_rng(path: &str, state: &State, rng: rng::Kind) {
+    let saved = state.rng.unwrap_or(rng::Kind::Pcg64);
+    if saved != rng {
+        error!("{} was saved with --rng {}, not {}!", path, saved.name(), rng.name());
+        status::fail(Code::Args);
+    }
+}
+
+// downsample `infiles`, concatenated, to `num` templates in `outfile`, picking up from `resumed` if given
+fn subsample(params: &Params, infiles: &[String], outfile: &str, num: usize, seed: Seed, resumed: Option<(State, Vec<RecordSet>)>, reports: &mut Reports) {
+    let threads = params.threads;
+    let checkpoint = &params.checkpoint;
+    let checkpoint_every = params.checkpoint_every;
+    // --time-limit stops the passes drawing the sample, up to those writing it out
+    interrupt::arm();
+    let mut infhs = open_inputs(infiles, threads, params.group_by.sort_order());
+    let (mut header, tid_maps) = merged_header(&infhs);
+    if !params.comments.is_empty() || params.read_group.is_some() || params.sample_name.is_some() {
+        header = header::relabel(&header, &params.comments, params.read_group.as_ref().map(|(id, line)| (id.as_str(), line.as_str())), params.sample_name.as_deref());
+    }
+    reports.refs = header::names(&header);
+    let mut loci = new_loci(params, &bam::HeaderView::from_header(&header));
+
+    let strategy = strategy(params, infiles, num, resumed.is_some());
+    if strategy == Strategy::TwoPass && checkpoint.is_some() {
+        warn!("--checkpoint is ignored when reading the inputs twice.");
+    }
+
+    // outputs keep the sort order of a single input only when written in input order, which the
+    // reservoir (bar --deterministic) does not
+    let unsorted = header::with_sort_order(&header, "unsorted");
+    let in_order = match infiles.len() {
+        1 => &header,
+        _ => &unsorted,
+    };
+    let coordinate = header::with_sort_order(&header, "coordinate");
+    let out_header = match strategy {
+        _ if params.sort_output => &coordinate,
+        Strategy::Reservoir if !params.deterministic && !params.keep_order && params.pick == Pick::Random => &unsorted,
+        Strategy::Pipeline => &unsorted,
+        _ => in_order,
+    };
+    let mut outfh = match (params.split_by, params.records_per_file) {
+        _ if params.shards.is_some() => Output::new(Sink::Shards(Shards::new(outfile, out_header, threads, params.shards.unwrap_or_default(), params.shard_by)), out_header),
+        (Some(by), _) => Output::new(Sink::Split(Box::new(Splitter::new(by, outfile, out_header, threads, params.min_records, params.allowlist.as_ref(), params.max_open_files))), out_header),
+        (None, Some(per_file)) => Output::new(Sink::Chunks(Chunks::new(outfile, out_header, threads, per_file)), out_header),
+        (None, None) if params.sort_output => Output::new(Sink::Sorted(open_output(outfile, out_header, threads), Vec::new()), out_header),
+        (None, None) => Output::new(Sink::One(open_output(outfile, out_header, threads)), out_header),
+    };
+    let mut restfh = params.rest_outfile.as_ref().map(|f| open_output(f, in_order, threads));
+
+    let mut counts = Counts::default();
+
+    // an index of --cache-index over these inputs and filters, if one was written already
+    let mut index = match (strategy, &params.cache_index) {
+        (Strategy::Cached, Some((path, options))) => qidx::Index::open(path, &qidx::fingerprint(infiles, options)).unwrap_or_else(|e| {
+            error!("failed to read {}: {}", path, e);
+            status::fail(Code::Io)
+        }),
+        _ => None,
+    };
+
+    // the molecules of --dedup-by are found in a pass of their own, before the sampling one, unless the
+    // templates passing the filters are indexed already
+    let reps = (params.dedup && index.is_none()).then(|| {
+        info!("Finding the templates to stand for their duplicates.");
+        let reps = representatives(params, infiles);
+        info!("{} molecules with mapped reads found.", reps.len());
+        reps
+    });
+
+    info!("Iteration starts.");
+
+    // molecules found with --group-by tag, which --num counts instead of templates
+    let mut molecules_seen: Option<usize> = None;
+    // templates passing the filters, and how many of them should be written
+    let (seen, expected) = match strategy {
+        Strategy::Reservoir => {
+            // with several threads, contiguous chunks of templates get their own reservoirs, merged at the end;
+            // --deterministic ranks templates instead, on the reading thread
+            let mut start = None;
+            // the inputs a loaded state was built from, which the one saved was built from too
+            let mut previous: Vec<String> = Vec::new();
+            let mut res = match (threads, resumed) {
+                (_, Some((state, v))) => {
+                    counts.broken = state.n_broken;
+                    counts.singletons = state.n_singletons;
+                    counts.unmapped = state.n_unmapped;
+                    counts.tagged = state.n_tagged;
+                    counts.timed = state.n_timed;
+                    counts.off_contigs = state.n_off_contigs;
+                    counts.excluded = state.n_excluded;
+                    counts.off_regions = state.n_off_regions;
+                    counts.off_sites = state.n_off_sites;
+                    counts.improper = state.n_improper;
+                    counts.off_tlen = state.n_off_tlen;
+                    counts.duplicates = state.n_duplicates;
+                    counts.unflagged = state.n_unflagged;
+                    counts.divergent = state.n_divergent;
+                    counts.clipped = state.n_clipped;
+                    counts.splicing = state.n_splicing;
+                    counts.misnamed = state.n_misnamed;
+                    counts.off_tiles = state.n_off_tiles;
+                    counts.half_mapped = state.n_half_mapped;
+                    counts.overlapping = state.n_overlapping;
+                    // a state saved without --filter-stats has none, and one of other filters is not taken up
+                    if state.filter_first.len() == FILTERS.len() && state.filter_any.len() == FILTERS.len() {
+                        counts.filters.first.copy_from_slice(&state.filter_first);
+                        counts.filters.any.copy_from_slice(&state.filter_any);
+                        counts.filters.templates = state.filter_templates;
+                    }
+                    // a loaded state was left with its inputs read to the end, and the inputs are new ones
+                    match &params.load_state {
+                        Some(path) => {
+                            // the records of the state keep their tids, which have to mean the same references
+                            if state.refs != reports.refs {
+                                error!("the references of {} are not those of the inputs; a state carries on over inputs aligned to the same reference only!", path);
+                                status::fail(Code::Args);
+                            }
+                            previous = state.infiles;
+                        },
+                        None => start = Some((state.file, state.offset)),
+                    }
+                    Sampling::Serial(Reservoir::resume(num, seed, state.seen, v))
+                },
+                (_, None) if params.pick != Pick::Random => Sampling::Ends(Ends::new(num, params.pick == Pick::Last)),
+                (_, None) if params.deterministic => Sampling::Deterministic(BottomK::new(num, seed)),
+                (_, None) if params.keep_order => Sampling::Ordered(Reservoir::new(num, seed)),
+                // on the reading thread, the other threads decoding the inputs
+                (_, None) if params.compress_reservoir => Sampling::Packed(Reservoir::new(num, seed)),
+                (1, None) => Sampling::Serial(Reservoir::new(num, seed)),
+                _ => Sampling::Chunked(Chunked::new(num, seed, threads, params.chunk_size)),
+            };
+            let mut next_checkpoint = res.seen() + checkpoint_every;
+            // the templates of --always-include, set aside from the draw
+            let mut included: Vec<RecordSet> = Vec::new();
+            tui::reservoir(num);
+            for_each_template(&mut infhs, &tid_maps, start, checkpoint.is_some(), params.group_by, (params.skip, params.head), |rs, at| {
+                reports.input(&rs);
+                if params.always_include.as_ref().is_some_and(|names| names.contains(rs[0].qname())) {
+                    included.push(rs);
+                    return None;
+                }
+                if !admit(&rs, params, &mut loci, reps.as_ref(), &mut counts) {
+                    return Some(rs);
+                }
+                let out = res.sample(rs);
+                if res.seen().is_multiple_of(1_000_000) {
+                    info!("{} reads (read pairs) processed...", res.seen());
+                }
+                // --mode first needs nothing past its templates
+                if let Sampling::Ends(e) = &res {
+                    if e.full() {
+                        stop_reading();
+                    }
+                }
+                if let (Some(path), Sampling::Serial(serial)) = (checkpoint, &res) {
+                    if serial.k >= next_checkpoint {
+                        let state = state(infiles.to_vec(), num, seed, serial.k, at.next, &counts);
+                        checkpoint::save(path, &header, &state, &serial.v);
+                        info!("Checkpoint saved to {} after {} reads (read pairs).", path, serial.k);
+                        next_checkpoint = serial.k + checkpoint_every;
+                    }
+                }
+                out
+            });
+            if let (Some(path), Sampling::Serial(serial)) = (&params.save_state, &res) {
+                match interrupt::received() {
+                    // a state is of whole inputs, and the next run's are taken to follow on from the last of them
+                    Some(_) => warn!("Interrupted, so no state is saved to {}.", path),
+                    None => {
+                        let state = state([previous, infiles.to_vec()].concat(), num, seed, serial.k, (infiles.len(), 0), &counts);
+                        checkpoint::save(path, &header, &state, &serial.v);
+                        info!("State saved to {} after {} templates of {} inputs.", path, serial.k, state.infiles.len());
+                    },
+                }
+            }
+            let seen = res.seen() + included.len();
+            // a packed sample is unpacked a template at a time as it goes out, never all at once
+            let mut sample = match res {
+                Sampling::Packed(packed) => {
+                    for p in packed.v {
+                        let rs = p.unpack();
+                        if let Some(n) = outfh.write(&rs, outfile, params) {
+                            reports.output(&rs, n, params);
+                        }
+                    }
+                    Vec::new()
+                },
+                res => res.finish(),
+            };
+            // the reservoir holds --num, of which a uniform subset fills what the templates of --always-include
+            // leave of it; they go out first
+            if let Some(names) = &params.always_include {
+                let found: HashSet<&[u8]> = included.iter().map(|rs| rs[0].qname()).collect();
+                if found.len() < names.len() {
+                    warn!("{} of the {} names of --always-include not found in the input.", names.len() - found.len(), names.len());
+                }
+                if included.len() > num {
+                    warn!("--always-include: {} templates found, more than --num {}; all written, none drawn.", included.len(), num);
+                }
+                let room = num.saturating_sub(included.len());
+                if sample.len() > room {
+                    sample.shuffle(&mut seed.stage("always-include").generator());
+                    sample.truncate(room);
+                }
+                info!("--always-include: {} templates written, {} drawn at random.", included.len(), sample.len());
+                sample = included.into_iter().chain(sample).collect();
+            }
+            if let Some(max) = params.max_records {
+                let n = sample.len();
+                sample = within_records(sample, max, params, seed);
+                if sample.len() < n {
+                    info!("--max-records {} reached: {} of the {} templates sampled written.", max, sample.len(), n);
+                }
+            }
+            // with several --num, the sample goes out in a random order, and the smaller samples are
+            // its first templates, so that each is a uniform subset of the next larger
+            if !params.nested.is_empty() && params.pick == Pick::Random {
+                sample.shuffle(&mut seed.stage("nested").generator());
+            }
+            for rs in &sample {
+                if let Some(n) = outfh.write(rs, outfile, params) {
+                    reports.output(rs, n, params);
+                }
+            }
+            for (n, path) in &params.nested {
+                let mut fh = Output::new(Sink::One(open_output(path, out_header, threads)), out_header);
+                for rs in sample.iter().take(*n) {
+                    fh.write(rs, path, params);
+                }
+                info!("{} of the templates written to {} as well.", fh.n, path);
+            }
+            match params.max_records {
+                Some(_) => (seen, sample.len()),
+                None if params.always_include.is_some() => (seen, sample.len()),
+                None => (seen, num.min(seen)),
+            }
+        },
+        Strategy::TwoPass => {
+            // first pass counts the templates that pass in every stratum (just the one, unless
+            // --stratify-by or --balance-strands), second writes out those at the picked indices and, with
+            // --rest-outfile, all others there; --deterministic picks by rank rather than by index, so the
+            // first pass also keeps the lowest keys of every stratum
+            // strata of a tag or lane are numbered as their values turn up in the first pass, and found under
+            // the same numbers in the second
+            let mut strata = params.strata.clone();
+            if let Some(s) = &mut strata {
+                s.resolve(&header);
+            }
+            let mut sizes: Vec<usize> = vec![0; params.strata.as_ref().map_or(1, Strata::len)];
+            // most templates a stratum gives
+            let most = params.per_stratum.or_else(|| params.quotas.as_ref().and_then(|q| q.iter().map(|x| x.1).max())).unwrap_or(num);
+            let mut ranks: Vec<BottomK<()>> = (0..sizes.len()).map(|_| BottomK::new(most, seed)).collect();
+            let mut seen: usize = 0;
+            for_each_template(&mut infhs, &tid_maps, None, false, params.group_by, (params.skip, params.head), |rs, at| {
+                reports.input(&rs);
+                if admit(&rs, params, &mut loci, reps.as_ref(), &mut counts) {
+                    match strata.as_mut().map_or(Some(0), |s| s.of(&rs, at.first.0)) {
+                        Some(i) => {
+                            if i == sizes.len() {
+                                sizes.push(0);
+                                ranks.push(BottomK::new(most, seed));
+                            }
+                            sizes[i] += 1;
+                            if params.deterministic {
+                                ranks[i].offer(rs[0].qname(), ());
+                            }
+                        },
+                        None => counts.unstratified += 1,
+                    }
+                    seen += 1;
+                    if seen.is_multiple_of(1_000_000) {
+                        info!("{} reads (read pairs) counted...", seen);
+                    }
+                }
+                Some(rs)
+            });
+            let seen = sizes.iter().sum();
+            let n_strata = sizes.len();
+            let equal = vec![1.0; n_strata];
+            let fractions = match params.equal_strata {
+                true => Some(&equal),
+                false => params.fractions.as_ref(),
+            };
+            let nums = match (&strata, fractions, params.per_stratum) {
+                (Some(strata), _, None) if params.quotas.is_some() => {
+                    let quotas = params.quotas.as_ref().unwrap();
+                    let keys: Vec<String> = (0..n_strata).map(|i| strata::key(strata, i)).collect();
+                    for (key, _) in quotas.iter().filter(|q| !keys.contains(&q.0)) {
+                        warn!("{} of --quota is not a stratum of the input, which has none of its templates.", key);
+                    }
+                    let nums: Vec<usize> = keys.iter().map(|key| quotas.iter().find(|q| &q.0 == key).map_or(0, |q| q.1)).collect();
+                    for (i, (&n, &size)) in nums.iter().zip(&sizes).enumerate() {
+                        if n > size {
+                            warn!("{} has {} templates, fewer than the {} asked for.", strata.name(i), size, n);
+                        }
+                    }
+                    nums
+                },
+                (_, _, Some(n)) => {
+                    let short = sizes.iter().filter(|&&size| size < n).count();
+                    if short > 0 {
+                        let (what, option) = match strata {
+                            Some(Strata::Sample(_)) => ("samples", "--per-sample-num"),
+                            Some(Strata::Tile(_)) => ("tiles", "--per-tile-num"),
+                            _ => ("amplicons", "--per-amplicon"),
+                        };
+                        info!("{} of {} {} have fewer than {} {} templates; all of theirs are taken.", short, n_strata, what, option, n);
+                    }
+                    vec![n; n_strata]
+                },
+                (None, _, None) => vec![num],
+                // all of them, the rest of --num from the others
+                (Some(Strata::HalfMapped), None, None) => {
+                    let n = num.min(sizes[0]);
+                    vec![n, num - n]
+                },
+                (Some(_), None, None) => match params.min_per_stratum {
+                    Some(min) => {
+                        let short = sizes.iter().filter(|&&size| size < min).count();
+                        if short > 0 {
+                            info!("{} of {} strata have fewer than --min-per-stratum {} templates; all of theirs are taken.", short, n_strata, min);
+                        }
+                        if sizes.iter().map(|&size| size.min(min)).sum::<usize>() > num {
+                            warn!("--min-per-stratum {} over {} strata adds up to more than --num {}; splitting --num among the strata by their floors instead.", min, n_strata, num);
+                        }
+                        reservoir::allocate_min(num, &sizes, min)
+                    },
+                    None => reservoir::allocate(num, &sizes),
+                },
+                (Some(strata), Some(fractions), None) => {
+                    let nums = strata::shares(num, fractions);
+                    for (i, (&n, &size)) in nums.iter().zip(&sizes).enumerate() {
+                        if n > size {
+                            warn!("{} has {} templates, fewer than the {} asked for.", strata.name(i), size, n);
+                        }
+                    }
+                    nums
+                },
+            };
+            if let Some(Strata::Allele(alleles)) = &strata {
+                // a stratum per combination of alleles is too many to list; the sites are what matter
+                let fractions = alleles.fractions(&sizes, &nums);
+                let drifted: Vec<&(String, f64, f64, usize)> = fractions.iter().filter(|x| (x.2 - x.1).abs() > params.vaf_tolerance).collect();
+                let most = fractions.iter().map(|x| (x.2 - x.1).abs()).fold(0.0, f64::max);
+                info!("{} strata of alleles over {} sites covered; the allele fractions drift by {:.4} at most.", n_strata, fractions.len(), most);
+                for (site, vaf_in, vaf_out, depth) in drifted.iter().take(10) {
+                    warn!("{}: allele fraction {:.4} in the input, {:.4} in the sample of {} templates carrying REF or ALT.", site, vaf_in, vaf_out, depth);
+                }
+                if !drifted.is_empty() {
+                    warn!("{} of {} sites drift by more than --vaf-tolerance {}, most of them too shallow in the sample for a finer fraction.", drifted.len(), fractions.len(), params.vaf_tolerance);
+                    if params.strict {
+                        error!("--strict: allele fractions drift past --vaf-tolerance; sample more templates or raise it.");
+                        discard(outfh, outfile, restfh, &params.rest_outfile);
+                        status::fail(Code::Biased);
+                    }
+                }
+            } else if let Some(strata) = &strata {
+                for i in 0..n_strata {
+                    info!("{}: {} of {} templates to be sampled.", strata.name(i), nums[i].min(sizes[i]), sizes[i]);
+                }
+                if let Strata::Strand = strata {
+                    let (fwd, rev) = (nums[0].min(sizes[0]), nums[1].min(sizes[1]));
+                    info!("Strand balance: {} forward, {} reverse, {:.1}% forward.", fwd, rev, 100.0 * fwd as f64 / (fwd + rev).max(1) as f64);
+                }
+            }
+            // every stratum draws its own picks, with a seed of its own from its key, so that the picks of a
+            // stratum do not change with the others, e.g. as --contigs leaves some out; without strata, with
+            // the seed itself
+            let mut picks: Vec<_> = (0..n_strata)
+                .map(|i| match params.deterministic {
+                    true => Vec::new(),
+                    false => reservoir::pick_indices(sizes[i], nums[i], strata.as_ref().map_or(seed, |s| seed.derive(strata::key(s, i).as_bytes()))),
+                }.into_iter().peekable())
+                .collect();
+            // or, with --deterministic, the highest key taken from every stratum, if any
+            let cutoffs: Vec<Option<reservoir::Key>> = ranks.into_iter().zip(&nums).map(|(r, &n)| match n {
+                0 => None,
+                _ => {
+                    let mut v = r.into_sorted();
+                    v.truncate(n);
+                    v.pop().map(|(key, _)| key)
+                },
+            }).collect();
+            info!("Second pass starts.");
+            interrupt::disarm();
+            loci.rewind();
+            let mut infhs = open_inputs(infiles, threads, params.group_by.sort_order());
+            let mut k: Vec<usize> = vec![0; n_strata];
+            for_each_template(&mut infhs, &tid_maps, None, false, params.group_by, (params.skip, params.head), |rs, at| {
+                let mut picked = false;
+                let i = match admit(&rs, params, &mut loci, reps.as_ref(), &mut Counts::default()) {
+                    true => strata.as_mut().map_or(Some(0), |s| s.of(&rs, at.first.0)),
+                    false => None,
+                };
+                if let Some(i) = i {
+                    if params.deterministic {
+                        picked = cutoffs[i].as_ref().is_some_and(|c| reservoir::key(rs[0].qname(), seed) <= *c);
+                    } else if picks[i].peek() == Some(&k[i]) {
+                        picks[i].next();
+                        picked = true;
+                    }
+                    k[i] += 1;
+                }
+                match (picked, &mut restfh, &params.rest_outfile) {
+                    (true, _, _) => {
+                        if let Some(n) = outfh.write(&rs, outfile, params) {
+                            reports.output(&rs, n, params);
+                        }
+                    },
+                    (false, Some(fh), Some(f)) => rs.iter().for_each(|r| write(fh, r, f)),
+                    _ => {},
+                }
+                Some(rs)
+            });
+            // the second pass must find the templates the first one counted, or the picks are off
+            if params.exact && k != sizes {
+                error!("--exact: the second pass found {} templates where the first counted {}; did the inputs change in between?", k.iter().sum::<usize>(), seen);
+                discard(outfh, outfile, restfh, &params.rest_outfile);
+                status::fail(Code::Inexact);
+            }
+            (seen, nums.iter().zip(&sizes).map(|(&n, &size)| n.min(size)).sum())
+        },
+        Strategy::Molecules => {
+            // first pass numbers the molecules (the values of the --group-by tag) as they turn up and
+            // counts their templates; --num molecules are drawn and, with --max-per-molecule, which
+            // templates of the larger ones to keep; second writes those out and, with --rest-outfile,
+            // all others there
+            let mut molecules = match params.group_by {
+                GroupBy::Molecule(tag) => TagValues::new(tag),
+                _ => unreachable!("two passes by molecule without --group-by tag"),
+            };
+            let mut sizes: Vec<usize> = Vec::new();
+            let mut seen: usize = 0;
+            for_each_template(&mut infhs, &tid_maps, None, false, params.group_by, (params.skip, params.head), |rs, _| {
+                reports.input(&rs);
+                if admit(&rs, params, &mut loci, reps.as_ref(), &mut counts) {
+                    match molecules.of(&rs) {
+                        Some(i) => {
+                            if i == sizes.len() {
+                                sizes.push(0);
+                            }
+                            sizes[i] += 1;
+                            seen += 1;
+                            if seen.is_multiple_of(1_000_000) {
+                                info!("{} reads (read pairs) counted...", seen);
+                            }
+                        },
+                        None => counts.untagged += 1,
+                    }
+                }
+                Some(rs)
+            });
+            // templates to keep of every molecule picked: all, or those at the indices drawn, last first
+            let mut takes: HashMap<usize, Option<Vec<usize>>> = HashMap::new();
+            let mut expected = 0;
+            for i in reservoir::pick_indices(sizes.len(), num, seed) {
+                let take = match params.max_per_molecule {
+                    Some(most) if sizes[i] > most => {
+                        expected += most;
+                        let mut picks = reservoir::pick_indices(sizes[i], most, seed.nth(1 + i as u64));
+                        picks.reverse();
+                        Some(picks)
+                    },
+                    _ => {
+                        expected += sizes[i];
+                        None
+                    },
+                };
+                takes.insert(i, take);
+            }
+            info!("{} of {} molecules to be sampled, {} of their templates.", takes.len(), sizes.len(), expected);
+            molecules_seen = Some(sizes.len());
+            info!("Second pass starts.");
+            interrupt::disarm();
+            loci.rewind();
+            let mut infhs = open_inputs(infiles, threads, params.group_by.sort_order());
+            let mut k: Vec<usize> = vec![0; sizes.len()];
+            for_each_template(&mut infhs, &tid_maps, None, false, params.group_by, (params.skip, params.head), |rs, _| {
+                let mut picked = false;
+                if admit(&rs, params, &mut loci, reps.as_ref(), &mut Counts::default()) {
+                    if let Some(i) = molecules.of(&rs) {
+                        match takes.get_mut(&i) {
+                            Some(None) => picked = true,
+                            Some(Some(picks)) if picks.last() == Some(&k[i]) => {
+                                picks.pop();
+                                picked = true;
+                            },
+                            _ => {},
+                        }
+                        if i == k.len() {
+                            k.push(0);
+                        }
+                        k[i] += 1;
+                    }
+                }
+                match (picked, &mut restfh, &params.rest_outfile) {
+                    (true, _, _) => {
+                        if let Some(n) = outfh.write(&rs, outfile, params) {
+                            reports.output(&rs, n, params);
+                        }
+                    },
+                    (false, Some(fh), Some(f)) => rs.iter().for_each(|r| write(fh, r, f)),
+                    _ => {},
+                }
+                Some(rs)
+            });
+            // the second pass must find the templates the first one counted, or the picks are off
+            if params.exact && k != sizes {
+                error!("--exact: the second pass found {} templates where the first counted {}; did the inputs change in between?", k.iter().sum::<usize>(), seen);
+                discard(outfh, outfile, restfh, &params.rest_outfile);
+                status::fail(Code::Inexact);
+            }
+            (seen, expected)
+        },
+        Strategy::Systematic => {
+            // no sample to hold: every --every-th template passing the filters goes out as it is read
+            let every = params.every.unwrap_or(1);
+            let phase = params.phase.unwrap_or_else(|| reservoir::phase(every, seed));
+            info!("Keeping 1 in every {} templates, at offset {} (0-based).", every, phase);
+            let mut systematic = sampler::Systematic::new(every, phase);
+            let mut seen: usize = 0;
+            for_each_template(&mut infhs, &tid_maps, None, false, params.group_by, (params.skip, params.head), |rs, _| {
+                reports.input(&rs);
+                let mut picked = false;
+                if admit(&rs, params, &mut loci, reps.as_ref(), &mut counts) {
+                    picked = systematic.observe(&rs) == Decision::Keep;
+                    seen += 1;
+                    if seen.is_multiple_of(1_000_000) {
+                        info!("{} reads (read pairs) processed...", seen);
+                    }
+                }
+                match (picked, &mut restfh, &params.rest_outfile) {
+                    (true, _, _) => {
+                        if let Some(n) = outfh.write(&rs, outfile, params) {
+                            reports.output(&rs, n, params);
+                        }
+                    },
+                    (false, Some(fh), Some(f)) => rs.iter().for_each(|r| write(fh, r, f)),
+                    _ => {},
+                }
+                Some(rs)
+            });
+            let kept = seen / every + usize::from(seen % every > phase);
+            info!("{} of {} templates kept.", kept, seen);
+            (seen, kept)
+        },
+        Strategy::Pipeline => {
+            // the templates passing the filters of the command line go on through the stages of --pipeline,
+            // whose sample is written group by group at the end
+            let spec = params.pipeline.as_deref().unwrap_or_default();
+            let mut stages = pipeline::parse(spec, seed).unwrap_or_else(|e| bad_args(&format!("invalid --pipeline: {}", e)));
+            info!("Sampling through --pipeline '{}'.", spec);
+            let mut seen: usize = 0;
+            for_each_template(&mut infhs, &tid_maps, None, false, params.group_by, (params.skip, params.head), |rs, _| {
+                reports.input(&rs);
+                if !admit(&rs, params, &mut loci, reps.as_ref(), &mut counts) {
+                    return Some(rs);
+                }
+                seen += 1;
+                if seen.is_multiple_of(1_000_000) {
+                    info!("{} reads (read pairs) processed...", seen);
+                }
+                stages.offer(rs);
+                None
+            });
+            let (filtered, ungrouped, groups) = (stages.filtered, stages.ungrouped, stages.groups());
+            let sample = stages.finish();
+            info!("--pipeline: {} of {} templates kept, from {} groups; {} left out by its filters, {} of no group.", sample.len(), seen, groups, filtered, ungrouped);
+            for rs in &sample {
+                if let Some(n) = outfh.write(rs, outfile, params) {
+                    reports.output(rs, n, params);
+                }
+            }
+            (seen, sample.len())
+        },
+        Strategy::Bernoulli => {
+            // every template is kept with a probability a little over --num / templates, into a temporary
+            // BAM holding about as many as --num and at least as many most likely, which a pass over it cuts
+            // down to --num uniformly; the templates are estimated unless --expected-templates, so that the
+            // margin makes up for the estimate being off too
+            let total = match params.expected_templates {
+                Some(n) => n,
+                None => {
+                    let total = infiles.iter().map(|f| budget::template_count(f, 100_000).unwrap_or_else(|| {
+                        error!("cannot estimate the templates of {}, a gzipped SAM; please give --expected-templates.", f);
+                        status::fail(Code::Args)
+                    })).sum::<usize>().saturating_sub(params.skip);
+                    params.head.map_or(total, |h| h.min(total))
+                },
+            };
+            let margin = if params.expected_templates.is_some() { 1.0 } else { 1.05 };
+            let rate = (margin * (num as f64 + 5.0 * (num as f64).sqrt() + 10.0) / total.max(1) as f64).min(1.0);
+            info!("Keeping templates with probability {:.6}, against {} templates{}.", rate, total, if params.expected_templates.is_some() { "" } else { " estimated" });
+            let tmp = format!("{}.bernoulli.bam", outfile);
+            let mut tmpfh = open_output(&tmp, in_order, threads);
+            let mut bernoulli = sampler::Bernoulli::new(rate, seed);
+            let (mut seen, mut drawn): (usize, usize) = (0, 0);
+            for_each_template(&mut infhs, &tid_maps, None, false, params.group_by, (params.skip, params.head), |rs, _| {
+                reports.input(&rs);
+                if admit(&rs, params, &mut loci, reps.as_ref(), &mut counts) {
+                    seen += 1;
+                    if bernoulli.observe(&rs) == Decision::Keep {
+                        drawn += 1;
+                        rs.iter().for_each(|r| write(&mut tmpfh, r, &tmp));
+                    }
+                    if seen.is_multiple_of(1_000_000) {
+                        info!("{} reads (read pairs) processed...", seen);
+                    }
+                }
+                Some(rs)
+            });
+            drop(tmpfh);
+            if drawn < num && seen > drawn {
+                warn!("{} of {} templates drawn, fewer than --num {}; --expected-templates may be too large.", drawn, seen, num);
+            }
+            info!("{} of {} templates drawn, cutting them down to {}.", drawn, seen, num.min(drawn));
+            // of a seed of their own, the coin flips having drawn from `seed`
+            let picks = reservoir::pick_indices(drawn, num, seed.stage("bernoulli"));
+            let mut next = picks.iter().peekable();
+            let mut i = 0;
+            interrupt::disarm();
+            let mut tmpfhs = open_inputs(std::slice::from_ref(&tmp), threads, "");
+            for_each_template(&mut tmpfhs, &[vec![]], None, false, params.group_by, (0, None), |rs, _| {
+                if next.peek() == Some(&&i) {
+                    next.next();
+                    if let Some(n) = outfh.write(&rs, outfile, params) {
+                        reports.output(&rs, n, params);
+                    }
+                }
+                i += 1;
+                Some(rs)
+            });
+            let _ = fs::remove_file(ospath::path(&tmp));
+            (seen, num.min(seen))
+        },
+        Strategy::Cached => {
+            // the templates passing the filters are found once and for all, their count and where they are
+            // kept in --cache-index, of which the runs after draw their picks without reading the inputs
+            let (path, options) = params.cache_index.as_ref().expect("--cache-index");
+            let fingerprint = qidx::fingerprint(infiles, options);
+            match index.as_ref().map(|x| x.n) {
+                Some(n) => info!("Reusing {}, of {} templates passing the filters.", path, n),
+                None => {
+                    info!("Indexing the templates passing the filters into {}.", path);
+                    let mut writer = qidx::Writer::create(path, &fingerprint).unwrap_or_else(|e| {
+                        error!("failed to write {}: {}", path, e);
+                        status::fail(Code::Io)
+                    });
+                    for_each_template(&mut infhs, &tid_maps, None, true, params.group_by, (params.skip, params.head), |rs, at| {
+                        if admit(&rs, params, &mut loci, reps.as_ref(), &mut counts) {
+                            if let Err(e) = writer.add(at.first.0, at.first.1, rs.len()) {
+                                error!("failed to write {}: {}", path, e);
+                                status::fail(Code::Io);
+                            }
+                            if writer.n.is_multiple_of(1_000_000) {
+                                info!("{} reads (read pairs) indexed...", writer.n);
+                            }
+                        }
+                        Some(rs)
+                    });
+                    if interrupt::received().is_some() {
+                        writer.remove();
+                    } else {
+                        let n = writer.n;
+                        if let Err(e) = writer.finish() {
+                            error!("failed to write {}: {}", path, e);
+                            status::fail(Code::Io);
+                        }
+                        info!("{} templates indexed into {}.", n, path);
+                        index = qidx::Index::open(path, &fingerprint).ok().flatten();
+                    }
+                },
+            }
+            match index {
+                Some(mut index) => {
+                    let seen = index.n;
+                    let picks: Vec<(usize, i64, usize)> = reservoir::pick_indices(seen, num, seed).into_iter().map(|i| index.get(i).unwrap_or_else(|e| {
+                        error!("failed to read {}: {}", path, e);
+                        status::fail(Code::Io)
+                    })).collect();
+                    info!("Copying the {} templates sampled.", picks.len());
+                    copy_templates(params, infiles, &tid_maps, &picks, &mut outfh, outfile, reports);
+                    (seen, num.min(seen))
+                },
+                // interrupted while indexing
+                None => (0, 0),
+            }
+        },
+        Strategy::Offsets => {
+            // the reservoir draws as it does on the templates themselves, but holds the (input, virtual
+            // offset, records) of each, so the same templates are sampled; they are read back in input order
+            let mut res: Reservoir<(usize, i64, usize)> = Reservoir::new(num, seed);
+            for_each_template(&mut infhs, &tid_maps, None, true, params.group_by, (params.skip, params.head), |rs, at| {
+                reports.input(&rs);
+                if admit(&rs, params, &mut loci, reps.as_ref(), &mut counts) {
+                    res.sample((at.first.0, at.first.1, rs.len()));
+                    if res.k.is_multiple_of(1_000_000) {
+                        info!("{} reads (read pairs) processed...", res.k);
+                    }
+                }
+                Some(rs)
+            });
+            let seen = res.k;
+            let mut picks = res.v;
+            picks.sort_unstable();
+            info!("Copying the {} templates sampled.", picks.len());
+            copy_templates(params, infiles, &tid_maps, &picks, &mut outfh, outfile, reports);
+            (seen, num.min(seen))
+        },
+    };
+    // an interrupted run has its outputs removed, or written out as they are with --finalize-on-interrupt
+    // or once out of --time-limit, which is not held to --num
+    let interrupted = interrupt::received();
+    if let Some(signal) = interrupted {
+        if interrupt::timed_out() {
+            // the templates read are the start of the inputs rather than a random part of them; of input
+            // sorted by coordinate, they are of the first references only
+            let read = fraction_read(infiles).map(|x| format!(", {:.1}% of the input", 100.0 * x)).unwrap_or_default();
+            warn!("--time-limit reached; writing out the {} templates sampled of the {} read so far{}.", outfh.n, seen, read);
+            warn!("The sample is uniform over the templates read only, not the whole input, and is biased by however the input is ordered.");
+        } else if !params.finalize_on_interrupt {
+            warn!("{} received; removing the outputs.", signal);
+            discard(outfh, outfile, restfh, &params.rest_outfile);
+            for (_, path) in &params.nested {
+                let _ = fs::remove_file(ospath::path(path));
+            }
+            return;
+        } else {
+            warn!("{} received; writing out the {} templates sampled of the {} read so far.", signal, outfh.n, seen);
+        }
+    }
+    if params.exact && interrupted.is_none() {
+        if outfh.n != expected {
+            error!("--exact: {} templates written where {} were to be!", outfh.n, expected);
+            discard(outfh, outfile, restfh, &params.rest_outfile);
+            status::fail(Code::Inexact);
+        }
+        info!("--exact: {} templates written, as expected.", outfh.n);
+    }
+    // a truncated or wrong input would otherwise go through as a smaller sample
+    if seen < params.min_templates && interrupted.is_none() {
+        error!("{} templates pass the filters, fewer than --min-templates {}!", seen, params.min_templates);
+        discard(outfh, outfile, restfh, &params.rest_outfile);
+        status::fail(Code::TooFew);
+    }
+    let available = molecules_seen.unwrap_or(seen);
+    if available < num && !matches!(strategy, Strategy::Systematic | Strategy::Pipeline) && params.per_stratum.is_none() && params.quotas.is_none() && interrupted.is_none() {
+        if params.strict && !params.allow_fewer {
+            error!("--num exceeds the input read counts ({} < {})!", available, num);
+            discard(outfh, outfile, restfh, &params.rest_outfile);
+            status::fail(Code::TooFew);
+        }
+        warn!("--num exceeds the input read counts! output all.");
+    }
+    let fixes = &outfh.fixes;
+    if fixes.dangling_sa > 0 {
+        match params.repair_sa {
+            true => info!("{} records listed alignments left out in their SA tag; those entries were removed.", fixes.dangling_sa),
+            false => warn!("{} records list alignments left out in their SA tag; --repair-sa removes those entries.", fixes.dangling_sa),
+        }
+    }
+    if fixes.unpaired > 0 {
+        info!("--fixmate: {} records were written single-end, their mate left out.", fixes.unpaired);
+    }
+    if fixes.bad_modifications > 0 {
+        match params.strip_bad_modifications {
+            true => info!("{} records had MM/ML tags that do not fit them; those tags were removed.", fixes.bad_modifications),
+            false => warn!("{} records have MM/ML tags that do not fit them; --strip-bad-modifications removes those tags.", fixes.bad_modifications),
+        }
+    }
+    if let Some((id, _)) = &params.read_group {
+        info!("--set-read-group: {} records without a read group tagged RG:{}.", fixes.read_group, id);
+    }
+    if fixes.hits > 0 {
+        info!("{} records of multi-mapped reads had their NH lowered and HI numbered again for the secondary alignments left out.", fixes.hits);
+    }
+    if let Some(max) = params.max_records_per_template {
+        info!("--max-records-per-template {}: {} records left out of {} of the {} templates written.", max, fixes.capped_records, fixes.capped, outfh.n);
+    }
+    let templates = outfh.n;
+    let mateless = outfh.fixes.mateless;
+    // the outputs of --split-by below --min-records are removed with their templates
+    outfh.release(outfile);
+    let (written, counted) = match outfh.sink {
+        Sink::One(_) | Sink::Sorted(..) => (vec![outfile.to_string()], true),
+        Sink::Split(s) => (s.finish(), false),
+        Sink::Chunks(c) => (c.finish(), true),
+        Sink::Shards(s) => (s.finish(), true),
+    };
+    if counted {
+        reports.samples.push((written.clone(), templates, mateless));
+    }
+    reports.written.extend(written);
+    reports.written.extend(params.nested.iter().map(|x| x.1.clone()));
+    reports.written.extend(params.rest_outfile.clone());
+    if params.filter_stats {
+        counts.filters.log();
+    }
+    if counts.unstratified > 0 {
+        info!("{} templates fell in no stratum (none of --bins, no called base with --stratify-by gc, no qualities with meanq, no Illumina qname with lane, unmapped with chromosome, without the --stratify-by tag, unmapped with --balance-strands, off the --amplicons, of no sample with --per-sample-num, or no Illumina qname with --per-tile-num), dropped.", counts.unstratified);
+    }
+    if counts.untagged > 0 {
+        info!("{} templates without the --group-by tag dropped.", counts.untagged);
+    }
+    if counts.tagged > 0 {
+        info!("{} templates dropped by --require-tag/--exclude-tag.", counts.tagged);
+    }
+    if counts.misnamed > 0 {
+        info!("{} templates dropped by --name-regex{}.", counts.misnamed, if params.name_regex_invert { " --name-regex-invert" } else { "" });
+    }
+    if counts.off_tiles > 0 {
+        info!("{} templates dropped by --exclude-tiles.", counts.off_tiles);
+    }
+    if counts.timed > 0 {
+        info!("{} templates dropped by --time-range.", counts.timed);
+    }
+    if counts.off_contigs > 0 {
+        info!("{} templates dropped by --contigs.", counts.off_contigs);
+    }
+    if counts.excluded > 0 {
+        info!("{} templates dropped by --exclude-regions.", counts.excluded);
+    }
+    if counts.overlapping > 0 {
+        info!("{} templates dropped by --exclude-overlapping.", counts.overlapping);
+    }
+    if counts.off_regions > 0 {
+        info!("{} templates outside --regions dropped.", counts.off_regions);
+    }
+    if counts.off_sites > 0 {
+        info!("{} templates away from --sites (or past --max-per-site) dropped.", counts.off_sites);
+    }
+    if counts.improper > 0 {
+        info!("{} templates not in proper pair dropped by --proper-pairs-only.", counts.improper);
+    }
+    if counts.off_tlen > 0 {
+        info!("{} templates not in proper pair or out of --min-tlen/--max-tlen dropped.", counts.off_tlen);
+    }
+    if counts.duplicates > 0 {
+        info!("{} templates dropped as duplicates of others by --dedup-by.", counts.duplicates);
+    }
+    if counts.unflagged > 0 {
+        info!("{} templates not flagged as duplicates dropped by --only-duplicates.", counts.unflagged);
+    }
+    if counts.divergent > 0 {
+        info!("{} templates of too many mismatches dropped by --max-nm or --max-nm-frac.", counts.divergent);
+    }
+    if counts.clipped > 0 {
+        info!("{} templates of mostly clipped alignments dropped by --min-aligned-frac.", counts.clipped);
+    }
+    if counts.splicing > 0 {
+        let (what, how) = match params.spliced {
+            Spliced::Only => ("unspliced", "only"),
+            _ => ("spliced", "exclude"),
+        };
+        info!("{} {} templates dropped by --spliced {}.", counts.splicing, what, how);
+    }
+    if counts.unmapped > 0 && !params.unaligned {
+        info!("{} unmapped templates found, {}.", counts.unmapped, match params.unmapped {
+            Policy::Keep => "kept",
+            Policy::Drop => "dropped",
+            Policy::Only => "all other templates dropped",
+        });
+    }
+    if counts.singletons > 0 {
+        info!("{} singleton templates found, {}.", counts.singletons, match params.singletons {
+            Policy::Keep => "kept",
+            Policy::Drop => "dropped",
+            Policy::Only => "all other templates dropped",
+        });
+    }
+    if counts.half_mapped > 0 {
+        info!("{} templates of a mate unmapped found, {}.", counts.half_mapped, match (params.half_mapped, &params.strata) {
+            (_, Some(Strata::HalfMapped)) => "taken first",
+            (Policy::Keep, _) => "kept",
+            (Policy::Drop, _) => "dropped",
+            (Policy::Only, _) => "all other templates dropped",
+        });
+    }
+    if counts.broken > 0 {
+        warn!("{} incomplete templates (broken pairs) found, {}.", counts.broken, match params.on_broken_pairs {
+            BrokenPairs::Drop => "dropped",
+            _ => "kept",
+        });
+    }
+}
+
+// a setting of the environment, $SAM_SUBSAMPLE_<NAME>, for what a cluster sets once for every job
+// rather than on every command line; the option, where there is one, takes precedence
+fn env_default(name: &str) -> Option<String> {
+    env::var(format!("SAM_SUBSAMPLE_{}", name)).ok().filter(|x| !x.is_empty())
+}
+
+// the temporary directory: $SAM_SUBSAMPLE_TMPDIR, e.g. a node's local scratch, or else $TMPDIR
+fn temp_dir() -> PathBuf {
+    env::var_os("SAM_SUBSAMPLE_TMPDIR").filter(|x| !x.is_empty()).map_or_else(env::temp_dir, PathBuf::from)
+}
+
+// a path in the temporary directory, handed around as the inputs are
+fn temp_path(name: &str) -> String {
+    ospath::text(&temp_dir().join(name))
+}
+
+fn main() {
+    let args: Vec<String> = ospath::args();
+    let mut params = parse_args(&args, Options::new());
+    init_logger(&params.level, params.log_filter.as_deref(), params.log_file.as_deref(), params.log_format);
+    if params.plan {
+        plan(&params);
+        return;
+    }
+    let started = Instant::now();
+    if params.timing {
+        profile::enable();
+    }
+    if let Some(t) = &mut params.time_range {
+        info!("Finding the start of every run for --time-range.");
+        t.starts = timing::run_starts(&params.infiles);
+    }
+    if let Some(blocks) = params.check_input {
+        check_inputs(&params.infiles, blocks);
+    }
+    if let Some(max_frac) = params.skip_invalid {
+        invalid::enable(max_frac);
+    }
+    // resolved on the header of the input as it is read, which may be a pipe
+    let mut track = params.track.take();
+    let mut depths = params.depths.take();
+    if let Some(policy) = params.on_regrouped {
+        regroup::enable(policy);
+    }
+    // slices on htsget servers are read from named pipes their blocks are relayed to
+    let mut relayed: Vec<String> = Vec::new();
+    for (i, infile) in params.infiles.iter_mut().enumerate() {
+        if htsget::is_url(infile) {
+            let fifo = temp_path(&format!("sam_subsample.{}.htsget{}", std::process::id(), i));
+            htsget::open(infile, &fifo);
+            info!("Reading {} through {}.", infile, fifo);
+            *infile = fifo.clone();
+            relayed.push(fifo);
+        }
+    }
+    // inputs without @SQ lines, unaligned BAMs such as PacBio HiFi reads or Illumina uBAMs, are sampled by
+    // template alone; a pipe is not looked at, its header being gone once it is
+    let mut unaligned = false;
+    for infile in params.infiles.iter().filter(|f| !is_stream(f)) {
+        if open_inputs(std::slice::from_ref(infile), 1, "")[0].header().target_count() == 0 {
+            info!("{} has no @SQ lines; sampling it by template, as an unaligned BAM.", infile);
+            unaligned = true;
+        }
+    }
+    params.unaligned = unaligned;
+    if params.unaligned {
+        if let Some(opt) = params.coordinate_options.first() {
+            error!("{} goes by where reads align, which inputs without @SQ lines do not say!", opt);
+            status::fail(Code::Args);
+        }
+    }
+    // inputs whose records are not grouped by qname, or not said to be, are collated into temporary files,
+    // read in their place, within --max-mem; a pipe is, its records being gone once looked at
+    let mut collated: Vec<String> = Vec::new();
+    if params.auto_collate {
+        for (i, infile) in params.infiles.iter_mut().enumerate() {
+            if !is_stream(infile) && collate::is_grouped(&mut open_inputs(std::slice::from_ref(infile), 1, "")[0], params.group_by) {
+                continue;
+            }
+            let tmp = temp_path(&format!("sam_subsample.{}.collate{}.bam", std::process::id(), i));
+            info!("{} is not grouped by qname, collating it into {}.", infile, tmp);
+            status::temporary(&tmp);
+            collate::collate(infile, &tmp, params.group_by, params.threads, params.max_mem.unwrap_or(collate::BUDGET));
+            *infile = tmp.clone();
+            collated.push(tmp);
+        }
+    }
+
+    if let Some(fraction) = params.fraction {
+        params.num = fraction_num(&params.infiles, fraction, params.skip, params.head, params.threads);
+    }
+
+    // up once the inputs read are the ones sampled, the collated in place of those that were not grouped
+    if params.tui && !tui::start(&params.infiles) {
+        warn!("--tui needs stderr to be a terminal; logging as usual.");
+    }
+    let infiles = &params.infiles;
+    let outfiles = &params.outfiles;
+    let num = params.num;
+    let threads = params.threads;
+    let mode = params.mode;
+    // random access is quick to stop as it is
+    if mode != Mode::RandomAccess {
+        interrupt::install();
+        if let Some(limit) = params.time_limit {
+            interrupt::limit(limit.saturating_sub(started.elapsed()));
+        }
+    }
+
+    // pick up where a previous run stopped, if it left a checkpoint behind
+    let resumed = match &params.checkpoint {
+        Some(path) if ospath::path(path).exists() => {
+            let (state, v) = checkpoint::load(path);
+            if state.infiles != *infiles || state.num != num {
+                error!("{} was saved by a run with different --infile or --num!", path);
+                status::fail(Code::Args);
+            }
+            same_rng(path, &state, params.rng);
+            info!("Resuming from {} after {} reads (read pairs).", path, state.seen);
+            Some((state, v))
+        },
+        _ => match &params.load_state {
+            Some(path) => {
+                let (state, v) = checkpoint::load(path);
+                if state.num != num {
+                    error!("{} was saved with --num {}, not {}!", path, state.num, num);
+                    status::fail(Code::Args);
+                }
+                same_rng(path, &state, params.rng);
+                if let Some(f) = infiles.iter().find(|f| state.infiles.contains(f)) {
+                    error!("{} was already read into {}; reading it again would count its templates twice!", f, path);
+                    status::fail(Code::Args);
+                }
+                info!("Carrying on the reservoir of {} after {} templates of {}.", path, state.seen, state.infiles.join(","));
+                Some((state, v))
+            },
+            None => None,
+        },
+    };
+    let seed = Seed::new(params.rng, match &resumed {
+        Some((state, _)) => state.seed,
+        None => params.seed,
+    });
+    info!("{{ infile = {}, outfile = {}, num = {}, seed = {}, rng = {}, level = {}, singletons = {}, unmapped = {}, threads = {} }}", infiles.join(","), outfiles.join(","), num, seed.value, seed.kind.name(), params.level, params.singletons.name(), params.unmapped.name(), threads);
+
+    let mut reports = Reports {
+        names: params.names_out.as_deref().map(open_text),
+        stats: params.template_stats.as_ref().map(|_| TemplateStats::default()),
+        tallies: params.tallies.as_ref().map(|_| Tallies::default()),
+        dists: params.distribution_report.as_ref().map(|_| Distributions::default()),
+        dups: params.duplication.map(Duplication::new),
+        complexity: params.complexity.as_ref().map(|_| Complexity::default()),
+        saturation: params.saturation.as_ref().map(|_| Saturation::default()),
+        selection: params.digest.as_ref().map(|_| Selection::default()),
+        metadata: params.metadata_out.as_deref().map(|path| Metadata::new(open_text(path), params.metadata_tags.clone()).unwrap_or_else(|e| {
+            error!("failed to write {}: {}", path, e);
+            status::fail(Code::Io)
+        })),
+        columns: params.arrow_out.as_ref().map(|(path, format)| Columns::new(path, *format, params.arrow_tags.clone()).unwrap_or_else(|e| {
+            error!("failed to write {}: {}", path, e);
+            status::fail(Code::Io)
+        })),
+        spans: params.bed_out.as_deref().map(|path| Spans::new(open_text(path), path)),
+        written: Vec::new(),
+        samples: Vec::new(),
+        refs: Vec::new(),
+    };
+    match mode {
+        Mode::Fastq => {
+            let (seen, written) = fastq::subsample(infiles, outfiles, num, seed, params.finalize_on_interrupt);
+            info!("{} of {} templates written.", written, seen);
+            if seen < num && interrupt::received().is_none() {
+                if params.strict && !params.allow_fewer {
+                    error!("--num exceeds the input read counts ({} < {})!", seen, num);
+                    outfiles.iter().for_each(|f| {
+                        let _ = fs::remove_file(ospath::path(f));
+                    });
+                    status::fail(Code::TooFew);
+                }
+                warn!("--num exceeds the input read counts! output all.");
+            }
+        },
+        Mode::CapDepth | Mode::Uniform | Mode::Thin | Mode::StreamThrough => {
+            let mut infhs = open_inputs(infiles, threads, "coordinate");
+            tui::references(infhs[0].header());
+            let header = bam::Header::from_template(infhs[0].header());
+            let mut outfh = open_output(&outfiles[0], &header, threads);
+            if mode == Mode::StreamThrough {
+                let total = params.expected_templates.unwrap_or_else(|| budget::template_count(&infiles[0], 100_000).unwrap_or_else(|| {
+                    error!("cannot estimate the templates of {}, a gzipped SAM; please give --expected-templates.", infiles[0]);
+                    status::fail(Code::Args)
+                }));
+                let rate = (num as f64 / total.max(1) as f64).min(1.0);
+                info!("Keeping templates with probability {:.6}, against {} templates{}.", rate, total, if params.expected_templates.is_some() { "" } else { " estimated" });
+                stream_through(&mut infhs[0], &mut outfh, &outfiles[0], rate, seed);
+            } else if mode == Mode::CapDepth && (is_stream(&outfiles[0]) || outfiles[0] == "-") {
+                // a pipe cannot be read back for the orphans to be dropped, so what is kept goes to a
+                // temporary file first, and from there to the output without them
+                let tmp = temp_path(&format!("sam_subsample.{}.cap-depth.bam", std::process::id()));
+                let mut tmpfh = open_output(&tmp, &header, threads);
+                let orphans = cap_depth(&mut infhs[0], &mut tmpfh, &tmp, params.max_depth, seed);
+                drop(tmpfh);
+                if !abandoned(&params) {
+                    copy_without(&tmp, &mut outfh, &outfiles[0], &orphans, threads);
+                }
+                let _ = fs::remove_file(ospath::path(&tmp));
+            } else if mode == Mode::CapDepth {
+                let orphans = cap_depth(&mut infhs[0], &mut outfh, &outfiles[0], params.max_depth, seed);
+                if !orphans.is_empty() && !abandoned(&params) {
+                    drop(outfh);
+                    drop_orphans(&outfiles[0], &orphans, threads);
+                }
+            } else if let Some(track) = track.as_mut() {
+                track.resolve(infhs[0].header());
+                thin(&mut infhs[0], &mut outfh, &outfiles[0], track, params.off_track, seed);
+            } else if let Some(depths) = depths.as_mut() {
+                depths.resolve(infhs[0].header());
+                harmonize(&mut infhs[0], &mut outfh, &outfiles[0], depths, seed);
+            } else {
+                let mut again = open_inputs(infiles, threads, "coordinate");
+                flatten(&mut infhs[0], &mut again[0], &mut outfh, &outfiles[0], params.target_depth, params.window, seed);
+            }
+        },
+        Mode::RandomAccess => {
+            let n = seek::sample(&infiles[0], &outfiles[0], num, seed, threads);
+            if n < num && params.strict {
+                error!("--num exceeds the templates found ({} < {})!", n, num);
+                let _ = fs::remove_file(ospath::path(&outfiles[0]));
+                status::fail(Code::TooFew);
+            }
+        },
+        Mode::Subsample | Mode::Normalize if outfiles.len() > 1 => {
+            // every input goes to its own output, with its share of --num or, when normalizing, as much
+            // as the smallest input has
+            let nums = match (mode, params.allocate) {
+                _ if params.every.is_some() => vec![num; infiles.len()],
+                (Mode::Normalize, _) => {
+                    info!("Counting templates to normalize to the smallest input.");
+                    let sizes: Vec<(usize, u64)> = infiles.iter().map(|f| count_templates(&params, f)).collect();
+                    match (params.normalize_by, &params.ratio) {
+                        (NormalizeBy::Templates, Some(ratio)) => {
+                            // the largest multiple of the ratio every input has templates for
+                            let k = sizes.iter().zip(ratio).map(|(x, &r)| x.0 / r).min().unwrap_or_default();
+                            info!("Normalizing to {} times {}.", k, ratio.iter().map(|r| r.to_string()).collect::<Vec<_>>().join(":"));
+                            ratio.iter().map(|&r| k * r).collect()
+                        },
+                        (NormalizeBy::Templates, None) => {
+                            let min = sizes.iter().map(|x| x.0).min().unwrap_or_default();
+                            info!("Normalizing to {} templates.", min);
+                            vec![min; sizes.len()]
+                        },
+                        (NormalizeBy::Bases, _) => {
+                            // as many templates as it takes, on average, to reach the smallest base yield
+                            let min = sizes.iter().map(|x| x.1).min().unwrap_or_default();
+                            info!("Normalizing to {} bases.", min);
+                            sizes.iter().map(|&(n, b)| match b {
+                                0 => 0,
+                                _ => (n as f64 * min as f64 / b as f64).round() as usize,
+                            }).collect()
+                        },
+                    }
+                },
+                (_, Allocate::Proportional) => {
+                    info!("Counting templates for --allocate proportional.");
+                    let sizes: Vec<usize> = infiles.iter().map(|f| count_templates(&params, f).0).collect();
+                    reservoir::allocate(num, &sizes)
+                },
+                (_, Allocate::Equal) => {
+                    let k = infiles.len();
+                    (0..k).map(|i| num / k + usize::from(i < num % k)).collect()
+                },
+            };
+            for ((infile, outfile), n) in infiles.iter().zip(outfiles).zip(nums) {
+                // the inputs left once interrupted get no output
+                interrupt::arm();
+                if interrupt::stopping() {
+                    break;
+                }
+                info!("{} -> {}: --num {}", infile, outfile, n);
+                subsample(&params, std::slice::from_ref(infile), outfile, n, seed, None, &mut reports);
+            }
+        },
+        Mode::Subsample | Mode::Normalize => {
+            subsample(&params, infiles, &outfiles[0], num, seed, resumed, &mut reports);
+            // a checkpoint is kept for an interrupted run to resume from
+            if let (Some(path), None) = (&params.checkpoint, interrupt::received()) {
+                if ospath::path(path).exists() {
+                    fs::remove_file(ospath::path(path)).expect("failed to remove the checkpoint!");
+                }
+            }
+        },
+    }
+    collate::remove(&collated);
+    collate::remove(&relayed);
+    if invalid::report() {
+        for f in reports.written.iter().chain(outfiles) {
+            let _ = fs::remove_file(ospath::path(f));
+        }
+        status::fail(Code::Malformed);
+    }
+    if matches!(mode, Mode::CapDepth | Mode::Uniform | Mode::Thin | Mode::StreamThrough) && abandoned(&params) {
+        warn!("{} received; removing {}.", interrupt::received().unwrap_or_default(), outfiles[0]);
+        let _ = fs::remove_file(ospath::path(&outfiles[0]));
+    }
+    if let (Some(mut fh), Some(path)) = (reports.names, &params.names_out) {
+        if let Err(e) = fh.flush() {
+            error!("failed to write {}: {}", path, e);
+            status::fail(Code::Io);
+        }
+    }
+    if let (Some(metadata), Some(path)) = (reports.metadata, &params.metadata_out) {
+        if let Err(e) = metadata.finish() {
+            error!("failed to write {}: {}", path, e);
+            status::fail(Code::Io);
+        }
+    }
+    if let (Some(columns), Some((path, _))) = (reports.columns, &params.arrow_out) {
+        if let Err(e) = columns.finish() {
+            error!("failed to write {}: {}", path, e);
+            status::fail(Code::Io);
+        }
+    }
+    if let (Some(spans), Some(path)) = (reports.spans, &params.bed_out) {
+        if let Err(e) = spans.finish() {
+            error!("failed to write {}: {}", path, e);
+            status::fail(Code::Io);
+        }
+    }
+    if let (Some(stats), Some(path)) = (reports.stats, &params.template_stats) {
+        if let Err(e) = stats.write(path) {
+            error!("failed to write {}: {}", path, e);
+            status::fail(Code::Io);
+        }
+    }
+    if let (Some(tallies), Some(path)) = (reports.tallies, &params.tallies) {
+        let (references, read_groups) = tallies.summary();
+        info!("Templates written per reference: {}", references);
+        info!("Templates written per read group: {}", read_groups);
+        if let Err(e) = tallies.write(path) {
+            error!("failed to write {}: {}", path, e);
+            status::fail(Code::Io);
+        }
+    }
+    if let (Some(dists), Some(path)) = (reports.dists, &params.distribution_report) {
+        info!("KS distance of the output to the input: {}", dists.summary());
+        if let Err(e) = dists.write(path) {
+            error!("failed to write {}: {}", path, e);
+            status::fail(Code::Io);
+        }
+    }
+    if let Some(dups) = &reports.dups {
+        info!("Duplication rate: {}", dups.summary());
+    }
+    if let (Some(complexity), Some(path)) = (&reports.complexity, &params.complexity) {
+        match complexity.write(path) {
+            Ok(Some(size)) => info!("Library size estimated at {:.0} distinct molecules.", size),
+            Ok(None) => warn!("No mapped templates to estimate the library complexity from."),
+            Err(e) => {
+                error!("failed to write {}: {}", path, e);
+                status::fail(Code::Io);
+            },
+        }
+    }
+    if let (Some(saturation), Some(path)) = (&reports.saturation, &params.saturation) {
+        match saturation.write(path, params.per_cell) {
+            Ok(Some(x)) => info!("Sequencing saturation: {:.2}%", 100.0 * x),
+            Ok(None) => warn!("No templates with both a CB and a UB tag to estimate the sequencing saturation from."),
+            Err(e) => {
+                error!("failed to write {}: {}", path, e);
+                status::fail(Code::Io);
+            },
+        }
+    }
+    // before reading the outputs back, so that what is validated and digested is what is left
+    if params.trim_header {
+        for outfile in &reports.written {
+            match ospath::path(outfile).is_file() {
+                true => trim_header(outfile, params.threads),
+                false => warn!("{} is not a regular file; its header is not trimmed.", outfile),
+            }
+        }
+    }
+    if params.validate_output {
+        let mut found: HashMap<&str, validate::Found> = HashMap::new();
+        for outfile in &reports.written {
+            // a pipe such as /dev/stdout cannot be read back
+            if !ospath::path(outfile).is_file() {
+                warn!("{} is not a regular file; not validated.", outfile);
+                continue;
+            }
+            match validate::check(outfile, params.group_by) {
+                Ok(x) => {
+                    found.insert(outfile, x);
+                },
+                Err(e) => {
+                    error!("{} failed validation: {}!", outfile, e);
+                    status::fail(Code::Io);
+                },
+            }
+        }
+        // templates are told apart by qname, or by ZMW while the qnames keep them
+        if params.group_by == GroupBy::Qname || (params.group_by == GroupBy::Zmw && !params.anonymize_names) {
+            for (paths, n, mateless) in reports.samples.iter().filter(|x| x.0.iter().all(|p| found.contains_key(p.as_str()))) {
+                let read: usize = paths.iter().map(|p| found[p.as_str()].templates).sum();
+                if read != *n {
+                    error!("{} failed validation: {} templates read back, {} written!", paths.join(", "), read, n);
+                    status::fail(Code::Io);
+                }
+                // a mate gone missing on the way, which the count of templates does not tell
+                if let Some(read) = paths.iter().map(|p| found[p.as_str()].mateless).sum::<Option<usize>>() {
+                    if read != *mateless {
+                        error!("{} failed validation: {} paired primary records read back without their mate, {} written so!", paths.join(", "), read, mateless);
+                        status::fail(Code::Io);
+                    }
+                }
+            }
+        }
+        info!("{} outputs read back and validated.", found.len());
+    }
+    if let (Some(mut selection), Some(path)) = (reports.selection, &params.digest) {
+        let mut outputs = Vec::new();
+        for outfile in &reports.written {
+            // a pipe such as /dev/stdout cannot be read back
+            if !ospath::path(outfile).is_file() {
+                warn!("{} is not a regular file; not digested.", outfile);
+                continue;
+            }
+            let hex = digest::file(outfile).unwrap_or_else(|e| {
+                error!("failed to read {}: {}", outfile, e);
+                status::fail(Code::Io)
+            });
+            info!("SHA-256 of {}: {}", outfile, hex);
+            outputs.push((outfile.as_str(), hex));
+        }
+        let hex = selection.hex();
+        info!("SHA-256 of the qnames sampled: {}", hex);
+        if let Err(e) = digest::write(path, &outputs, &hex, selection.templates()) {
+            error!("failed to write {}: {}", path, e);
+            status::fail(Code::Io);
+        }
+    }
+    tui::finish();
+    if params.timing {
+        profile::report(started.elapsed());
+    }
+    info!("{}", resources::summary(started.elapsed()));
+    if let Some(signal) = interrupt::received() {
+        warn!("Stopped by {}, with the reports of what was read.", signal);
+        status::fail(if interrupt::timed_out() { Code::TimeLimit } else { Code::Interrupted });
+    }
+    info!("All done.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sam_subsample::backend::{parse_sam, Records};
+
+    // ten pairs, r00 to r09, and r05 with a secondary alignment as well
+    fn fixture() -> String {
+        let mut sam = String::from("@HD\tVN:1.6\tSO:queryname\n@SQ\tSN:chr1\tLN:10000\n");
+        for i in 0..10 {
+            let (a, b) = (1000 - 50 * i, 1200 - 50 * i);
+            sam += &format!("r{:02}\t99\tchr1\t{}\t60\t4M\t=\t{}\t204\tACGT\t*\n", i, a, b);
+            if i == 5 {
+                sam += &format!("r{:02}\t355\tchr1\t5000\t0\t4M\t=\t{}\t0\tACGT\t*\n", i, b);
+            }
+            sam += &format!("r{:02}\t147\tchr1\t{}\t60\t4M\t=\t{}\t-204\tACGT\t*\n", i, b, a);
+        }
+        sam
+    }
+
+    fn params(extra: &[&str]) -> Params {
+        let args: Vec<String> = ["sam_subsample", "--infile", "/dev/null", "--input-format", "sam", "--outfile", "/dev/null"].iter().chain(extra).map(|x| x.to_string()).collect();
+        parse_args(&args, Options::new())
+    }
+
+    // the templates of the fixture as `for_each_template` hands them over, within `window`
+    fn templates(window: (usize, Option<usize>)) -> Vec<RecordSet> {
+        let (_, records) = parse_sam(&fixture()).unwrap();
+        let mut out = Vec::new();
+        for_each_template(&mut [Records(records.into_iter())], &[TidMap::new()], None, false, GroupBy::Qname, window, |rs, _| {
+            out.push(rs);
+            None
+        });
+        out
+    }
+
+    fn qnames(sample: &[RecordSet]) -> Vec<String> {
+        sample.iter().map(|rs| String::from_utf8_lossy(rs[0].qname()).into_owned()).collect()
+    }
+
+    // the sample of `num` templates the reservoir draws from the fixture with `seed`
+    fn draw(num: usize, seed: u64) -> Vec<RecordSet> {
+        let params = params(&["--num", &num.to_string(), "--seed", &seed.to_string()]);
+        let (header, records) = parse_sam(&fixture()).unwrap();
+        let mut loci = new_loci(&params, &header);
+        let mut counts = Counts::default();
+        let mut res = Sampling::Serial(Reservoir::new(num, Seed::new(params.rng, seed)));
+        for_each_template(&mut [Records(records.into_iter())], &[TidMap::new()], None, false, params.group_by, (0, None), |rs, _| {
+            match admit(&rs, &params, &mut loci, None, &mut counts) {
+                true => res.sample(rs),
+                false => Some(rs),
+            }
+        });
+        res.finish()
+    }
+
+    #[test]
+    fn records_are_grouped_by_qname() {
+        let ts = templates((0, None));
+        assert_eq!(qnames(&ts), (0..10).map(|i| format!("r{:02}", i)).collect::<Vec<_>>());
+        assert_eq!(ts.iter().map(Vec::len).collect::<Vec<_>>(), [2, 2, 2, 2, 2, 3, 2, 2, 2, 2]);
+    }
+
+    // the qnames of the fixture `admit` lets through with `extra`, and what it counted
+    fn admitted(extra: &[&str]) -> (Vec<String>, Counts) {
+        let params = params(extra);
+        let (header, _) = parse_sam(&fixture()).unwrap();
+        let mut loci = new_loci(&params, &header);
+        let mut counts = Counts::default();
+        let ts: Vec<RecordSet> = templates((0, None)).into_iter().filter(|rs| admit(rs, &params, &mut loci, None, &mut counts)).collect();
+        (qnames(&ts), counts)
+    }
+
+    #[test]
+    fn filter_stats_count_what_admit_turns_down() {
+        let filters = ["--num", "5", "--name-regex", "r0[0-6]", "--max-tlen", "150", "--only-duplicates"];
+        let (kept, counts) = admitted(&filters);
+        let (kept_too, stats) = admitted(&[&filters[..], &["--filter-stats"]].concat());
+        assert!(kept.is_empty() && kept_too.is_empty());
+        assert_eq!((counts.misnamed, counts.off_tlen, counts.unflagged), (3, 7, 0));
+        assert_eq!((stats.misnamed, stats.off_tlen, stats.unflagged), (3, 7, 0));
+        let at = |name: &str| FILTERS.iter().position(|&f| f == name).unwrap();
+        let (regex, tlen, duplicates) = (at("--name-regex"), at("--min-tlen/--max-tlen"), at("--only-duplicates"));
+        assert_eq!(stats.filters.templates, 10);
+        assert_eq!((stats.filters.first[regex], stats.filters.first[tlen], stats.filters.first[duplicates]), (3, 7, 0));
+        assert_eq!((stats.filters.any[regex], stats.filters.any[tlen], stats.filters.any[duplicates]), (3, 10, 10));
+    }
+
+    #[test]
+    fn window_skips_then_takes_head() {
+        assert_eq!(qnames(&templates((3, Some(4)))), ["r03", "r04", "r05", "r06"]);
+        assert_eq!(qnames(&templates((8, None))), ["r08", "r09"]);
+    }
+
+    #[test]
+    fn reservoir_keeps_num_whole_templates() {
+        let sample = draw(4, 7);
+        assert_eq!(sample.len(), 4);
+        let mut names = qnames(&sample);
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), 4);
+        assert!(sample.iter().all(|rs| rs.iter().all(|r| r.qname() == rs[0].qname())));
+        assert_eq!(qnames(&draw(4, 7)), qnames(&sample));
+        assert_eq!(draw(20, 7).len(), 10);
+    }
+
+    #[test]
+    fn output_writes_the_prepared_records() {
+        let params = params(&["--num", "10", "--primary-only"]);
+        let (view, _) = parse_sam(&fixture()).unwrap();
+        let header = bam::Header::from_template(&view);
+        let mut outfh: Output<Vec<Record>> = Output::new(Sink::One(Vec::new()), &header);
+        for rs in templates((0, None)) {
+            assert_eq!(outfh.write(&rs, "-", &params), Some(2));
+        }
+        assert_eq!(outfh.n, 10);
+        let Sink::One(written) = outfh.sink else { unreachable!() };
+        assert_eq!(written.len(), 20);
+        assert!(written.iter().all(|r| !r.is_secondary()));
+    }
+
+    #[test]
+    fn sorted_output_goes_out_by_position() {
+        let params = params(&["--num", "10", "--sort-output"]);
+        let (view, _) = parse_sam(&fixture()).unwrap();
+        let header = bam::Header::from_template(&view);
+        let mut outfh: Output<Vec<Record>> = Output::new(Sink::Sorted(Vec::new(), Vec::new()), &header);
+        for rs in templates((0, None)) {
+            outfh.write(&rs, "-", &params);
+        }
+        outfh.release("-");
+        let Sink::Sorted(written, held) = outfh.sink else { unreachable!() };
+        assert!(held.is_empty());
+        assert_eq!(written.len(), 21);
+        assert!(written.windows(2).all(|x| x[0].pos() <= x[1].pos()));
+    }
 }
@@ -0,0 +1,50 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::io::Write;
+use rust_htslib::bam::Record;
+
+use crate::tags;
+
+// a TSV of the records written, one row each, for a look at the sample in pandas or R without parsing
+// the BAM again: the SAM fields worth filtering on, then the values of the tags asked for, NA if absent
+pub struct Metadata {
+    fh: Box<dyn Write>,
+    tags: Vec<[u8; 2]>,
+}
+
+impl Metadata {
+    pub fn new(mut fh: Box<dyn Write>, tags: Vec<[u8; 2]>) -> std::io::Result<Metadata> {
+        write!(fh, "qname\tflag\tchrom\tpos\tmapq\ttlen\tlength")?;
+        for tag in &tags {
+            write!(fh, "\t{}", String::from_utf8_lossy(tag))?;
+        }
+        writeln!(fh)?;
+        Ok(Metadata { fh, tags })
+    }
+
+    // `refs` names the tids; the position is 1-based, 0 and * when unmapped, as in SAM
+    pub fn add(&mut self, r: &Record, refs: &[String]) -> std::io::Result<()> {
+        let chrom = match r.tid() {
+            tid if tid >= 0 => refs.get(tid as usize).map_or("*", |x| x.as_str()),
+            _ => "*",
+        };
+        // the qname as it is, whatever its bytes
+        self.fh.write_all(r.qname())?;
+        write!(self.fh, "\t{}\t{}\t{}\t{}\t{}\t{}", r.flags(), chrom, r.pos() + 1, r.mapq(), r.insert_size(), r.seq_len())?;
+        for tag in &self.tags {
+            match r.aux(tag).ok().and_then(|a| tags::text(&a)) {
+                Some(value) => write!(self.fh, "\t{}", value)?,
+                None => write!(self.fh, "\tNA")?,
+            }
+        }
+        writeln!(self.fh)
+    }
+
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.fh.flush()
+    }
+}
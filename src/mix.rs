@@ -0,0 +1,181 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::collections::HashSet;
+use getopts::Options;
+use rust_htslib::{bam::HeaderView, bam::Read, bam::record::Aux};
+use log::{error, info};
+
+use crate::completions::{OptDef, Kind};
+use crate::header;
+use crate::ospath;
+use crate::reservoir;
+use crate::rng::{self, Seed};
+use crate::status::{self, Code, bad_args};
+use crate::strata::shares;
+use crate::{GroupBy, LogFormat};
+
+// the options of `sam_subsample mix`
+static OPTIONS: &[OptDef] = &[
+    ("i", "infile", "input BAM/SAM, sorted by queryname; repeat for every input of the mix", "FILE", Kind::Multi),
+    ("", "proportions", "comma-separated shares of the output to take from the inputs, one per --infile, e.g. 0.95,0.05 or 95,5; scaled to add up to 1", "NUMBERS", Kind::Opt),
+    ("o", "outfile", "output BAM", "FILE", Kind::Opt),
+    ("n", "num", "number of templates in all; K/M/G suffixes allowed (default: as many as the inputs have at the --proportions)", "INTEGER", Kind::Opt),
+    ("", "labels", "comma-separated labels of the inputs, which their read groups are prefixed with, e.g. normal,tumor (default: the names of the inputs, less the extension)", "STRINGS", Kind::Opt),
+    ("s", "seed", "seed, from which every input gets its own by its order (default: 43)", "INTEGER", Kind::Opt),
+    ("h", "help", "print usage", "", Kind::Flag),
+];
+
+fn usage(opts: &Options) {
+    let s = "\
+Summary:
+Mix inputs in given proportions, e.g. a tumor diluted in a normal, into one output whose read groups tell them apart
+
+Usage:
+sam_subsample mix --infile normal.bam --infile tumor.bam --proportions 0.95,0.05 --outfile mix.bam [--num 1M] [--labels normal,tumor] [--seed 43]";
+    println!("{}", opts.usage(s));
+}
+
+// the header of an input with its read groups prefixed with `label`, and a read group of `label` itself
+// for its records without one
+fn relabel(view: &HeaderView, label: &str, untagged: bool) -> HeaderView {
+    let text = String::from_utf8_lossy(view.as_bytes()).into_owned();
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.split('\n').filter(|l| !l.is_empty()) {
+        match line.starts_with("@RG") {
+            true => lines.push(line.split('\t').map(|f| match f.strip_prefix("ID:") {
+                Some(id) => format!("ID:{}.{}", label, id),
+                None => f.to_string(),
+            }).collect::<Vec<_>>().join("\t")),
+            false => lines.push(line.to_string()),
+        }
+    }
+    if untagged {
+        lines.push(format!("@RG\tID:{}\tSM:{}", label, label));
+    }
+    HeaderView::from_bytes((lines.join("\n") + "\n").as_bytes())
+}
+
+// `sam_subsample mix`, given the arguments after it
+pub fn run(args: &[String]) {
+    let mut opts = Options::new();
+    for &(short, long, desc, hint, kind) in OPTIONS {
+        match kind {
+            Kind::Flag => opts.optflag(short, long, desc),
+            Kind::Opt => opts.optopt(short, long, desc, hint),
+            Kind::Multi => opts.optmulti(short, long, desc, hint),
+            Kind::Count => opts.optflagmulti(short, long, desc),
+        };
+    }
+    let m = opts.parse(args).unwrap_or_else(|e| bad_args(&format!("failed to parse arguments: {}", e)));
+    if m.opt_present("h") {
+        usage(&opts);
+        return;
+    }
+    let infiles = m.opt_strs("infile");
+    if infiles.len() < 2 {
+        bad_args("--infile is required, once for every input of the mix, at least two");
+    }
+    let outfile = m.opt_str("outfile").unwrap_or_else(|| bad_args("--outfile is required!"));
+    let proportions: Vec<f64> = m.opt_str("proportions").unwrap_or_else(|| bad_args("--proportions is required!"))
+        .split(',')
+        .map(|x| x.trim().parse::<f64>().ok().filter(|x| x.is_finite() && *x > 0.0).unwrap_or_else(|| bad_args("invalid --proportions, e.g. 0.95,0.05")))
+        .collect();
+    if proportions.len() != infiles.len() {
+        bad_args("--proportions needs one share per --infile");
+    }
+    let total: f64 = proportions.iter().sum();
+    let proportions: Vec<f64> = proportions.iter().map(|p| p / total).collect();
+    let num = m.opt_str("num").map(|_| crate::opt_count(&m, "num", 0));
+    if num == Some(0) {
+        bad_args("--num must be positive");
+    }
+    let labels: Vec<String> = match m.opt_str("labels") {
+        Some(x) => x.split(',').map(|l| l.trim().to_string()).collect(),
+        None => infiles.iter().map(|f| ospath::path(f).file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default()).collect(),
+    };
+    if labels.len() != infiles.len() {
+        bad_args("--labels needs one label per --infile");
+    }
+    if labels.iter().any(|l| l.is_empty() || l.contains(char::is_whitespace)) {
+        bad_args("invalid --labels, must be non-empty and without spaces");
+    }
+    if labels.iter().collect::<HashSet<_>>().len() < labels.len() {
+        bad_args(match m.opt_present("labels") {
+            true => "--labels must be distinct",
+            false => "the inputs have the same names; tell them apart with --labels",
+        });
+    }
+    let seed = Seed::new(rng::Kind::Pcg64, m.opt_get_default("seed", 43).unwrap_or_else(|_| bad_args("invalid --seed, must be integer")));
+    crate::init_logger("info", None, None, LogFormat::Plain);
+
+    for f in &infiles {
+        if !ospath::path(f).exists() {
+            error!("{} does not exist!", f);
+            status::fail(Code::Missing);
+        }
+    }
+    // a first pass counts the templates of every input, and whether any record of it has no read group
+    let mut sizes: Vec<usize> = Vec::new();
+    let mut untagged: Vec<bool> = Vec::new();
+    let mut views: Vec<HeaderView> = Vec::new();
+    for infile in &infiles {
+        let mut infhs = crate::open_inputs(std::slice::from_ref(infile), 1, "queryname");
+        let has_rg = String::from_utf8_lossy(infhs[0].header().as_bytes()).split('\n').any(|l| l.starts_with("@RG"));
+        let (mut n, mut bare) = (0, !has_rg);
+        crate::for_each_template(&mut infhs, &[vec![]], None, false, GroupBy::Qname, (0, None), |rs, _| {
+            n += 1;
+            bare |= rs.iter().any(|r| r.aux(b"RG").is_err());
+            Some(rs)
+        });
+        sizes.push(n);
+        untagged.push(bare);
+        views.push(infhs[0].header().clone());
+    }
+    let num = num.unwrap_or_else(|| {
+        // the most templates whose shares every input has
+        let mut num = sizes.iter().zip(&proportions).map(|(&n, p)| (n as f64 / p).floor() as usize).min().unwrap_or_default();
+        while num > 0 && shares(num, &proportions).iter().zip(&sizes).any(|(s, n)| s > n) {
+            num -= 1;
+        }
+        num
+    });
+    let nums = shares(num, &proportions);
+    for ((infile, &n), &share) in infiles.iter().zip(&sizes).zip(&nums) {
+        if share > n {
+            error!("{} has {} templates, fewer than its share {} of --num {}!", infile, n, share, num);
+            status::fail(Code::TooFew);
+        }
+    }
+
+    let relabeled: Vec<HeaderView> = views.iter().zip(&labels).zip(&untagged).map(|((v, l), &u)| relabel(v, l, u)).collect();
+    let (merged, tid_maps) = header::merge(&relabeled);
+    let merged = header::with_sort_order(&merged, "unsorted");
+    let mut outfh = crate::open_output(&outfile, &merged, 1);
+    for (i, infile) in infiles.iter().enumerate() {
+        let picks = reservoir::pick_indices(sizes[i], nums[i], seed.nth(i as u64));
+        let mut infhs = crate::open_inputs(std::slice::from_ref(infile), 1, "queryname");
+        let (mut k, mut next) = (0, 0);
+        crate::for_each_template(&mut infhs, std::slice::from_ref(&tid_maps[i]), None, false, GroupBy::Qname, (0, None), |mut rs, _| {
+            if picks.get(next) == Some(&k) {
+                next += 1;
+                for r in rs.iter_mut() {
+                    let rg = match r.aux(b"RG") {
+                        Ok(Aux::String(id)) => format!("{}.{}", labels[i], id),
+                        _ => labels[i].clone(),
+                    };
+                    let _ = r.remove_aux(b"RG");
+                    r.push_aux(b"RG", Aux::String(&rg)).expect("failed to set RG!");
+                    crate::write(&mut outfh, r, &outfile);
+                }
+            }
+            k += 1;
+            Some(rs)
+        });
+        info!("{}: {} of {} templates ({:.2}%) as read group {}", infile, nums[i], sizes[i], 100.0 * proportions[i], labels[i]);
+    }
+    info!("{} templates mixed into {}.", num, outfile);
+}
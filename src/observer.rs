@@ -0,0 +1,207 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::time::{Duration, Instant};
+use rust_htslib::{bam::Record, errors::Error};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+use crate::backend::{each_template, RecordSource};
+use crate::reservoir::{RecordSet, Reservoir};
+use crate::rng::Seed;
+
+// how many templates go by between calls to `Observer::on_progress`
+pub static PROGRESS_EVERY: usize = 100_000;
+
+// what a program subsampling through `sample` is told as it goes, to drive a progress bar or
+// gather its own metrics from; every hook does nothing unless implemented
+pub trait Observer {
+    // every PROGRESS_EVERY templates read, and once more when the input is done
+    fn on_progress(&mut self, _templates_seen: usize) {}
+
+    // a template of the sample, once the input is done and the sample is final
+    fn on_template_selected(&mut self, _rs: &[Record]) {}
+}
+
+// for when nothing is to be observed
+impl Observer for () {}
+
+// templates and records, and how many of the templates are paired, single-end or unmapped (no
+// primary read aligned)
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Tally {
+    pub templates: usize,
+    pub records: usize,
+    pub paired: usize,
+    pub single: usize,
+    pub unmapped: usize,
+}
+
+impl Tally {
+    fn add(&mut self, rs: &[Record]) {
+        self.templates += 1;
+        self.records += rs.len();
+        match rs.iter().any(|r| r.is_paired()) {
+            true => self.paired += 1,
+            false => self.single += 1,
+        }
+        if rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary()).all(|r| r.is_unmapped()) {
+            self.unmapped += 1;
+        }
+    }
+}
+
+impl Serialize for Tally {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("Tally", 5)?;
+        s.serialize_field("templates", &self.templates)?;
+        s.serialize_field("records", &self.records)?;
+        s.serialize_field("paired", &self.paired)?;
+        s.serialize_field("single", &self.single)?;
+        s.serialize_field("unmapped", &self.unmapped)?;
+        s.end()
+    }
+}
+
+// what `run` did: what it was asked for, what it read and what it kept, and how long it took; it
+// serializes with the durations in seconds
+#[derive(Clone, Debug, PartialEq)]
+pub struct SamplingStats {
+    pub num: usize,
+    pub seed: u64,
+    pub rng: &'static str,
+    pub seen: Tally,
+    pub sampled: Tally,
+    pub elapsed: Duration,
+}
+
+impl Serialize for SamplingStats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("SamplingStats", 6)?;
+        s.serialize_field("num", &self.num)?;
+        s.serialize_field("seed", &self.seed)?;
+        s.serialize_field("rng", self.rng)?;
+        s.serialize_field("seen", &self.seen)?;
+        s.serialize_field("sampled", &self.sampled)?;
+        s.serialize_field("elapsed", &self.elapsed.as_secs_f64())?;
+        s.end()
+    }
+}
+
+// a uniform sample of `num` of the templates read off `infh`, as the command line draws it with the same
+// `seed` (and generator) and a single thread; the templates come back in the order the reservoir holds them
+pub fn sample<R: RecordSource + ?Sized, O: Observer + ?Sized>(infh: &mut R, num: usize, seed: Seed, observer: &mut O) -> Result<Vec<RecordSet>, Error> {
+    run(infh, num, seed, observer).map(|x| x.0)
+}
+
+// `sample`, along with the stats of the run
+pub fn run<R: RecordSource + ?Sized, O: Observer + ?Sized>(infh: &mut R, num: usize, seed: Seed, observer: &mut O) -> Result<(Vec<RecordSet>, SamplingStats), Error> {
+    let start = Instant::now();
+    let mut res: Reservoir = Reservoir::new(num, seed);
+    let mut seen = Tally::default();
+    each_template(infh, |rs| {
+        seen.add(&rs);
+        res.sample(rs);
+        if res.k.is_multiple_of(PROGRESS_EVERY) {
+            observer.on_progress(res.k);
+        }
+    })?;
+    // once at the end, unless the last template was reported already
+    if res.k == 0 || !res.k.is_multiple_of(PROGRESS_EVERY) {
+        observer.on_progress(res.k);
+    }
+    let mut sampled = Tally::default();
+    for rs in &res.v {
+        sampled.add(rs);
+        observer.on_template_selected(rs);
+    }
+    let stats = SamplingStats {
+        num,
+        seed: seed.value,
+        rng: seed.kind.name(),
+        seen,
+        sampled,
+        elapsed: start.elapsed(),
+    };
+    Ok((res.v, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Records;
+    use crate::rng::Kind;
+
+    // what the hooks were called with
+    #[derive(Default)]
+    struct Seen {
+        progress: Vec<usize>,
+        selected: Vec<Vec<u8>>,
+    }
+
+    impl Observer for Seen {
+        fn on_progress(&mut self, templates_seen: usize) {
+            self.progress.push(templates_seen);
+        }
+
+        fn on_template_selected(&mut self, rs: &[Record]) {
+            self.selected.push(rs[0].qname().to_vec());
+        }
+    }
+
+    // `n` templates of two mates each
+    fn records(n: usize) -> Records<impl Iterator<Item = Record>> {
+        Records((0..n).flat_map(|i| {
+            (0..2).map(move |_| {
+                let mut r = Record::new();
+                r.set(format!("t{}", i).as_bytes(), None, b"ACGT", &[30; 4]);
+                r.set_paired();
+                r
+            })
+        }))
+    }
+
+    #[test]
+    fn progress_comes_every_so_many_templates_and_at_the_end() {
+        let mut seen = Seen::default();
+        run(&mut records(2 * PROGRESS_EVERY + 5), 3, Seed::new(Kind::Pcg64, 43), &mut seen).unwrap();
+        assert_eq!(seen.progress, [PROGRESS_EVERY, 2 * PROGRESS_EVERY, 2 * PROGRESS_EVERY + 5]);
+        // not twice for the last template
+        let mut seen = Seen::default();
+        run(&mut records(PROGRESS_EVERY), 3, Seed::new(Kind::Pcg64, 43), &mut seen).unwrap();
+        assert_eq!(seen.progress, [PROGRESS_EVERY]);
+        let mut seen = Seen::default();
+        run(&mut records(0), 3, Seed::new(Kind::Pcg64, 43), &mut seen).unwrap();
+        assert_eq!(seen.progress, [0]);
+    }
+
+    #[test]
+    fn the_selected_are_the_sample() {
+        let mut seen = Seen::default();
+        let (sample, stats) = run(&mut records(50), 10, Seed::new(Kind::Xoshiro, 7), &mut seen).unwrap();
+        let qnames: Vec<Vec<u8>> = sample.iter().map(|rs| rs[0].qname().to_vec()).collect();
+        assert_eq!(qnames.len(), 10);
+        assert_eq!(seen.selected, qnames);
+        assert_eq!(stats.seen, Tally { templates: 50, records: 100, paired: 50, single: 0, unmapped: 0 });
+        assert_eq!(stats.sampled, Tally { templates: 10, records: 20, paired: 10, single: 0, unmapped: 0 });
+        // as `sample` draws them
+        let again = super::sample(&mut records(50), 10, Seed::new(Kind::Xoshiro, 7), &mut ()).unwrap();
+        assert!(again.iter().map(|rs| rs[0].qname()).eq(qnames.iter().map(Vec::as_slice)));
+    }
+
+    #[test]
+    fn stats_serialize_as_they_are() {
+        let (_, stats) = run(&mut records(20), 5, Seed::new(Kind::ChaCha, 43), &mut ()).unwrap();
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["num"], 5);
+        assert_eq!(json["seed"], 43);
+        assert_eq!(json["rng"], Kind::ChaCha.name());
+        assert_eq!(json["seen"]["templates"], 20);
+        assert_eq!(json["seen"]["records"], 40);
+        assert_eq!(json["sampled"]["templates"], 5);
+        assert_eq!(json["sampled"]["paired"], 5);
+        assert_eq!(json["elapsed"], stats.elapsed.as_secs_f64());
+    }
+}
@@ -0,0 +1,88 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+// file names as the command line and the environment give them, which are bytes and need not be
+// UTF-8: they are carried around as the other options are, as text, every byte not part of valid
+// UTF-8 standing in as a character of its own at the end of the last private use plane, U+10FF80 to
+// U+10FFFF, and are turned back into those bytes where a file is opened, so the file opened is the
+// one named whatever the name is
+const ESCAPE: u32 = 0x10_ff00;
+
+// the arguments of the command line, as text
+pub fn args() -> Vec<String> {
+    env::args_os().map(|a| escape(&a)).collect()
+}
+
+// `s` as text, the bytes not part of valid UTF-8 escaped
+pub fn escape(s: &OsStr) -> String {
+    let mut out = String::new();
+    let mut bytes = s.as_bytes();
+    while !bytes.is_empty() {
+        match std::str::from_utf8(bytes) {
+            Ok(x) => {
+                out.push_str(x);
+                break;
+            },
+            Err(e) => {
+                let (ok, rest) = bytes.split_at(e.valid_up_to());
+                out.push_str(std::str::from_utf8(ok).unwrap_or_default());
+                let bad = e.error_len().unwrap_or(rest.len());
+                out.extend(rest[..bad].iter().filter_map(|&b| char::from_u32(ESCAPE + u32::from(b))));
+                bytes = &rest[bad..];
+            },
+        }
+    }
+    out
+}
+
+// the file `s` names, its escaped bytes back as they were
+pub fn path(s: &str) -> PathBuf {
+    let mut bytes = Vec::with_capacity(s.len());
+    for c in s.chars() {
+        match u32::from(c) {
+            x if (ESCAPE + 0x80..=ESCAPE + 0xff).contains(&x) => bytes.push((x - ESCAPE) as u8),
+            _ => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+// `path` as text, for one made here, e.g. in the temporary directory
+pub fn text(path: &Path) -> String {
+    escape(path.as_os_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utf8_goes_through_as_it_is() {
+        assert_eq!(escape(OsStr::new("input é.bam")), "input é.bam");
+        assert_eq!(path("input é.bam"), PathBuf::from("input é.bam"));
+    }
+
+    #[test]
+    fn other_bytes_come_back_as_they_were() {
+        for name in [&b"caf\xe9.bam"[..], b"\xff\xfe", b"a\xc3", b"\xe2\x82.sam", b"x\x80y\xc3\xa9"] {
+            let os = OsStr::from_bytes(name);
+            let text = escape(os);
+            assert_eq!(path(&text).as_os_str(), os, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn extensions_survive() {
+        let text = escape(OsStr::from_bytes(b"caf\xe9.bam"));
+        assert!(text.ends_with(".bam"));
+        assert_eq!(path(&text).extension(), Some(OsStr::new("bam")));
+    }
+}
@@ -0,0 +1,377 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::collections::HashMap;
+use rust_htslib::{bam::Record, errors::Error};
+
+use crate::backend::{each_template, RecordSource};
+use crate::reservoir::{RecordSet, Reservoir};
+use crate::rng::Seed;
+use crate::sampler::{Bernoulli, HashThreshold, Sampler, Systematic, Weighted};
+use crate::tagging::{parse_list, passes, text, TagEdit, TagFilter};
+
+// whether a template goes on to be sampled
+pub trait Filter {
+    fn admits(&mut self, rs: &[Record]) -> bool;
+}
+
+impl<F: FnMut(&[Record]) -> bool> Filter for F {
+    fn admits(&mut self, rs: &[Record]) -> bool {
+        self(rs)
+    }
+}
+
+// what is done to a template of the sample before it goes out
+pub trait Transform {
+    fn apply(&mut self, rs: &mut RecordSet);
+}
+
+impl<F: FnMut(&mut RecordSet)> Transform for F {
+    fn apply(&mut self, rs: &mut RecordSet) {
+        self(rs)
+    }
+}
+
+// the group a template is sampled within; None for a template of no group, which is left out
+pub type Key = Box<dyn FnMut(&[Record]) -> Option<Vec<u8>>>;
+
+// a sampler for the group of a key, made when its first template shows up
+pub type MakeSampler = Box<dyn FnMut(&[u8]) -> Box<dyn Sampler>>;
+
+struct Group {
+    sampler: Box<dyn Sampler>,
+    kept: Vec<RecordSet>,
+    held: Vec<RecordSet>,
+}
+
+// templates taken one at a time through filters, into the group of their key, each group sampled on
+// its own, and the templates of the sample through transforms at the end: what the command line does
+// with its options, put together out of stages instead. Without a key, every template is of one group
+pub struct Pipeline {
+    filters: Vec<Box<dyn Filter>>,
+    key: Option<Key>,
+    make_sampler: MakeSampler,
+    transforms: Vec<Box<dyn Transform>>,
+    // the groups in the order their first template showed up, and the index of every key among them
+    groups: Vec<Group>,
+    index: HashMap<Vec<u8>, usize>,
+    // templates offered, left out by a filter, and of no group
+    pub seen: usize,
+    pub filtered: usize,
+    pub ungrouped: usize,
+}
+
+impl Pipeline {
+    pub fn new(make_sampler: MakeSampler) -> Pipeline {
+        Pipeline {
+            filters: Vec::new(),
+            key: None,
+            make_sampler,
+            transforms: Vec::new(),
+            groups: Vec::new(),
+            index: HashMap::new(),
+            seen: 0,
+            filtered: 0,
+            ungrouped: 0,
+        }
+    }
+
+    pub fn filter<F: Filter + 'static>(mut self, f: F) -> Pipeline {
+        self.filters.push(Box::new(f));
+        self
+    }
+
+    pub fn group_by(mut self, key: Key) -> Pipeline {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn transform<T: Transform + 'static>(mut self, t: T) -> Pipeline {
+        self.transforms.push(Box::new(t));
+        self
+    }
+
+    // the groups made so far
+    pub fn groups(&self) -> usize {
+        self.groups.len()
+    }
+
+    pub fn offer(&mut self, rs: RecordSet) {
+        self.seen += 1;
+        if !self.filters.iter_mut().all(|f| f.admits(&rs)) {
+            self.filtered += 1;
+            return;
+        }
+        let key = match &mut self.key {
+            Some(key) => match key(&rs) {
+                Some(k) => k,
+                None => {
+                    self.ungrouped += 1;
+                    return;
+                },
+            },
+            None => Vec::new(),
+        };
+        let i = match self.index.get(&key) {
+            Some(&i) => i,
+            None => {
+                let sampler = (self.make_sampler)(&key);
+                self.groups.push(Group { sampler, kept: Vec::new(), held: Vec::new() });
+                self.index.insert(key, self.groups.len() - 1);
+                self.groups.len() - 1
+            },
+        };
+        let group = &mut self.groups[i];
+        group.sampler.observe(&rs).place(rs, &mut group.kept, &mut group.held);
+    }
+
+    // the sample, transformed: group by group, those kept in input order, then those held by slot
+    pub fn finish(mut self) -> Vec<RecordSet> {
+        let mut out: Vec<RecordSet> = Vec::new();
+        for mut group in self.groups.drain(..) {
+            out.append(&mut group.kept);
+            out.append(&mut group.held);
+        }
+        for rs in out.iter_mut() {
+            self.transforms.iter_mut().for_each(|t| t.apply(rs));
+        }
+        out
+    }
+
+    // the sample of all the templates of `infh`, offered as `backend::each_template` cuts them
+    pub fn run<R: RecordSource + ?Sized>(mut self, infh: &mut R) -> Result<Vec<RecordSet>, Error> {
+        each_template(infh, |rs| self.offer(rs))?;
+        Ok(self.finish())
+    }
+}
+
+fn primary(r: &Record) -> bool {
+    !r.is_secondary() && !r.is_supplementary()
+}
+
+// the value of `tag` on the first primary record of a template that has it
+fn tag_value(rs: &[Record], tag: &[u8; 2]) -> Option<String> {
+    rs.iter().filter(|r| primary(r)).find_map(|r| r.aux(tag).ok().and_then(|a| text(&a)))
+}
+
+fn tags_of(x: &str) -> Result<Vec<[u8; 2]>, String> {
+    parse_list(x).ok_or_else(|| format!("'{}' is not a tag, or comma-separated tags", x))
+}
+
+fn tag_of(x: &str) -> Result<[u8; 2], String> {
+    match tags_of(x)?.as_slice() {
+        &[tag] => Ok(tag),
+        _ => Err(format!("'{}' is not a tag", x)),
+    }
+}
+
+fn number<T: std::str::FromStr>(stage: &str, x: &str) -> Result<T, String> {
+    x.parse().map_err(|_| format!("'{}' is not a number in stage '{}'", x, stage))
+}
+
+// a pipeline of stages written out, separated by '|', as --pipeline takes it, e.g.
+// 'min-mapq:30 | group-by:tag:CB | weighted:1000:XW | drop-tags:OQ,BI,BD'; filters and the grouping
+// come first, then a single sampler, then transforms:
+//   filters      min-mapq:N (every mapped primary read), require-tag:XX[:VALUE] (every primary read),
+//                exclude-tag:XX[:VALUE] (no primary read), as --require-tag and --exclude-tag
+//   grouping     group-by:tag:XX (by the value on the first primary read that has it), group-by:read-group
+//   samplers     reservoir:N, weighted:N:XX (by the numeric tag XX of the first primary read),
+//                bernoulli:F, hash:F, every:K; with a grouping, N is per group
+//   transforms   primary-only, drop-tags:XX,YY, keep-tags:XX,YY
+// every group draws with a seed of its own, from `seed` and its key, so that the sample of a group
+// stays the same whatever the others
+pub fn parse(spec: &str, seed: Seed) -> Result<Pipeline, String> {
+    // 0 for filters and the grouping, 1 once the sampler is given, for transforms
+    let mut stage = 0;
+    let mut filters: Vec<Box<dyn FnMut(&[Record]) -> bool>> = Vec::new();
+    let mut key: Option<Key> = None;
+    let mut make_sampler: Option<MakeSampler> = None;
+    let mut transforms: Vec<Box<dyn FnMut(&mut RecordSet)>> = Vec::new();
+    for part in spec.split('|').map(str::trim) {
+        let (name, arg) = part.split_once(':').unwrap_or((part, ""));
+        let order = |want: usize| match stage == want {
+            true => Ok(()),
+            false => Err(format!("stage '{}' is out of order; filters and group-by come before the sampler, transforms after", part)),
+        };
+        match name {
+            "min-mapq" => {
+                order(0)?;
+                let min: u8 = number(part, arg)?;
+                filters.push(Box::new(move |rs: &[Record]| rs.iter().filter(|r| primary(r) && !r.is_unmapped()).all(|r| r.mapq() >= min)));
+            },
+            "require-tag" | "exclude-tag" => {
+                order(0)?;
+                // TAG:VALUE as the options take it, or TAG alone for any value
+                let condition = match arg.contains(':') {
+                    true => TagFilter::parse(arg),
+                    false => TagFilter::parse(&format!("{}:*", arg)),
+                };
+                let condition = condition.ok_or_else(|| format!("invalid stage '{}', e.g. {}:NH:1 or {}:vA", part, name, name))?;
+                let (require, exclude) = match name {
+                    "require-tag" => (vec![condition], Vec::new()),
+                    _ => (Vec::new(), vec![condition]),
+                };
+                filters.push(Box::new(move |rs: &[Record]| passes(rs, &require, &exclude)));
+            },
+            "group-by" => {
+                order(0)?;
+                if key.is_some() {
+                    return Err(String::from("more than one group-by stage"));
+                }
+                let tag = match arg {
+                    "read-group" => *b"RG",
+                    _ => match arg.strip_prefix("tag:") {
+                        Some(t) => tag_of(t)?,
+                        None => return Err(format!("invalid stage '{}', choose from group-by:tag:XX, group-by:read-group", part)),
+                    },
+                };
+                key = Some(Box::new(move |rs: &[Record]| tag_value(rs, &tag).map(String::into_bytes)));
+            },
+            "reservoir" | "weighted" | "bernoulli" | "hash" | "every" => {
+                order(0)?;
+                stage = 1;
+                let make: MakeSampler = match name {
+                    "reservoir" => {
+                        let num: usize = number(part, arg)?;
+                        Box::new(move |k: &[u8]| Box::new(Reservoir::<RecordSet>::new(num, seed.derive(k))) as Box<dyn Sampler>)
+                    },
+                    "weighted" => {
+                        let (num, tag) = arg.split_once(':').ok_or_else(|| format!("invalid stage '{}', e.g. weighted:1000:XW", part))?;
+                        let (num, tag): (usize, [u8; 2]) = (number(part, num)?, tag_of(tag)?);
+                        Box::new(move |k: &[u8]| {
+                            let weight = move |rs: &[Record]| tag_value(rs, &tag).and_then(|x| x.parse::<f64>().ok()).unwrap_or(0.0);
+                            Box::new(Weighted::new(num, seed.derive(k), weight)) as Box<dyn Sampler>
+                        })
+                    },
+                    "bernoulli" | "hash" => {
+                        let fraction: f64 = number(part, arg)?;
+                        if !(0.0..=1.0).contains(&fraction) {
+                            return Err(format!("stage '{}' needs a fraction in [0, 1]", part));
+                        }
+                        match name {
+                            "bernoulli" => Box::new(move |k: &[u8]| Box::new(Bernoulli::new(fraction, seed.derive(k))) as Box<dyn Sampler>),
+                            // the same seed for every group, so that a template is kept or not whichever it is in
+                            _ => Box::new(move |_: &[u8]| Box::new(HashThreshold::new(fraction, seed)) as Box<dyn Sampler>),
+                        }
+                    },
+                    _ => {
+                        let every: usize = number(part, arg)?;
+                        if every == 0 {
+                            return Err(format!("stage '{}' needs a positive number", part));
+                        }
+                        Box::new(move |_: &[u8]| Box::new(Systematic::new(every, 0)) as Box<dyn Sampler>)
+                    },
+                };
+                make_sampler = Some(make);
+            },
+            "primary-only" => {
+                order(1)?;
+                transforms.push(Box::new(|rs: &mut RecordSet| rs.retain(primary)));
+            },
+            "drop-tags" | "keep-tags" => {
+                order(1)?;
+                let edit = match name {
+                    "drop-tags" => TagEdit::Drop(tags_of(arg)?),
+                    _ => TagEdit::Keep(tags_of(arg)?),
+                };
+                transforms.push(Box::new(move |rs: &mut RecordSet| rs.iter_mut().for_each(|r| edit.apply(r))));
+            },
+            _ => return Err(format!("unknown stage '{}'", part)),
+        }
+    }
+    let make_sampler = make_sampler.ok_or_else(|| String::from("no sampler stage, e.g. reservoir:1000"))?;
+    // through the builder, as a program of its own would put them together
+    let mut pipeline = Pipeline::new(make_sampler);
+    for f in filters {
+        pipeline = pipeline.filter(f);
+    }
+    if let Some(key) = key {
+        pipeline = pipeline.group_by(key);
+    }
+    for t in transforms {
+        pipeline = pipeline.transform(t);
+    }
+    Ok(pipeline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::Aux;
+    use crate::backend::Records;
+    use crate::rng::Kind;
+
+    // a record of template `qname`, secondary or not, with the tags given
+    fn record(qname: &str, secondary: bool, tags: &[(&[u8; 2], Aux)]) -> Record {
+        let mut r = Record::new();
+        r.set(qname.as_bytes(), None, b"ACGT", &[30; 4]);
+        if secondary {
+            r.set_secondary();
+        }
+        for (tag, value) in tags {
+            r.push_aux(*tag, value.clone()).unwrap();
+        }
+        r
+    }
+
+    fn sample(spec: &str, records: Vec<Record>) -> Vec<String> {
+        let stages = parse(spec, Seed::new(Kind::Pcg64, 43)).unwrap();
+        let sample = stages.run(&mut Records(records.into_iter())).unwrap();
+        sample.iter().map(|rs| String::from_utf8_lossy(rs[0].qname()).into_owned()).collect()
+    }
+
+    fn mates(qname: &str, first: Option<Aux<'static>>, second: Option<Aux<'static>>) -> Vec<Record> {
+        vec![first, second].into_iter().map(|x| match x {
+            Some(value) => record(qname, false, &[(b"NH", value)]),
+            None => record(qname, false, &[]),
+        }).collect()
+    }
+
+    #[test]
+    fn tags_are_required_of_every_primary_read() {
+        let mut records = mates("both", Some(Aux::U8(1)), Some(Aux::U8(1)));
+        records.extend(mates("one", Some(Aux::U8(1)), None));
+        records.extend(mates("other", Some(Aux::U8(1)), Some(Aux::U8(2))));
+        // a secondary record without it does not count
+        records.extend(mates("secondary", Some(Aux::U8(1)), Some(Aux::U8(1))));
+        records.push(record("secondary", true, &[]));
+        assert_eq!(sample("require-tag:NH | every:1", records.clone()), ["both", "other", "secondary"]);
+        assert_eq!(sample("require-tag:NH:1 | every:1", records.clone()), ["both", "secondary"]);
+        assert_eq!(sample("exclude-tag:NH:2 | every:1", records), ["both", "one", "secondary"]);
+    }
+
+    #[test]
+    fn stages_take_the_tags_as_the_options_do() {
+        assert!(parse("require-tag:N | every:1", Seed::new(Kind::Pcg64, 43)).is_err());
+        assert!(parse("require-tag:NH: | every:1", Seed::new(Kind::Pcg64, 43)).is_err());
+        assert!(parse("every:1 | drop-tags:OQ,B", Seed::new(Kind::Pcg64, 43)).is_err());
+        let stages = parse("every:1 | keep-tags:NM", Seed::new(Kind::Pcg64, 43)).unwrap();
+        let records = vec![record("a", false, &[(b"NM", Aux::U8(2)), (b"OQ", Aux::String("IIII"))])];
+        let sample = stages.run(&mut Records(records.into_iter())).unwrap();
+        assert!(sample[0][0].aux(b"NM").is_ok());
+        assert!(sample[0][0].aux(b"OQ").is_err());
+    }
+
+    #[test]
+    fn built_stages_sample_as_parsed_ones() {
+        let mut records = Vec::new();
+        for (i, cb) in ["A", "B", "A", "B", "C", "A"].iter().enumerate() {
+            records.extend(mates(&format!("t{}", i), Some(Aux::String(*cb)), Some(Aux::String(*cb))));
+        }
+        records.push(record("t6", false, &[]));
+        records.push(record("t6", true, &[]));
+        let exclude = vec![TagFilter::parse("NH:C").unwrap()];
+        let built = Pipeline::new(Box::new(|_: &[u8]| Box::new(Systematic::new(2, 0)) as Box<dyn Sampler>))
+            .filter(move |rs: &[Record]| passes(rs, &[], &exclude))
+            .group_by(Box::new(|rs: &[Record]| tag_value(rs, b"NH").map(String::into_bytes)))
+            .transform(|rs: &mut RecordSet| rs.retain(primary));
+        let picked = built.run(&mut Records(records.clone().into_iter())).unwrap();
+        // every other template of A, then of B; C left out, and t6 of no group
+        let qnames: Vec<String> = picked.iter().map(|rs| String::from_utf8_lossy(rs[0].qname()).into_owned()).collect();
+        assert_eq!(qnames, ["t0", "t5", "t1"]);
+        assert_eq!(qnames, sample("exclude-tag:NH:C | group-by:tag:NH | every:2 | primary-only", records));
+    }
+}
@@ -0,0 +1,106 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use log::info;
+
+// where the time of --timing goes
+#[derive(Clone, Copy)]
+pub enum Phase {
+    // reading and decoding records
+    Read,
+    // putting records together into templates
+    Group,
+    // filtering templates and drawing the sample
+    Select,
+    // encoding, compressing and writing records
+    Write,
+}
+
+static NAMES: [&str; 4] = ["reading", "grouping", "selection", "writing"];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// nanoseconds spent in every phase, over all threads
+static NANOS: [AtomicU64; 4] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+static RECORDS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // the phase this thread is in, and since when it was last charged
+    static CURRENT: Cell<Option<(Phase, Instant)>> = const { Cell::new(None) };
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+fn charge(phase: Phase, since: Instant, now: Instant) {
+    NANOS[phase as usize].fetch_add((now - since).as_nanos() as u64, Ordering::Relaxed);
+}
+
+// in `phase` until dropped; phases nest, the time of an inner one not counting towards the outer, so
+// that every moment is charged to exactly one phase
+pub struct Span {
+    outer: Option<Phase>,
+    on: bool,
+}
+
+pub fn enter(phase: Phase) -> Span {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Span { outer: None, on: false };
+    }
+    let outer = CURRENT.with(|c| {
+        let now = Instant::now();
+        let outer = c.get();
+        if let Some((p, since)) = outer {
+            charge(p, since, now);
+        }
+        c.set(Some((phase, now)));
+        outer
+    });
+    Span { outer: outer.map(|x| x.0), on: true }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if !self.on {
+            return;
+        }
+        CURRENT.with(|c| {
+            let now = Instant::now();
+            if let Some((p, since)) = c.get() {
+                charge(p, since, now);
+            }
+            c.set(self.outer.map(|p| (p, now)));
+        });
+    }
+}
+
+// run `f` in `phase`
+pub fn time<T, F: FnOnce() -> T>(phase: Phase, f: F) -> T {
+    let _span = enter(phase);
+    f()
+}
+
+pub fn count_record() {
+    if ENABLED.load(Ordering::Relaxed) {
+        RECORDS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// log the time of every phase against the `wall` time of the run, what is left over being setup,
+// counting passes and the like, and the throughput in records read per second
+pub fn report(wall: Duration) {
+    let wall = wall.as_secs_f64();
+    let secs: Vec<f64> = NANOS.iter().map(|x| x.load(Ordering::Relaxed) as f64 / 1e9).collect();
+    let pct = |x: f64| if wall > 0.0 { 100.0 * x / wall } else { 0.0 };
+    let phases: Vec<String> = NAMES.iter().zip(&secs).map(|(name, &x)| format!("{} {:.3}s ({:.1}%)", name, x, pct(x))).collect();
+    let other = (wall - secs.iter().sum::<f64>()).max(0.0);
+    info!("Timing: {}, other {:.3}s ({:.1}%), of {:.3}s wall clock.", phases.join(", "), other, pct(other), wall);
+    let records = RECORDS.load(Ordering::Relaxed);
+    info!("Throughput: {} records read in {:.3}s, {:.0} records/s.", records, wall, if wall > 0.0 { records as f64 / wall } else { 0.0 });
+}
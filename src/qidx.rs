@@ -0,0 +1,120 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::time::UNIX_EPOCH;
+
+use crate::ospath;
+
+static MAGIC: &[u8; 8] = b"SSQIDX1\n";
+// bytes of a template: its input (u32), the virtual offset of its first record (i64) and its records (u32)
+const ENTRY: u64 = 16;
+
+// what a --cache-index is only good for: the inputs, by path, size and modification time, and the options
+// deciding which templates pass the filters, as `options` has them
+pub fn fingerprint(infiles: &[String], options: &str) -> String {
+    let mut parts: Vec<String> = infiles.iter().map(|f| {
+        let (len, mtime) = fs::metadata(ospath::path(f)).map(|m| {
+            let mtime = m.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+            (m.len(), mtime.as_nanos())
+        }).unwrap_or_default();
+        format!("{}\t{}\t{}", f, len, mtime)
+    }).collect();
+    parts.push(options.to_string());
+    parts.join("\n")
+}
+
+// the where of the templates passing the filters, in input order, written to `path` as they are found: the
+// magic, the length of the fingerprint and the fingerprint, then an entry of `ENTRY` bytes for every
+// template; it goes to a temporary file until `finish`, so that a run stopped halfway leaves none behind
+pub struct Writer {
+    fh: BufWriter<File>,
+    tmp: String,
+    path: String,
+    pub n: usize,
+}
+
+impl Writer {
+    pub fn create(path: &str, fingerprint: &str) -> io::Result<Writer> {
+        let tmp = format!("{}.tmp", path);
+        let mut fh = BufWriter::new(File::create(ospath::path(&tmp))?);
+        fh.write_all(MAGIC)?;
+        fh.write_all(&(fingerprint.len() as u64).to_le_bytes())?;
+        fh.write_all(fingerprint.as_bytes())?;
+        Ok(Writer { fh, tmp, path: path.to_string(), n: 0 })
+    }
+
+    pub fn add(&mut self, file: usize, offset: i64, records: usize) -> io::Result<()> {
+        self.fh.write_all(&(file as u32).to_le_bytes())?;
+        self.fh.write_all(&offset.to_le_bytes())?;
+        self.fh.write_all(&(records as u32).to_le_bytes())?;
+        self.n += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.fh.flush()?;
+        drop(self.fh);
+        fs::rename(ospath::path(&self.tmp), ospath::path(&self.path))
+    }
+
+    // leave no index behind, for a run that did not read the inputs to the end
+    pub fn remove(self) {
+        drop(self.fh);
+        let _ = fs::remove_file(ospath::path(&self.tmp));
+    }
+}
+
+// an index of `path` whose fingerprint is `fingerprint`, read entry by entry
+pub struct Index {
+    fh: BufReader<File>,
+    // where the entries start, and how many there are
+    start: u64,
+    pub n: usize,
+}
+
+impl Index {
+    // None if there is no index at `path`, or one of other inputs or options
+    pub fn open(path: &str, fingerprint: &str) -> io::Result<Option<Index>> {
+        let mut fh = match File::open(ospath::path(path)) {
+            Ok(f) => BufReader::new(f),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut magic = [0u8; 8];
+        let mut len = [0u8; 8];
+        if fh.read_exact(&mut magic).is_err() || &magic != MAGIC || fh.read_exact(&mut len).is_err() {
+            return Ok(None);
+        }
+        let len = u64::from_le_bytes(len);
+        if len != fingerprint.len() as u64 {
+            return Ok(None);
+        }
+        let mut stored = vec![0u8; len as usize];
+        fh.read_exact(&mut stored)?;
+        if stored != fingerprint.as_bytes() {
+            return Ok(None);
+        }
+        let start = 16 + len;
+        let size = fh.get_ref().metadata()?.len();
+        if (size - start) % ENTRY != 0 {
+            return Ok(None);
+        }
+        Ok(Some(Index { fh, start, n: ((size - start) / ENTRY) as usize }))
+    }
+
+    // the (input, virtual offset, records) of the `i`th template
+    pub fn get(&mut self, i: usize) -> io::Result<(usize, i64, usize)> {
+        self.fh.seek(SeekFrom::Start(self.start + i as u64 * ENTRY))?;
+        let mut entry = [0u8; ENTRY as usize];
+        self.fh.read_exact(&mut entry)?;
+        let file = u32::from_le_bytes(entry[0..4].try_into().unwrap_or_default());
+        let offset = i64::from_le_bytes(entry[4..12].try_into().unwrap_or_default());
+        let records = u32::from_le_bytes(entry[12..16].try_into().unwrap_or_default());
+        Ok((file as usize, offset, records as usize))
+    }
+}
@@ -0,0 +1,166 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU8, Ordering as Atomic};
+use log::{error, warn};
+
+use crate::status::{self, Code};
+
+// what --on-regrouped does with a qname turning up again after its template closed
+#[derive(Clone, Copy, PartialEq)]
+pub enum OnRegrouped {
+    Warn,
+    Fail,
+}
+
+// 0 when not checking, else 1 + the policy
+static POLICY: AtomicU8 = AtomicU8::new(0);
+
+// bits of the Bloom filter, 128 MiB, and bits set per qname
+const BITS: usize = 1 << 30;
+const HASHES: u64 = 7;
+// templates closed last, whose qnames are held as they are
+const RECENT: usize = 1024;
+
+pub fn enable(policy: OnRegrouped) {
+    POLICY.store(1 + policy as u8, Atomic::Relaxed);
+}
+
+// the qnames of the templates of an input closed so far, to tell those turning up again: an input
+// merged or edited by hand may claim to be sorted by queryname and still split templates, which would
+// then be sampled twice over. Whatever the collation, only identical qnames have to be next to each
+// other, so that the last `RECENT` are always held and one found among them again is certain, which
+// is what a merge of inputs sorted by different collations leaves. With --on-regrouped, every other
+// qname goes into a Bloom filter too; as long as the qnames have only gone up, in byte order or in
+// the natural order of `samtools sort -n`, no template can have been split, so that the false
+// positives of the filter only count where it is not
+pub struct Check {
+    policy: OnRegrouped,
+    bits: Option<Vec<u64>>,
+    recent: VecDeque<Vec<u8>>,
+    held: HashSet<Vec<u8>>,
+    prev: Vec<u8>,
+    lexical: bool,
+    natural: bool,
+    // qnames found again, and the first of them
+    found: usize,
+    first: Option<String>,
+}
+
+// a check for a pass over the inputs; without --on-regrouped, of the recent qnames only, and warning
+pub fn check() -> Check {
+    let (policy, bits) = match POLICY.load(Atomic::Relaxed) {
+        0 => (OnRegrouped::Warn, None),
+        1 => (OnRegrouped::Warn, Some(vec![0; BITS / 64])),
+        _ => (OnRegrouped::Fail, Some(vec![0; BITS / 64])),
+    };
+    Check { policy, bits, recent: VecDeque::with_capacity(RECENT), held: HashSet::new(), prev: Vec::new(), lexical: true, natural: true, found: 0, first: None }
+}
+
+impl Check {
+    // the templates of one input are checked against one another only
+    pub fn reset(&mut self) {
+        self.bits.iter_mut().flatten().for_each(|x| *x = 0);
+        self.recent.clear();
+        self.held.clear();
+        self.prev.clear();
+        self.lexical = true;
+        self.natural = true;
+    }
+
+    // a template of qname (or ZMW) `key` starts
+    pub fn open(&mut self, key: &[u8]) {
+        if !self.prev.is_empty() {
+            self.lexical &= self.prev.as_slice() <= key;
+            self.natural &= natural(&self.prev, key) != Ordering::Greater;
+        }
+        self.prev.clear();
+        self.prev.extend_from_slice(key);
+        let certain = self.held.contains(key);
+        if !certain {
+            if self.recent.len() == RECENT {
+                if let Some(old) = self.recent.pop_front() {
+                    self.held.remove(&old);
+                }
+            }
+            self.recent.push_back(key.to_vec());
+            self.held.insert(key.to_vec());
+        }
+        let mut seen = certain;
+        if let Some(bits) = &mut self.bits {
+            let (h1, h2) = (hash(key, 0), hash(key, 1) | 1);
+            let mut set = true;
+            for i in 0..HASHES {
+                let bit = (h1.wrapping_add(i.wrapping_mul(h2)) % BITS as u64) as usize;
+                set &= bits[bit / 64] & (1 << (bit % 64)) != 0;
+                bits[bit / 64] |= 1 << (bit % 64);
+            }
+            seen |= set && !self.lexical && !self.natural;
+        }
+        if !seen {
+            return;
+        }
+        let qname = String::from_utf8_lossy(key).into_owned();
+        if self.policy == OnRegrouped::Fail {
+            error!("{} turns up again after its template closed; the input is not grouped by qname, whatever its header says! Please run 'samtools sort -n' or 'samtools collate' on it first.", qname);
+            status::fail(Code::Malformed);
+        }
+        self.found += 1;
+        self.first.get_or_insert(qname);
+    }
+
+    pub fn finish(self) {
+        if let Some(first) = self.first {
+            warn!("{} qnames, {} the first, turn up again after their template closed, so that their templates were split and counted more than once; the input is not grouped by qname, whatever its header says.", self.found, first);
+        }
+    }
+}
+
+fn hash(key: &[u8], seed: u64) -> u64 {
+    let mut h = DefaultHasher::new();
+    seed.hash(&mut h);
+    key.hash(&mut h);
+    h.finish()
+}
+
+// the order of `samtools sort -n`: runs of digits compare as numbers, everything else byte by byte
+fn natural(a: &[u8], b: &[u8]) -> Ordering {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+            let (si, sj) = (i, j);
+            while i < a.len() && a[i].is_ascii_digit() {
+                i += 1;
+            }
+            while j < b.len() && b[j].is_ascii_digit() {
+                j += 1;
+            }
+            let x = trim_zeros(&a[si..i]);
+            let y = trim_zeros(&b[sj..j]);
+            match x.len().cmp(&y.len()).then_with(|| x.cmp(y)) {
+                Ordering::Equal => {},
+                o => return o,
+            }
+        } else {
+            match a[i].cmp(&b[j]) {
+                Ordering::Equal => {},
+                o => return o,
+            }
+            i += 1;
+            j += 1;
+        }
+    }
+    (a.len() - i).cmp(&(b.len() - j))
+}
+
+fn trim_zeros(x: &[u8]) -> &[u8] {
+    let n = x.iter().take_while(|&&c| c == b'0').count();
+    &x[n..]
+}
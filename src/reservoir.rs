@@ -0,0 +1,591 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::mem::{take, replace};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread::{self, JoinHandle};
+use rand::prelude::*;
+use rust_htslib::bam::Record;
+use rust_htslib::htslib::bam1_core_t;
+
+use crate::rng::{Generator, Seed};
+
+pub type RecordSet = Vec<Record>;
+
+// a uniform sample of `num` templates, or of whatever stands for them, e.g. where they are in the input;
+// once full, by Vitter's Algorithm L: rather than drawing for every template whether it goes in, the
+// number of templates to pass over before the next one that does is drawn, along with `w`, which it
+// goes by, so that only about num * (1 + ln(k / num)) draws are made over k templates
+pub struct Reservoir<T = RecordSet> {
+    pub num: usize,
+    pub k: usize,
+    pub v: Vec<T>,
+    rng: Generator,
+    w: f64,
+    // the (0-based) template to go in next
+    next: usize,
+}
+
+impl<T> Reservoir<T> {
+    pub fn new(num: usize, seed: Seed) -> Reservoir<T> {
+        Reservoir {
+            num,
+            k: 0,
+            v: Vec::new(),
+            rng: seed.generator(),
+            w: 1.0,
+            next: usize::MAX,
+        }
+    }
+
+    // pick a reservoir back up after `k` templates with `v` kept; the draws only depend on the rng, not
+    // on the templates, so replaying them up to `k` restores its state
+    pub fn resume(num: usize, seed: Seed, k: usize, v: Vec<T>) -> Reservoir<T> {
+        let mut res = Reservoir::new(num, seed);
+        if num > 0 && k >= num {
+            res.start();
+            while res.next < k {
+                res.rng.gen_range(0..num);
+                res.skip();
+            }
+        }
+        res.k = k;
+        res.v = v;
+        res
+    }
+
+    // the reservoir just filled up; find the first template to replace one of it
+    fn start(&mut self) {
+        self.w = 1.0;
+        self.next = self.num - 1;
+        self.skip();
+    }
+
+    fn skip(&mut self) {
+        // in (0, 1], as the logs need
+        let (u1, u2) = (1.0 - self.rng.gen::<f64>(), 1.0 - self.rng.gen::<f64>());
+        self.w *= (u1.ln() / self.num as f64).exp();
+        let skip = (u2.ln() / (1.0 - self.w).ln()).floor() as usize;
+        self.next = self.next.saturating_add(skip).saturating_add(1);
+    }
+
+    // the slot of the reservoir the next template goes in, if any, without handing it over, for a
+    // caller holding the templates itself
+    pub fn slot(&mut self) -> Option<usize> {
+        let slot = if self.k < self.num {
+            if self.k + 1 == self.num {
+                self.start();
+            }
+            Some(self.k)
+        } else if self.k == self.next {
+            // in place of one of the reservoir, uniformly
+            let i = self.rng.gen_range(0..self.num);
+            self.skip();
+            Some(i)
+        } else {
+            None
+        };
+        self.k += 1;
+        slot
+    }
+
+    // offer a template to the reservoir; returns whichever template is left out, either this one
+    // or the one it evicted, so its records can be reused
+    pub fn sample(&mut self, rs: T) -> Option<T> {
+        match self.slot() {
+            Some(i) if i < self.v.len() => Some(replace(&mut self.v[i], rs)),
+            Some(_) => {
+                self.v.push(rs);
+                None
+            },
+            None => Some(rs),
+        }
+    }
+}
+
+// combine reservoirs built over disjoint parts of the input into a sample of `num` templates of the
+// whole: the number taken from each part follows the multivariate hypergeometric distribution over
+// the parts' template counts, and since every reservoir is a uniform sample of its part, any subset
+// of it is as well
+pub fn merge(parts: Vec<Reservoir>, num: usize, rng: &mut Generator) -> Vec<RecordSet> {
+    let mut left: Vec<usize> = parts.iter().map(|p| p.k).collect();
+    let mut total: usize = left.iter().sum();
+    let mut picks = vec![0; parts.len()];
+    for _ in 0..num.min(total) {
+        let mut x = rng.gen_range(0..total);
+        let i = left.iter().position(|&l| if x < l { true } else { x -= l; false }).unwrap();
+        left[i] -= 1;
+        total -= 1;
+        picks[i] += 1;
+    }
+    parts.into_iter().zip(picks).flat_map(|(mut p, c)| {
+        p.v.shuffle(rng);
+        p.v.truncate(c);
+        p.v
+    }).collect()
+}
+
+// what --deterministic ranks a template by: a hash of its qname and the seed, then the qname itself
+// to settle (unlikely) ties
+pub type Key = (u64, Vec<u8>);
+
+pub fn key(qname: &[u8], seed: Seed) -> Key {
+    (seed.key(qname), qname.to_vec())
+}
+
+struct Keyed<T> {
+    key: Key,
+    item: T,
+}
+
+impl<T> PartialEq for Keyed<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl<T> Eq for Keyed<T> {}
+
+impl<T> PartialOrd for Keyed<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Keyed<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+// the first or the last `num` templates offered, in the order they came in, per --mode first and last;
+// no draws are made
+pub struct Ends {
+    num: usize,
+    last: bool,
+    pub k: usize,
+    v: VecDeque<RecordSet>,
+}
+
+impl Ends {
+    pub fn new(num: usize, last: bool) -> Ends {
+        Ends {
+            num,
+            last,
+            k: 0,
+            v: VecDeque::new(),
+        }
+    }
+
+    // whether no template offered from now on would go in
+    pub fn full(&self) -> bool {
+        !self.last && self.v.len() >= self.num
+    }
+
+    // offer a template; returns whichever is left out, either this one or, once full, the earliest one
+    pub fn offer(&mut self, rs: RecordSet) -> Option<RecordSet> {
+        self.k += 1;
+        if self.v.len() < self.num {
+            self.v.push_back(rs);
+            return None;
+        }
+        match self.last && self.num > 0 {
+            true => {
+                let out = self.v.pop_front();
+                self.v.push_back(rs);
+                out
+            },
+            false => Some(rs),
+        }
+    }
+}
+
+// the `num` items with the smallest keys (bottom-k sampling); unlike with the reservoir, which ones
+// those are does not depend on the order they come in
+pub struct BottomK<T> {
+    num: usize,
+    seed: Seed,
+    pub k: usize,
+    heap: BinaryHeap<Keyed<T>>,
+}
+
+impl<T> BottomK<T> {
+    pub fn new(num: usize, seed: Seed) -> BottomK<T> {
+        BottomK {
+            num,
+            seed,
+            k: 0,
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    // offer the item of a qname; returns whichever item is left out, either this one or the one it
+    // displaced
+    pub fn offer(&mut self, qname: &[u8], item: T) -> Option<T> {
+        match self.rank(qname) {
+            Some(key) => self.insert(key, item),
+            None => Some(item),
+        }
+    }
+
+    // count a qname in, and its key if its item is to go in; the qname is only copied then, the rest
+    // being hashed where they are
+    pub fn rank(&mut self, qname: &[u8]) -> Option<Key> {
+        self.k += 1;
+        let h = self.seed.key(qname);
+        let admitted = self.heap.len() < self.num || self.heap.peek().is_some_and(|top| (h, qname) < (top.key.0, &top.key.1[..]));
+        admitted.then(|| (h, qname.to_vec()))
+    }
+
+    // put in an item `rank` admitted, and hand back the one it displaced, if any
+    pub fn insert(&mut self, key: Key, item: T) -> Option<T> {
+        let out = if self.heap.len() < self.num { None } else { self.heap.pop().map(|x| x.item) };
+        self.heap.push(Keyed { key, item });
+        out
+    }
+
+    // keys and items kept, smallest key first
+    pub fn into_sorted(self) -> Vec<(Key, T)> {
+        self.heap.into_sorted_vec().into_iter().map(|x| (x.key, x.item)).collect()
+    }
+}
+
+// contiguous chunks of templates dealt round-robin to worker threads, each running its own reservoir
+pub struct Chunked {
+    num: usize,
+    seed: Seed,
+    pub k: usize,
+    chunk: Vec<RecordSet>,
+    chunk_size: usize,
+    next: usize,
+    senders: Vec<SyncSender<Vec<RecordSet>>>,
+    handles: Vec<JoinHandle<Reservoir>>,
+}
+
+impl Chunked {
+    pub fn new(num: usize, seed: Seed, threads: usize, chunk_size: usize) -> Chunked {
+        let mut senders = Vec::new();
+        let mut handles = Vec::new();
+        for i in 0..threads {
+            // a couple of chunks in flight per worker is enough to keep it busy
+            let (tx, rx) = sync_channel::<Vec<RecordSet>>(2);
+            let mut res = Reservoir::new(num, seed.nth(i as u64 + 1));
+            handles.push(thread::spawn(move || {
+                for chunk in rx {
+                    for rs in chunk {
+                        res.sample(rs);
+                    }
+                }
+                res
+            }));
+            senders.push(tx);
+        }
+        Chunked {
+            num,
+            seed,
+            k: 0,
+            chunk: Vec::with_capacity(chunk_size),
+            chunk_size,
+            next: 0,
+            senders,
+            handles,
+        }
+    }
+
+    pub fn sample(&mut self, rs: RecordSet) {
+        self.chunk.push(rs);
+        self.k += 1;
+        if self.chunk.len() == self.chunk_size {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.chunk.is_empty() {
+            return;
+        }
+        let chunk = replace(&mut self.chunk, Vec::with_capacity(self.chunk_size));
+        self.senders[self.next].send(chunk).expect("sampling thread died!");
+        self.next = (self.next + 1) % self.senders.len();
+    }
+
+    pub fn finish(mut self) -> Vec<RecordSet> {
+        self.flush();
+        self.senders.clear();
+        let parts: Vec<Reservoir> = take(&mut self.handles).into_iter().map(|h| h.join().expect("sampling thread panicked!")).collect();
+        let mut rng = self.seed.generator();
+        merge(parts, self.num, &mut rng)
+    }
+}
+
+// a template as --compress-reservoir holds it: the fixed fields of its records as they are, and their
+// variable-length data (qname, CIGAR, sequence, qualities and tags) back to back, deflated; a template
+// of short reads takes a fraction of the memory of its records, for a deflate on the way in and an
+// inflate on the way out
+pub struct Packed {
+    cores: Vec<bam1_core_t>,
+    lens: Vec<u32>,
+    data: Vec<u8>,
+}
+
+impl Packed {
+    pub fn new(rs: &[Record]) -> Packed {
+        let mut raw: Vec<u8> = Vec::new();
+        for r in rs {
+            // the data htslib holds a record in, as long as it says
+            raw.extend_from_slice(unsafe { std::slice::from_raw_parts(r.inner().data, r.inner().l_data as usize) });
+        }
+        let mut len = unsafe { libz_sys::compressBound(raw.len() as libz_sys::uLong) };
+        let mut data = vec![0u8; len as usize];
+        // level 1: most of the saving, at a fraction of the time of the default
+        let ret = unsafe { libz_sys::compress2(data.as_mut_ptr(), &mut len, raw.as_ptr(), raw.len() as libz_sys::uLong, 1) };
+        assert_eq!(ret, libz_sys::Z_OK, "failed to deflate a template!");
+        data.truncate(len as usize);
+        data.shrink_to_fit();
+        Packed {
+            cores: rs.iter().map(|r| r.inner().core).collect(),
+            lens: rs.iter().map(|r| r.inner().l_data as u32).collect(),
+            data,
+        }
+    }
+
+    // the records back, as they went in
+    pub fn unpack(&self) -> RecordSet {
+        let total: usize = self.lens.iter().map(|&l| l as usize).sum();
+        let mut raw = vec![0u8; total];
+        let mut len = total as libz_sys::uLong;
+        let ret = unsafe { libz_sys::uncompress(raw.as_mut_ptr(), &mut len, self.data.as_ptr(), self.data.len() as libz_sys::uLong) };
+        assert!(ret == libz_sys::Z_OK && len as usize == total, "failed to inflate a template!");
+        let mut at = 0;
+        self.cores.iter().zip(&self.lens).map(|(&core, &l)| {
+            let mut r = Record::new();
+            r.set_data(&raw[at..at + l as usize]);
+            r.inner_mut().core = core;
+            at += l as usize;
+            r
+        }).collect()
+    }
+}
+
+// the reservoir of the whole input, either kept on the reading thread or split across workers
+pub enum Sampling {
+    Serial(Reservoir),
+    // per --compress-reservoir
+    Packed(Reservoir<Packed>),
+    Chunked(Chunked),
+    // per --deterministic; templates are numbered so they come out in input order
+    Deterministic(BottomK<(usize, RecordSet)>),
+    // per --keep-order, likewise numbered
+    Ordered(Reservoir<(usize, RecordSet)>),
+    // per --mode first and last
+    Ends(Ends),
+}
+
+impl Sampling {
+    // templates offered so far
+    pub fn seen(&self) -> usize {
+        match self {
+            Sampling::Serial(res) => res.k,
+            Sampling::Packed(res) => res.k,
+            Sampling::Chunked(c) => c.k,
+            Sampling::Deterministic(b) => b.k,
+            Sampling::Ordered(res) => res.k,
+            Sampling::Ends(e) => e.k,
+        }
+    }
+
+    pub fn sample(&mut self, rs: RecordSet) -> Option<RecordSet> {
+        match self {
+            Sampling::Serial(res) => res.sample(rs),
+            // only a template going in is packed, the records being reused whether it went in or not
+            Sampling::Packed(res) => {
+                match res.slot() {
+                    Some(i) if i < res.v.len() => res.v[i] = Packed::new(&rs),
+                    Some(_) => res.v.push(Packed::new(&rs)),
+                    None => {},
+                }
+                Some(rs)
+            },
+            Sampling::Chunked(c) => {
+                c.sample(rs);
+                None
+            },
+            Sampling::Deterministic(b) => {
+                let i = b.k;
+                match b.rank(rs[0].qname()) {
+                    Some(key) => b.insert(key, (i, rs)).map(|(_, rs)| rs),
+                    None => Some(rs),
+                }
+            },
+            Sampling::Ordered(res) => {
+                let i = res.k;
+                res.sample((i, rs)).map(|(_, rs)| rs)
+            },
+            Sampling::Ends(e) => e.offer(rs),
+        }
+    }
+
+    pub fn finish(self) -> Vec<RecordSet> {
+        match self {
+            Sampling::Serial(res) => res.v,
+            Sampling::Packed(res) => res.v.iter().map(Packed::unpack).collect(),
+            Sampling::Chunked(c) => c.finish(),
+            Sampling::Deterministic(b) => {
+                let mut v: Vec<(usize, RecordSet)> = b.into_sorted().into_iter().map(|(_, x)| x).collect();
+                v.sort_unstable_by_key(|x| x.0);
+                v.into_iter().map(|(_, rs)| rs).collect()
+            },
+            Sampling::Ordered(mut res) => {
+                res.v.sort_unstable_by_key(|x| x.0);
+                res.v.into_iter().map(|(_, rs)| rs).collect()
+            },
+            Sampling::Ends(e) => e.v.into(),
+        }
+    }
+}
+
+// indices of `num` out of `n` templates, uniformly at random and in ascending order, for a second
+// pass over the input to pick up
+pub fn pick_indices(n: usize, num: usize, seed: Seed) -> Vec<usize> {
+    let mut rng = seed.generator();
+    let mut picks = rand::seq::index::sample(&mut rng, n, num.min(n)).into_vec();
+    picks.sort_unstable();
+    picks
+}
+
+// where --every starts when not given a --phase: one of the first `every` templates, at random
+pub fn phase(every: usize, seed: Seed) -> usize {
+    seed.generator().gen_range(0..every)
+}
+
+// split `num` among parts in proportion to their weights, by largest remainder so the shares add up
+// to `num` exactly (or to the total weight, if that is smaller)
+pub fn allocate(num: usize, weights: &[usize]) -> Vec<usize> {
+    let total: usize = weights.iter().sum();
+    if total == 0 {
+        return vec![0; weights.len()];
+    }
+    let num = num.min(total) as u128;
+    let exact: Vec<u128> = weights.iter().map(|&w| num * w as u128).collect();
+    let mut shares: Vec<usize> = exact.iter().map(|&x| (x / total as u128) as usize).collect();
+    let mut left = num as usize - shares.iter().sum::<usize>();
+    let mut order: Vec<usize> = (0..weights.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(exact[i] % total as u128));
+    for i in order {
+        if left == 0 {
+            break;
+        }
+        if shares[i] < weights[i] {
+            shares[i] += 1;
+            left -= 1;
+        }
+    }
+    shares
+}
+
+// `allocate`, after every part has been given `min` first, or all it has if fewer, per --min-per-stratum;
+// the rest of `num` is split by what the parts have left. Should the floors add up to more than `num`,
+// `num` is split among them in proportion to them instead, so that the shares still add up to `num`
+pub fn allocate_min(num: usize, weights: &[usize], min: usize) -> Vec<usize> {
+    let floors: Vec<usize> = weights.iter().map(|&w| w.min(min)).collect();
+    let floor: usize = floors.iter().sum();
+    if floor >= num {
+        return allocate(num, &floors);
+    }
+    let left: Vec<usize> = weights.iter().zip(&floors).map(|(&w, &f)| w - f).collect();
+    allocate(num - floor, &left).into_iter().zip(floors).map(|(x, f)| x + f).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Kind;
+
+    // how often each of `n` items ends up in a reservoir of `num`, over `runs` seeds
+    fn inclusions(n: usize, num: usize, runs: u64) -> Vec<usize> {
+        let mut counts = vec![0; n];
+        for seed in 0..runs {
+            let mut res: Reservoir<usize> = Reservoir::new(num, Seed::new(Kind::Pcg64, seed));
+            for i in 0..n {
+                res.sample(i);
+            }
+            assert_eq!(res.v.len(), num);
+            res.v.iter().for_each(|&i| counts[i] += 1);
+        }
+        counts
+    }
+
+    // Pearson's chi-square of `counts` against the same count expected of each
+    fn chi_square(counts: &[usize]) -> f64 {
+        let expected = counts.iter().sum::<usize>() as f64 / counts.len() as f64;
+        counts.iter().map(|&x| (x as f64 - expected).powi(2) / expected).sum()
+    }
+
+    #[test]
+    fn inclusion_is_uniform() {
+        // 49 degrees of freedom; 85.35 is the 99.9th percentile
+        let counts = inclusions(50, 10, 20_000);
+        assert!(chi_square(&counts) < 85.35, "{:?}", counts);
+    }
+
+    #[test]
+    fn inclusion_is_uniform_past_the_skips() {
+        // most of the 1000 are passed over by Algorithm L; 999 degrees of freedom, 1143.9 the 99.9th percentile
+        let counts = inclusions(1000, 5, 40_000);
+        assert!(chi_square(&counts) < 1143.9, "{}", chi_square(&counts));
+        // the first ones are not favoured for having filled the reservoir
+        let first: usize = counts[..5].iter().sum();
+        assert!((first as f64 - 1000.0).abs() < 5.0 * 1000f64.sqrt(), "{}", first);
+    }
+
+    #[test]
+    fn resumed_draws_as_if_never_stopped() {
+        let mut whole: Reservoir<usize> = Reservoir::new(7, Seed::new(Kind::Pcg64, 43));
+        (0..500).for_each(|i| { whole.sample(i); });
+        let mut first: Reservoir<usize> = Reservoir::new(7, Seed::new(Kind::Pcg64, 43));
+        (0..200).for_each(|i| { first.sample(i); });
+        let mut resumed = Reservoir::resume(7, Seed::new(Kind::Pcg64, 43), first.k, first.v);
+        (200..500).for_each(|i| { resumed.sample(i); });
+        assert_eq!(resumed.v, whole.v);
+    }
+
+    #[test]
+    fn fewer_than_num_are_all_kept() {
+        let mut res: Reservoir<usize> = Reservoir::new(10, Seed::new(Kind::Pcg64, 1));
+        (0..4).for_each(|i| { res.sample(i); });
+        assert_eq!(res.v, [0, 1, 2, 3]);
+    }
+
+    fn template(i: usize) -> RecordSet {
+        let mut r = Record::new();
+        r.set(format!("r{}", i).as_bytes(), None, b"ACGTACGT", &[30; 8]);
+        vec![r]
+    }
+
+    #[test]
+    fn packed_draws_as_serial() {
+        let seed = Seed::new(Kind::Pcg64, 43);
+        let (mut serial, mut packed) = (Sampling::Serial(Reservoir::new(5, seed)), Sampling::Packed(Reservoir::new(5, seed)));
+        for i in 0..300 {
+            serial.sample(template(i));
+            // every template is handed back, in or not, for its records to be reused
+            assert!(packed.sample(template(i)).is_some());
+        }
+        let qnames = |v: Vec<RecordSet>| v.iter().map(|rs| rs[0].qname().to_vec()).collect::<Vec<_>>();
+        assert_eq!(qnames(packed.finish()), qnames(serial.finish()));
+    }
+
+    #[test]
+    fn packed_unpacks_as_it_went_in() {
+        let rs = template(7);
+        let back = Packed::new(&rs).unpack();
+        assert_eq!(back.len(), 1);
+        assert_eq!(back[0].qname(), b"r7");
+        assert_eq!(back[0].seq().as_bytes(), b"ACGTACGT");
+    }
+}
@@ -0,0 +1,43 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::fs;
+use std::time::Duration;
+
+use crate::units::format_size;
+
+// bytes read and written by the process, from pipes as well as files, per /proc where there is one
+fn io() -> Option<(u64, u64)> {
+    let io = fs::read_to_string("/proc/self/io").ok()?;
+    let field = |key: &str| io.lines().find_map(|l| l.strip_prefix(key)?.trim().parse().ok());
+    Some((field("rchar:")?, field("wchar:")?))
+}
+
+fn secs(t: libc::timeval) -> f64 {
+    t.tv_sec as f64 + t.tv_usec as f64 / 1e6
+}
+
+// what the run took of the machine, in one line to size a cluster job by: the peak memory resident, the
+// CPU time of every thread, and the bytes read and written
+pub fn summary(wall: Duration) -> String {
+    let mut ru: libc::rusage = unsafe { std::mem::zeroed() };
+    let mut parts = Vec::new();
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut ru) } == 0 {
+        // in kilobytes on Linux, in bytes on macOS
+        let rss = match cfg!(target_os = "macos") {
+            true => ru.ru_maxrss as usize,
+            false => ru.ru_maxrss as usize * 1024,
+        };
+        let (user, sys) = (secs(ru.ru_utime), secs(ru.ru_stime));
+        parts.push(format!("max RSS {}", format_size(rss)));
+        parts.push(format!("CPU time {:.1}s ({:.1}s user, {:.1}s system)", user + sys, user, sys));
+    }
+    parts.push(format!("wall clock {:.1}s", wall.as_secs_f64()));
+    if let Some((read, written)) = io() {
+        parts.push(format!("{} read, {} written", format_size(read as usize), format_size(written as usize)));
+    }
+    format!("Resources: {}.", parts.join(", "))
+}
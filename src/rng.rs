@@ -0,0 +1,181 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use rand::rngs::SmallRng;
+use rand::{Error, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use rand_pcg::Pcg64;
+
+use crate::uniform;
+
+// the generators the samplers may draw from, per --rng: PCG64, the default; xoshiro256++, the
+// quickest; and ChaCha20, cryptographically secure, so that the draws cannot be told from the ones
+// seen short of the seed
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Kind {
+    Pcg64,
+    Xoshiro,
+    ChaCha,
+}
+
+impl Kind {
+    pub fn parse(s: &str) -> Option<Kind> {
+        match s {
+            "pcg64" => Some(Kind::Pcg64),
+            "xoshiro" => Some(Kind::Xoshiro),
+            "chacha" => Some(Kind::ChaCha),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Pcg64 => "pcg64",
+            Kind::Xoshiro => "xoshiro",
+            Kind::ChaCha => "chacha",
+        }
+    }
+}
+
+// a seed and the kind of generator it seeds, as --seed and --rng give them; it is handed to whatever
+// draws, down to the keyed hashes shared by the records of a template, so that a run draws from the
+// one kind throughout
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Seed {
+    pub kind: Kind,
+    pub value: u64,
+}
+
+impl Seed {
+    pub fn new(kind: Kind, value: u64) -> Seed {
+        Seed { kind, value }
+    }
+
+    // a generator of the kind, seeded with the value; the same seed gives the same draws of a kind,
+    // different ones of another
+    pub fn generator(self) -> Generator {
+        match self.kind {
+            Kind::Pcg64 => Generator::Pcg64(Pcg64::seed_from_u64(self.value)),
+            Kind::Xoshiro => Generator::Xoshiro(SmallRng::seed_from_u64(self.value)),
+            Kind::ChaCha => Generator::ChaCha(Box::new(ChaCha20Rng::seed_from_u64(self.value))),
+        }
+    }
+
+    // a number that depends only on `key` and the seed, for the draws every record of a template must
+    // agree on (the ranks of --deterministic, the coins of --keep-track, --target-depth and
+    // --stream-through): the keyed hash of `uniform::hash` for pcg64 and xoshiro, and the first draw of
+    // a ChaCha20 seeded with it for chacha, so that it cannot be worked out from the key short of the seed
+    pub fn key(self, key: &[u8]) -> u64 {
+        let h = uniform::hash(key, self.value);
+        match self.kind {
+            Kind::ChaCha => ChaCha20Rng::seed_from_u64(h).next_u64(),
+            _ => h,
+        }
+    }
+
+    // a number in [0, 1) from `key` as above
+    pub fn draw(self, key: &[u8]) -> f64 {
+        (self.key(key) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // the seed of whatever goes by `key`, e.g. a stratum or a locus, of the same kind
+    pub fn derive(self, key: &[u8]) -> Seed {
+        Seed::new(self.kind, self.key(key))
+    }
+
+    // the seed of a `stage` of the run, e.g. the picks that follow the coin flips of --bernoulli, so that
+    // the draws of one stage have nothing to do with those of another
+    pub fn stage(self, stage: &str) -> Seed {
+        self.derive(stage.as_bytes())
+    }
+
+    // the seed of the `i`th of a run's parts, e.g. the workers of a chunked reservoir
+    pub fn nth(self, i: u64) -> Seed {
+        Seed::new(self.kind, self.value.wrapping_add(i))
+    }
+}
+
+// a generator of one kind or another, which the samplers draw from through RngCore whatever it is
+pub enum Generator {
+    Pcg64(Pcg64),
+    Xoshiro(SmallRng),
+    ChaCha(Box<ChaCha20Rng>),
+}
+
+impl RngCore for Generator {
+    fn next_u32(&mut self) -> u32 {
+        match self {
+            Generator::Pcg64(g) => g.next_u32(),
+            Generator::Xoshiro(g) => g.next_u32(),
+            Generator::ChaCha(g) => g.next_u32(),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        match self {
+            Generator::Pcg64(g) => g.next_u64(),
+            Generator::Xoshiro(g) => g.next_u64(),
+            Generator::ChaCha(g) => g.next_u64(),
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match self {
+            Generator::Pcg64(g) => g.fill_bytes(dest),
+            Generator::Xoshiro(g) => g.fill_bytes(dest),
+            Generator::ChaCha(g) => g.fill_bytes(dest),
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        match self {
+            Generator::Pcg64(g) => g.try_fill_bytes(dest),
+            Generator::Xoshiro(g) => g.try_fill_bytes(dest),
+            Generator::ChaCha(g) => g.try_fill_bytes(dest),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_draws_of_a_kind() {
+        for kind in [Kind::Pcg64, Kind::Xoshiro, Kind::ChaCha] {
+            let seed = Seed::new(kind, 43);
+            assert_eq!(seed.generator().next_u64(), seed.generator().next_u64());
+            assert_eq!(seed.key(b"read1"), seed.key(b"read1"));
+            assert_ne!(seed.key(b"read1"), seed.key(b"read2"));
+        }
+    }
+
+    #[test]
+    fn kinds_draw_differently() {
+        let draws: Vec<u64> = [Kind::Pcg64, Kind::Xoshiro, Kind::ChaCha].iter().map(|&k| Seed::new(k, 43).generator().next_u64()).collect();
+        assert_ne!(draws[0], draws[1]);
+        assert_ne!(draws[1], draws[2]);
+        assert_ne!(draws[0], draws[2]);
+    }
+
+    #[test]
+    fn keys_go_through_the_kind() {
+        // pcg64 and xoshiro keep the plain keyed hash, so their picks stay those of earlier releases
+        assert_eq!(Seed::new(Kind::Pcg64, 43).key(b"read1"), uniform::hash(b"read1", 43));
+        assert_eq!(Seed::new(Kind::Xoshiro, 43).key(b"read1"), uniform::hash(b"read1", 43));
+        assert_ne!(Seed::new(Kind::ChaCha, 43).key(b"read1"), uniform::hash(b"read1", 43));
+        assert_eq!(Seed::new(Kind::ChaCha, 43).stage("nested").kind, Kind::ChaCha);
+    }
+
+    #[test]
+    fn draws_fall_in_unit_interval() {
+        let seed = Seed::new(Kind::ChaCha, 7);
+        for i in 0..1000u32 {
+            let x = seed.draw(&i.to_le_bytes());
+            assert!((0.0..1.0).contains(&x));
+        }
+    }
+}
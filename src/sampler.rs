@@ -0,0 +1,292 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use rand::prelude::*;
+use rust_htslib::{bam::Record, errors::Error};
+
+use crate::backend::{each_template, RecordSource};
+use crate::reservoir::{RecordSet, Reservoir};
+use crate::rng::{Generator, Seed};
+
+// what a sampler makes of a template offered to it
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Decision {
+    // in the sample for good, e.g. to be written out as it is read
+    Keep,
+    // left out for good
+    Drop,
+    // held in slot `i` of the sample for now, in place of whatever was there; a template held may yet
+    // be displaced by a later one, so the sample is only final at the end of the input
+    Hold(usize),
+}
+
+impl Decision {
+    // put `t` where the decision has it: onto the end of `kept`, into its slot of `held`, or nowhere
+    pub fn place<T>(self, t: T, kept: &mut Vec<T>, held: &mut Vec<T>) {
+        match self {
+            Decision::Keep => kept.push(t),
+            Decision::Drop => {},
+            Decision::Hold(i) if i < held.len() => held[i] = t,
+            Decision::Hold(_) => held.push(t),
+        }
+    }
+}
+
+// a policy deciding which templates make it into a sample, one template at a time in input order; the
+// sampler holds no records, only what it needs to decide, so that the caller keeps the templates
+// wherever suits it (see `select`). --every, --bernoulli, --stream-through and the samplers of
+// --pipeline decide through it
+pub trait Sampler {
+    fn observe(&mut self, rs: &[Record]) -> Decision;
+}
+
+impl<S: Sampler + ?Sized> Sampler for Box<S> {
+    fn observe(&mut self, rs: &[Record]) -> Decision {
+        (**self).observe(rs)
+    }
+}
+
+// a uniform sample of `num` templates, as the reservoir draws it; only its draws are made, its `v`
+// stays empty
+impl<T> Sampler for Reservoir<T> {
+    fn observe(&mut self, _rs: &[Record]) -> Decision {
+        match self.slot() {
+            Some(i) => Decision::Hold(i),
+            None => Decision::Drop,
+        }
+    }
+}
+
+// every template kept with probability `fraction`, by a seeded random number generator, as --bernoulli
+// draws them
+pub struct Bernoulli {
+    fraction: f64,
+    rng: Generator,
+}
+
+impl Bernoulli {
+    pub fn new(fraction: f64, seed: Seed) -> Bernoulli {
+        Bernoulli {
+            fraction,
+            rng: seed.generator(),
+        }
+    }
+}
+
+impl Sampler for Bernoulli {
+    fn observe(&mut self, _rs: &[Record]) -> Decision {
+        match self.rng.gen::<f64>() < self.fraction {
+            true => Decision::Keep,
+            false => Decision::Drop,
+        }
+    }
+}
+
+// every template kept whose qname hashes with the seed below `fraction`, as --mode uniform and thin
+// decide, so that a template is kept or not alike in every run and in every file it is in
+pub struct HashThreshold {
+    fraction: f64,
+    seed: Seed,
+}
+
+impl HashThreshold {
+    pub fn new(fraction: f64, seed: Seed) -> HashThreshold {
+        HashThreshold {
+            fraction,
+            seed,
+        }
+    }
+}
+
+impl Sampler for HashThreshold {
+    fn observe(&mut self, rs: &[Record]) -> Decision {
+        match self.seed.draw(rs[0].qname()) < self.fraction {
+            true => Decision::Keep,
+            false => Decision::Drop,
+        }
+    }
+}
+
+// a sample of `num` templates drawn with probability in proportion to the weight `weight` gives each,
+// by Efraimidis and Spirakis' A-Res: every template gets the key u^(1/weight) of a uniform draw u, and
+// the `num` largest keys are held; a template of no weight (or a negative or NaN one) is never drawn
+pub struct Weighted<F> {
+    num: usize,
+    weight: F,
+    rng: Generator,
+    // keys held and their slots, smallest key on top; keys are in [0, 1], where the bits of an f64 order
+    // as its value
+    heap: BinaryHeap<Reverse<(u64, usize)>>,
+}
+
+impl<F: FnMut(&[Record]) -> f64> Weighted<F> {
+    pub fn new(num: usize, seed: Seed, weight: F) -> Weighted<F> {
+        Weighted {
+            num,
+            weight,
+            rng: seed.generator(),
+            heap: BinaryHeap::new(),
+        }
+    }
+}
+
+impl<F: FnMut(&[Record]) -> f64> Sampler for Weighted<F> {
+    fn observe(&mut self, rs: &[Record]) -> Decision {
+        let w = (self.weight)(rs);
+        if w.is_nan() || w <= 0.0 || self.num == 0 {
+            return Decision::Drop;
+        }
+        let key = self.rng.gen::<f64>().powf(1.0 / w).to_bits();
+        if self.heap.len() < self.num {
+            let slot = self.heap.len();
+            self.heap.push(Reverse((key, slot)));
+            return Decision::Hold(slot);
+        }
+        match self.heap.peek() {
+            Some(&Reverse((top, slot))) if key > top => {
+                self.heap.pop();
+                self.heap.push(Reverse((key, slot)));
+                Decision::Hold(slot)
+            },
+            _ => Decision::Drop,
+        }
+    }
+}
+
+// 1 in every `every` templates, those at `phase` (0-based) and every `every` after it, as --every keeps them
+pub struct Systematic {
+    every: usize,
+    phase: usize,
+    // templates offered so far
+    k: usize,
+}
+
+impl Systematic {
+    pub fn new(every: usize, phase: usize) -> Systematic {
+        Systematic {
+            every,
+            phase,
+            k: 0,
+        }
+    }
+}
+
+impl Sampler for Systematic {
+    fn observe(&mut self, _rs: &[Record]) -> Decision {
+        let keep = self.k % self.every == self.phase;
+        self.k += 1;
+        match keep {
+            true => Decision::Keep,
+            false => Decision::Drop,
+        }
+    }
+}
+
+// the templates `sampler` decides on of those `infh` holds, read in queryname order: those kept, in
+// input order, then those held at the end, by slot
+pub fn select<R: RecordSource + ?Sized, S: Sampler + ?Sized>(infh: &mut R, sampler: &mut S) -> Result<Vec<RecordSet>, Error> {
+    let mut kept: Vec<RecordSet> = Vec::new();
+    let mut held: Vec<RecordSet> = Vec::new();
+    each_template(infh, |rs| sampler.observe(&rs).place(rs, &mut kept, &mut held))?;
+    kept.append(&mut held);
+    Ok(kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Records;
+    use crate::rng::Kind;
+
+    fn template(i: usize) -> RecordSet {
+        let mut r = Record::new();
+        r.set(format!("r{}", i).as_bytes(), None, b"ACGT", &[30; 4]);
+        vec![r]
+    }
+
+    fn seed(value: u64) -> Seed {
+        Seed::new(Kind::Pcg64, value)
+    }
+
+    // the templates out of `n` that `sampler` ends up with, kept or held, as `select` has them
+    fn picks<S: Sampler>(sampler: &mut S, n: usize) -> Vec<usize> {
+        let (mut kept, mut held) = (Vec::new(), Vec::new());
+        for i in 0..n {
+            sampler.observe(&template(i)).place(i, &mut kept, &mut held);
+        }
+        kept.append(&mut held);
+        kept
+    }
+
+    #[test]
+    fn reservoir_holds_num_uniformly() {
+        // 5 of 20 over 4,000 seeds, each held 1,000 times on average; 19 degrees of freedom, 43.82 the
+        // 99.9th percentile
+        let mut counts = vec![0usize; 20];
+        for s in 0..4000 {
+            let p = picks(&mut Reservoir::<RecordSet>::new(5, seed(s)), 20);
+            assert_eq!(p.len(), 5);
+            p.iter().for_each(|&i| counts[i] += 1);
+        }
+        let chi: f64 = counts.iter().map(|&x| (x as f64 - 1000.0).powi(2) / 1000.0).sum();
+        assert!(chi < 43.82, "{:?}", counts);
+    }
+
+    #[test]
+    fn bernoulli_keeps_about_the_fraction() {
+        // 4 standard deviations of a binomial of 10,000 at 0.3 is 183
+        let k = picks(&mut Bernoulli::new(0.3, seed(43)), 10_000).len();
+        assert!((k as f64 - 3000.0).abs() < 183.0, "{}", k);
+        assert!(picks(&mut Bernoulli::new(0.0, seed(43)), 1000).is_empty());
+        assert_eq!(picks(&mut Bernoulli::new(1.0, seed(43)), 1000).len(), 1000);
+    }
+
+    #[test]
+    fn hash_threshold_keeps_the_same_whatever_the_order() {
+        let k = picks(&mut HashThreshold::new(0.3, seed(43)), 10_000).len();
+        assert!((k as f64 - 3000.0).abs() < 183.0, "{}", k);
+        let mut backward = HashThreshold::new(0.3, seed(43));
+        let mut picked: Vec<usize> = (0..1000).rev().filter(|&i| backward.observe(&template(i)) == Decision::Keep).collect();
+        picked.reverse();
+        assert_eq!(picked, picks(&mut HashThreshold::new(0.3, seed(43)), 1000));
+    }
+
+    #[test]
+    fn systematic_keeps_every_kth_from_the_phase() {
+        assert_eq!(picks(&mut Systematic::new(4, 1), 20), [1, 5, 9, 13, 17]);
+        assert_eq!(picks(&mut Systematic::new(1, 0), 3), [0, 1, 2]);
+    }
+
+    #[test]
+    fn weighted_favours_the_heavier() {
+        // 2 of 0 and 1 of no weight, 2 to 5 of weight 1 and 6 to 9 of weight 4: drawn one after another,
+        // the heavy ones make up 1.57 of the 2 picks on average, the light ones 0.43
+        let weight = |rs: &[Record]| match String::from_utf8_lossy(&rs[0].qname()[1..]).parse::<usize>().unwrap_or_default() {
+            0 | 1 => 0.0,
+            2..=5 => 1.0,
+            _ => 4.0,
+        };
+        let mut counts = vec![0usize; 10];
+        for s in 0..2000 {
+            let p = picks(&mut Weighted::new(2, seed(s), weight), 10);
+            assert_eq!(p.len(), 2);
+            p.iter().for_each(|&i| counts[i] += 1);
+        }
+        assert_eq!(counts[0] + counts[1], 0);
+        let (light, heavy): (usize, usize) = (counts[2..6].iter().sum(), counts[6..].iter().sum());
+        assert!(heavy > 3 * light, "{:?}", counts);
+    }
+
+    #[test]
+    fn select_gives_the_kept_then_the_held() {
+        let records = (0..6).flat_map(template);
+        let sample = select(&mut Records(records), &mut Systematic::new(2, 0)).unwrap();
+        let qnames: Vec<&[u8]> = sample.iter().map(|rs| rs[0].qname()).collect();
+        assert_eq!(qnames, [&b"r0"[..], b"r2", b"r4"]);
+    }
+}
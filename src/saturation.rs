@@ -0,0 +1,95 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use rust_htslib::bam::Record;
+
+use crate::ospath;
+use crate::tags;
+
+// fractions of the input depth --saturation is given at
+static DEPTHS: &[f64] = &[0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0];
+
+// the CB and UB of the first primary read of a template carrying both
+fn barcodes(rs: &[Record]) -> Option<(String, String)> {
+    rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary()).find_map(|r| {
+        let cb = r.aux(b"CB").ok().and_then(|a| tags::text(&a))?;
+        let ub = r.aux(b"UB").ok().and_then(|a| tags::text(&a))?;
+        Some((cb, ub))
+    })
+}
+
+// the templates and UMIs of a cell, or of all of them, expected at the fraction `f` of its templates
+// drawn one by one with probability `f`: a UMI of c templates is kept unless all c are missed; the
+// saturation is the fraction of the templates that are duplicates of another of its UMI, as CellRanger
+// has it
+fn at(hist: &BTreeMap<usize, usize>, f: f64) -> (f64, f64, f64) {
+    let reads: f64 = hist.iter().map(|(&c, &n)| (c * n) as f64).sum::<f64>() * f;
+    let umis: f64 = hist.iter().map(|(&c, &n)| n as f64 * (1.0 - (1.0 - f).powi(c as i32))).sum();
+    let saturation = if reads > 0.0 { 1.0 - umis / reads } else { 0.0 };
+    (reads, umis, saturation)
+}
+
+// how many templates every UMI of every cell barcode has, by the CB and UB tags, to tell how much
+// more sequencing would add new UMIs; templates short of either are left out
+#[derive(Default)]
+pub struct Saturation {
+    counts: HashMap<String, HashMap<String, usize>>,
+}
+
+impl Saturation {
+    pub fn add(&mut self, rs: &[Record]) {
+        if let Some((cb, ub)) = barcodes(rs) {
+            *self.counts.entry(cb).or_default().entry(ub).or_default() += 1;
+        }
+    }
+
+    // the UMIs seen once, twice, ... of `cells`
+    fn histogram<'a>(cells: impl Iterator<Item = &'a HashMap<String, usize>>) -> BTreeMap<usize, usize> {
+        let mut hist = BTreeMap::new();
+        for &c in cells.flat_map(|umis| umis.values()) {
+            *hist.entry(c).or_default() += 1;
+        }
+        hist
+    }
+
+    // the saturation at fractions of the input depth as a TSV, over all cells or, with `per_cell`,
+    // cell by cell in the order of the barcodes; the saturation at the input depth over all cells, None
+    // if no template had both tags
+    pub fn write(&self, path: &str, per_cell: bool) -> std::io::Result<Option<f64>> {
+        let mut fh = BufWriter::new(File::create(ospath::path(path))?);
+        match per_cell {
+            true => writeln!(fh, "cell\tfraction\ttemplates\tumis\tsaturation")?,
+            false => writeln!(fh, "fraction\ttemplates\tumis\tsaturation")?,
+        }
+        if self.counts.is_empty() {
+            fh.flush()?;
+            return Ok(None);
+        }
+        if per_cell {
+            let mut cells: Vec<&String> = self.counts.keys().collect();
+            cells.sort();
+            for cell in cells {
+                let hist = Saturation::histogram(std::iter::once(&self.counts[cell]));
+                for &f in DEPTHS {
+                    let (reads, umis, saturation) = at(&hist, f);
+                    writeln!(fh, "{}\t{}\t{:.1}\t{:.1}\t{:.4}", cell, f, reads, umis, saturation)?;
+                }
+            }
+        }
+        let hist = Saturation::histogram(self.counts.values());
+        if !per_cell {
+            for &f in DEPTHS {
+                let (reads, umis, saturation) = at(&hist, f);
+                writeln!(fh, "{}\t{:.1}\t{:.1}\t{:.4}", f, reads, umis, saturation)?;
+            }
+        }
+        fh.flush()?;
+        Ok(Some(at(&hist, 1.0).2))
+    }
+}
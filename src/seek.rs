@@ -0,0 +1,241 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::io::Read as _;
+use rand::prelude::*;
+use rust_htslib::{bam, bam::Read, bam::Record};
+use log::{error, warn, info, debug};
+
+use crate::ospath;
+use crate::rng::Seed;
+use crate::status::{self, Code};
+
+// the bin of a BAI holding the span of a reference, then its mapped and unmapped read counts
+static PSEUDO_BIN: u32 = 37450;
+
+// the BGZF EOF marker closing every BAM
+static EOF_LEN: u64 = 28;
+
+// the index next to a BAM, input.bam.bai or input.bai
+pub fn find_bai(infile: &str) -> Option<String> {
+    vec![format!("{}.bai", infile), format!("{}.bai", infile.strip_suffix(".bam").unwrap_or(infile))]
+        .into_iter()
+        .find(|p| ospath::path(p).exists())
+}
+
+fn u32_le(fh: &mut &[u8]) -> Option<u32> {
+    let mut b = [0; 4];
+    fh.read_exact(&mut b).ok()?;
+    Some(u32::from_le_bytes(b))
+}
+
+fn u64_le(fh: &mut &[u8]) -> Option<u64> {
+    let mut b = [0; 8];
+    fh.read_exact(&mut b).ok()?;
+    Some(u64::from_le_bytes(b))
+}
+
+// virtual offsets a BAI knows records to start at: the ends of the chunks of its bins as well as
+// their starts, as the end of one record is where the next starts, and the linear index
+fn parse_bai(data: &[u8]) -> Option<BTreeSet<u64>> {
+    let mut fh = data.strip_prefix(b"BAI\x01")?;
+    let mut offsets = BTreeSet::new();
+    for _ in 0..u32_le(&mut fh)? {
+        for _ in 0..u32_le(&mut fh)? {
+            let bin = u32_le(&mut fh)?;
+            for j in 0..u32_le(&mut fh)? {
+                let (beg, end) = (u64_le(&mut fh)?, u64_le(&mut fh)?);
+                if bin != PSEUDO_BIN || j == 0 {
+                    offsets.insert(beg);
+                    offsets.insert(end);
+                }
+            }
+        }
+        for _ in 0..u32_le(&mut fh)? {
+            match u64_le(&mut fh)? {
+                0 => {},
+                x => {
+                    offsets.insert(x);
+                },
+            }
+        }
+    }
+    Some(offsets)
+}
+
+pub fn read_bai(path: &str) -> Result<BTreeSet<u64>, String> {
+    let data = fs::read(ospath::path(path)).map_err(|e| format!("failed to read {}: {}", path, e))?;
+    parse_bai(&data).ok_or_else(|| format!("{} is not a BAI index (CSI is not supported)!", path))
+}
+
+// primary records of a template
+fn is_primary(r: &Record) -> bool {
+    !r.is_secondary() && !r.is_supplementary()
+}
+
+fn is_mate(r: &Record, of: &Record) -> bool {
+    is_primary(r) && r.qname() == of.qname() && r.is_first_in_template() != of.is_first_in_template()
+}
+
+// sample `num` templates of an indexed, coordinate-sorted BAM without reading it through: a random
+// compressed byte is drawn, reading resumes from the closest record boundary the index knows before it,
+// and one of the records starting in the BGZF block of that byte is picked; its mate is fetched through
+// the index where it aligns. Blocks are drawn in proportion to their compressed size, so templates are
+// about as likely to be picked as one another as long as their reads compress alike; a pair found
+// whole is kept on half of its draws, so that it is no likelier than a single read. Secondary and
+// supplementary alignments are left out. Returns the number of templates written.
+pub fn sample(infile: &str, outfile: &str, num: usize, seed: Seed, threads: usize) -> usize {
+    let bai = match find_bai(infile) {
+        Some(p) => p,
+        None => {
+            error!("{} has no index! Please run 'samtools index {}' first!", infile, infile);
+            status::fail(Code::Missing);
+        },
+    };
+    let mut offsets = read_bai(&bai).unwrap_or_else(|e| {
+        error!("{}", e);
+        status::fail(Code::Malformed)
+    });
+    let (mut infh, mut idx) = match (bam::Reader::from_path(ospath::path(infile)), bam::IndexedReader::from_path_and_index(ospath::path(infile), &bai)) {
+        (Ok(a), Ok(b)) => (a, b),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("failed to read {}: {}", infile, e);
+            status::fail(Code::Malformed);
+        },
+    };
+    let header = bam::Header::from_template(infh.header());
+    crate::check_header(&header, "coordinate");
+    if threads > 1 {
+        infh.set_threads(threads).expect("failed to set --threads!");
+    }
+    let records: u64 = match idx.index_stats() {
+        Ok(v) => v.iter().map(|x| x.2 + x.3).sum(),
+        Err(e) => {
+            error!("failed to read {}: {}", bai, e);
+            status::fail(Code::Malformed);
+        },
+    };
+    info!("{} records in {} by its index.", records, infile);
+    // past a small fraction of the input, draws keep hitting templates already taken
+    if num as u64 * 10 > records {
+        error!("--num {} is over a tenth of the {} records; --mode random-access is for small samples of big inputs, use --mode subsample on a name-sorted copy instead.", num, records);
+        status::fail(Code::Args);
+    }
+    // the first record starts right after the header
+    let first = infh.tell() as u64;
+    offsets.insert(first);
+    let end = match fs::metadata(ospath::path(infile)) {
+        Ok(m) => m.len().saturating_sub(EOF_LEN),
+        Err(e) => {
+            error!("failed to read {}: {}", infile, e);
+            status::fail(Code::Io);
+        },
+    };
+
+    let mut rng = seed.generator();
+    let mut taken: HashSet<Vec<u8>> = HashSet::new();
+    let mut out: Vec<Record> = Vec::new();
+    let (mut draws, mut broken) = (0, 0);
+    let max_draws = 20 * num + 10_000;
+    while taken.len() < num && draws < max_draws && first >> 16 < end {
+        draws += 1;
+        let u = rng.gen_range((first >> 16)..end);
+        // a boundary in the block of the drawn byte may be past some of its records, so reading
+        // resumes from one in an earlier block
+        let near = *offsets.range(..(u + 1) << 16).next_back().unwrap_or(&first);
+        let v = match near & 0xffff {
+            0 => near,
+            _ => *offsets.range(..(near >> 16) << 16).next_back().unwrap_or(&first),
+        };
+        if let Err(e) = infh.seek(v as i64) {
+            error!("failed to seek in {}: {}", infile, e);
+            status::fail(Code::Malformed);
+        }
+        // the records ending in the block of the drawn byte, i.e. starting in it as htslib does not
+        // split records between blocks, along with that block, the record before them and the first one
+        // past; where every block ends is remembered as where its next record starts, so that later draws
+        // nearby have less to read, as among the unmapped reads at the end, which the index has nothing on
+        let mut window: Vec<(u64, Record)> = Vec::new();
+        loop {
+            let pos = infh.tell() as u64;
+            let mut r = Record::new();
+            match infh.read(&mut r) {
+                None => break,
+                Some(Ok(())) => {},
+                Some(Err(e)) => {
+                    error!("empty record: {}", e);
+                    status::fail(Code::Malformed);
+                },
+            }
+            let block = infh.tell() as u64 >> 16;
+            if window.last().is_some_and(|x| x.0 != block) {
+                offsets.insert(pos);
+                if block <= u {
+                    window.drain(..window.len() - 1);
+                }
+            }
+            window.push((block, r));
+            if block > u {
+                break;
+            }
+        }
+        let last = match window.iter().rev().find(|x| x.0 <= u) {
+            Some(x) => x.0,
+            None => continue,
+        };
+        let candidates: Vec<usize> = (0..window.len()).filter(|&i| window[i].0 == last).collect();
+        let i = candidates[rng.gen_range(0..candidates.len())];
+        let r = &window[i].1;
+        if !is_primary(r) || taken.contains(r.qname()) {
+            continue;
+        }
+        let mut rs = vec![r.clone()];
+        if r.is_paired() {
+            let mate = match r.mtid() {
+                // a mate with no position is unmapped and sits next to the read at the end of the file
+                t if t < 0 => [i.checked_sub(1), Some(i + 1)].iter().flatten().filter_map(|&j| window.get(j)).map(|x| &x.1).find(|m| is_mate(m, r)).cloned(),
+                t => {
+                    if let Err(e) = idx.fetch((t, r.mpos(), r.mpos() + 1)) {
+                        error!("failed to fetch the mate of {} from {}: {}", String::from_utf8_lossy(r.qname()), infile, e);
+                        status::fail(Code::Malformed);
+                    }
+                    idx.records().filter_map(Result::ok).find(|m| is_mate(m, r))
+                },
+            };
+            match mate {
+                // either mate could have been drawn, so a pair is kept on every other draw
+                Some(_) if rng.gen::<bool>() => continue,
+                Some(m) => rs.push(m),
+                None => {
+                    broken += 1;
+                    debug!("broken pair: {}", String::from_utf8_lossy(r.qname()));
+                },
+            }
+        }
+        taken.insert(r.qname().to_vec());
+        out.extend(rs);
+    }
+    info!("{} templates picked in {} draws.", taken.len(), draws);
+    if taken.len() < num {
+        warn!("{} templates found of the {} asked for, giving up after {} draws.", taken.len(), num, draws);
+    }
+    if broken > 0 {
+        warn!("{} incomplete templates (broken pairs) found, kept.", broken);
+    }
+
+    // back in coordinate order, unplaced reads last
+    out.sort_by_key(|r| (r.tid() as u32, r.pos(), !r.is_first_in_template()));
+    let mut outfh = crate::open_output(outfile, &header, threads);
+    for r in &out {
+        if let Err(e) = outfh.write(r) {
+            error!("failed to write {}: {}", outfile, e);
+            status::fail(Code::Io);
+        }
+    }
+    taken.len()
+}
@@ -0,0 +1,254 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use getopts::Options;
+use rust_htslib::{bam, bam::Read, bam::Record};
+use log::{error, info, warn};
+
+use crate::completions::{OptDef, Kind};
+use crate::ospath;
+use crate::reservoir;
+use crate::rng::{self, Seed};
+use crate::seek::find_bai;
+use crate::status::{self, Code, bad_args};
+use crate::LogFormat;
+
+// the options of `sam_subsample snapshot`
+static OPTIONS: &[OptDef] = &[
+    ("i", "infile", "input BAM, sorted by coordinate and indexed", "FILE", Kind::Opt),
+    ("", "loci", "loci to make a bundle of: a BED (chrom, start, end and an optional name), or chrom:pos and chrom:start-end lines, 1-based as IGV shows them", "FILE", Kind::Opt),
+    ("o", "outdir", "directory to write the BAM and BAI of every locus to, created if need be", "DIR", Kind::Opt),
+    ("n", "num", "most templates per locus, all of them where fewer overlap it; K/M/G suffixes allowed (default: 50)", "INTEGER", Kind::Opt),
+    ("", "padding", "bases on either side of a locus that templates may overlap instead (default: 0)", "INTEGER", Kind::Opt),
+    ("s", "seed", "seed, from which every locus gets its own by its name (default: 43)", "INTEGER", Kind::Opt),
+    ("h", "help", "print usage", "", Kind::Flag),
+];
+
+// a locus of --loci: its name, the name of its bundle, and its 0-based half-open span
+struct Locus {
+    name: String,
+    file: String,
+    chrom: String,
+    start: i64,
+    end: i64,
+}
+
+// the loci of a BED, or of chrom:pos and chrom:start-end lines; a BED line without a name, and any other
+// line, is named chrom_start_end, 1-based
+fn read_loci(path: &str) -> Result<Vec<Locus>, String> {
+    let text = fs::read_to_string(ospath::path(path)).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    let mut loci: Vec<Locus> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") {
+            continue;
+        }
+        let malformed = || format!("{} line {} is neither chrom, start and end nor chrom:pos or chrom:start-end: {}", path, i + 1, line);
+        let fields: Vec<&str> = line.split('\t').collect();
+        let (chrom, start, end, name) = match fields.len() {
+            1 => {
+                let (chrom, span) = line.trim().rsplit_once(':').ok_or_else(malformed)?;
+                let (start, end) = match span.replace(',', "").split_once('-') {
+                    Some((s, e)) => (s.parse::<i64>().ok(), e.parse::<i64>().ok()),
+                    None => (span.replace(',', "").parse::<i64>().ok(), span.replace(',', "").parse::<i64>().ok()),
+                };
+                match (start, end) {
+                    (Some(s), Some(e)) if 1 <= s && s <= e => (chrom, s - 1, e, None),
+                    _ => return Err(malformed()),
+                }
+            },
+            _ if fields.len() >= 3 => match (fields[1].parse::<i64>(), fields[2].parse::<i64>()) {
+                (Ok(s), Ok(e)) if 0 <= s && s < e => (fields[0], s, e, fields.get(3).filter(|x| !x.is_empty()).map(|x| x.to_string())),
+                _ => return Err(malformed()),
+            },
+            _ => return Err(malformed()),
+        };
+        let name = name.unwrap_or_else(|| format!("{}_{}_{}", chrom, start + 1, end));
+        if loci.iter().any(|l| l.name == name) {
+            return Err(format!("{} line {}: locus {} is given more than once", path, i + 1, name));
+        }
+        // told apart regardless of case, as file systems may not
+        let file = format!("{}.bam", sanitize(&name));
+        if let Some(l) = loci.iter().find(|l| l.file.eq_ignore_ascii_case(&file)) {
+            return Err(format!("{} line {}: locus {} would go to {}, as locus {} does; rename either", path, i + 1, name, file, l.name));
+        }
+        loci.push(Locus { name, file, chrom: chrom.to_string(), start, end });
+    }
+    if loci.is_empty() {
+        return Err(format!("{} has no loci", path));
+    }
+    Ok(loci)
+}
+
+// a locus name as it goes into a file name
+fn sanitize(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || "._-".contains(c) { c } else { '_' }).collect()
+}
+
+fn is_primary(r: &Record) -> bool {
+    !r.is_secondary() && !r.is_supplementary()
+}
+
+fn usage(opts: &Options) {
+    let s = "\
+Summary:
+Write a small, indexed BAM of up to --num random templates overlapping every locus of a list, to review in IGV
+
+Usage:
+sam_subsample snapshot --infile input.bam --loci variants.bed --outdir bundles [--num 50] [--padding 100] [--seed 43]";
+    println!("{}", opts.usage(s));
+}
+
+// `sam_subsample snapshot`, given the arguments after it
+pub fn run(args: &[String]) {
+    let mut opts = Options::new();
+    for &(short, long, desc, hint, kind) in OPTIONS {
+        match kind {
+            Kind::Flag => opts.optflag(short, long, desc),
+            _ => opts.optopt(short, long, desc, hint),
+        };
+    }
+    let m = opts.parse(args).unwrap_or_else(|e| bad_args(&format!("failed to parse arguments: {}", e)));
+    if m.opt_present("h") {
+        usage(&opts);
+        return;
+    }
+    let infile = m.opt_str("infile").unwrap_or_else(|| bad_args("--infile is required!"));
+    let loci_file = m.opt_str("loci").unwrap_or_else(|| bad_args("--loci is required!"));
+    let outdir = m.opt_str("outdir").unwrap_or_else(|| bad_args("--outdir is required!"));
+    let num = crate::opt_count(&m, "num", 50);
+    if num == 0 {
+        bad_args("--num must be positive");
+    }
+    let padding: i64 = match m.opt_get_default("padding", 0) {
+        Ok(x) if x >= 0 => x,
+        _ => bad_args("invalid --padding, must be a non-negative integer"),
+    };
+    let seed = Seed::new(rng::Kind::Pcg64, m.opt_get_default("seed", 43).unwrap_or_else(|_| bad_args("invalid --seed, must be integer")));
+    crate::init_logger("info", None, None, LogFormat::Plain);
+
+    for f in [&infile, &loci_file] {
+        if !ospath::path(f).exists() {
+            error!("{} does not exist!", f);
+            status::fail(Code::Missing);
+        }
+    }
+    let loci = read_loci(&loci_file).unwrap_or_else(|e| {
+        error!("{}", e);
+        status::fail(Code::Malformed)
+    });
+    let bai = find_bai(&infile).unwrap_or_else(|| {
+        error!("{} has no index! Please run 'samtools index {}' first!", infile, infile);
+        status::fail(Code::Missing)
+    });
+    let mut idx = bam::IndexedReader::from_path_and_index(ospath::path(&infile), &bai).unwrap_or_else(|e| {
+        error!("failed to read {}: {}", infile, e);
+        status::fail(Code::Malformed)
+    });
+    let header = bam::Header::from_template(idx.header());
+    crate::check_header(&header, "coordinate");
+    if let Err(e) = fs::create_dir_all(ospath::path(&outdir)) {
+        error!("failed to create {}: {}", outdir, e);
+        status::fail(Code::Io);
+    }
+
+    let mut short = 0;
+    for locus in &loci {
+        let tid = match idx.header().tid(locus.chrom.as_bytes()) {
+            Some(tid) => tid,
+            None => {
+                warn!("{} is on {}, not a reference of {}; skipped.", locus.name, locus.chrom, infile);
+                continue;
+            },
+        };
+        let (start, end) = ((locus.start - padding).max(0), locus.end + padding);
+        if let Err(e) = idx.fetch((tid, start, end)) {
+            error!("failed to fetch {} from {}: {}", locus.name, infile, e);
+            status::fail(Code::Malformed);
+        }
+        let records: Vec<Record> = idx.records().map(|r| r.unwrap_or_else(|e| {
+            error!("failed to read {}: {}", infile, e);
+            status::fail(Code::Malformed)
+        })).collect();
+        // templates by their first primary alignment overlapping the locus, in coordinate order
+        let mut qnames: Vec<&[u8]> = Vec::new();
+        let mut seen: HashSet<&[u8]> = HashSet::new();
+        for r in records.iter().filter(|r| is_primary(r) && !r.is_unmapped()) {
+            if seen.insert(r.qname()) {
+                qnames.push(r.qname());
+            }
+        }
+        if qnames.len() < num {
+            short += 1;
+        }
+        // every locus draws with a seed of its own, so that a bundle stays the same whatever the others
+        let picks = reservoir::pick_indices(qnames.len(), num, seed.derive(locus.name.as_bytes()));
+        let taken: HashSet<&[u8]> = picks.iter().map(|&i| qnames[i]).collect();
+        let mut out: Vec<Record> = records.iter().filter(|r| taken.contains(r.qname())).cloned().collect();
+        // mates aligned away from the locus are fetched where they are, for IGV to show the pairs
+        let found: HashSet<(Vec<u8>, bool)> = out.iter().filter(|r| is_primary(r)).map(|r| (r.qname().to_vec(), r.is_first_in_template())).collect();
+        let away: Vec<(Vec<u8>, bool, i32, i64)> = out.iter()
+            .filter(|r| is_primary(r) && r.is_paired() && r.mtid() >= 0 && !found.contains(&(r.qname().to_vec(), !r.is_first_in_template())))
+            .map(|r| (r.qname().to_vec(), !r.is_first_in_template(), r.mtid(), r.mpos()))
+            .collect();
+        let mut mates: HashMap<Vec<u8>, Record> = HashMap::new();
+        for (qname, first, mtid, mpos) in away {
+            if let Err(e) = idx.fetch((mtid, mpos, mpos + 1)) {
+                error!("failed to fetch the mate of {} from {}: {}", String::from_utf8_lossy(&qname), infile, e);
+                status::fail(Code::Malformed);
+            }
+            if let Some(m) = idx.records().filter_map(Result::ok).find(|m| is_primary(m) && m.qname() == &qname[..] && m.is_first_in_template() == first) {
+                mates.insert(qname, m);
+            }
+        }
+        out.extend(mates.into_values());
+        out.sort_by_key(|r| (r.tid() as u32, r.pos()));
+
+        let outfile = format!("{}/{}", outdir, locus.file);
+        let mut outfh = crate::open_output(&outfile, &header, 1);
+        for r in &out {
+            crate::write(&mut outfh, r, &outfile);
+        }
+        drop(outfh);
+        if let Err(e) = bam::index::build(ospath::path(&outfile), None, bam::index::Type::Bai, 1) {
+            error!("failed to index {}: {}", outfile, e);
+            status::fail(Code::Io);
+        }
+        info!("{}: {} of {} templates, {} records -> {}", locus.name, taken.len(), qnames.len(), out.len(), outfile);
+    }
+    if short > 0 {
+        info!("{} of {} loci have fewer than --num {} templates; all of theirs are taken.", short, loci.len(), num);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loci(text: &str) -> Result<Vec<Locus>, String> {
+        let path = std::env::temp_dir().join(format!("sam_subsample.{}.{}.loci", std::process::id(), crate::uniform::hash(text.as_bytes(), 0)));
+        fs::write(&path, text).unwrap();
+        let loci = read_loci(path.to_str().unwrap());
+        let _ = fs::remove_file(&path);
+        loci
+    }
+
+    #[test]
+    fn loci_are_named_and_filed() {
+        let l = loci("chr1\t99\t200\trs1\nchr2:1,000-2,000\nchr3:5\n").unwrap();
+        assert_eq!(l.iter().map(|l| l.name.as_str()).collect::<Vec<_>>(), ["rs1", "chr2_1000_2000", "chr3_5_5"]);
+        assert_eq!((l[1].start, l[1].end), (999, 2000));
+        assert_eq!(l[0].file, "rs1.bam");
+    }
+
+    #[test]
+    fn names_of_the_same_file_are_turned_down() {
+        assert!(loci("chr1\t0\t10\ta/b\nchr1\t20\t30\ta_b\n").unwrap_err().contains("would go to a_b.bam"));
+        assert!(loci("chr1\t0\t10\tBRCA1\nchr1\t20\t30\tbrca1\n").is_err());
+        assert!(loci("chr1\t0\t10\tx\nchr1\t20\t30\tx\n").unwrap_err().contains("more than once"));
+    }
+}
@@ -0,0 +1,422 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::mem::take;
+use rust_htslib::{bam, bam::Read, bam::Record, bam::record::Aux, bgzf};
+use log::{error, info, warn};
+
+use crate::header;
+use crate::ospath;
+use crate::uniform;
+use crate::tags;
+use crate::status::{self, Code};
+
+// what --split-by sends templates to separate outputs by
+#[derive(Clone, Copy, PartialEq)]
+pub enum SplitBy {
+    ReadGroup,
+    Chromosome,
+    Tag([u8; 2]),
+    // the SM of the read group, per --per-sample-num
+    Sample,
+}
+
+impl SplitBy {
+    pub fn parse(s: &str) -> Option<SplitBy> {
+        match s {
+            "read-group" => Some(SplitBy::ReadGroup),
+            "chromosome" => Some(SplitBy::Chromosome),
+            _ => {
+                let tag: [u8; 2] = s.strip_prefix("tag:")?.as_bytes().try_into().ok()?;
+                match tag[0].is_ascii_alphabetic() && tag[1].is_ascii_alphanumeric() {
+                    true => Some(SplitBy::Tag(tag)),
+                    false => None,
+                }
+            },
+        }
+    }
+}
+
+// the groups given to --allowlist, one per line, e.g. the barcodes.tsv.gz of Cell Ranger; gzipped or not
+pub fn read_allowlist(path: &str) -> Result<HashSet<String>, String> {
+    let fh = bgzf::Reader::from_path(ospath::path(path)).map_err(|e| format!("failed to read {}: {}", path, e))?;
+    let mut values = HashSet::new();
+    for line in BufReader::new(fh).lines() {
+        let line = line.map_err(|e| format!("failed to read {}: {}", path, e))?;
+        let value = line.split('\t').next().unwrap_or_default().trim();
+        if !value.is_empty() {
+            values.insert(value.to_string());
+        }
+    }
+    Ok(values)
+}
+
+// an output of a group, and the records written to it
+struct Part {
+    path: String,
+    fh: bam::Writer,
+    n: usize,
+}
+
+// the read group of the first primary read of a template that has one
+pub fn read_group(rs: &[Record]) -> Option<String> {
+    rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary()).find_map(|r| match r.aux(b"RG") {
+        Ok(Aux::String(s)) => Some(s.to_string()),
+        _ => None,
+    })
+}
+
+// the group of a template: the read group or tag value of the first primary read that has one, or
+// the reference (`names` by tid) the primary alignment of its first mate (or else of any mapped read)
+// is on; the sample of its read group is in the header, which only the `Splitter` has
+pub fn group(by: SplitBy, names: &[String], rs: &[Record]) -> Option<String> {
+    let mut primary = rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary());
+    match by {
+        SplitBy::ReadGroup => read_group(rs),
+        SplitBy::Sample => None,
+        SplitBy::Chromosome => {
+            let mut mapped = primary.filter(|r| !r.is_unmapped());
+            let r = mapped.clone().find(|r| r.is_first_in_template()).or_else(|| mapped.next())?;
+            names.get(r.tid() as usize).cloned()
+        },
+        SplitBy::Tag(tag) => primary.find_map(|r| r.aux(&tag).ok().and_then(|a| tags::text(&a))),
+    }
+}
+
+// one output per group, opened as the groups turn up: out.bam splits into out.<group>.bam, or the {group}
+// of --outfile-template is filled in; past
+// --max-open-files, templates of groups not open yet are spilled to a temporary BAM and split off it
+// in further passes, as many groups at a time
+pub struct Splitter {
+    by: SplitBy,
+    prefix: String,
+    pattern: Option<String>,
+    header: bam::Header,
+    // reference names by tid
+    names: Vec<String>,
+    // samples by read group
+    samples: HashMap<String, String>,
+    threads: usize,
+    min_records: usize,
+    allowlist: Option<HashSet<String>>,
+    max_open: usize,
+    outputs: HashMap<String, Part>,
+    // the paths given to groups so far, lowercased, as file systems may not tell case apart
+    paths: HashSet<String>,
+    // outputs closed after an earlier pass, and the records written to them
+    closed: Vec<(String, usize)>,
+    // the spill of the current pass, and the group and record count of every template in it
+    spill: Option<(String, bam::Writer)>,
+    spilled: Vec<(String, usize)>,
+    passes: usize,
+    // templates of no group, or of one not in --allowlist, dropped
+    lost: usize,
+}
+
+// a group as it goes into a file name
+fn sanitize(group: &str) -> String {
+    group.chars().map(|c| if c.is_ascii_alphanumeric() || "._-".contains(c) { c } else { '_' }).collect()
+}
+
+impl Splitter {
+    pub fn new(by: SplitBy, outfile: &str, header: &bam::Header, threads: usize, min_records: usize, allowlist: Option<&HashSet<String>>, max_open: usize) -> Splitter {
+        let names = header::names(header);
+        // spills of an --outfile-template with {group} go by the name of none of the groups
+        let base = outfile.replace("{group}", "groups");
+        Splitter {
+            by,
+            prefix: base.strip_suffix(".bam").unwrap_or(&base).to_string(),
+            pattern: outfile.contains("{group}").then(|| outfile.to_string()),
+            header: header.clone(),
+            names,
+            samples: header::samples(header),
+            threads,
+            min_records,
+            allowlist: allowlist.cloned(),
+            max_open,
+            outputs: HashMap::new(),
+            paths: HashSet::new(),
+            closed: Vec::new(),
+            spill: None,
+            spilled: Vec::new(),
+            passes: 0,
+            lost: 0,
+        }
+    }
+
+    fn group(&self, rs: &[Record]) -> Option<String> {
+        match self.by {
+            SplitBy::Sample => read_group(rs).and_then(|rg| self.samples.get(&rg).cloned()),
+            by => group(by, &self.names, rs),
+        }
+    }
+
+    // the output a template goes to, as it was read; None (and counted) if it has no group or one not
+    // in --allowlist
+    pub fn group_of(&mut self, rs: &[Record]) -> Option<String> {
+        match self.group(rs) {
+            Some(g) if self.allowlist.as_ref().is_none_or(|a| a.contains(&g)) => Some(g),
+            _ => {
+                self.lost += 1;
+                None
+            },
+        }
+    }
+
+    // write the records of a template to the output of `group`, or spill them
+    pub fn write(&mut self, group: String, rs: &[Record]) {
+        if !self.outputs.contains_key(&group) && self.outputs.len() >= self.max_open {
+            if self.spill.is_none() {
+                let path = format!("{}.spill{}.bam", self.prefix, self.passes);
+                let fh = crate::open_output(&path, &self.header, self.threads);
+                self.spill = Some((path, fh));
+            }
+            let (path, fh) = self.spill.as_mut().unwrap();
+            for r in rs {
+                if let Err(e) = fh.write(r) {
+                    error!("failed to write {}: {}", path, e);
+                    status::fail(Code::Io);
+                }
+            }
+            self.spilled.push((group, rs.len()));
+            return;
+        }
+        if !self.outputs.contains_key(&group) {
+            let path = self.path_of(&group);
+            // references stay in the header of every output so that tids need no remapping
+            let header = match self.by {
+                SplitBy::ReadGroup => header::only_rg(&self.header, &group),
+                SplitBy::Sample => header::only_sample(&self.header, &group),
+                _ => self.header.clone(),
+            };
+            let fh = crate::open_output(&path, &header, self.threads);
+            self.outputs.insert(group.clone(), Part { path, fh, n: 0 });
+        }
+        let part = self.outputs.get_mut(&group).unwrap();
+        for r in rs {
+            if let Err(e) = part.fh.write(r) {
+                error!("failed to write {}: {}", part.path, e);
+                status::fail(Code::Io);
+            }
+        }
+        part.n += rs.len();
+    }
+
+    // the output a group goes to, named after it; should another group have come out the same, e.g. a/b
+    // and a_b, it is numbered, out.a_b.2.bam, rather than writing over the other
+    fn path_of(&mut self, group: &str) -> String {
+        let named = |name: &str| match &self.pattern {
+            Some(p) => p.replace("{group}", name),
+            None => format!("{}.{}.bam", self.prefix, name),
+        };
+        let name = sanitize(group);
+        let mut path = named(&name);
+        let mut n = 1;
+        while self.paths.contains(&path.to_lowercase()) {
+            n += 1;
+            path = named(&format!("{}.{}", name, n));
+        }
+        if n > 1 {
+            warn!("{} goes to {}, as {} is another group's.", group, path, named(&name));
+        }
+        self.paths.insert(path.to_lowercase());
+        path
+    }
+
+    // close the outputs of a pass; their groups are complete, as a group only spills when not open
+    fn close(&mut self) {
+        for (_, part) in self.outputs.drain() {
+            drop(part.fh);
+            self.closed.push((part.path, part.n));
+        }
+    }
+
+    // split the spills in further passes, close the outputs, removing those of fewer than
+    // --min-records, and log what went where; the outputs kept
+    pub fn finish(mut self) -> Vec<String> {
+        while let Some((path, fh)) = self.spill.take() {
+            drop(fh);
+            self.close();
+            self.passes += 1;
+            info!("Splitting the templates of {} more groups off {}.", self.spilled.iter().map(|x| &x.0).collect::<HashSet<_>>().len(), path);
+            let mut infh = match bam::Reader::from_path(ospath::path(&path)) {
+                Ok(f) => f,
+                Err(e) => {
+                    error!("failed to read {}: {}", path, e);
+                    status::fail(Code::Io);
+                },
+            };
+            for (group, n) in take(&mut self.spilled) {
+                let mut rs: Vec<Record> = Vec::with_capacity(n);
+                for _ in 0..n {
+                    let mut r = Record::new();
+                    match infh.read(&mut r) {
+                        Some(Ok(())) => rs.push(r),
+                        Some(Err(e)) => {
+                            error!("failed to read {}: {}", path, e);
+                            status::fail(Code::Io);
+                        },
+                        None => {
+                            error!("{} ended early!", path);
+                            status::fail(Code::Io);
+                        },
+                    }
+                }
+                self.write(group, &rs);
+            }
+            drop(infh);
+            let _ = fs::remove_file(ospath::path(&path));
+        }
+        self.close();
+        let mut kept: Vec<String> = Vec::new();
+        let mut small = 0;
+        for (path, n) in self.closed {
+            if n < self.min_records {
+                let _ = fs::remove_file(ospath::path(&path));
+                small += 1;
+            } else {
+                kept.push(path);
+            }
+        }
+        kept.sort();
+        info!("Split into {} outputs: {}.", kept.len(), kept.join(", "));
+        if small > 0 {
+            info!("{} outputs of fewer than --min-records {} records removed.", small, self.min_records);
+        }
+        if self.lost > 0 {
+            info!("{} templates without a group (or not in --allowlist) dropped by --split-by.", self.lost);
+        }
+        kept
+    }
+
+    // remove the outputs and spill, as when failing on --strict
+    pub fn remove(mut self) {
+        self.close();
+        for (path, _) in self.closed {
+            let _ = fs::remove_file(ospath::path(&path));
+        }
+        if let Some((path, fh)) = self.spill {
+            drop(fh);
+            let _ = fs::remove_file(ospath::path(&path));
+        }
+    }
+}
+
+// the output rolled over into numbered files of --records-per-file templates each, out.bam into
+// out.0001.bam, out.0002.bam, ...; the first is opened up front, so that there is one even when
+// nothing is sampled
+pub struct Chunks {
+    prefix: String,
+    header: bam::Header,
+    threads: usize,
+    per_file: usize,
+    fh: bam::Writer,
+    // templates written to the current file
+    n: usize,
+    paths: Vec<String>,
+}
+
+impl Chunks {
+    pub fn new(outfile: &str, header: &bam::Header, threads: usize, per_file: usize) -> Chunks {
+        let prefix = outfile.strip_suffix(".bam").unwrap_or(outfile).to_string();
+        let path = format!("{}.{:04}.bam", prefix, 1);
+        let fh = crate::open_output(&path, header, threads);
+        Chunks { prefix, header: header.clone(), threads, per_file, fh, n: 0, paths: vec![path] }
+    }
+
+    // write the records of a template, to the next file if the current one is full; a template is
+    // never split between files
+    pub fn write(&mut self, rs: &[Record]) {
+        if self.n == self.per_file {
+            let path = format!("{}.{:04}.bam", self.prefix, self.paths.len() + 1);
+            // the previous file is flushed and closed as it is dropped
+            self.fh = crate::open_output(&path, &self.header, self.threads);
+            self.paths.push(path);
+            self.n = 0;
+        }
+        let path = self.paths.last().unwrap();
+        for r in rs {
+            if let Err(e) = self.fh.write(r) {
+                error!("failed to write {}: {}", path, e);
+                status::fail(Code::Io);
+            }
+        }
+        self.n += 1;
+    }
+
+    pub fn finish(self) -> Vec<String> {
+        info!("Wrote {} files of up to {} templates each: {}.", self.paths.len(), self.per_file, self.paths.join(", "));
+        self.paths
+    }
+
+    // remove the files, as when failing on --strict
+    pub fn remove(self) {
+        drop(self.fh);
+        for path in self.paths {
+            let _ = fs::remove_file(ospath::path(&path));
+        }
+    }
+}
+
+// how --shard assigns templates to shards
+#[derive(Clone, Copy, PartialEq)]
+pub enum ShardBy {
+    RoundRobin,
+    Qname,
+}
+
+// the outputs of --shard, named by --shard-template, all opened up front so that every shard exists
+// even when nothing is sampled
+pub struct Shards {
+    by: ShardBy,
+    paths: Vec<String>,
+    fhs: Vec<bam::Writer>,
+    // templates written to every shard
+    counts: Vec<usize>,
+}
+
+impl Shards {
+    pub fn new(template: &str, header: &bam::Header, threads: usize, n: usize, by: ShardBy) -> Shards {
+        let width = n.to_string().len();
+        let paths: Vec<String> = (1..=n).map(|i| template.replacen("{}", &format!("{:0w$}", i, w = width), 1)).collect();
+        let fhs = paths.iter().map(|p| crate::open_output(p, header, threads)).collect();
+        Shards { by, paths, fhs, counts: vec![0; n] }
+    }
+
+    // write the records of a template to its shard: the next in turn, or that of its qname, hashed with
+    // a seed of its own so that the shard does not depend on --seed
+    pub fn write(&mut self, rs: &[Record]) {
+        let i = match self.by {
+            ShardBy::RoundRobin => self.counts.iter().sum::<usize>() % self.fhs.len(),
+            ShardBy::Qname => (uniform::hash(rs.first().map_or(&b""[..], |r| r.qname()), 0) % self.fhs.len() as u64) as usize,
+        };
+        for r in rs {
+            if let Err(e) = self.fhs[i].write(r) {
+                error!("failed to write {}: {}", self.paths[i], e);
+                status::fail(Code::Io);
+            }
+        }
+        self.counts[i] += 1;
+    }
+
+    pub fn finish(self) -> Vec<String> {
+        let (min, max) = (self.counts.iter().min().copied().unwrap_or_default(), self.counts.iter().max().copied().unwrap_or_default());
+        info!("Wrote {} shards of {} to {} templates each: {}.", self.paths.len(), min, max, self.paths.join(", "));
+        self.paths
+    }
+
+    // remove the shards, as when failing on --strict
+    pub fn remove(self) {
+        drop(self.fhs);
+        for path in self.paths {
+            let _ = fs::remove_file(ospath::path(&path));
+        }
+    }
+}
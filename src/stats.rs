@@ -0,0 +1,336 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::collections::{BTreeMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use rust_htslib::bam::Record;
+
+use crate::ospath;
+use crate::strata;
+
+// how many templates have 1, 2, 3, ... records, in the input and in the output
+#[derive(Default)]
+pub struct TemplateStats {
+    input: Vec<usize>,
+    output: Vec<usize>,
+}
+
+fn add(hist: &mut Vec<usize>, n: usize) {
+    if hist.len() <= n {
+        hist.resize(n + 1, 0);
+    }
+    hist[n] += 1;
+}
+
+impl TemplateStats {
+    pub fn add_input(&mut self, n: usize) {
+        add(&mut self.input, n);
+    }
+
+    pub fn add_output(&mut self, n: usize) {
+        add(&mut self.output, n);
+    }
+
+    // a TSV of records per template against the templates of the input and output with that many
+    pub fn write(&self, path: &str) -> std::io::Result<()> {
+        let mut fh = BufWriter::new(File::create(ospath::path(path))?);
+        writeln!(fh, "records\tinput\toutput")?;
+        for n in 1..self.input.len().max(self.output.len()) {
+            let x = self.input.get(n).copied().unwrap_or_default();
+            let y = self.output.get(n).copied().unwrap_or_default();
+            if x > 0 || y > 0 {
+                writeln!(fh, "{}\t{}\t{}", n, x, y)?;
+            }
+        }
+        fh.flush()
+    }
+}
+
+// how many templates of the output are on each reference and in each read group, "*" standing for
+// none (unmapped, or no RG tag)
+#[derive(Default)]
+pub struct Tallies {
+    references: BTreeMap<String, usize>,
+    read_groups: BTreeMap<String, usize>,
+}
+
+impl Tallies {
+    pub fn add(&mut self, reference: Option<String>, read_group: Option<String>) {
+        *self.references.entry(reference.unwrap_or_else(|| String::from("*"))).or_default() += 1;
+        *self.read_groups.entry(read_group.unwrap_or_else(|| String::from("*"))).or_default() += 1;
+    }
+
+    // "name=count" pairs, for the log
+    pub fn summary(&self) -> (String, String) {
+        let join = |m: &BTreeMap<String, usize>| m.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join(", ");
+        (join(&self.references), join(&self.read_groups))
+    }
+
+    // a TSV of the templates written per reference, then per read group
+    pub fn write(&self, path: &str) -> std::io::Result<()> {
+        let mut fh = BufWriter::new(File::create(ospath::path(path))?);
+        writeln!(fh, "kind\tname\ttemplates")?;
+        for (kind, m) in &[("reference", &self.references), ("read_group", &self.read_groups)] {
+            for (name, n) in m.iter() {
+                writeln!(fh, "{}\t{}\t{}", kind, name, n)?;
+            }
+        }
+        fh.flush()
+    }
+}
+
+// a histogram of integer values
+#[derive(Default)]
+struct Histogram(BTreeMap<i64, usize>);
+
+impl Histogram {
+    fn add(&mut self, x: i64) {
+        *self.0.entry(x).or_default() += 1;
+    }
+
+    fn n(&self) -> usize {
+        self.0.values().sum()
+    }
+
+    fn mean(&self) -> f64 {
+        self.0.iter().map(|(&x, &c)| x as f64 * c as f64).sum::<f64>() / self.n() as f64
+    }
+
+    // the smallest value with at least a fraction `q` of the values at or below it
+    fn quantile(&self, q: f64) -> i64 {
+        let rank = ((q * self.n() as f64).ceil() as usize).max(1);
+        let mut cum = 0;
+        for (&x, &c) in &self.0 {
+            cum += c;
+            if cum >= rank {
+                return x;
+            }
+        }
+        0
+    }
+
+    // the Kolmogorov-Smirnov distance, the largest gap between the cumulative distributions
+    fn ks(&self, other: &Histogram) -> f64 {
+        let (n, m) = (self.n() as f64, other.n() as f64);
+        let (mut a, mut b) = (self.0.iter().peekable(), other.0.iter().peekable());
+        let (mut fa, mut fb, mut d) = (0.0, 0.0, 0.0f64);
+        loop {
+            let x = match (a.peek(), b.peek()) {
+                (Some(p), Some(q)) => *p.0.min(q.0),
+                (Some(p), None) => *p.0,
+                (None, Some(q)) => *q.0,
+                (None, None) => break,
+            };
+            if let Some((_, &c)) = a.next_if(|p| *p.0 == x) {
+                fa += c as f64 / n;
+            }
+            if let Some((_, &c)) = b.next_if(|q| *q.0 == x) {
+                fb += c as f64 / m;
+            }
+            d = d.max((fa - fb).abs());
+        }
+        d
+    }
+}
+
+static METRICS: &[&str] = &["template_length", "read_length", "mapq"];
+
+static QUANTILES: &[(&str, f64)] = &[("q05", 0.05), ("q25", 0.25), ("median", 0.5), ("q75", 0.75), ("q95", 0.95)];
+
+// the template lengths (|TLEN| of pairs on one reference), read lengths and MAPQs (of mapped primary
+// reads) of the input and the output, for --distribution-report
+#[derive(Default)]
+pub struct Distributions {
+    input: [Histogram; 3],
+    output: [Histogram; 3],
+}
+
+fn add_template(hists: &mut [Histogram; 3], rs: &[Record]) {
+    match strata::insert_size(rs) {
+        0 => {},
+        x => hists[0].add(x),
+    }
+    for r in rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary()) {
+        hists[1].add(r.seq_len() as i64);
+        if !r.is_unmapped() {
+            hists[2].add(i64::from(r.mapq()));
+        }
+    }
+}
+
+impl Distributions {
+    pub fn add_input(&mut self, rs: &[Record]) {
+        add_template(&mut self.input, rs);
+    }
+
+    pub fn add_output(&mut self, rs: &[Record]) {
+        add_template(&mut self.output, rs);
+    }
+
+    // the KS distance of every metric, for the log
+    pub fn summary(&self) -> String {
+        METRICS.iter().enumerate().map(|(i, name)| match self.input[i].n() > 0 && self.output[i].n() > 0 {
+            true => format!("{}={:.4}", name, self.input[i].ks(&self.output[i])),
+            false => format!("{}=NA", name),
+        }).collect::<Vec<_>>().join(", ")
+    }
+
+    // a TSV of the count, mean, extremes and quantiles of every metric in the input and the output,
+    // along with the KS distance between the two
+    pub fn write(&self, path: &str) -> std::io::Result<()> {
+        let mut fh = BufWriter::new(File::create(ospath::path(path))?);
+        let qs: Vec<&str> = QUANTILES.iter().map(|q| q.0).collect();
+        writeln!(fh, "metric\tsample\tn\tmean\tmin\t{}\tmax\tks_distance", qs.join("\t"))?;
+        for (i, name) in METRICS.iter().enumerate() {
+            let ks = match self.input[i].n() > 0 && self.output[i].n() > 0 {
+                true => format!("{:.4}", self.input[i].ks(&self.output[i])),
+                false => String::from("NA"),
+            };
+            for (sample, h) in &[("input", &self.input[i]), ("output", &self.output[i])] {
+                match h.n() {
+                    0 => writeln!(fh, "{}\t{}\t0\tNA\tNA\t{}\tNA\t{}", name, sample, vec!["NA"; qs.len()].join("\t"), ks)?,
+                    n => {
+                        let qv: Vec<String> = QUANTILES.iter().map(|q| h.quantile(q.1).to_string()).collect();
+                        writeln!(fh, "{}\t{}\t{}\t{:.2}\t{}\t{}\t{}\t{}", name, sample, n, h.mean(), h.quantile(0.0), qv.join("\t"), h.quantile(1.0), ks)?;
+                    },
+                }
+            }
+        }
+        fh.flush()
+    }
+}
+
+// what --duplication takes a duplicate template for: one with a primary read flagged so (0x400), or
+// one whose mapped primary reads start where those of an earlier template did, on the same strands
+#[derive(Clone, Copy, PartialEq)]
+pub enum DupBy {
+    Flag,
+    Position,
+}
+
+impl DupBy {
+    pub fn parse(s: &str) -> Option<DupBy> {
+        match s {
+            "flag" => Some(DupBy::Flag),
+            "position" => Some(DupBy::Position),
+            _ => None,
+        }
+    }
+}
+
+// where the mapped primary reads of a template start: reference, unclipped 5' end and strand of each
+pub type Signature = Vec<(i32, i64, bool)>;
+
+pub fn signature(rs: &[Record]) -> Signature {
+    let mut sig: Signature = rs.iter()
+        .filter(|r| !r.is_secondary() && !r.is_supplementary() && !r.is_unmapped())
+        .map(|r| match r.is_reverse() {
+            true => (r.tid(), r.cigar().end_pos() + r.cigar().trailing_softclips(), true),
+            false => (r.tid(), r.pos() - r.cigar().leading_softclips(), false),
+        })
+        .collect();
+    sig.sort_unstable();
+    sig
+}
+
+// distinct signatures kept with DupBy::Position, as 64-bit hashes, about 16 bytes each; past as many,
+// only those with a hash under a threshold halved each time the set fills up again are kept, and the
+// distinct count is estimated from them, to a relative error of about 1 / sqrt(MAX_SIGNATURES)
+const MAX_SIGNATURES: usize = 1 << 22;
+
+fn signature_hash(sig: &Signature) -> u64 {
+    let mut h = DefaultHasher::new();
+    sig.hash(&mut h);
+    h.finish()
+}
+
+// mapped and duplicate templates of one side, and the hashes of the signatures seen so far with
+// DupBy::Position, of which only those under 2^64 >> `level` are kept
+#[derive(Default)]
+struct Dups {
+    mapped: usize,
+    dups: usize,
+    seen: HashSet<u64>,
+    level: u32,
+}
+
+impl Dups {
+    fn add(&mut self, by: DupBy, rs: &[Record]) {
+        let sig = signature(rs);
+        if sig.is_empty() {
+            return;
+        }
+        self.mapped += 1;
+        match by {
+            DupBy::Flag => {
+                if rs.iter().any(|r| !r.is_secondary() && !r.is_supplementary() && r.is_duplicate()) {
+                    self.dups += 1;
+                }
+            },
+            DupBy::Position => {
+                let h = signature_hash(&sig);
+                if h.checked_shr(64 - self.level).unwrap_or(0) == 0 && self.seen.insert(h) && self.seen.len() > MAX_SIGNATURES {
+                    self.level += 1;
+                    let level = self.level;
+                    self.seen.retain(|&h| h >> (64 - level) == 0);
+                }
+            },
+        }
+    }
+
+    // duplicates, counted with DupBy::Flag, and with DupBy::Position the templates less the distinct
+    // signatures, exact until the set first filled up
+    fn dups(&self, by: DupBy) -> usize {
+        match by {
+            DupBy::Flag => self.dups,
+            DupBy::Position => self.mapped.saturating_sub(self.seen.len() << self.level),
+        }
+    }
+
+    fn rate(&self, by: DupBy) -> f64 {
+        match self.mapped {
+            0 => 0.0,
+            n => self.dups(by) as f64 / n as f64,
+        }
+    }
+}
+
+// the duplication rate of the input and of the output, per --duplication; only templates with a
+// mapped primary read count
+pub struct Duplication {
+    by: DupBy,
+    input: Dups,
+    output: Dups,
+}
+
+impl Duplication {
+    pub fn new(by: DupBy) -> Duplication {
+        Duplication {
+            by,
+            input: Dups::default(),
+            output: Dups::default(),
+        }
+    }
+
+    pub fn add_input(&mut self, rs: &[Record]) {
+        self.input.add(self.by, rs);
+    }
+
+    pub fn add_output(&mut self, rs: &[Record]) {
+        self.output.add(self.by, rs);
+    }
+
+    // the two rates, for the log; estimated ones say so
+    pub fn summary(&self) -> String {
+        let about = |d: &Dups| if d.level > 0 { "about " } else { "" };
+        format!("input {}{:.2}% ({} of {} mapped templates), output {}{:.2}% ({} of {})",
+            about(&self.input), 100.0 * self.input.rate(self.by), self.input.dups(self.by), self.input.mapped,
+            about(&self.output), 100.0 * self.output.rate(self.by), self.output.dups(self.by), self.output.mapped)
+    }
+}
@@ -0,0 +1,103 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// exit codes, so that a pipeline can tell why a run stopped; a panic (101) is left to bugs
+#[derive(Clone, Copy)]
+pub enum Code {
+    Args = 2,
+    Missing = 3,
+    Malformed = 4,
+    Io = 5,
+    TooFew = 6,
+    Inexact = 7,
+    Biased = 8,
+    Interrupted = 9,
+    NotContained = 10,
+    TimeLimit = 11,
+}
+
+impl Code {
+    // what --error-format json calls it
+    pub fn name(self) -> &'static str {
+        match self {
+            Code::Args => "bad-arguments",
+            Code::Missing => "missing-input",
+            Code::Malformed => "malformed-input",
+            Code::Io => "io-error",
+            Code::TooFew => "too-few-templates",
+            Code::Inexact => "inexact-count",
+            Code::Biased => "biased-selection",
+            Code::Interrupted => "interrupted",
+            Code::NotContained => "not-contained",
+            Code::TimeLimit => "time-limit",
+        }
+    }
+}
+
+// per --error-format json, a failure also goes to stderr as one JSON object, after the text saying why,
+// for a workflow engine to tell failures apart by
+static JSON: AtomicBool = AtomicBool::new(false);
+// the last error said, and whatever is known of where it happened, for that object
+static MESSAGE: Mutex<Option<String>> = Mutex::new(None);
+static CONTEXT: Mutex<Vec<(&'static str, String)>> = Mutex::new(Vec::new());
+// temporary files of the run, e.g. the named pipes of htsget inputs, which a failure removes too
+static TEMPORARY: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub fn json_errors() {
+    JSON.store(true, Ordering::Relaxed);
+}
+
+// note an error as the one to fail with; the logger notes every error it logs
+pub fn note(msg: &str) {
+    *MESSAGE.lock().unwrap() = Some(msg.to_string());
+}
+
+// note where the failure about to be reported happened, e.g. the file and the number of the record
+pub fn context(key: &'static str, value: &str) {
+    let mut c = CONTEXT.lock().unwrap();
+    c.retain(|x| x.0 != key);
+    c.push((key, value.to_string()));
+}
+
+// note a temporary file for `fail` to remove
+pub fn temporary(path: &str) {
+    TEMPORARY.lock().unwrap().push(path.to_string());
+}
+
+fn json(code: Code) -> String {
+    let message = MESSAGE.lock().unwrap().clone().unwrap_or_default();
+    let context: Vec<String> = CONTEXT.lock().unwrap().iter().map(|(k, v)| format!("\"{}\":\"{}\"", k, crate::json_escape(v))).collect();
+    format!("{{\"code\":{},\"error\":\"{}\",\"message\":\"{}\",\"context\":{{{}}}}}", code as i32, code.name(), crate::json_escape(&message), context.join(","))
+}
+
+pub fn fail(code: Code) -> ! {
+    if JSON.load(Ordering::Relaxed) {
+        eprintln!("{}", json(code));
+    }
+    // not waiting on a lock some other thread failing holds
+    if let Ok(paths) = TEMPORARY.try_lock() {
+        for path in paths.iter() {
+            let _ = std::fs::remove_file(crate::ospath::path(path));
+        }
+    }
+    process::exit(code as i32)
+}
+
+// the logger is not up yet while parsing arguments, so complaints go straight to stderr
+pub fn bad_args(msg: &str) -> ! {
+    complain(msg);
+    fail(Code::Args)
+}
+
+// say what is wrong before the logger is up, e.g. a file of an option missing, ahead of `fail`
+pub fn complain(msg: &str) {
+    eprintln!("{}", msg);
+    note(msg);
+}
@@ -0,0 +1,761 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use rust_htslib::bgzf;
+use rust_htslib::bam::{self, HeaderView, Record};
+use log::warn;
+
+use crate::ospath;
+use crate::tags;
+
+// half-open [lo, hi) ranges of insert size or mean Q, as given to --bins, e.g. 0-200,200-400,400-1000;
+// the last may be open-ended, e.g. 20+
+#[derive(Clone)]
+pub struct Bins(Vec<(i64, i64)>);
+
+impl Bins {
+    pub fn parse(s: &str) -> Option<Bins> {
+        let mut v = Vec::new();
+        for bin in s.split(',') {
+            let bin = bin.trim();
+            let (lo, hi): (i64, i64) = match bin.strip_suffix('+') {
+                Some(lo) => (lo.parse().ok()?, i64::MAX),
+                None => {
+                    let (lo, hi) = bin.split_once('-')?;
+                    (lo.parse().ok()?, hi.parse().ok()?)
+                },
+            };
+            if lo < 0 || lo >= hi {
+                return None;
+            }
+            v.push((lo, hi));
+        }
+        Some(Bins(v))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn name(&self, i: usize) -> String {
+        match self.0[i] {
+            (lo, i64::MAX) => format!("{}+", lo),
+            (lo, hi) => format!("{}-{}", lo, hi),
+        }
+    }
+
+    fn find(&self, x: f64) -> Option<usize> {
+        self.0.iter().position(|&(lo, hi)| lo as f64 <= x && x < hi as f64)
+    }
+
+    // the first bin holding the insert size of a template, if any
+    pub fn of(&self, rs: &[Record]) -> Option<usize> {
+        self.find(insert_size(rs) as f64)
+    }
+
+    // the first bin holding the mean Q of a template, if any
+    pub fn of_mean_q(&self, rs: &[Record]) -> Option<usize> {
+        self.find(mean_q(rs)?)
+    }
+}
+
+// equal bins of GC content over [0, 1], as many as --gc-bins; the last one is closed, so a template
+// of only G and C falls in it
+#[derive(Clone)]
+pub struct GcBins(usize);
+
+impl GcBins {
+    pub fn new(n: usize) -> GcBins {
+        GcBins(n)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0
+    }
+
+    pub fn name(&self, i: usize) -> String {
+        format!("{:.2}-{:.2}", i as f64 / self.0 as f64, (i + 1) as f64 / self.0 as f64)
+    }
+
+    pub fn of(&self, rs: &[Record]) -> Option<usize> {
+        let x = gc_content(rs)?;
+        Some(((x * self.0 as f64) as usize).min(self.0 - 1))
+    }
+}
+
+// values turning up while reading, numbered in the order they do
+#[derive(Clone, Default)]
+struct Values {
+    values: Vec<String>,
+    ids: HashMap<String, usize>,
+}
+
+impl Values {
+    fn id(&mut self, value: String) -> usize {
+        if let Some(&i) = self.ids.get(&value) {
+            return i;
+        }
+        self.values.push(value.clone());
+        self.ids.insert(value, self.values.len() - 1);
+        self.values.len() - 1
+    }
+}
+
+// the values of a tag, and with `untagged` the templates without it as one more, e.g. the unphased
+// reads among the HP haplotypes
+#[derive(Clone)]
+pub struct TagValues {
+    tag: [u8; 2],
+    untagged: bool,
+    values: Values,
+}
+
+impl TagValues {
+    pub fn new(tag: [u8; 2]) -> TagValues {
+        TagValues {
+            tag,
+            untagged: false,
+            values: Values::default(),
+        }
+    }
+
+    pub fn with_untagged(tag: [u8; 2]) -> TagValues {
+        TagValues {
+            untagged: true,
+            ..TagValues::new(tag)
+        }
+    }
+
+    // the value of the tag on the first primary read of a template carrying it; an empty one stands
+    // for none
+    pub fn of(&mut self, rs: &[Record]) -> Option<usize> {
+        let value = rs.iter()
+            .filter(|r| !r.is_secondary() && !r.is_supplementary())
+            .find_map(|r| r.aux(&self.tag).ok().and_then(|a| tags::text(&a)));
+        match (value, self.untagged) {
+            (Some(value), _) => Some(self.values.id(value)),
+            (None, true) => Some(self.values.id(String::new())),
+            (None, false) => None,
+        }
+    }
+}
+
+// the flowcell lanes of Illumina qnames
+#[derive(Clone, Default)]
+pub struct Lanes {
+    values: Values,
+}
+
+impl Lanes {
+    fn of(&mut self, rs: &[Record]) -> Option<usize> {
+        Some(self.values.id(lane(rs[0].qname())?))
+    }
+}
+
+// the flowcell tiles of Illumina qnames, per --per-tile-num
+#[derive(Clone, Default)]
+pub struct Tiles {
+    values: Values,
+}
+
+impl Tiles {
+    fn of(&mut self, rs: &[Record]) -> Option<usize> {
+        Some(self.values.id(tile(rs[0].qname())?))
+    }
+}
+
+// the unit (flowcell or instrument), lane and tile of an Illumina qname:
+// INSTRUMENT:RUN:FLOWCELL:LANE:TILE:X:Y (CASAVA 1.8 and later) or INSTRUMENT:LANE:TILE:X:Y#INDEX/MATE
+// (earlier); None for other qnames
+fn illumina(qname: &[u8]) -> Option<(&str, &str, &str)> {
+    let qname = std::str::from_utf8(qname).ok()?;
+    let qname = qname.split(['#', '/', ' ']).next()?;
+    let fields: Vec<&str> = qname.split(':').collect();
+    let unit = match fields.len() {
+        7 => fields[2],
+        5 => fields[0],
+        _ => return None,
+    };
+    // lane, tile, x and y are numbers
+    let number = |f: &&str| !f.is_empty() && f.bytes().all(|b| b.is_ascii_digit());
+    if unit.is_empty() || !fields[fields.len() - 4..].iter().all(number) {
+        return None;
+    }
+    Some((unit, fields[fields.len() - 4], fields[fields.len() - 3]))
+}
+
+// the lane a read was sequenced on from its Illumina qname: FLOWCELL:LANE, or INSTRUMENT:LANE of
+// the earlier qnames; None for other qnames
+pub fn lane(qname: &[u8]) -> Option<String> {
+    let (unit, lane, _) = illumina(qname)?;
+    Some(format!("{}:{}", unit, lane))
+}
+
+// the tile a read was sequenced on from its Illumina qname: FLOWCELL:LANE:TILE, or INSTRUMENT:LANE:TILE
+// of the earlier qnames; None for other qnames
+pub fn tile(qname: &[u8]) -> Option<String> {
+    let (unit, lane, tile) = illumina(qname)?;
+    Some(format!("{}:{}:{}", unit, lane, tile))
+}
+
+// the tiles of --exclude-tiles, comma-separated, each TILE on any lane, LANE:TILE or FLOWCELL:LANE:TILE,
+// e.g. 1101,2:1102
+pub struct TileList(Vec<String>);
+
+impl TileList {
+    pub fn parse(s: &str) -> Option<TileList> {
+        let mut v = Vec::new();
+        for t in s.split(',') {
+            let fields: Vec<&str> = t.trim().split(':').collect();
+            // the tile, and the lane if given, are numbers
+            let number = |f: &&str| !f.is_empty() && f.bytes().all(|b| b.is_ascii_digit());
+            if fields.len() > 3 || fields[0].is_empty() || !fields.iter().rev().take(2).all(number) {
+                return None;
+            }
+            v.push(t.trim().to_string());
+        }
+        Some(TileList(v))
+    }
+
+    // whether a template was sequenced on one of the tiles, by the qname of its first record; those of
+    // other qnames are not
+    pub fn contains(&self, rs: &[Record]) -> bool {
+        tile(rs[0].qname()).is_some_and(|t| self.0.iter().any(|x| t == *x || t.ends_with(&format!(":{}", x))))
+    }
+}
+
+// the references templates are on, by the primary alignment of their first mate as with --split-by
+// chromosome
+#[derive(Clone, Default)]
+pub struct Chromosomes {
+    // reference names by tid, once the header is known
+    names: Vec<String>,
+    values: Values,
+}
+
+impl Chromosomes {
+    pub fn resolve(&mut self, header: &bam::Header) {
+        self.names = crate::header::names(header);
+    }
+
+    fn of(&mut self, rs: &[Record]) -> Option<usize> {
+        Some(self.values.id(crate::split::group(crate::split::SplitBy::Chromosome, &self.names, rs)?))
+    }
+}
+
+// the samples (SM) of the read groups, per --per-sample-num
+#[derive(Clone, Default)]
+pub struct Samples {
+    // sample of every read group, once the header is known
+    of_rg: HashMap<String, String>,
+    values: Values,
+}
+
+impl Samples {
+    pub fn resolve(&mut self, header: &bam::Header) {
+        self.of_rg = crate::header::samples(header);
+    }
+
+    // the sample of the read group of a template; None without a read group, or one of no SM
+    fn of(&mut self, rs: &[Record]) -> Option<usize> {
+        let sm = self.of_rg.get(&crate::split::read_group(rs)?)?.clone();
+        Some(self.values.id(sm))
+    }
+}
+
+// the amplicons of a primer BED (chrom, start, end, name): those of ARTIC-style primers
+// NAME_LEFT[_alt*] and NAME_RIGHT[_alt*] span from the first base of their left primers to the last
+// of their right ones; a line whose name is neither is an amplicon of its own
+#[derive(Clone)]
+pub struct Amplicons {
+    names: Vec<String>,
+    spans: Vec<(String, i64, i64)>,
+    // spans by tid, once the header is known
+    by_tid: HashMap<u32, Vec<(i64, i64, usize)>>,
+}
+
+fn amplicon_of(primer: &str) -> &str {
+    ["_LEFT", "_RIGHT"].iter()
+        .filter_map(|side| primer.rfind(side).map(|i| (i, i + side.len())))
+        .find(|&(_, end)| end == primer.len() || primer[end..].starts_with("_alt"))
+        .map_or(primer, |(i, _)| &primer[..i])
+}
+
+impl Amplicons {
+    pub fn from_bed(path: &str) -> Result<Amplicons, String> {
+        let text = fs::read_to_string(ospath::path(path)).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+        let mut names: Vec<String> = Vec::new();
+        let mut spans: Vec<(String, i64, i64)> = Vec::new();
+        let mut ids: HashMap<String, usize> = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let malformed = || format!("{} line {} is not chrom, start, end and name: {}", path, i + 1, line);
+            if fields.len() < 4 {
+                return Err(malformed());
+            }
+            let (start, end) = match (fields[1].parse::<i64>(), fields[2].parse::<i64>()) {
+                (Ok(start), Ok(end)) if 0 <= start && start < end => (start, end),
+                _ => return Err(malformed()),
+            };
+            let name = amplicon_of(fields[3]);
+            match ids.get(name) {
+                Some(&j) if spans[j].0 == fields[0] => {
+                    spans[j].1 = spans[j].1.min(start);
+                    spans[j].2 = spans[j].2.max(end);
+                },
+                Some(_) => return Err(format!("{} line {}: amplicon {} is on more than one reference", path, i + 1, name)),
+                None => {
+                    ids.insert(name.to_string(), names.len());
+                    names.push(name.to_string());
+                    spans.push((fields[0].to_string(), start, end));
+                },
+            }
+        }
+        if names.is_empty() {
+            return Err(format!("{} has no amplicons", path));
+        }
+        Ok(Amplicons { names, spans, by_tid: HashMap::new() })
+    }
+
+    // find the references of the amplicons in `header`
+    pub fn resolve(&mut self, header: &HeaderView) {
+        self.by_tid.clear();
+        let mut missing = 0;
+        for (i, (chrom, start, end)) in self.spans.iter().enumerate() {
+            match header.tid(chrom.as_bytes()) {
+                Some(tid) => self.by_tid.entry(tid).or_default().push((*start, *end, i)),
+                None => missing += 1,
+            }
+        }
+        if missing > 0 {
+            warn!("{} of {} amplicons are on references not in the header and get no templates.", missing, self.names.len());
+        }
+    }
+
+    // the amplicon overlapping most the span of the primary reads of a template on the reference of
+    // its first mapped one; None if it overlaps none
+    fn of(&self, rs: &[Record]) -> Option<usize> {
+        let mapped = rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary() && !r.is_unmapped());
+        let tid = mapped.clone().next()?.tid() as u32;
+        let (start, end) = mapped
+            .filter(|r| r.tid() as u32 == tid)
+            .fold((i64::MAX, i64::MIN), |(s, e), r| (s.min(r.pos()), e.max(r.cigar().end_pos())));
+        self.by_tid.get(&tid)?.iter()
+            .map(|&(s, e, i)| (e.min(end) - s.max(start), i))
+            .filter(|&(overlap, _)| overlap > 0)
+            .fold(None, |best: Option<(i64, usize)>, x| match best {
+                Some(b) if b.0 >= x.0 => Some(b),
+                _ => Some(x),
+            })
+            .map(|(_, i)| i)
+    }
+}
+
+// what a template carries at a site of --preserve-vaf: the REF base, an ALT one, or anything else, e.g.
+// another base, a deletion, or mates that disagree
+const ALLELES: [&str; 3] = ["ref", "alt", "other"];
+
+// the SNVs of a VCF, per --preserve-vaf: a template falls in the stratum of the alleles its primary
+// reads carry at every site they cover, so that sampling every combination in proportion keeps the
+// allele fractions of every site, those of templates covering several sites included; templates at no
+// site make up one more
+#[derive(Clone)]
+pub struct Alleles {
+    // reference name, 0-based position, REF and ALT bases of every site
+    sites: Vec<(String, i64, u8, Vec<u8>)>,
+    // records other than SNVs, which are left out
+    skipped: usize,
+    // sites by tid, sorted by position, once the header is known
+    by_tid: HashMap<u32, Vec<(i64, usize)>>,
+    // the site and allele of every site of a combination
+    combos: Vec<Vec<(usize, usize)>>,
+    ids: HashMap<Vec<(usize, usize)>, usize>,
+}
+
+impl Alleles {
+    // a VCF, bgzipped or not
+    pub fn from_vcf(path: &str) -> Result<Alleles, String> {
+        let fh = bgzf::Reader::from_path(ospath::path(path)).map_err(|e| format!("failed to read {}: {}", path, e))?;
+        let mut sites: Vec<(String, i64, u8, Vec<u8>)> = Vec::new();
+        let mut skipped = 0;
+        for (i, line) in BufReader::new(fh).lines().enumerate() {
+            let line = line.map_err(|e| format!("failed to read {}: {}", path, e))?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let bad = || format!("{}:{} is not a VCF record: {}", path, i + 1, line);
+            if fields.len() < 5 {
+                return Err(bad());
+            }
+            let pos: i64 = fields[1].parse().ok().filter(|&x| x > 0).ok_or_else(bad)?;
+            let alts: Vec<&str> = fields[4].split(',').collect();
+            match (fields[3].as_bytes(), alts.iter().all(|a| a.len() == 1 && *a != "*" && *a != ".")) {
+                (&[r], true) => sites.push((fields[0].to_string(), pos - 1, r.to_ascii_uppercase(), alts.iter().map(|a| a.as_bytes()[0].to_ascii_uppercase()).collect())),
+                _ => skipped += 1,
+            }
+        }
+        if sites.is_empty() {
+            return Err(format!("{} has no SNVs", path));
+        }
+        Ok(Alleles { sites, skipped, by_tid: HashMap::new(), combos: Vec::new(), ids: HashMap::new() })
+    }
+
+    // find the references of the sites in `header`
+    pub fn resolve(&mut self, header: &HeaderView) {
+        self.by_tid.clear();
+        let mut missing = 0;
+        for (i, (chrom, pos, _, _)) in self.sites.iter().enumerate() {
+            match header.tid(chrom.as_bytes()) {
+                Some(tid) => self.by_tid.entry(tid).or_default().push((*pos, i)),
+                None => missing += 1,
+            }
+        }
+        for v in self.by_tid.values_mut() {
+            v.sort_unstable();
+        }
+        if missing > 0 {
+            warn!("{} of {} sites of --preserve-vaf are on references not in the header.", missing, self.sites.len());
+        }
+        if self.skipped > 0 {
+            warn!("{} records of --preserve-vaf are not SNVs, and are left out.", self.skipped);
+        }
+    }
+
+    fn site(&self, i: usize) -> String {
+        let (chrom, pos, r, alts) = &self.sites[i];
+        let alts: Vec<String> = alts.iter().map(|&a| char::from(a).to_string()).collect();
+        format!("{}:{} {}>{}", chrom, pos + 1, char::from(*r), alts.join(","))
+    }
+
+    fn of(&mut self, rs: &[Record]) -> Option<usize> {
+        let mut combo: Vec<(usize, usize)> = Vec::new();
+        for r in rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary() && !r.is_unmapped()) {
+            let sites = match self.by_tid.get(&(r.tid() as u32)) {
+                Some(v) => v,
+                None => continue,
+            };
+            let cigar = r.cigar();
+            let (start, end) = (r.pos(), cigar.end_pos());
+            let seq = r.seq();
+            let first = sites.partition_point(|&(pos, _)| pos < start);
+            for &(pos, i) in sites[first..].iter().take_while(|&&(pos, _)| pos < end) {
+                let allele = match cigar.read_pos(pos as u32, false, false) {
+                    Ok(Some(q)) if (q as usize) < seq.len() => {
+                        let base = seq[q as usize].to_ascii_uppercase();
+                        match (base == self.sites[i].2, self.sites[i].3.contains(&base)) {
+                            (true, _) => 0,
+                            (_, true) => 1,
+                            _ => 2,
+                        }
+                    },
+                    _ => 2,
+                };
+                match combo.iter_mut().find(|c| c.0 == i) {
+                    Some(c) if c.1 != allele => c.1 = 2,
+                    Some(_) => {},
+                    None => combo.push((i, allele)),
+                }
+            }
+        }
+        combo.sort_unstable();
+        if let Some(&i) = self.ids.get(&combo) {
+            return Some(i);
+        }
+        self.combos.push(combo.clone());
+        self.ids.insert(combo, self.combos.len() - 1);
+        Some(self.combos.len() - 1)
+    }
+
+    fn name(&self, i: usize) -> String {
+        match self.combos[i].is_empty() {
+            true => String::from("at no site"),
+            false => self.combos[i].iter().map(|&(site, a)| format!("{} {}", self.site(site), ALLELES[a])).collect::<Vec<String>>().join(", "),
+        }
+    }
+
+    // the allele fraction of every site covered, in the input and in the sample, of the templates of
+    // every stratum and those to be sampled of it: the site, the fraction in and out, and how many of the
+    // sample carry REF or ALT, leaving out those of neither
+    pub fn fractions(&self, sizes: &[usize], nums: &[usize]) -> Vec<(String, f64, f64, usize)> {
+        let mut counts = vec![[[0usize; 2]; 2]; self.sites.len()];
+        for (i, combo) in self.combos.iter().enumerate() {
+            for &(site, a) in combo.iter().filter(|c| c.1 < 2) {
+                counts[site][0][a] += sizes[i];
+                counts[site][1][a] += nums[i].min(sizes[i]);
+            }
+        }
+        let vaf = |x: [usize; 2]| x[1] as f64 / (x[0] + x[1]).max(1) as f64;
+        counts.iter().enumerate()
+            .filter(|(_, c)| c[0][0] + c[0][1] > 0)
+            .map(|(site, c)| (self.site(site), vaf(c[0]), vaf(c[1]), c[1][0] + c[1][1]))
+            .collect()
+    }
+}
+
+// the combinations of several kinds of strata, e.g. chromosome and read group of --stratify-by
+// chromosome,read-group, numbered in the order they turn up
+#[derive(Clone)]
+pub struct Combined {
+    parts: Vec<Strata>,
+    // the stratum of every part of a combination
+    combos: Vec<Vec<usize>>,
+    ids: HashMap<Vec<usize>, usize>,
+}
+
+impl Combined {
+    pub fn new(parts: Vec<Strata>) -> Combined {
+        Combined {
+            parts,
+            combos: Vec::new(),
+            ids: HashMap::new(),
+        }
+    }
+
+    // the combination of a template, if it falls in a stratum of every part
+    fn of(&mut self, rs: &[Record], input: usize) -> Option<usize> {
+        let combo = self.parts.iter_mut().map(|p| p.of(rs, input)).collect::<Option<Vec<usize>>>()?;
+        if let Some(&i) = self.ids.get(&combo) {
+            return Some(i);
+        }
+        self.combos.push(combo.clone());
+        self.ids.insert(combo, self.combos.len() - 1);
+        Some(self.combos.len() - 1)
+    }
+}
+
+// what templates are sampled within: bins of insert size or GC content, values of a tag, lanes or
+// references per --stratify-by, or combinations of them, the strand per --balance-strands, the amplicons
+// of --amplicons, the samples of --per-sample-num, the tiles of --per-tile-num, the alleles carried at
+// the sites of --preserve-vaf, or the inputs of --pool
+#[derive(Clone)]
+pub enum Strata {
+    InsertSize(Bins),
+    Gc(GcBins),
+    MeanQ(Bins),
+    Tag(TagValues),
+    Lane(Lanes),
+    Tile(Tiles),
+    Chromosome(Chromosomes),
+    Combined(Combined),
+    Strand,
+    // per --half-mapped prefer: those of a mate unmapped, then the rest
+    HalfMapped,
+    Amplicon(Amplicons),
+    Sample(Samples),
+    Allele(Alleles),
+    // the inputs, by their paths
+    Input(Vec<String>),
+}
+
+impl Strata {
+    // strata known so far; those of a tag or lane only turn up while reading
+    pub fn len(&self) -> usize {
+        match self {
+            Strata::InsertSize(bins) => bins.len(),
+            Strata::Gc(bins) => bins.len(),
+            Strata::MeanQ(bins) => bins.len(),
+            Strata::Tag(t) => t.values.values.len(),
+            Strata::Lane(l) => l.values.values.len(),
+            Strata::Tile(t) => t.values.values.len(),
+            Strata::Chromosome(c) => c.values.values.len(),
+            Strata::Combined(c) => c.combos.len(),
+            Strata::Strand => 2,
+            Strata::HalfMapped => 2,
+            Strata::Amplicon(a) => a.names.len(),
+            Strata::Sample(s) => s.values.values.len(),
+            Strata::Allele(a) => a.combos.len(),
+            Strata::Input(files) => files.len(),
+        }
+    }
+
+    pub fn name(&self, i: usize) -> String {
+        match self {
+            Strata::InsertSize(bins) => format!("bin {}", bins.name(i)),
+            Strata::Gc(bins) => format!("GC {}", bins.name(i)),
+            Strata::MeanQ(bins) => format!("mean Q {}", bins.name(i)),
+            Strata::Tag(t) => match &*t.values.values[i] {
+                "" => format!("no {}", String::from_utf8_lossy(&t.tag)),
+                value => format!("{}:{}", String::from_utf8_lossy(&t.tag), value),
+            },
+            Strata::Lane(l) => format!("lane {}", l.values.values[i]),
+            Strata::Tile(t) => format!("tile {}", t.values.values[i]),
+            Strata::Chromosome(c) => c.values.values[i].clone(),
+            Strata::Combined(c) => c.parts.iter().zip(&c.combos[i]).map(|(p, &j)| p.name(j)).collect::<Vec<String>>().join(", "),
+            Strata::Strand => String::from(["forward", "reverse"][i]),
+            Strata::HalfMapped => String::from(["half-mapped", "other"][i]),
+            Strata::Amplicon(a) => format!("amplicon {}", a.names[i]),
+            Strata::Sample(s) => format!("sample {}", s.values.values[i]),
+            Strata::Allele(a) => a.name(i),
+            Strata::Input(files) => files[i].clone(),
+        }
+    }
+
+    // the stratum of a template of the `input`th input
+    pub fn of(&mut self, rs: &[Record], input: usize) -> Option<usize> {
+        match self {
+            Strata::InsertSize(bins) => bins.of(rs),
+            Strata::Gc(bins) => bins.of(rs),
+            Strata::MeanQ(bins) => bins.of_mean_q(rs),
+            Strata::Tag(t) => t.of(rs),
+            Strata::Lane(l) => l.of(rs),
+            Strata::Tile(t) => t.of(rs),
+            Strata::Chromosome(c) => c.of(rs),
+            Strata::Combined(c) => c.of(rs, input),
+            Strata::Strand => strand(rs),
+            Strata::HalfMapped => Some(usize::from(!half_mapped(rs))),
+            Strata::Amplicon(a) => a.of(rs),
+            Strata::Sample(s) => s.of(rs),
+            Strata::Allele(a) => a.of(rs),
+            Strata::Input(_) => Some(input),
+        }
+    }
+
+    // find what the strata need of `header`: the references of amplicons and sites, the reference names of
+    // chromosomes and the samples of read groups
+    pub fn resolve(&mut self, header: &bam::Header) {
+        match self {
+            Strata::Amplicon(a) => a.resolve(&HeaderView::from_header(header)),
+            Strata::Allele(a) => a.resolve(&HeaderView::from_header(header)),
+            Strata::Sample(s) => s.resolve(header),
+            Strata::Chromosome(c) => c.resolve(header),
+            Strata::Combined(c) => c.parts.iter_mut().for_each(|p| p.resolve(header)),
+            _ => {},
+        }
+    }
+}
+
+// a stratum as --quota names it: the bin, tag value ('*' for none), lane, tile, reference, strand, amplicon,
+// sample or input by itself, and those of a combination in as many columns
+pub fn key(strata: &Strata, i: usize) -> String {
+    match strata {
+        Strata::InsertSize(bins) => bins.name(i),
+        Strata::Gc(bins) => bins.name(i),
+        Strata::MeanQ(bins) => bins.name(i),
+        Strata::Tag(t) => match &*t.values.values[i] {
+            "" => String::from("*"),
+            value => value.to_string(),
+        },
+        Strata::Lane(l) => l.values.values[i].clone(),
+        Strata::Tile(t) => t.values.values[i].clone(),
+        Strata::Chromosome(c) => c.values.values[i].clone(),
+        Strata::Combined(c) => c.parts.iter().zip(&c.combos[i]).map(|(p, &j)| key(p, j)).collect::<Vec<String>>().join("\t"),
+        Strata::Strand => String::from(["forward", "reverse"][i]),
+        Strata::HalfMapped => String::from(["half-mapped", "other"][i]),
+        Strata::Amplicon(a) => a.names[i].clone(),
+        Strata::Sample(s) => s.values.values[i].clone(),
+        Strata::Allele(a) => a.combos[i].iter().map(|&(site, x)| format!("{}:{}", a.site(site), ALLELES[x])).collect::<Vec<String>>().join(","),
+        Strata::Input(files) => files[i].clone(),
+    }
+}
+
+// the templates to take from strata, by key, of a --quota TSV: key and count (K/M/G suffixes allowed)
+// on every line, in the order given; the key of a combination of strata takes a column for each
+pub fn read_quotas(path: &str) -> Result<Vec<(String, usize)>, String> {
+    let text = fs::read_to_string(ospath::path(path)).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    let mut quotas: Vec<(String, usize)> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let quota = line.rsplit_once('\t').and_then(|(key, n)| Some((key.to_string(), crate::units::parse_count(n.trim())?)));
+        match quota {
+            Some((key, _)) if quotas.iter().any(|q| q.0 == key) => return Err(format!("{} line {}: {} is given more than once", path, i + 1, key)),
+            Some(q) if !q.0.is_empty() => quotas.push(q),
+            _ => return Err(format!("{} line {} is not a stratum and a count: {}", path, i + 1, line)),
+        }
+    }
+    if quotas.is_empty() {
+        return Err(format!("{} has no quotas", path));
+    }
+    Ok(quotas)
+}
+
+// strand of a template, that of its first mate (or only read): 0 forward, 1 reverse, None if unmapped
+pub fn strand(rs: &[Record]) -> Option<usize> {
+    let mut primary = rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary());
+    let r = primary.clone().find(|r| r.is_first_in_template()).or_else(|| primary.next())?;
+    match r.is_unmapped() {
+        true => None,
+        false => Some(usize::from(r.is_reverse())),
+    }
+}
+
+// whether a template is a pair with one mate mapped and the other not, as a local assembly around the
+// mapped one would take it
+pub fn half_mapped(rs: &[Record]) -> bool {
+    let primary: Vec<&Record> = rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary()).collect();
+    primary.len() == 2 && primary.iter().all(|r| r.is_paired()) && primary[0].is_unmapped() != primary[1].is_unmapped()
+}
+
+// |TLEN| of the primary reads of a template; 0 for SE reads and pairs not aligned to the same reference
+pub fn insert_size(rs: &[Record]) -> i64 {
+    rs.iter()
+        .filter(|r| !r.is_secondary() && !r.is_supplementary())
+        .map(|r| r.insert_size().abs())
+        .max()
+        .unwrap_or_default()
+}
+
+// fraction of G and C among the called bases (A, C, G, T) of the primary reads of a template; None
+// if there is none, e.g. SEQ is * or all N
+pub fn gc_content(rs: &[Record]) -> Option<f64> {
+    let (mut gc, mut called) = (0usize, 0usize);
+    for r in rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary()) {
+        let seq = r.seq();
+        for i in 0..seq.len() {
+            match seq[i].to_ascii_uppercase() {
+                b'G' | b'C' => { gc += 1; called += 1; },
+                b'A' | b'T' => called += 1,
+                _ => (),
+            }
+        }
+    }
+    match called {
+        0 => None,
+        _ => Some(gc as f64 / called as f64),
+    }
+}
+
+// mean Q of the primary reads of a template, as basecallers give it: the Phred score of the mean error
+// probability of their bases rather than the mean of their scores, which a few bad bases would hardly
+// move; None if no read has qualities, e.g. QUAL is *
+pub fn mean_q(rs: &[Record]) -> Option<f64> {
+    let (mut err, mut n) = (0.0f64, 0usize);
+    for r in rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary()) {
+        let qual = r.qual();
+        if qual.first().is_none_or(|&q| q == 0xff) {
+            continue;
+        }
+        err += qual.iter().map(|&q| 10f64.powf(-(q as f64) / 10.0)).sum::<f64>();
+        n += qual.len();
+    }
+    match n {
+        0 => None,
+        _ => Some(-10.0 * (err / n as f64).log10()),
+    }
+}
+
+// split `num` among bins by the fractions given to --bin-fractions, or among the inputs of `mix` by
+// its --proportions, by largest remainder so the shares add up to `num`
+pub fn shares(num: usize, fractions: &[f64]) -> Vec<usize> {
+    let total: f64 = fractions.iter().sum();
+    let exact: Vec<f64> = fractions.iter().map(|&f| num as f64 * f / total).collect();
+    let mut shares: Vec<usize> = exact.iter().map(|&x| x.floor() as usize).collect();
+    let left = num.saturating_sub(shares.iter().sum());
+    let mut order: Vec<usize> = (0..fractions.len()).collect();
+    order.sort_by(|&i, &j| (exact[j] - exact[j].floor()).total_cmp(&(exact[i] - exact[i].floor())));
+    for &i in order.iter().take(left) {
+        shares[i] += 1;
+    }
+    shares
+}
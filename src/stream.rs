@@ -0,0 +1,94 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use rust_htslib::{bam::Record, errors::Error};
+
+use crate::backend::RecordSource;
+use crate::reservoir::RecordSet;
+use crate::rng::Seed;
+use crate::sampler::{Bernoulli, Decision, HashThreshold, Sampler};
+
+// how `SubsampledTemplates` decides on a template, on its own rather than against the others, so
+// that nothing has to be held back
+#[derive(Clone, Copy)]
+pub enum Selection {
+    // kept if a hash of its qname and the seed falls below the fraction, so that a template is kept
+    // or not alike in every run and in every file it is in
+    Hash { fraction: f64, seed: Seed },
+    // kept with probability `fraction`, by a seeded random number generator
+    Bernoulli { fraction: f64, seed: Seed },
+}
+
+// the templates of a queryname-sorted input that make it into the sample, one at a time as they are
+// read, for a pipeline to take in without a BAM in between; only those the sampler keeps come out, so
+// one holding templates until the end, as the reservoir does, is for `sampler::select` instead
+pub struct SubsampledTemplates<R: RecordSource> {
+    infh: R,
+    sampler: Box<dyn Sampler>,
+    // the first record of the next template, read already
+    next: Option<Record>,
+    // templates read so far, kept or not
+    pub seen: usize,
+}
+
+impl<R: RecordSource> SubsampledTemplates<R> {
+    pub fn new(infh: R, selection: Selection) -> SubsampledTemplates<R> {
+        let sampler: Box<dyn Sampler> = match selection {
+            Selection::Hash { fraction, seed } => Box::new(HashThreshold::new(fraction, seed)),
+            Selection::Bernoulli { fraction, seed } => Box::new(Bernoulli::new(fraction, seed)),
+        };
+        SubsampledTemplates::with_sampler(infh, sampler)
+    }
+
+    // the templates `sampler` keeps, for a policy of the caller's own
+    pub fn with_sampler(infh: R, sampler: Box<dyn Sampler>) -> SubsampledTemplates<R> {
+        SubsampledTemplates {
+            infh,
+            sampler,
+            next: None,
+            seen: 0,
+        }
+    }
+
+    // the records of the next template, or None at the end of the input
+    fn template(&mut self) -> Result<Option<RecordSet>, Error> {
+        let mut rs: RecordSet = self.next.take().into_iter().collect();
+        loop {
+            let mut r = Record::new();
+            match self.infh.read_record(&mut r) {
+                None => break,
+                Some(x) => x?,
+            }
+            if !rs.is_empty() && rs[0].qname() != r.qname() {
+                self.next = Some(r);
+                break;
+            }
+            rs.push(r);
+        }
+        Ok(match rs.is_empty() {
+            true => None,
+            false => Some(rs),
+        })
+    }
+}
+
+impl<R: RecordSource> Iterator for SubsampledTemplates<R> {
+    type Item = Result<RecordSet, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let rs = match self.template() {
+                Ok(Some(rs)) => rs,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            };
+            self.seen += 1;
+            if self.sampler.observe(&rs) == Decision::Keep {
+                return Some(Ok(rs));
+            }
+        }
+    }
+}
@@ -0,0 +1,105 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::convert::TryInto;
+use rust_htslib::bam::{Record, record::Aux};
+
+// a TAG:VALUE condition on a record, e.g. NH:1; a VALUE of * stands for any, i.e. the tag being present
+pub struct TagFilter {
+    tag: [u8; 2],
+    value: Option<String>,
+}
+
+impl TagFilter {
+    pub fn parse(s: &str) -> Option<TagFilter> {
+        let (tag, value) = s.split_once(':')?;
+        let tag: [u8; 2] = tag.as_bytes().try_into().ok()?;
+        if !tag[0].is_ascii_alphabetic() || !tag[1].is_ascii_alphanumeric() || value.is_empty() {
+            return None;
+        }
+        Some(TagFilter {
+            tag,
+            value: match value {
+                "*" => None,
+                _ => Some(value.to_string()),
+            },
+        })
+    }
+
+    // whether `r` carries the tag with the value, compared as SAM text; array tags only match *
+    pub fn matches(&self, r: &Record) -> bool {
+        let aux = match r.aux(&self.tag) {
+            Ok(a) => a,
+            Err(_) => return false,
+        };
+        let want = match &self.value {
+            Some(x) => x,
+            None => return true,
+        };
+        match aux {
+            Aux::Float(x) => want.parse::<f32>() == Ok(x),
+            Aux::Double(x) => want.parse::<f64>() == Ok(x),
+            _ => text(&aux).is_some_and(|t| t == *want),
+        }
+    }
+}
+
+// the value of a tag as SAM prints it; None for arrays
+pub fn text(aux: &Aux) -> Option<String> {
+    Some(match *aux {
+        Aux::Char(c) => (c as char).to_string(),
+        Aux::I8(x) => x.to_string(),
+        Aux::U8(x) => x.to_string(),
+        Aux::I16(x) => x.to_string(),
+        Aux::U16(x) => x.to_string(),
+        Aux::I32(x) => x.to_string(),
+        Aux::U32(x) => x.to_string(),
+        Aux::Float(x) => x.to_string(),
+        Aux::Double(x) => x.to_string(),
+        Aux::String(s) | Aux::HexByteArray(s) => s.to_string(),
+        _ => return None,
+    })
+}
+
+// whether every primary record of a template meets all of `require` and none of `exclude`
+pub fn passes(rs: &[Record], require: &[TagFilter], exclude: &[TagFilter]) -> bool {
+    rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary()).all(|r| {
+        require.iter().all(|f| f.matches(r)) && !exclude.iter().any(|f| f.matches(r))
+    })
+}
+
+// what --drop-tags or --keep-tags leave of the tags of a record written out
+pub enum TagEdit {
+    Drop(Vec<[u8; 2]>),
+    Keep(Vec<[u8; 2]>),
+}
+
+// comma-separated tags, e.g. OQ,BI,BD
+pub fn parse_list(s: &str) -> Option<Vec<[u8; 2]>> {
+    s.split(',').map(|t| {
+        let tag: [u8; 2] = t.trim().as_bytes().try_into().ok()?;
+        match tag[0].is_ascii_alphabetic() && tag[1].is_ascii_alphanumeric() {
+            true => Some(tag),
+            false => None,
+        }
+    }).collect()
+}
+
+impl TagEdit {
+    pub fn apply(&self, r: &mut Record) {
+        let doomed: Vec<[u8; 2]> = r.aux_iter()
+            .filter_map(|x| x.ok())
+            .map(|(t, _)| [t[0], t[1]])
+            .filter(|t| match self {
+                TagEdit::Drop(v) => v.contains(t),
+                TagEdit::Keep(v) => !v.contains(t),
+            })
+            .collect();
+        for t in doomed {
+            let _ = r.remove_aux(&t);
+        }
+    }
+}
@@ -0,0 +1,360 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::collections::HashMap;
+use rand::seq::SliceRandom;
+use rust_htslib::bam::{Record, record::Aux, record::Cigar};
+
+use crate::rng::Seed;
+// the tag conditions and edits of the command line, in the library so that the stages of --pipeline
+// go by them too
+pub use crate::tagging::{parse_list, passes, text, TagEdit, TagFilter};
+
+// the read a record is part of, as SA goes by: its qname and which mate it is
+fn read_of(r: &Record) -> (&[u8], bool) {
+    (r.qname(), r.is_paired() && r.is_last_in_template())
+}
+
+// whether a primary alignment of a template has more mismatches and indels, by its NM tag, than `max`
+// or than `max_frac` of its aligned bases; records without NM pass
+pub fn too_divergent(rs: &[Record], max: Option<i64>, max_frac: Option<f64>) -> bool {
+    rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary() && !r.is_unmapped()).any(|r| {
+        let nm = match r.aux(b"NM").ok().and_then(|a| text(&a)).and_then(|x| x.parse::<i64>().ok()) {
+            Some(nm) => nm,
+            None => return false,
+        };
+        let aligned: u32 = r.cigar().iter().map(|c| match *c {
+            Cigar::Match(l) | Cigar::Ins(l) | Cigar::Equal(l) | Cigar::Diff(l) => l,
+            _ => 0,
+        }).sum();
+        max.is_some_and(|m| nm > m) || max_frac.is_some_and(|f| nm as f64 > f * f64::from(aligned.max(1)))
+    })
+}
+
+// whether a primary alignment of a template has fewer aligned bases (M, = and X) than `min_frac` of
+// its read, clipped bases (S and H) and insertions counting toward the read; unmapped reads pass
+pub fn mostly_clipped(rs: &[Record], min_frac: f64) -> bool {
+    rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary() && !r.is_unmapped()).any(|r| {
+        let (mut aligned, mut len) = (0u32, 0u32);
+        for c in r.cigar().iter() {
+            match *c {
+                Cigar::Match(l) | Cigar::Equal(l) | Cigar::Diff(l) => {
+                    aligned += l;
+                    len += l;
+                },
+                Cigar::Ins(l) | Cigar::SoftClip(l) | Cigar::HardClip(l) => len += l,
+                _ => {},
+            }
+        }
+        f64::from(aligned) < min_frac * f64::from(len)
+    })
+}
+
+// whether a primary alignment of a template skips part of the reference (N in CIGAR), as a read spanning
+// a splice junction does
+pub fn spliced(rs: &[Record]) -> bool {
+    rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary() && !r.is_unmapped())
+        .any(|r| r.cigar().iter().any(|c| matches!(c, Cigar::RefSkip(_))))
+}
+
+// tag a record without a read group with the read group `id`, per --set-read-group; whether it had none
+pub fn set_read_group(r: &mut Record, id: &str) -> bool {
+    if r.aux(b"RG").is_ok() {
+        return false;
+    }
+    r.push_aux(b"RG", Aux::String(id)).expect("failed to add RG!");
+    true
+}
+
+// keep at most `max` secondary alignments of every read of a template, drawn by its qname and `seed` so
+// that reruns agree
+pub fn cap_secondary(rs: &mut Vec<Record>, max: usize, seed: Seed) {
+    let mut secondary: HashMap<(Vec<u8>, bool), Vec<usize>> = HashMap::new();
+    for (i, r) in rs.iter().enumerate() {
+        if r.is_secondary() {
+            let (qname, last) = read_of(r);
+            secondary.entry((qname.to_vec(), last)).or_default().push(i);
+        }
+    }
+    let mut left_out = vec![false; rs.len()];
+    for (read, mut v) in secondary.into_iter().filter(|x| x.1.len() > max) {
+        v.shuffle(&mut Seed::new(seed.kind, seed.value ^ u64::from(read.1)).derive(&read.0).generator());
+        v[max..].iter().for_each(|&i| left_out[i] = true);
+    }
+    leave_out(rs, &left_out);
+}
+
+// keep at most `max` of the secondary and supplementary records of a template, every primary one going
+// out, drawn by its qname and `seed` so that reruns agree. Returns how many were left out
+pub fn cap_records(rs: &mut Vec<Record>, max: usize, seed: Seed) -> usize {
+    let mut extra: Vec<usize> = (0..rs.len()).filter(|&i| rs[i].is_secondary() || rs[i].is_supplementary()).collect();
+    if extra.len() <= max {
+        return 0;
+    }
+    extra.shuffle(&mut seed.derive(rs[extra[0]].qname()).generator());
+    let mut left_out = vec![false; rs.len()];
+    extra[max..].iter().for_each(|&i| left_out[i] = true);
+    leave_out(rs, &left_out);
+    extra.len() - max
+}
+
+// remove the records of a template marked in `left_out`
+fn leave_out(rs: &mut Vec<Record>, left_out: &[bool]) {
+    if !left_out.contains(&true) {
+        return;
+    }
+    let mut i = 0;
+    rs.retain(|_| {
+        i += 1;
+        !left_out[i - 1]
+    });
+}
+
+fn integer(r: &Record, tag: &[u8]) -> Option<i64> {
+    r.aux(tag).ok().and_then(|a| text(&a)).and_then(|x| x.parse::<i64>().ok())
+}
+
+fn set_integer(r: &mut Record, tag: &[u8], x: i64) {
+    let _ = r.remove_aux(tag);
+    let value = match x {
+        0..=255 => Aux::U8(x as u8),
+        _ => Aux::I32(x as i32),
+    };
+    r.push_aux(tag, value).expect("failed to rewrite an integer tag!");
+}
+
+// the NH and HI tags of the records `out` of a template `rs` as written, once --primary-only, --reads,
+// --max-secondary or --max-records-per-template left some of its secondary alignments out: NH is lowered
+// by the secondary alignments of the read left out, so that it still counts the alignments reported, and
+// the HI of the alignments left, which the mates of a hit share, are numbered again from the first HI
+// of the template (0 or 1, per the aligner), in their order. Returns the records rewritten
+pub fn recount_hits(rs: &[Record], out: &mut [Record]) -> usize {
+    let key = |r: &Record| {
+        let (qname, last) = read_of(r);
+        (qname.to_vec(), last)
+    };
+    let mut lowered: HashMap<(Vec<u8>, bool), i64> = HashMap::new();
+    for r in rs.iter().filter(|r| r.is_secondary()) {
+        *lowered.entry(key(r)).or_default() += 1;
+    }
+    for r in out.iter().filter(|r| r.is_secondary()) {
+        *lowered.entry(key(r)).or_default() -= 1;
+    }
+    lowered.retain(|_, n| *n > 0);
+    if lowered.is_empty() {
+        return 0;
+    }
+    let mut his: Vec<i64> = out.iter().filter_map(|r| integer(r, b"HI")).collect();
+    his.sort_unstable();
+    his.dedup();
+    let base = rs.iter().filter_map(|r| integer(r, b"HI")).min().unwrap_or(1);
+    let mut rewritten = 0;
+    for r in out.iter_mut() {
+        let mut changed = false;
+        if let (Some(&n), Some(nh)) = (lowered.get(&key(&*r)), integer(r, b"NH")) {
+            set_integer(r, b"NH", (nh - n).max(1));
+            changed = true;
+        }
+        if let Some(hi) = integer(r, b"HI") {
+            let renumbered = base + his.partition_point(|&x| x < hi) as i64;
+            if renumbered != hi {
+                set_integer(r, b"HI", renumbered);
+                changed = true;
+            }
+        }
+        rewritten += usize::from(changed);
+    }
+    rewritten
+}
+
+// RNAME,POS,STRAND,CIGAR of an SA entry, MAPQ and NM left out, with hard clips as soft ones, as SA gives
+// the clipping against the whole read
+fn sa_key(entry: &str) -> Option<String> {
+    let fields: Vec<&str> = entry.splitn(5, ',').collect();
+    match fields.len() {
+        5 => Some(format!("{},{},{},{}", fields[0], fields[1], fields[2], fields[3].replace('H', "S"))),
+        _ => None,
+    }
+}
+
+// the SA key of a record, as the other parts of its read would list it; None if unmapped
+fn record_key(r: &Record, refs: &[String]) -> Option<String> {
+    if r.is_unmapped() || r.tid() < 0 {
+        return None;
+    }
+    let strand = if r.is_reverse() { '-' } else { '+' };
+    let cigar = r.cigar().to_string().replace('H', "S");
+    Some(format!("{},{},{},{}", refs.get(r.tid() as usize)?, r.pos() + 1, strand, cigar))
+}
+
+// the references the SA tag of a record names its other alignments on
+pub fn sa_references(r: &Record) -> Vec<String> {
+    match r.aux(b"SA") {
+        Ok(Aux::String(sa)) => sa.split(';').filter_map(|e| e.split(',').next()).filter(|x| !x.is_empty()).map(String::from).collect(),
+        _ => Vec::new(),
+    }
+}
+
+// the records of a template whose SA tag lists alignments of their read not among `rs`, such as the
+// supplementary ones --primary-only leaves out; with `repair`, those entries are removed, and the tag
+// with them if none is left
+pub fn check_sa(rs: &mut [Record], refs: &[String], repair: bool) -> usize {
+    let keys: Vec<Option<String>> = rs.iter().map(|r| record_key(r, refs)).collect();
+    // the records to fix, and their SA tag without the entries left out, if any is left
+    let mut fixes: Vec<(usize, Option<String>)> = Vec::new();
+    for (i, r) in rs.iter().enumerate() {
+        let sa = match r.aux(b"SA") {
+            Ok(Aux::String(s)) => s,
+            _ => continue,
+        };
+        let written: Vec<&str> = rs.iter().zip(&keys)
+            .filter(|(x, _)| read_of(x) == read_of(r))
+            .filter_map(|(_, k)| k.as_deref())
+            .collect();
+        let (kept, gone): (Vec<&str>, Vec<&str>) = sa.split(';')
+            .filter(|e| !e.is_empty())
+            .partition(|e| sa_key(e).is_some_and(|k| written.contains(&k.as_str())));
+        if !gone.is_empty() {
+            fixes.push((i, match kept.is_empty() {
+                true => None,
+                false => Some(kept.iter().map(|e| format!("{};", e)).collect()),
+            }));
+        }
+    }
+    let n = fixes.len();
+    if repair {
+        for (i, sa) in fixes {
+            let _ = rs[i].remove_aux(b"SA");
+            if let Some(sa) = sa {
+                rs[i].push_aux(b"SA", Aux::String(&sa)).expect("failed to rewrite SA!");
+            }
+        }
+    }
+    n
+}
+
+// the number of calls an MM tag makes, with a probability each in ML, and whether none of them is past the
+// last base of its kind in `seq`, the sequence as sequenced; None if it does not parse. Every group, e.g.
+// C+mh?,5,12, skips that many of its base (any for N) before every call, and gives a probability for
+// every modification it names, a ChEBI number standing for one
+fn modification_calls(mm: &str, seq: &[u8]) -> Option<(usize, bool)> {
+    let (mut calls, mut within) = (0, true);
+    for group in mm.split(';').filter(|g| !g.is_empty()) {
+        let mut fields = group.split(',');
+        let head = fields.next()?.as_bytes();
+        let (&base, rest) = head.split_first()?;
+        let (&strand, codes) = rest.split_first()?;
+        if !b"ACGTUN".contains(&base) || (strand != b'+' && strand != b'-') {
+            return None;
+        }
+        let codes = codes.strip_suffix(b".").or_else(|| codes.strip_suffix(b"?")).unwrap_or(codes);
+        let n_codes = match codes {
+            [] => return None,
+            _ if codes.iter().all(u8::is_ascii_digit) => 1,
+            _ if codes.iter().all(u8::is_ascii_alphabetic) => codes.len(),
+            _ => return None,
+        };
+        let total = seq.iter().filter(|&&b| base == b'N' || b == base || (base == b'U' && b == b'T')).count();
+        let mut at = 0;
+        for delta in fields {
+            at += delta.parse::<usize>().ok()? + 1;
+            calls += n_codes;
+        }
+        within &= at <= total;
+    }
+    Some((calls, within))
+}
+
+// whether the modified-base tags of a record, MM and ML (or Mm and Ml, as they were before SAM made them
+// standard), fit it: both there or neither, as many probabilities in ML as calls in MM, none of them past
+// the end of the sequence, and MN, if there, its length. A record without a sequence (SEQ *, as
+// secondary and supplementary records often are) has no bases to check the calls against, its tags
+// being those of the primary, and is taken as it is
+pub fn modifications_consistent(r: &Record) -> bool {
+    if r.seq_len() == 0 {
+        return true;
+    }
+    let (mm, ml) = match (r.aux(b"MM"), r.aux(b"Mm")) {
+        (Ok(mm), _) => (mm, r.aux(b"ML")),
+        (_, Ok(mm)) => (mm, r.aux(b"Ml")),
+        _ => return r.aux(b"ML").is_err() && r.aux(b"Ml").is_err(),
+    };
+    let mm = match mm {
+        Aux::String(s) => s,
+        _ => return false,
+    };
+    let probs = match ml {
+        Ok(Aux::ArrayU8(a)) => a.len(),
+        Ok(_) => return false,
+        Err(_) => 0,
+    };
+    if let Ok(mn) = r.aux(b"MN") {
+        if text(&mn).and_then(|x| x.parse::<usize>().ok()) != Some(r.seq_len()) {
+            return false;
+        }
+    }
+    // the sequence as sequenced, which MM counts bases along
+    let mut seq = r.seq().as_bytes();
+    if r.is_reverse() {
+        seq.reverse();
+        seq.iter_mut().for_each(|b| *b = match *b {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            x => x,
+        });
+    }
+    match modification_calls(mm, &seq) {
+        Some((calls, within)) => within && calls == probs,
+        None => false,
+    }
+}
+
+// the records whose modified-base tags do not fit them (see `modifications_consistent`); with `strip`,
+// those tags are removed from them
+pub fn check_modifications(rs: &mut [Record], strip: bool) -> usize {
+    let mut n = 0;
+    for r in rs.iter_mut().filter(|r| !modifications_consistent(r)) {
+        n += 1;
+        if strip {
+            for t in [b"MM", b"ML", b"Mm", b"Ml", b"MN"] {
+                let _ = r.remove_aux(t);
+            }
+        }
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(seq: &[u8], mm: &str, ml: &[u8]) -> Record {
+        let mut r = Record::new();
+        r.set(b"r0", None, seq, &vec![30; seq.len()]);
+        r.push_aux(b"MM", Aux::String(mm)).unwrap();
+        r.push_aux(b"ML", Aux::ArrayU8(ml.into())).unwrap();
+        r
+    }
+
+    #[test]
+    fn calls_must_fit_the_sequence() {
+        assert!(modifications_consistent(&record(b"ACGCA", "C+m,0,1;", &[200, 100])));
+        // one probability short, and a call past the last C
+        assert!(!modifications_consistent(&record(b"ACGCA", "C+m,0,1;", &[200])));
+        assert!(!modifications_consistent(&record(b"ACGCA", "C+m,0,2;", &[200, 100])));
+    }
+
+    #[test]
+    fn records_without_a_sequence_are_left_alone() {
+        let mut rs = vec![record(b"", "C+m,0,1;", &[200, 100])];
+        rs[0].set_secondary();
+        assert!(modifications_consistent(&rs[0]));
+        assert_eq!(check_modifications(&mut rs, true), 0);
+        assert!(rs[0].aux(b"MM").is_ok());
+    }
+}
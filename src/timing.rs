@@ -0,0 +1,128 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::collections::HashMap;
+use chrono::DateTime;
+use rust_htslib::{bam, bam::Read, bam::Record, bam::record::Aux};
+use log::error;
+
+use crate::ospath;
+use crate::status::{self, Code};
+
+// a window of sequencing time, in seconds since the start of the run, as given to --time-range
+pub struct TimeRange {
+    lo: f64,
+    hi: f64,
+    // milliseconds since the epoch of the earliest read of every run (RG), found by a pass over the inputs
+    pub starts: HashMap<Vec<u8>, i64>,
+}
+
+// a duration such as 90, 30m, 6h or 1.5d, in seconds
+pub fn parse_duration(s: &str) -> Option<f64> {
+    let (x, unit) = match s.char_indices().last()? {
+        (i, 's') => (&s[..i], 1.0),
+        (i, 'm') => (&s[..i], 60.0),
+        (i, 'h') => (&s[..i], 3600.0),
+        (i, 'd') => (&s[..i], 86400.0),
+        _ => (s, 1.0),
+    };
+    // f64 takes inf and NaN too, and 1e300d is past any Duration
+    x.parse::<f64>().ok().map(|x| x * unit).filter(|x| x.is_finite() && *x >= 0.0)
+}
+
+impl TimeRange {
+    // START..END, either of which may be left out for an open end, e.g. 0..6h, 1h..2h, ..30m
+    pub fn parse(s: &str) -> Option<TimeRange> {
+        let (lo, hi) = s.split_once("..")?;
+        let lo = match lo {
+            "" => 0.0,
+            _ => parse_duration(lo)?,
+        };
+        let hi = match hi {
+            "" => f64::INFINITY,
+            _ => parse_duration(hi)?,
+        };
+        if lo >= hi {
+            return None;
+        }
+        Some(TimeRange {
+            lo,
+            hi,
+            starts: HashMap::new(),
+        })
+    }
+
+    // whether the template started sequencing within the window; templates without a start time never do
+    pub fn admits(&self, rs: &[Record]) -> bool {
+        let (rg, t) = match rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary()).find_map(|r| start_time(r).map(|t| (run(r), t))) {
+            Some(a) => a,
+            None => return false,
+        };
+        match self.starts.get(&rg) {
+            Some(&t0) => {
+                let dt = (t - t0) as f64 / 1000.0;
+                self.lo <= dt && dt < self.hi
+            },
+            None => false,
+        }
+    }
+}
+
+// the st tag of a nanopore read, an ISO 8601 time, in milliseconds since the epoch
+fn start_time(r: &Record) -> Option<i64> {
+    match r.aux(b"st") {
+        Ok(Aux::String(s)) => DateTime::parse_from_rfc3339(s).ok().map(|t| t.timestamp_millis()),
+        _ => None,
+    }
+}
+
+fn run(r: &Record) -> Vec<u8> {
+    match r.aux(b"RG") {
+        Ok(Aux::String(s)) => s.as_bytes().to_vec(),
+        _ => Vec::new(),
+    }
+}
+
+// the earliest start time of every run (RG) across the inputs, which --time-range counts from
+pub fn run_starts(infiles: &[String]) -> HashMap<Vec<u8>, i64> {
+    let mut starts: HashMap<Vec<u8>, i64> = HashMap::new();
+    for infile in infiles {
+        let mut fh = match bam::Reader::from_path(ospath::path(infile)) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("failed to read {}: {}", infile, e);
+                status::fail(Code::Malformed);
+            },
+        };
+        let mut r = Record::new();
+        while let Some(res) = fh.read(&mut r) {
+            if let Err(e) = res {
+                error!("empty record: {}", e);
+                status::fail(Code::Malformed);
+            }
+            if let Some(t) = start_time(&r) {
+                let t0 = starts.entry(run(&r)).or_insert(t);
+                *t0 = (*t0).min(t);
+            }
+        }
+    }
+    starts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_are_finite_seconds() {
+        assert_eq!(parse_duration("90"), Some(90.0));
+        assert_eq!(parse_duration("30m"), Some(1800.0));
+        assert_eq!(parse_duration("1.5d"), Some(129_600.0));
+        for bad in ["", "h", "-1h", "inf", "infh", "NaN", "1e308d"] {
+            assert_eq!(parse_duration(bad), None, "{}", bad);
+        }
+    }
+}
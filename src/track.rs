@@ -0,0 +1,110 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use rust_htslib::bgzf;
+use rust_htslib::bam::HeaderView;
+use log::{info, warn};
+
+use crate::ospath;
+
+// probabilities of keeping a template over intervals of the genome, or the depths to thin them down to,
+// 0-based half-open and sorted by start, by reference name and, once the header is known, by tid
+pub struct Track {
+    by_name: HashMap<String, Vec<(i64, i64, f64)>>,
+    by_tid: Vec<Vec<(i64, i64, f64)>>,
+}
+
+impl Track {
+    // a BED with the probability as its score (5th column), or a bedGraph-like TSV with it as the 4th,
+    // bgzipped or not; scores over 1 are taken for BED's 0-1000 scale, for the whole file
+    pub fn from_file(path: &str) -> Result<Track, String> {
+        let mut by_name = read(path, "a probability", |n| if n == 4 { 3 } else { 4 }, 1000.0)?;
+        let most = by_name.values().flatten().map(|x| x.2).fold(0.0, f64::max);
+        if most > 1.0 {
+            info!("{} has scores over 1, read as 0-1000.", path);
+            by_name.values_mut().flatten().for_each(|x| x.2 /= 1000.0);
+        }
+        Ok(Track { by_name, by_tid: Vec::new() })
+    }
+
+    // target depths per --target-depths, in the last column, e.g. the mean depths of mosdepth's
+    // regions.bed.gz of another sample, with or without a name before them
+    pub fn depths_from_file(path: &str) -> Result<Track, String> {
+        let by_name = read(path, "a depth", |n| n - 1, f64::INFINITY)?;
+        Ok(Track { by_name, by_tid: Vec::new() })
+    }
+
+    // find the references of the intervals in `header`
+    pub fn resolve(&mut self, header: &HeaderView) {
+        self.by_tid = vec![Vec::new(); header.target_count() as usize];
+        let mut missing = 0;
+        for (name, v) in &self.by_name {
+            match header.tid(name.as_bytes()) {
+                Some(tid) => self.by_tid[tid as usize] = v.clone(),
+                None => missing += 1,
+            }
+        }
+        if missing > 0 {
+            warn!("{} references of the track are not in the header, ignored.", missing);
+        }
+    }
+
+    // the probability of the last interval starting at or before `pos`, if it holds `pos` (intervals
+    // are not expected to overlap); None off the track
+    pub fn at(&self, tid: i32, pos: i64) -> Option<f64> {
+        self.index(tid, pos).map(|i| self.by_tid[tid as usize][i].2)
+    }
+
+    // which interval of reference `tid` holds `pos`, as `at` finds it
+    pub fn index(&self, tid: i32, pos: i64) -> Option<usize> {
+        if tid < 0 {
+            return None;
+        }
+        let v = self.by_tid.get(tid as usize)?;
+        let i = v.partition_point(|x| x.0 <= pos);
+        v[..i].last().filter(|x| pos < x.1).map(|_| i - 1)
+    }
+
+    // the intervals of reference `tid`: start, end and value
+    pub fn intervals(&self, tid: i32) -> &[(i64, i64, f64)] {
+        match tid < 0 {
+            true => &[],
+            false => self.by_tid.get(tid as usize).map_or(&[], |v| &v[..]),
+        }
+    }
+}
+
+// the intervals of a BED-like file, bgzipped or not, sorted by start per reference, with their value
+// from the column `column` picks given the number of columns, `what` it is, from 0 to `most`
+fn read(path: &str, what: &str, column: fn(usize) -> usize, most: f64) -> Result<HashMap<String, Vec<(i64, i64, f64)>>, String> {
+    let fh = bgzf::Reader::from_path(ospath::path(path)).map_err(|e| format!("failed to read {}: {}", path, e))?;
+    let mut by_name: HashMap<String, Vec<(i64, i64, f64)>> = HashMap::new();
+    for (i, line) in BufReader::new(fh).lines().enumerate() {
+        let line = line.map_err(|e| format!("failed to read {}: {}", path, e))?;
+        if line.trim().is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let bad = || format!("{}:{} is not chrom, start, end and {}: {}", path, i + 1, what, line);
+        if fields.len() < 4 {
+            return Err(bad());
+        }
+        let start: i64 = fields[1].trim().parse().map_err(|_| bad())?;
+        let end: i64 = fields[2].trim().parse().map_err(|_| bad())?;
+        let x: f64 = fields[column(fields.len())].trim().parse().map_err(|_| bad())?;
+        if start < 0 || end < start || !(0.0..=most).contains(&x) {
+            return Err(bad());
+        }
+        by_name.entry(fields[0].to_string()).or_default().push((start, end, x));
+    }
+    if by_name.is_empty() {
+        return Err(format!("{} has no intervals", path));
+    }
+    by_name.values_mut().for_each(|v| v.sort_by_key(|x| (x.0, x.1)));
+    Ok(by_name)
+}
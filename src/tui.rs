@@ -0,0 +1,251 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::fs;
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::Level;
+use rust_htslib::bam::{HeaderView, Record};
+
+use crate::ospath;
+
+// what the --tui dashboard shows, updated by the reading loops as they go and drawn on stderr by a
+// thread of its own every second, and whenever something is logged
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STARTED: OnceLock<Instant> = OnceLock::new();
+// when the pass under way started, which its ETA goes by
+static PASS: Mutex<Option<Instant>> = Mutex::new(None);
+static TEMPLATES: AtomicU64 = AtomicU64::new(0);
+static RECORDS: AtomicU64 = AtomicU64::new(0);
+// templates passing the filters, and --num if a reservoir holds the sample
+static ADMITTED: AtomicU64 = AtomicU64::new(0);
+static NUM: AtomicU64 = AtomicU64::new(0);
+// bytes of the inputs read so far, by compressed offset, of as many as they add up to; 0 for a pipe
+static DONE: AtomicU64 = AtomicU64::new(0);
+static TOTAL: AtomicU64 = AtomicU64::new(0);
+// where a coordinate pass is, by tid and position, -1 before the first mapped record
+static TID: AtomicI64 = AtomicI64::new(-1);
+static POS: AtomicI64 = AtomicI64::new(-1);
+// the sizes of the inputs, and the names and lengths of the references of a coordinate pass
+static SIZES: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+static REFS: Mutex<Vec<(String, u64)>> = Mutex::new(Vec::new());
+
+// lines of the dashboard on screen, to go back up over, and the last message logged below warnings
+struct Screen {
+    lines: usize,
+    last: String,
+}
+
+static SCREEN: Mutex<Screen> = Mutex::new(Screen { lines: 0, last: String::new() });
+
+// show the dashboard for a run over `infiles`, unless stderr is not a terminal to draw it on
+pub fn start(infiles: &[String]) -> bool {
+    if unsafe { libc::isatty(libc::STDERR_FILENO) } != 1 {
+        return false;
+    }
+    let sizes: Vec<u64> = infiles.iter().map(|f| match fs::metadata(ospath::path(f)) {
+        Ok(m) if m.is_file() => m.len(),
+        _ => 0,
+    }).collect();
+    if sizes.iter().all(|&x| x > 0) {
+        TOTAL.store(sizes.iter().sum(), Ordering::Relaxed);
+    }
+    *SIZES.lock().unwrap() = sizes;
+    STARTED.get_or_init(Instant::now);
+    ENABLED.store(true, Ordering::Relaxed);
+    thread::spawn(|| {
+        while on() {
+            draw(&mut SCREEN.lock().unwrap());
+            thread::sleep(Duration::from_secs(1));
+        }
+    });
+    true
+}
+
+pub fn on() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// draw the dashboard a last time, left on screen, and log to stderr as usual from then on
+pub fn finish() {
+    if !on() {
+        return;
+    }
+    let mut screen = SCREEN.lock().unwrap();
+    draw(&mut screen);
+    ENABLED.store(false, Ordering::Relaxed);
+    screen.lines = 0;
+}
+
+// a line the logger would have written to stderr: warnings and errors go above the dashboard, to stay,
+// the rest only in it until the next
+pub fn message(level: Level, line: &str) {
+    let mut screen = SCREEN.lock().unwrap();
+    match level {
+        Level::Error | Level::Warn => {
+            clear(&mut screen);
+            eprintln!("{}", line);
+        },
+        _ => screen.last = line.to_string(),
+    }
+    draw(&mut screen);
+}
+
+// a pass over the inputs starting, from their first byte and record
+pub fn pass() {
+    if on() {
+        *PASS.lock().unwrap() = Some(Instant::now());
+        DONE.store(0, Ordering::Relaxed);
+        TID.store(-1, Ordering::Relaxed);
+        POS.store(-1, Ordering::Relaxed);
+    }
+}
+
+// a template read by a pass over inputs grouped by qname, of `records` records
+pub fn template(records: usize) {
+    if on() {
+        TEMPLATES.fetch_add(1, Ordering::Relaxed);
+        RECORDS.fetch_add(records as u64, Ordering::Relaxed);
+    }
+}
+
+// how far into the `i`th input a pass is, by the virtual offset of its next record
+pub fn offset(i: usize, voffset: i64) {
+    if on() {
+        let before: u64 = SIZES.lock().unwrap().iter().take(i).sum();
+        DONE.store(before + (voffset >> 16) as u64, Ordering::Relaxed);
+    }
+}
+
+// a record read by a pass over a coordinate-sorted input, which goes by where it is
+pub fn record(r: &Record) {
+    if on() {
+        RECORDS.fetch_add(1, Ordering::Relaxed);
+        if r.tid() >= 0 {
+            TID.store(i64::from(r.tid()), Ordering::Relaxed);
+            POS.store(r.pos(), Ordering::Relaxed);
+        }
+    }
+}
+
+// the references of a coordinate-sorted input, for where a pass over it is
+pub fn references(header: &HeaderView) {
+    if on() {
+        *REFS.lock().unwrap() = (0..header.target_count()).map(|tid| {
+            (String::from_utf8_lossy(header.tid2name(tid)).into_owned(), header.target_len(tid).unwrap_or_default())
+        }).collect();
+    }
+}
+
+// a template passing the filters
+pub fn admitted() {
+    if on() {
+        ADMITTED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// the sample is held in a reservoir of `num` templates, which fills up as templates pass the filters
+pub fn reservoir(num: usize) {
+    if on() {
+        NUM.store(num as u64, Ordering::Relaxed);
+        ADMITTED.store(0, Ordering::Relaxed);
+    }
+}
+
+fn hms(secs: f64) -> String {
+    let s = secs.max(0.0).round() as u64;
+    format!("{:02}:{:02}:{:02}", s / 3600, s / 60 % 60, s % 60)
+}
+
+// bytes resident in memory, per /proc where there is one
+fn resident() -> Option<u64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * u64::try_from(page).ok()?)
+}
+
+// the lines of the dashboard as things stand
+fn dashboard() -> Vec<String> {
+    let elapsed = STARTED.get().map_or(0.0, |t| t.elapsed().as_secs_f64());
+    let templates = TEMPLATES.load(Ordering::Relaxed);
+    let records = RECORDS.load(Ordering::Relaxed);
+    let this_pass = PASS.lock().unwrap().map_or(elapsed, |t| t.elapsed().as_secs_f64());
+    let per_sec = |n: u64| if elapsed > 0.0 { n as f64 / elapsed } else { 0.0 };
+    let refs = REFS.lock().unwrap();
+    // how far the pass is, by bytes of the inputs or, going through a coordinate-sorted one, by position
+    // along the genome
+    let (tid, pos) = (TID.load(Ordering::Relaxed), POS.load(Ordering::Relaxed));
+    let genome: u64 = refs.iter().map(|x| x.1).sum();
+    let done = match TOTAL.load(Ordering::Relaxed) {
+        _ if tid >= 0 && genome > 0 => {
+            let before: u64 = refs.iter().take(tid as usize).map(|x| x.1).sum();
+            Some((before + pos.max(0) as u64) as f64 / genome as f64)
+        },
+        0 => None,
+        total => Some(DONE.load(Ordering::Relaxed) as f64 / total as f64),
+    };
+    let mut lines = vec![match done {
+        Some(f) if f > 0.0 => format!("elapsed   {}   {:.1}% of this pass, ETA {}", hms(elapsed), 100.0 * f.min(1.0), hms(this_pass * (1.0 - f.min(1.0)) / f)),
+        _ => format!("elapsed   {}", hms(elapsed)),
+    }];
+    lines.push(match templates {
+        0 => format!("read      {} records, {:.0}/s", records, per_sec(records)),
+        _ => format!("read      {} templates, {:.0}/s, of {} records", templates, per_sec(templates), records),
+    });
+    let (admitted, num) = (ADMITTED.load(Ordering::Relaxed), NUM.load(Ordering::Relaxed));
+    if num > 0 {
+        lines.push(format!("reservoir {} of {} ({:.1}%), of {} passing the filters", admitted.min(num), num, 100.0 * admitted.min(num) as f64 / num as f64, admitted));
+    } else if admitted > 0 {
+        lines.push(format!("passing   {} templates", admitted));
+    }
+    if let Some(bytes) = resident() {
+        lines.push(format!("memory    {} resident", crate::units::format_size(bytes as usize)));
+    }
+    if let Some((name, len)) = refs.get(tid.max(0) as usize).filter(|_| tid >= 0) {
+        lines.push(format!("position  {}:{} ({:.1}% of it, reference {} of {})", name, pos + 1, 100.0 * pos as f64 / (*len).max(1) as f64, tid + 1, refs.len()));
+    }
+    lines
+}
+
+// go back up over the dashboard and clear it
+fn clear(screen: &mut Screen) {
+    let mut err = std::io::stderr().lock();
+    if screen.lines > 0 {
+        let _ = write!(err, "\x1b[{}F", screen.lines);
+    }
+    let _ = write!(err, "\x1b[J");
+    screen.lines = 0;
+}
+
+// columns of the terminal, which lines are cut to so that every one takes up a row of it
+fn width() -> usize {
+    let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+    match unsafe { libc::ioctl(libc::STDERR_FILENO, libc::TIOCGWINSZ, &mut ws) } {
+        0 if ws.ws_col > 0 => usize::from(ws.ws_col),
+        _ => 80,
+    }
+}
+
+fn draw(screen: &mut Screen) {
+    if !on() {
+        return;
+    }
+    let mut lines = dashboard();
+    if !screen.last.is_empty() {
+        lines.push(screen.last.clone());
+    }
+    clear(screen);
+    let mut err = std::io::stderr().lock();
+    let cols = width();
+    let text: String = lines.iter().map(|l| format!("{}\n", l.chars().take(cols).collect::<String>())).collect();
+    let _ = err.write_all(text.as_bytes());
+    let _ = err.flush();
+    screen.lines = lines.len();
+}
@@ -0,0 +1,94 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use rust_htslib::bam::{HeaderView, Record};
+
+// aligned bases falling into each step of every reference, for the mean depth over a window sliding
+// along with where it is asked at; a step is an eighth of the window, so the window is centred on the
+// position to within a sixteenth of it
+pub struct Coverage {
+    window: usize,
+    step: usize,
+    lens: Vec<usize>,
+    bases: Vec<Vec<u64>>,
+}
+
+impl Coverage {
+    pub fn new(header: &HeaderView, window: usize) -> Coverage {
+        let step = (window / 8).max(1);
+        let lens: Vec<usize> = (0..header.target_count()).map(|tid| header.target_len(tid).unwrap_or_default() as usize).collect();
+        let bases = lens.iter().map(|&len| vec![0; len.div_ceil(step).max(1)]).collect();
+        Coverage {
+            window,
+            step,
+            lens,
+            bases,
+        }
+    }
+
+    // spread the reference span of a primary alignment over the steps it overlaps
+    pub fn add(&mut self, r: &Record) {
+        if r.is_unmapped() || r.is_secondary() || r.is_supplementary() || r.tid() < 0 {
+            return;
+        }
+        let steps = &mut self.bases[r.tid() as usize];
+        let (start, end) = (r.pos() as usize, r.cigar().end_pos() as usize);
+        let mut x = start;
+        while x < end {
+            let w = x / self.step;
+            let next = ((w + 1) * self.step).min(end);
+            if let Some(b) = steps.get_mut(w) {
+                *b += (next - x) as u64;
+            }
+            x = next;
+        }
+    }
+
+    // mean depth of the window centred on `pos`, cut short at the ends of the reference
+    pub fn depth(&self, tid: i32, pos: i64) -> f64 {
+        let (steps, len) = match (self.bases.get(tid as usize), self.lens.get(tid as usize)) {
+            (Some(steps), Some(&len)) if tid >= 0 => (steps, len.max(1)),
+            _ => return 0.0,
+        };
+        let half = self.window / 2;
+        let pos = pos.max(0) as usize;
+        let lo = pos.saturating_sub(half) / self.step;
+        let hi = (pos + half).max(pos + 1).div_ceil(self.step).min(steps.len());
+        if lo >= hi {
+            return 0.0;
+        }
+        let span = (hi * self.step).min(len).saturating_sub(lo * self.step).max(1);
+        steps[lo..hi].iter().sum::<u64>() as f64 / span as f64
+    }
+}
+
+// where the template of a record is anchored: the leftmost of the read and its mate when both sit on
+// the same reference, so that mates land in the same window
+pub fn anchor(r: &Record) -> (i32, i64) {
+    if r.is_paired() && !r.is_mate_unmapped() && r.mtid() == r.tid() && r.mpos() >= 0 {
+        (r.tid(), r.pos().min(r.mpos()))
+    } else {
+        (r.tid(), r.pos())
+    }
+}
+
+// a hash of the qname and the seed: FNV-1a over the qname, then a splitmix64 finalizer to spread the bits
+pub fn hash(qname: &[u8], seed: u64) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325 ^ seed;
+    for &b in qname {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+// a number in [0, 1) that depends only on the qname and the seed, so that every record of a template
+// draws the same one
+pub fn draw(qname: &[u8], seed: u64) -> f64 {
+    (hash(qname, seed) >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,127 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::convert::TryFrom;
+
+// split a number like "2.5M" into a fraction, 25 * 1000^2 / 10, with `base` the step between
+// suffixes K, M, G, T; the digits are kept as integers so 2.5M is exactly 2500000
+fn parse_scaled(s: &str, base: u128) -> Option<(u128, u128)> {
+    let s = s.trim();
+    let (x, power) = match s.chars().last()?.to_ascii_uppercase() {
+        'K' => (&s[..s.len() - 1], 1),
+        'M' => (&s[..s.len() - 1], 2),
+        'G' => (&s[..s.len() - 1], 3),
+        'T' => (&s[..s.len() - 1], 4),
+        _ => (s, 0),
+    };
+    let (int, frac) = x.split_once('.').unwrap_or((x, ""));
+    if int.is_empty() && frac.is_empty() {
+        return None;
+    }
+    if !int.chars().chain(frac.chars()).all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let digits: u128 = format!("{}{}", int, frac).parse().ok()?;
+    let num = digits.checked_mul(base.checked_pow(power)?)?;
+    let den = 10u128.checked_pow(frac.len() as u32)?;
+    Some((num, den))
+}
+
+// a count with an optional K/M/G/T suffix in powers of 1000, e.g. 5k or 2.5M; must come out whole
+pub fn parse_count(s: &str) -> Option<usize> {
+    let (num, den) = parse_scaled(s, 1000)?;
+    if num % den != 0 {
+        return None;
+    }
+    usize::try_from(num / den).ok()
+}
+
+// a number of bytes with an optional K/M/G/T suffix in powers of 1024, e.g. 512M or 8G; a trailing B
+// is allowed
+pub fn parse_size(s: &str) -> Option<usize> {
+    let s = s.trim();
+    let s = s.strip_suffix(['B', 'b']).unwrap_or(s);
+    let (num, den) = parse_scaled(s, 1024)?;
+    usize::try_from(num / den).ok()
+}
+
+// a number of bytes as parse_size takes it, with the largest suffix it comes to at least one of, e.g. 1.5G
+pub fn format_size(bytes: usize) -> String {
+    let mut x = bytes as f64;
+    for suffix in ["", "K", "M", "G"] {
+        if x < 1024.0 {
+            return match suffix {
+                "" => format!("{}", bytes),
+                _ => format!("{:.1}{}", x, suffix),
+            };
+        }
+        x /= 1024.0;
+    }
+    format!("{:.1}T", x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_take_suffixes_in_thousands() {
+        assert_eq!(parse_count("5000"), Some(5000));
+        assert_eq!(parse_count("5k"), Some(5000));
+        assert_eq!(parse_count("2.5M"), Some(2_500_000));
+        assert_eq!(parse_count("1.5K"), Some(1500));
+        assert_eq!(parse_count(" 3G "), Some(3_000_000_000));
+        assert_eq!(parse_count("1T"), Some(1_000_000_000_000));
+        assert_eq!(parse_count(".5K"), Some(500));
+        // not whole
+        assert_eq!(parse_count("1.5"), None);
+        assert_eq!(parse_count("1.0005K"), None);
+        assert_eq!(parse_count(""), None);
+        assert_eq!(parse_count("K"), None);
+        assert_eq!(parse_count("."), None);
+        assert_eq!(parse_count("-1"), None);
+        assert_eq!(parse_count("1e3"), None);
+        assert_eq!(parse_count("2X"), None);
+    }
+
+    #[test]
+    fn sizes_take_suffixes_in_1024s() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("1K"), Some(1024));
+        assert_eq!(parse_size("1.5K"), Some(1536));
+        assert_eq!(parse_size("512M"), Some(512 << 20));
+        assert_eq!(parse_size("8gb"), Some(8 << 30));
+        assert_eq!(parse_size("2TB"), Some(2 << 40));
+        // a fraction of a byte is let go
+        assert_eq!(parse_size("1.0001K"), Some(1024));
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("K"), None);
+        assert_eq!(parse_size("B"), None);
+    }
+
+    #[test]
+    fn overflow_is_none() {
+        assert_eq!(parse_count("99999999999999999999T"), None);
+        assert_eq!(parse_size("99999999999999999999T"), None);
+        // past what u128 holds, in the digits or scaled
+        assert_eq!(parse_count(&"9".repeat(40)), None);
+        assert_eq!(parse_count(&format!("{}T", "9".repeat(30))), None);
+        assert_eq!(parse_count(&format!("1.{}", "0".repeat(40))), None);
+    }
+
+    #[test]
+    fn sizes_format_as_they_parse() {
+        assert_eq!(format_size(0), "0");
+        assert_eq!(format_size(1023), "1023");
+        assert_eq!(format_size(1536), "1.5K");
+        assert_eq!(format_size(512 << 20), "512.0M");
+        assert_eq!(format_size(3 << 40), "3.0T");
+        assert_eq!(format_size(5 << 50), "5120.0T");
+        for bytes in [1536, 3 << 29, 7 << 40] {
+            assert_eq!(parse_size(&format_size(bytes)), Some(bytes));
+        }
+    }
+}
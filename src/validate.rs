@@ -0,0 +1,180 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Read as _, Seek, SeekFrom};
+use rust_htslib::{bam, bam::Read, bam::Record};
+
+use crate::ospath;
+use crate::{group_key, GroupBy};
+
+// the BGZF EOF marker closing every BAM, an empty block; a BAM cut short lacks it
+static EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43,
+    0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+fn has_eof(path: &str) -> Result<bool, String> {
+    let mut fh = File::open(ospath::path(path)).map_err(|e| format!("failed to read it: {}", e))?;
+    let mut tail = [0; 28];
+    match fh.metadata().map(|m| m.len()) {
+        Ok(len) if len >= EOF.len() as u64 => {},
+        _ => return Ok(false),
+    }
+    fh.seek(SeekFrom::End(-(EOF.len() as i64))).and_then(|_| fh.read_exact(&mut tail)).map_err(|e| format!("failed to read it: {}", e))?;
+    Ok(tail == EOF)
+}
+
+// BGZF blocks from the start of `fh` to its end, `len` bytes on: each a gzip member with the BC
+// subfield giving its size, the last one ending right at the end
+fn walk_blocks(fh: File, len: u64) -> Result<usize, String> {
+    let mut fh = BufReader::new(fh);
+    let (mut at, mut n) = (0u64, 0usize);
+    while at < len {
+        let broken = |what: &str| format!("BGZF block {} at byte {} {}", n + 1, at, what);
+        let mut head = [0u8; 12];
+        fh.read_exact(&mut head).map_err(|_| broken("is cut short"))?;
+        if head[..4] != [0x1f, 0x8b, 0x08, 0x04] {
+            return Err(broken("has no BGZF header"));
+        }
+        let xlen = u16::from_le_bytes([head[10], head[11]]) as usize;
+        let mut extra = vec![0u8; xlen];
+        fh.read_exact(&mut extra).map_err(|_| broken("is cut short"))?;
+        // subfields are SI1, SI2, SLEN and SLEN bytes of data; BC holds the block size less one
+        let mut size = None;
+        let mut i = 0;
+        while i + 4 <= xlen {
+            let slen = u16::from_le_bytes([extra[i + 2], extra[i + 3]]) as usize;
+            if extra[i..i + 2] == *b"BC" && slen == 2 && i + 6 <= xlen {
+                size = Some(u16::from_le_bytes([extra[i + 4], extra[i + 5]]) as u64 + 1);
+            }
+            i += 4 + slen;
+        }
+        let size = size.ok_or_else(|| broken("has no BC subfield"))?;
+        if size < 12 + xlen as u64 || at + size > len {
+            return Err(broken(&format!("of {} bytes runs past the end", size)));
+        }
+        fh.seek_relative((size - 12 - xlen as u64) as i64).map_err(|e| format!("failed to read it: {}", e))?;
+        at += size;
+        n += 1;
+    }
+    Ok(n)
+}
+
+// check an input before it is sampled, so that a truncated upload is turned down at once rather than
+// hours into a run: that it ends with the EOF block and, with `blocks`, that its BGZF blocks follow one
+// another to its end; the blocks walked, if any. A plain or gzipped SAM, which is not BGZF, is not checked
+pub fn check_input(path: &str, blocks: bool) -> Result<Option<usize>, String> {
+    let mut fh = File::open(ospath::path(path)).map_err(|e| format!("failed to read it: {}", e))?;
+    let len = fh.metadata().map_err(|e| format!("failed to read it: {}", e))?.len();
+    let mut head = [0u8; 16];
+    if fh.read_exact(&mut head).is_err() || head[..4] != [0x1f, 0x8b, 0x08, 0x04] || head[12..14] != *b"BC" {
+        return Ok(None);
+    }
+    if !has_eof(path)? {
+        return Err(String::from("no BGZF EOF block at its end, so it was cut short"));
+    }
+    if !blocks {
+        return Ok(Some(0));
+    }
+    fh.seek(SeekFrom::Start(0)).map_err(|e| format!("failed to read it: {}", e))?;
+    walk_blocks(fh, len).map(Some)
+}
+
+// the SO of the @HD of a header, if any
+fn sort_order(view: &bam::HeaderView) -> String {
+    let text = String::from_utf8_lossy(view.as_bytes()).into_owned();
+    text.split('\n').filter(|l| l.starts_with("@HD")).flat_map(|l| l.split('\t')).find_map(|f| f.strip_prefix("SO:")).unwrap_or_default().to_string()
+}
+
+// the paired primary records of a template whose mate has no primary record in it
+pub fn mateless(rs: &[Record]) -> usize {
+    let primary = |r: &&Record| !r.is_secondary() && !r.is_supplementary();
+    rs.iter().filter(primary).filter(|r| r.is_paired()).filter(|r| {
+        !rs.iter().filter(primary).any(|m| m.is_paired() && m.qname() == r.qname() && m.is_first_in_template() != r.is_first_in_template())
+    }).count()
+}
+
+// the primary first mates, last mates and single reads of every qname of a template; more than one of
+// any is a record written twice
+fn check_primaries(rs: &[Record]) -> Result<(), String> {
+    let mut seen: HashMap<(&[u8], u8), usize> = HashMap::new();
+    for r in rs.iter().filter(|r| !r.is_secondary() && !r.is_supplementary()) {
+        let mate = match (r.is_paired(), r.is_first_in_template()) {
+            (false, _) => 0,
+            (true, true) => 1,
+            (true, false) => 2,
+        };
+        let n = seen.entry((r.qname(), mate)).or_default();
+        *n += 1;
+        if *n > 1 {
+            let which = ["read", "first mate", "last mate"][mate as usize];
+            return Err(format!("{} has more than one primary {}", String::from_utf8_lossy(r.qname()), which));
+        }
+    }
+    Ok(())
+}
+
+// what an output read back holds: its templates, and, where they are together, the primary records
+// of pairs whose mate is not (see `mateless`), for the caller to check against those it wrote
+pub struct Found {
+    pub templates: usize,
+    pub mateless: Option<usize>,
+}
+
+// read an output back, checking that it ends with the EOF block, that every record decodes, that the
+// records are in the order of its @HD SO, and, unless sorted by coordinate or sampled per read, that the
+// records of a template (by `group_by`) are together, with a primary record per mate at most
+pub fn check(path: &str, group_by: GroupBy) -> Result<Found, String> {
+    if !has_eof(path)? {
+        return Err(String::from("no BGZF EOF block at its end, so it was cut short"));
+    }
+    let mut fh = bam::Reader::from_path(ospath::path(path)).map_err(|e| format!("failed to read it: {}", e))?;
+    let coordinate = sort_order(fh.header()) == "coordinate";
+    let grouped = !coordinate && group_by != GroupBy::Read;
+    let mut keys: HashSet<Vec<u8>> = HashSet::new();
+    let mut template: Vec<Record> = Vec::new();
+    let mut last: (u32, i64) = (0, 0);
+    let mut n: usize = 0;
+    let mut unpaired: usize = 0;
+    let mut r = Record::new();
+    loop {
+        match fh.read(&mut r) {
+            None => break,
+            Some(Ok(())) => {},
+            Some(Err(e)) => return Err(format!("record {} does not decode: {}", n + 1, e)),
+        }
+        n += 1;
+        if coordinate {
+            let at = (r.tid() as u32, r.pos());
+            if at < last {
+                return Err(format!("record {} ({}) is out of coordinate order, as its @HD SO:coordinate has it", n, String::from_utf8_lossy(r.qname())));
+            }
+            last = at;
+        }
+        if !grouped {
+            keys.insert(group_key(r.qname(), group_by).to_vec());
+            continue;
+        }
+        let key = group_key(r.qname(), group_by);
+        if template.first().is_some_and(|t| group_key(t.qname(), group_by) != key) {
+            check_primaries(&template)?;
+            unpaired += mateless(&template);
+            template.clear();
+            if keys.contains(key) {
+                return Err(format!("the records of {} are not together", String::from_utf8_lossy(key)));
+            }
+        }
+        if template.is_empty() {
+            keys.insert(key.to_vec());
+        }
+        template.push(r.clone());
+    }
+    check_primaries(&template)?;
+    unpaired += mateless(&template);
+    Ok(Found { templates: keys.len(), mateless: grouped.then_some(unpaired) })
+}
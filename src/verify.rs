@@ -0,0 +1,188 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::io::Write;
+use getopts::Options;
+use log::{error, info, warn};
+
+use crate::completions::{OptDef, Kind};
+use crate::ospath;
+use crate::reservoir::{self, Reservoir, BottomK};
+use crate::rng::{self, Seed};
+use crate::status::{self, Code, bad_args};
+use crate::{GroupBy, LogFormat};
+
+// the options of `sam_subsample verify`
+static OPTIONS: &[OptDef] = &[
+    ("i", "infile", "input BAM/SAM, sorted by queryname; small, as it is sampled over and over", "FILE", Kind::Opt),
+    ("n", "num", "number of templates per round; K/M/G suffixes allowed (default: 100)", "INTEGER", Kind::Opt),
+    ("", "rounds", "number of rounds, each with its own seed; K/M/G suffixes allowed (default: 1000)", "INTEGER", Kind::Opt),
+    ("", "strategy", "sampler to check: reservoir, two-pass (as with --max-mem) or deterministic (default: reservoir)", "STRING", Kind::Opt),
+    ("s", "seed", "seed of the first round, the next ones counting up from it (default: 43)", "INTEGER", Kind::Opt),
+    ("", "alpha", "significance level below which the sample is called biased (default: 0.001)", "NUMBER", Kind::Opt),
+    ("o", "outfile", "TSV of how many times each template was picked, against how many were expected", "FILE", Kind::Opt),
+    ("h", "help", "print usage", "", Kind::Flag),
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Sampler {
+    Reservoir,
+    TwoPass,
+    Deterministic,
+}
+
+// the complementary error function, to a fractional error under 1.2e-7 (Numerical Recipes' erfcc)
+fn erfc(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.5 * x.abs());
+    let y = t * (-x * x - 1.26551223 + t * (1.00002368 + t * (0.37409196 + t * (0.09678418 + t * (-0.18628806
+        + t * (0.27886807 + t * (-1.13520398 + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277))))))))).exp();
+    if x >= 0.0 { y } else { 2.0 - y }
+}
+
+// P(X > x) for X chi-square with `df` degrees of freedom, by the Wilson-Hilferty approximation,
+// which is close enough for the hundreds of degrees of freedom a template count gives
+fn chi2_sf(x: f64, df: f64) -> f64 {
+    let v = 2.0 / (9.0 * df);
+    let z = ((x / df).cbrt() - (1.0 - v)) / v.sqrt();
+    0.5 * erfc(z / std::f64::consts::SQRT_2)
+}
+
+// the chi-square statistic of how often each of `n` templates was picked, `num` per round over
+// `rounds` rounds, against all of them being equally likely. Picks within a round are without
+// replacement, so a template's count is binomial with p = num / n, and the counts are negatively
+// correlated as they add up to num * rounds; scaled by (1 - p) n / (n - 1), the sum of (O - E)^2 / E
+// is chi-square with n - 1 degrees of freedom
+fn chi2(freqs: &[usize], num: usize, rounds: usize) -> f64 {
+    let n = freqs.len() as f64;
+    let p = num as f64 / n;
+    let e = rounds as f64 * p;
+    let ss: f64 = freqs.iter().map(|&o| (o as f64 - e).powi(2)).sum();
+    ss / (e * (1.0 - p)) * (n - 1.0) / n
+}
+
+fn usage(opts: &Options) {
+    let s = "\
+Summary:
+Subsample a small input over and over with different seeds, and test that every template is as likely to be picked
+
+Usage:
+sam_subsample verify --infile small.bam [--num 100] [--rounds 1000] [--strategy reservoir|two-pass|deterministic] [--seed 43] [--alpha 0.001] [--outfile freqs.tsv]";
+    println!("{}", opts.usage(s));
+}
+
+// `sam_subsample verify`, given the arguments after it
+pub fn run(args: &[String]) {
+    let mut opts = Options::new();
+    for &(short, long, desc, hint, kind) in OPTIONS {
+        match kind {
+            Kind::Flag => opts.optflag(short, long, desc),
+            _ => opts.optopt(short, long, desc, hint),
+        };
+    }
+    let m = opts.parse(args).unwrap_or_else(|e| bad_args(&format!("failed to parse arguments: {}", e)));
+    if m.opt_present("h") {
+        usage(&opts);
+        return;
+    }
+    let infile = m.opt_str("infile").unwrap_or_else(|| bad_args("--infile is required!"));
+    let num = crate::opt_count(&m, "num", 100);
+    let rounds = crate::opt_count(&m, "rounds", 1000);
+    if num == 0 || rounds == 0 {
+        bad_args("--num and --rounds must be positive");
+    }
+    let sampler = match m.opt_str("strategy").as_deref() {
+        None | Some("reservoir") => Sampler::Reservoir,
+        Some("two-pass") => Sampler::TwoPass,
+        Some("deterministic") => Sampler::Deterministic,
+        Some(x) => bad_args(&format!("invalid --strategy {}, must be reservoir, two-pass or deterministic", x)),
+    };
+    let seed = Seed::new(rng::Kind::Pcg64, m.opt_get_default("seed", 43).unwrap_or_else(|_| bad_args("invalid --seed, must be integer")));
+    let alpha: f64 = match m.opt_get_default("alpha", 0.001) {
+        Ok(x) if x > 0.0 && x < 1.0 => x,
+        _ => bad_args("invalid --alpha, must be within 0 and 1"),
+    };
+    crate::init_logger("info", None, None, LogFormat::Plain);
+
+    if !ospath::path(&infile).exists() {
+        error!("{} does not exist!", infile);
+        status::fail(Code::Missing);
+    }
+    let mut infhs = crate::open_inputs(std::slice::from_ref(&infile), 1, "queryname");
+    let (_, tid_maps) = crate::merged_header(&infhs);
+    let mut qnames: Vec<Vec<u8>> = Vec::new();
+    crate::for_each_template(&mut infhs, &tid_maps, None, false, GroupBy::Qname, (0, None), |rs, _| {
+        qnames.push(rs[0].qname().to_vec());
+        Some(rs)
+    });
+    let n = qnames.len();
+    if num >= n {
+        error!("--num {} leaves nothing to choose from the {} templates in {}; it must be fewer.", num, n, infile);
+        status::fail(Code::TooFew);
+    }
+    info!("{} templates in {}; picking {} of them in each of {} rounds.", n, infile, num, rounds);
+
+    // the templates stand in for themselves by their index in the input
+    let mut freqs = vec![0; n];
+    for round in 0..rounds {
+        let seed = seed.nth(round as u64);
+        let picks: Vec<usize> = match sampler {
+            Sampler::Reservoir => {
+                let mut res: Reservoir<usize> = Reservoir::new(num, seed);
+                for i in 0..n {
+                    res.sample(i);
+                }
+                res.v
+            },
+            Sampler::TwoPass => reservoir::pick_indices(n, num, seed),
+            Sampler::Deterministic => {
+                let mut b = BottomK::new(num, seed);
+                for (i, qname) in qnames.iter().enumerate() {
+                    b.offer(qname, i);
+                }
+                b.into_sorted().into_iter().map(|x| x.1).collect()
+            },
+        };
+        for i in picks {
+            freqs[i] += 1;
+        }
+    }
+
+    let expected = (num * rounds) as f64 / n as f64;
+    let x = chi2(&freqs, num, rounds);
+    let p = chi2_sf(x, (n - 1) as f64);
+    let (lo, hi) = (freqs.iter().min().copied().unwrap_or_default(), freqs.iter().max().copied().unwrap_or_default());
+    info!("Each template picked {} to {} times, {:.2} expected.", lo, hi, expected);
+    // templates early and late in the input are where a reservoir off by one would show
+    let edge = (n / 10).max(1);
+    let mean = |v: &[usize]| v.iter().sum::<usize>() as f64 / v.len() as f64;
+    info!("First {} templates picked {:.2} times on average, last {} {:.2}.", edge, mean(&freqs[..edge]), edge, mean(&freqs[n - edge..]));
+    if expected < 5.0 {
+        warn!("Fewer than 5 picks expected per template; the chi-square test is unreliable, add --rounds.");
+    }
+    println!("templates\t{}", n);
+    println!("num\t{}", num);
+    println!("rounds\t{}", rounds);
+    println!("chi_square\t{:.4}", x);
+    println!("df\t{}", n - 1);
+    println!("p_value\t{:.6}", p);
+
+    if let Some(outfile) = m.opt_str("outfile") {
+        let mut fh = crate::open_text(&outfile);
+        let mut res = writeln!(fh, "qname\tpicked\texpected");
+        for (qname, o) in qnames.iter().zip(&freqs) {
+            res = res.and_then(|_| writeln!(fh, "{}\t{}\t{:.2}", String::from_utf8_lossy(qname), o, expected));
+        }
+        if let Err(e) = res.and_then(|_| fh.flush()) {
+            error!("failed to write {}: {}", outfile, e);
+            status::fail(Code::Io);
+        }
+    }
+    if p < alpha {
+        error!("Selection is not uniform: chi-square {:.2} on {} degrees of freedom, p = {:.3e} < {}.", x, n - 1, p, alpha);
+        status::fail(Code::Biased);
+    }
+    info!("Selection is consistent with uniform (p = {:.4}).", p);
+}
@@ -0,0 +1,28 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Youtao Lu@Kim Lab, 2016-2020
+
+use std::fs;
+use std::process::Command;
+
+// a run that reads its input through well before --time-limit is up finishes as one without it: the
+// sample and the state written, and exit code 0 rather than 11
+#[test]
+fn a_run_done_before_the_limit_exits_0() {
+    let dir = std::env::temp_dir().join(format!("sam_subsample.time_limit.{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (outfile, state) = (dir.join("out.bam"), dir.join("state.bam"));
+    let output = Command::new(env!("CARGO_BIN_EXE_sam_subsample"))
+        .args(["--infile", concat!(env!("CARGO_MANIFEST_DIR"), "/test/input.bam"), "--num", "10", "--seed", "43", "--time-limit", "1h"])
+        .arg("--outfile").arg(&outfile)
+        .arg("--save-state").arg(&state)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr);
+    assert!(!stderr.contains("--time-limit reached"), "{}", stderr);
+    assert!(outfile.exists() && state.exists());
+    fs::remove_dir_all(&dir).unwrap();
+}